@@ -0,0 +1,128 @@
+//! Measures how many instructions per second the interpreter executes.
+//!
+//! With no arguments this runs a built in loop of loads, stores and arithmetic. Otherwise each
+//! argument is an ELF with a `tohost` symbol, such as the `rv64ui-p-*` binaries from riscv-tests,
+//! which is run to completion repeatedly:
+//!
+//! ```text
+//! cargo bench --bench mips -- riscv-tests/isa/rv64ui-p-*
+//! ```
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use riscv::{device::Device, htif::Htif, mem::RAM_BASE, Emulator};
+
+/// Loops a million times over a word and a double word load and store.
+const LOOP: &[u32] = &[
+    0x00100293, // li t0, 1
+    0x01f29293, // slli t0, t0, 31
+    0x40028313, // addi t1, t0, 1024
+    0x000f4e37, // lui t3, 244
+    0x240e0e1b, // addiw t3, t3, 576
+    0x00032383, // loop: lw t2, 0(t1)
+    0x00138393, // addi t2, t2, 1
+    0x00732023, // sw t2, 0(t1)
+    0x00833e83, // ld t4, 8(t1)
+    0x007e8eb3, // add t4, t4, t2
+    0x01d33423, // sd t4, 8(t1)
+    0xfffe0e13, // addi t3, t3, -1
+    0xfe0e12e3, // bnez t3, loop
+    0x7ff28293, // addi t0, t0, 2047
+    0x7ff28293, // addi t0, t0, 2047
+    0x00228293, // addi t0, t0, 2
+    0x00100313, // li t1, 1
+    0x0062a023, // sw t1, 0(t0)
+    0x0000006f, // j .
+];
+const LOOP_TOHOST: usize = RAM_BASE + 0x1000;
+
+/// Give up on a program that hasn't finished after this many cycles.
+const MAX_CYCLES: u64 = 1 << 32;
+/// Keep rerunning each program until at least this much time has been spent executing it.
+const MIN_TIME: Duration = Duration::from_millis(500);
+
+/// Run until the program writes to tohost, returning the number of cycles executed.
+fn run(emu: &mut Emulator, tohost: &RefCell<dyn Device>) -> Option<u64> {
+    let mut cycles = 0;
+    while tohost.borrow().exit_code().is_none() {
+        emu.cycle();
+        cycles += 1;
+        if cycles == MAX_CYCLES {
+            return None;
+        }
+    }
+    Some(cycles)
+}
+
+/// Repeatedly set up an emulator with `setup` and run it, returning the total cycles executed and
+/// the time spent executing them.
+fn measure(setup: impl Fn() -> (Emulator, Rc<RefCell<dyn Device>>)) -> Option<(u64, Duration)> {
+    let mut cycles = 0;
+    let mut time = Duration::ZERO;
+    while time < MIN_TIME {
+        let (mut emu, tohost) = setup();
+        let start = Instant::now();
+        cycles += run(&mut emu, &tohost)?;
+        time += start.elapsed();
+    }
+    Some((cycles, time))
+}
+
+fn with_htif(mut emu: Emulator, tohost: usize) -> (Emulator, Rc<RefCell<dyn Device>>) {
+    let mut devices = Htif::new(Vec::new(), None).into_devices(tohost, None);
+    let tohost = devices.remove(0);
+    emu.add_device(tohost.clone());
+    (emu, tohost)
+}
+
+fn main() {
+    // cargo passes --bench, and any other flags are for the libtest harness we don't use
+    let paths: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+
+    let mut total_cycles = 0;
+    let mut total_time = Duration::ZERO;
+    let mut report = |name: &str, result: Option<(u64, Duration)>| match result {
+        Some((cycles, time)) => {
+            let mips = cycles as f64 / time.as_secs_f64() / 1e6;
+            println!("{name}: {mips:.2} MIPS");
+            total_cycles += cycles;
+            total_time += time;
+        }
+        None => println!("{name}: did not finish"),
+    };
+
+    if paths.is_empty() {
+        report(
+            "loop",
+            measure(|| {
+                let mut emu = Emulator::new(128 * 1024 * 1024);
+                let program: Vec<u8> = LOOP.iter().flat_map(|i| i.to_le_bytes()).collect();
+                emu.write_bytes(RAM_BASE, &program).unwrap();
+                emu.set_pc(RAM_BASE as u64);
+                with_htif(emu, LOOP_TOHOST)
+            }),
+        );
+    }
+
+    for path in &paths {
+        report(
+            path,
+            measure(|| {
+                let mut emu = Emulator::new(128 * 1024 * 1024);
+                let elf = emu.load_binary(path).unwrap();
+                let tohost = elf.get_symbol("tohost").expect("no tohost symbol").value;
+                with_htif(emu, tohost)
+            }),
+        );
+    }
+
+    if paths.len() > 1 {
+        let mips = total_cycles as f64 / total_time.as_secs_f64() / 1e6;
+        println!("total: {mips:.2} MIPS");
+    }
+}
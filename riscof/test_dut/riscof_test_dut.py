@@ -106,6 +106,10 @@ class test_dut(pluginTemplate):
       if "C" in ispec["ISA"]:
           self.isa += 'c'
 
+      # The emulator understands riscv-config ISA strings directly, so pass the one from the isa
+      # yaml through unchanged. This keeps the emulated core in sync with the yaml.
+      self.dut_isa = ispec["ISA"]
+
       #TODO: The following assumes you are using the riscv-gcc toolchain. If
       #      not please change appropriately
       self.compile_cmd = self.compile_cmd+' -mabi='+('lp64 ' if 64 in ispec['supported_xlen'] else 'ilp32 ')
@@ -160,7 +164,7 @@ class test_dut(pluginTemplate):
             # set up the simulation command. Template is for spike. Please change.
             # TODO
             #simcmd = self.dut_exe + ' --isa={0} +signature={1} +signature-granularity=4 {2}'.format(self.isa, sig_file, elf)
-            simcmd = self.dut_exe + ' --signature {0} {1}'.format(sig_file, elf)
+            simcmd = self.dut_exe + ' --isa {0} --signature {1} {2}'.format(self.dut_isa, sig_file, elf)
           else:
             simcmd = 'echo "NO RUN"'
 
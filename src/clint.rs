@@ -0,0 +1,105 @@
+//! The core-local interruptor (CLINT) of the default memory map, which holds the machine timer.
+//!
+//! Only `mtimecmp`, at offset `0x4000`, and `mtime`, at offset `0x8000`, are implemented. Both are
+//! 64 bits wide, and can be accessed at any width that lies within them. `mtime` only changes when
+//! the guest writes it or the host sets it with [`Emulator::set_mtime`](crate::Emulator::set_mtime),
+//! and it reaching `mtimecmp` doesn't raise the timer interrupt.
+//!
+//! The timer is part of the emulator whether or not the CLINT is mapped, since the `time` csr
+//! reads `mtime` too, so it is saved in snapshots along with the csrs rather than as a device.
+
+use std::ops::Range;
+
+use crate::device::{mask, AccessType, BusError, Device, DeviceRegister};
+use crate::mem::{CLINT_BASE, CLINT_SIZE};
+
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0x8000;
+
+const REGISTERS: &[DeviceRegister] = &[
+    DeviceRegister {
+        offset: MTIMECMP,
+        size: 8,
+        access_type: AccessType::ReadWrite,
+    },
+    DeviceRegister {
+        offset: MTIME,
+        size: 8,
+        access_type: AccessType::ReadWrite,
+    },
+];
+
+#[derive(Debug, Default)]
+pub(crate) struct Clint {
+    pub mtime: u64,
+    pub mtimecmp: u64,
+}
+
+impl Clint {
+    fn register(&mut self, offset: usize) -> &mut u64 {
+        match offset {
+            MTIMECMP => &mut self.mtimecmp,
+            _ => &mut self.mtime,
+        }
+    }
+}
+
+impl Device for Clint {
+    fn address_range(&self) -> Range<usize> {
+        CLINT_BASE..CLINT_BASE + CLINT_SIZE
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError> {
+        let register = DeviceRegister::decode(REGISTERS, offset, width, false)?;
+        let shift = (offset - register.offset) * 8;
+        Ok(*self.register(register.offset) >> shift & mask(width))
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: u64) -> Result<(), BusError> {
+        let register = DeviceRegister::decode(REGISTERS, offset, width, true)?;
+        let shift = (offset - register.offset) * 8;
+        let word = self.register(register.offset);
+        *word = *word & !(mask(width) << shift) | (val & mask(width)) << shift;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::Isa;
+    use crate::mem::{MemoryMap, RegionSpec};
+    use crate::Emulator;
+
+    #[test]
+    fn registers_can_be_accessed_at_any_width() {
+        let mut emu = Emulator::new(4096);
+        emu.write_u64(CLINT_BASE + MTIME, 0x1122334455667788)
+            .unwrap();
+        assert_eq!(emu.read_u32(CLINT_BASE + MTIME + 4).unwrap(), 0x11223344);
+        assert_eq!(emu.read_u8(CLINT_BASE + MTIME + 1).unwrap(), 0x77);
+        emu.write_u32(CLINT_BASE + MTIMECMP + 4, 7).unwrap();
+        emu.write_u16(CLINT_BASE + MTIMECMP, 0x1234).unwrap();
+        assert_eq!(
+            emu.read_u64(CLINT_BASE + MTIMECMP).unwrap(),
+            7 << 32 | 0x1234
+        );
+        assert_eq!(
+            (emu.clint.borrow().mtime, emu.clint.borrow().mtimecmp),
+            (0x1122334455667788, 7 << 32 | 0x1234)
+        );
+        // msip isn't implemented, and registers can't be straddled
+        assert!(emu.read_u32(CLINT_BASE).is_err());
+        assert!(emu.read_u64(CLINT_BASE + MTIME + 4).is_err());
+    }
+
+    #[test]
+    fn only_mapped_when_mmio_covers_it() {
+        let ram: RegionSpec = "ram:0x80000000:4K".parse().unwrap();
+        let mut emu =
+            Emulator::with_memory_map(MemoryMap::from_specs(&[ram]).unwrap(), Isa::default());
+        assert!(emu.read_u64(CLINT_BASE + MTIME).is_err());
+        assert!(emu.write_u64(CLINT_BASE + MTIME, 1).is_err());
+        assert_eq!(emu.clint.borrow().mtime, 0);
+    }
+}
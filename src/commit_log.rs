@@ -0,0 +1,557 @@
+//! Logging every retired instruction in the format of Spike's `--log-commits`, so that runs can
+//! be compared line by line with Spike or Sail.
+//!
+//! Each retired instruction is logged on one line, with the privilege mode it ran in, its pc and
+//! encoding, followed by the registers and csrs it wrote and the memory it accessed:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000004 (0x0002a303) x6  0x0000000000000001 mem 0x0000000080001000
+//! core   0: 3 0x0000000080000008 (0x00532423) mem 0x0000000080001008 0x00000001
+//! core   0: 3 0x000000008000000c (0x30529073) c773_mtvec 0x0000000080000100
+//! ```
+//!
+//! Instructions that trap don't retire, so they are not logged. Instead each trap is logged the
+//! way Spike does with `-l`, along with `mtval` for exceptions that set it:
+//!
+//! ```text
+//! core   0: exception trap_illegal_instruction, epc 0x0000000080000010
+//! core   0:           tval 0x0000000000000000
+//! ```
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::{
+    csr::csr_name,
+    instructions::{
+        base::BaseInstruction,
+        machine::MachineInstruction,
+        zicsr::{ZOp, ZicsrInstruction},
+        AccessKind, Instruction,
+    },
+    Emulator, Privilege,
+};
+
+/// The csr written as a side effect of `mret`.
+const MSTATUS: u16 = 0x300;
+
+/// Spike's names for exceptions, by cause.
+const TRAP_NAMES: [(u64, &str); 11] = [
+    (0, "instruction_address_misaligned"),
+    (1, "instruction_access_fault"),
+    (2, "illegal_instruction"),
+    (3, "breakpoint"),
+    (4, "load_address_misaligned"),
+    (5, "load_access_fault"),
+    (6, "store_address_misaligned"),
+    (7, "store_access_fault"),
+    (8, "user_ecall"),
+    (9, "supervisor_ecall"),
+    (11, "machine_ecall"),
+];
+
+/// An access to memory made by a retired instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryEntry {
+    Load {
+        addr: u64,
+    },
+    /// A store of the low `width` bytes of `data`.
+    Store {
+        addr: u64,
+        width: usize,
+        data: u64,
+    },
+}
+
+/// A retired instruction and its effects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub privilege: u8,
+    pub pc: u64,
+    /// The encoding of the instruction, which is compressed unless the low bits are `0b11`.
+    pub opcode: u32,
+    /// The integer registers written and their new values, never including x0.
+    pub registers: Vec<(usize, u64)>,
+    /// The csrs written and their new values.
+    pub csrs: Vec<(u16, u64)>,
+    /// The memory accessed, with loads before stores.
+    pub memory: Vec<MemoryEntry>,
+}
+
+/// An entry of the log: a retired instruction, or a trap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Commit(Commit),
+    Trap {
+        cause: u64,
+        epc: u64,
+        tval: Option<u64>,
+    },
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Commit {
+            privilege,
+            pc,
+            opcode,
+            ..
+        } = self;
+        write!(f, "core   0: {privilege} 0x{pc:016x} ")?;
+        if opcode & 0b11 == 0b11 {
+            write!(f, "(0x{opcode:08x})")?;
+        } else {
+            write!(f, "(0x{opcode:04x})")?;
+        }
+        for (rd, val) in &self.registers {
+            write!(f, " x{rd:<2} 0x{val:016x}")?;
+        }
+        for (addr, val) in &self.csrs {
+            let name = csr_name(*addr).unwrap_or("unknown");
+            write!(f, " c{addr}_{name} 0x{val:016x}")?;
+        }
+        for entry in &self.memory {
+            match *entry {
+                MemoryEntry::Load { addr } => write!(f, " mem 0x{addr:016x}")?,
+                MemoryEntry::Store { addr, width, data } => write!(
+                    f,
+                    " mem 0x{addr:016x} 0x{data:0digits$x}",
+                    digits = 2 * width
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Traps with a trap value take two lines.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Commit(commit) => write!(f, "{commit}"),
+            Event::Trap { cause, epc, tval } => {
+                if cause >> 63 == 1 {
+                    write!(f, "core   0: exception interrupt #{}", cause & 0xff)?;
+                } else if let Some((_, name)) = TRAP_NAMES.iter().find(|(c, _)| c == cause) {
+                    write!(f, "core   0: exception trap_{name}")?;
+                } else {
+                    write!(f, "core   0: exception trap #{cause}")?;
+                }
+                write!(f, ", epc 0x{epc:016x}")?;
+                if let Some(tval) = tval {
+                    write!(f, "\ncore   0:           tval 0x{tval:016x}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A line of a commit log.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Line {
+    Event(Event),
+    /// The trap value of the trap on the previous line.
+    Tval(u64),
+}
+
+impl FromStr for Line {
+    type Err = ();
+
+    /// Parse a line logged by `--log-commits` or Spike. Spike's `-l` also logs the disassembly of
+    /// every instruction, which doesn't parse.
+    fn from_str(line: &str) -> Result<Self, ()> {
+        let hex =
+            |s: &str| u64::from_str_radix(s.strip_prefix("0x").ok_or(())?, 16).map_err(|_| ());
+        let mut words = line.split_whitespace().peekable();
+        if words.next() != Some("core") {
+            return Err(());
+        }
+        words.next().filter(|id| id.ends_with(':')).ok_or(())?;
+        match words.next().ok_or(())? {
+            "tval" => Ok(Line::Tval(hex(words.next().ok_or(())?)?)),
+            "exception" => {
+                let rest = words.collect::<Vec<_>>().join(" ");
+                let (name, epc) = rest.split_once(", epc ").ok_or(())?;
+                let cause = if let Some(code) = name.strip_prefix("interrupt #") {
+                    1 << 63 | code.parse::<u64>().map_err(|_| ())?
+                } else if let Some(code) = name.strip_prefix("trap #") {
+                    code.parse().map_err(|_| ())?
+                } else {
+                    let name = name.strip_prefix("trap_").ok_or(())?;
+                    TRAP_NAMES.iter().find(|(_, n)| *n == name).ok_or(())?.0
+                };
+                Ok(Line::Event(Event::Trap {
+                    cause,
+                    epc: hex(epc)?,
+                    tval: None,
+                }))
+            }
+            privilege => {
+                let privilege = privilege.parse().map_err(|_| ())?;
+                let pc = hex(words.next().ok_or(())?)?;
+                let opcode = words.next().ok_or(())?;
+                let opcode = opcode.strip_prefix('(').and_then(|o| o.strip_suffix(')'));
+                let mut commit = Commit {
+                    privilege,
+                    pc,
+                    opcode: hex(opcode.ok_or(())?)? as u32,
+                    registers: Vec::new(),
+                    csrs: Vec::new(),
+                    memory: Vec::new(),
+                };
+                while let Some(word) = words.next() {
+                    if word == "mem" {
+                        let addr = hex(words.next().ok_or(())?)?;
+                        // A store is followed by its data, a load by the next write if anything
+                        match words.next_if(|word| word.starts_with("0x")) {
+                            Some(data) => commit.memory.push(MemoryEntry::Store {
+                                addr,
+                                width: (data.len() - 2) / 2,
+                                data: hex(data)?,
+                            }),
+                            None => commit.memory.push(MemoryEntry::Load { addr }),
+                        }
+                    } else if let Some(rd) = word.strip_prefix('x') {
+                        let val = hex(words.next().ok_or(())?)?;
+                        commit.registers.push((rd.parse().map_err(|_| ())?, val));
+                    } else if let Some(csr) = word.strip_prefix('c') {
+                        let (addr, _name) = csr.split_once('_').ok_or(())?;
+                        let val = hex(words.next().ok_or(())?)?;
+                        commit.csrs.push((addr.parse().map_err(|_| ())?, val));
+                    } else {
+                        return Err(());
+                    }
+                }
+                Ok(Line::Event(Event::Commit(commit)))
+            }
+        }
+    }
+}
+
+pub(crate) struct CommitLog {
+    out: Box<dyn Write>,
+    /// The first error writing to `out`, which is reported by `finish_commit_log`.
+    error: Option<io::Error>,
+}
+
+/// What is needed to log an instruction, taken before it executes.
+pub(crate) struct Retiring {
+    instruction: Instruction,
+    opcode: u32,
+    pc: u64,
+    privilege: Privilege,
+    x: [u64; 32],
+}
+
+impl Emulator {
+    /// Start logging every retired instruction and trap to `out`, replacing any log in progress.
+    /// While logging, everything is executed in the interpreter.
+    pub fn log_commits(&mut self, out: impl Write + 'static) {
+        self.commit_log = Some(CommitLog {
+            out: Box::new(out),
+            error: None,
+        });
+    }
+
+    /// Stop logging, flushing the log and reporting any error that occurred while writing it.
+    pub fn finish_commit_log(&mut self) -> io::Result<()> {
+        match self.commit_log.take() {
+            Some(CommitLog {
+                error: Some(err), ..
+            }) => Err(err),
+            Some(CommitLog { mut out, .. }) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether retired instructions are being logged or compared with a reference. Steps
+    /// executed again after going back in history were already observed the first time.
+    pub(crate) fn observing_commits(&self) -> bool {
+        (self.commit_log.is_some() || self.cosim.is_some())
+            && !self
+                .history
+                .as_ref()
+                .is_some_and(|history| history.borrow().is_repeating())
+    }
+
+    /// Take what is needed to log `instruction` before executing it, if logging.
+    pub(crate) fn begin_commit(&self, instruction: &Instruction, opcode: u32) -> Option<Retiring> {
+        self.observing_commits().then(|| Retiring {
+            instruction: instruction.clone(),
+            opcode,
+            pc: self.pc,
+            privilege: self.privilege,
+            x: self.x,
+        })
+    }
+
+    /// Log the instruction taken by `begin_commit` once it has executed, unless it trapped.
+    pub(crate) fn log_commit(&mut self, retiring: Retiring) {
+        if self.trap.is_some() {
+            return;
+        }
+        let Retiring {
+            instruction,
+            opcode,
+            pc,
+            privilege,
+            x,
+        } = retiring;
+
+        let registers = instruction
+            .destination()
+            .map(|rd| (rd, self.x[rd]))
+            .into_iter()
+            .collect();
+
+        let csr = match &instruction {
+            // csrrs and csrrc don't write the csr when rs1 is x0
+            Instruction::Zicsr(ZicsrInstruction(op, _, i)) if *op == ZOp::Csrrw || i.rs1 != 0 => {
+                instruction.csr()
+            }
+            Instruction::Machine(MachineInstruction::MRet(_)) => Some(MSTATUS),
+            _ => None,
+        };
+        let csrs = csr
+            .and_then(|addr| {
+                let csr = self.csr_access(addr, false).ok()?;
+                Some((addr, self.csr_value(csr)))
+            })
+            .into_iter()
+            .collect();
+
+        let mut memory = Vec::new();
+        if let Some(access) = instruction.memory_access() {
+            let addr = x[access.base].wrapping_add_signed(access.offset);
+            let width = access.width;
+            let mask = u64::MAX >> (64 - 8 * width);
+            if access.kind != AccessKind::Store {
+                memory.push(MemoryEntry::Load { addr });
+            }
+            // An AMO stores a value computed from memory, so that is read back, which is only
+            // possible outside of devices.
+            let stored = match access.kind {
+                AccessKind::Load => None,
+                AccessKind::Store if self.store_succeeded(&instruction, addr, x) => {
+                    Some(x[store_source(&instruction)] & mask)
+                }
+                AccessKind::Store => None,
+                AccessKind::Amo => {
+                    let bytes = self.debug_read(addr as usize, width);
+                    (bytes.len() == width).then(|| {
+                        bytes
+                            .iter()
+                            .rev()
+                            .fold(0, |val, &byte| val << 8 | u64::from(byte))
+                    })
+                }
+            };
+            if let Some(data) = stored {
+                memory.push(MemoryEntry::Store { addr, width, data });
+            }
+        }
+
+        self.observe(Event::Commit(Commit {
+            privilege: u64::from(privilege) as u8,
+            pc,
+            opcode,
+            registers,
+            csrs,
+            memory,
+        }));
+    }
+
+    /// Log a trap with cause `cause` taken at `epc`.
+    pub(crate) fn log_trap(&mut self, cause: u64, epc: u64) {
+        if !self.observing_commits() {
+            return;
+        }
+        // Spike reports the trap value of every exception other than the environment calls
+        let tval =
+            (cause >> 63 == 0 && !matches!(cause, 8..=11)).then_some(self.machine_csrs.mtval);
+        self.observe(Event::Trap { cause, epc, tval });
+    }
+
+    fn observe(&mut self, event: Event) {
+        if let Some(log) = &mut self.commit_log {
+            if log.error.is_none() {
+                log.error = writeln!(log.out, "{event}").err();
+            }
+        }
+        if let Some(cosim) = &mut self.cosim {
+            cosim.compare(event);
+        }
+    }
+
+    /// Whether a store reached memory, which a failed `sc` doesn't. When `sc` discards its
+    /// result, the outcome is told from memory instead.
+    pub(crate) fn store_succeeded(
+        &self,
+        instruction: &Instruction,
+        addr: u64,
+        x: [u64; 32],
+    ) -> bool {
+        let Instruction::Atomic(atomic) = instruction else {
+            return true;
+        };
+        let rd = atomic.instr.rd;
+        if rd != 0 {
+            return self.x[rd] == 0;
+        }
+        let width = instruction.memory_access().map_or(8, |access| access.width);
+        let stored = x[atomic.instr.rs2].to_le_bytes();
+        self.debug_read(addr as usize, width) == stored[..width]
+    }
+}
+
+/// The register holding the value written by a store.
+fn store_source(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::Base(BaseInstruction::Store(_, s)) => s.rs2,
+        Instruction::Atomic(atomic) => atomic.instr.rs2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The events logged in `log`, with each trap value on the trap before it. Lines that don't
+    /// parse are skipped.
+    fn parse(log: &str) -> Vec<Event> {
+        let mut events = Vec::new();
+        for line in log.lines() {
+            match line.parse() {
+                Ok(Line::Event(event)) => events.push(event),
+                Ok(Line::Tval(val)) => match events.last_mut() {
+                    Some(Event::Trap { tval, .. }) => *tval = Some(val),
+                    _ => panic!("tval without a trap: {line}"),
+                },
+                Err(()) => {}
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let events = vec![
+            Event::Commit(Commit {
+                privilege: 3,
+                pc: 0x8000_0004,
+                opcode: 0x0002a303,
+                registers: vec![(6, 1)],
+                csrs: vec![],
+                memory: vec![MemoryEntry::Load { addr: 0x8000_1000 }],
+            }),
+            Event::Commit(Commit {
+                privilege: 0,
+                pc: 0x8000_0008,
+                opcode: 0xc10c,
+                registers: vec![],
+                csrs: vec![],
+                memory: vec![MemoryEntry::Store {
+                    addr: 0x8000_1008,
+                    width: 1,
+                    data: 0xff,
+                }],
+            }),
+            Event::Commit(Commit {
+                privilege: 3,
+                pc: 0x8000_000c,
+                opcode: 0x30200073,
+                registers: vec![],
+                csrs: vec![(0x300, 0x80)],
+                memory: vec![],
+            }),
+            Event::Trap {
+                cause: 2,
+                epc: 0x8000_0010,
+                tval: Some(0xffff_ffff),
+            },
+            Event::Trap {
+                cause: 11,
+                epc: 0x8000_0014,
+                tval: None,
+            },
+            Event::Trap {
+                cause: 1 << 63 | 7,
+                epc: 0x8000_0018,
+                tval: None,
+            },
+            Event::Trap {
+                cause: 24,
+                epc: 0x8000_001c,
+                tval: None,
+            },
+        ];
+        let log: String = events.iter().map(|event| format!("{event}\n")).collect();
+        assert_eq!(parse(&log), events);
+    }
+
+    #[test]
+    fn parses_spike_logs() {
+        // As logged by Spike with -l --log-commits, which logs the disassembly of each
+        // instruction before its commit
+        let log = "\
+core   0: 0x0000000080000000 (0x30529073) csrw    mtvec, t0
+core   0: 3 0x0000000080000000 (0x30529073) c773_mtvec 0x0000000080000100
+core   0: 0x0000000080000004 (0x00b6252f) amoadd.w a0, a1, (a2)
+core   0: 3 0x0000000080000004 (0x00b6252f) x10 0x0000000000000005 mem 0x0000000080001000 mem 0x0000000080001000 0x00000007
+core   0: 0x0000000080000008 (0x00000000) c.unimp
+core   0: exception trap_illegal_instruction, epc 0x0000000080000008
+core   0:           tval 0x0000000000000000
+";
+        assert_eq!(
+            parse(log),
+            [
+                Event::Commit(Commit {
+                    privilege: 3,
+                    pc: 0x8000_0000,
+                    opcode: 0x30529073,
+                    registers: vec![],
+                    csrs: vec![(0x305, 0x8000_0100)],
+                    memory: vec![],
+                }),
+                Event::Commit(Commit {
+                    privilege: 3,
+                    pc: 0x8000_0004,
+                    opcode: 0x00b6252f,
+                    registers: vec![(10, 5)],
+                    csrs: vec![],
+                    memory: vec![
+                        MemoryEntry::Load { addr: 0x8000_1000 },
+                        MemoryEntry::Store {
+                            addr: 0x8000_1000,
+                            width: 4,
+                            data: 7,
+                        },
+                    ],
+                }),
+                Event::Trap {
+                    cause: 2,
+                    epc: 0x8000_0008,
+                    tval: Some(0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_disassembly_and_other_lines() {
+        for line in [
+            "core   0: 0x0000000080000000 (0x00000297) auipc   t0, 0x0",
+            "core   0: 3 0x0000000080000000 (0x00000297) x5",
+            "core   0: 3 0x0000000080000000 (0x00000297) f5 0x0000000000000000",
+            "core   0: exception trap_unknown, epc 0x0000000080000000",
+            "bbl loader",
+            "",
+        ] {
+            assert_eq!(line.parse::<Line>(), Err(()), "{line}");
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Co-simulation, comparing every instruction retired by the emulator with a reference model.
+//!
+//! The reference is anything implementing [`Reference`], which could be a second model driven in
+//! step, or a [`CommitLogReader`] reading a log recorded earlier by Spike's `--log-commits`, or by
+//! [`Emulator::log_commits`] of a run known to be good. After each instruction retires, the next
+//! instruction retired by the reference is compared with it, and at the first one that differs
+//! [`Emulator::run`] stops with [`StopReason::CosimDiverged`](crate::StopReason::CosimDiverged). The [`Divergence`] then shows
+//! what went wrong, along with the last few entries leading up to it.
+//!
+//! Only retired instructions are compared. Traps are left out, since Spike only logs them with
+//! `-l`, but they still show up in the context of a divergence. Entries of the reference from
+//! before the emulator's starting pc are skipped, so a Spike log can start in its boot ROM.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+
+use crate::commit_log::{Commit, Event, Line};
+use crate::Emulator;
+
+/// The number of entries leading up to a divergence that are kept to show with it.
+const CONTEXT: usize = 8;
+
+/// A model to compare the emulator with.
+pub trait Reference {
+    /// The next instruction retired or trap taken by the reference, or `None` once it has
+    /// stopped.
+    fn next_event(&mut self) -> io::Result<Option<Event>>;
+}
+
+/// A reference replaying a commit log in the format of Spike's `--log-commits`. Lines that aren't
+/// entries of the log, such as the disassembly logged by Spike's `-l`, are skipped.
+pub struct CommitLogReader<R> {
+    lines: io::Lines<R>,
+    /// The last entry read, which is held back in case it is a trap followed by its trap value.
+    pending: Option<Event>,
+}
+
+impl<R: BufRead> CommitLogReader<R> {
+    pub fn new(log: R) -> Self {
+        CommitLogReader {
+            lines: log.lines(),
+            pending: None,
+        }
+    }
+}
+
+impl<R: BufRead> Reference for CommitLogReader<R> {
+    fn next_event(&mut self) -> io::Result<Option<Event>> {
+        while let Some(line) = self.lines.next().transpose()? {
+            match line.parse() {
+                Ok(Line::Event(event)) => {
+                    if let Some(event) = self.pending.replace(event) {
+                        return Ok(Some(event));
+                    }
+                }
+                Ok(Line::Tval(val)) => {
+                    if let Some(Event::Trap { tval, .. }) = &mut self.pending {
+                        *tval = Some(val);
+                    }
+                }
+                Err(()) => {}
+            }
+        }
+        Ok(self.pending.take())
+    }
+}
+
+/// What the reference did instead of what the emulator did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Commit(Commit),
+    /// The reference stopped before the emulator.
+    End,
+    /// The reference couldn't be read.
+    Error(String),
+}
+
+/// The first instruction retired differently by the emulator and the reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The number of instructions that retired the same way before this one.
+    pub matched: u64,
+    /// The entries logged by the emulator just before this one, oldest first.
+    pub context: Vec<Event>,
+    /// What the emulator retired.
+    pub actual: Commit,
+    pub expected: Expected,
+}
+
+impl Divergence {
+    /// The parts of the retired instructions that differ.
+    pub fn differences(&self) -> Vec<&'static str> {
+        let Expected::Commit(expected) = &self.expected else {
+            return Vec::new();
+        };
+        let actual = &self.actual;
+        let sorted = |writes: &[(u16, u64)]| {
+            let mut writes = writes.to_vec();
+            writes.sort_unstable();
+            writes
+        };
+        let mut differences = Vec::new();
+        if expected.pc != actual.pc {
+            differences.push("pc");
+        }
+        if expected.opcode != actual.opcode {
+            differences.push("opcode");
+        }
+        if expected.privilege != actual.privilege {
+            differences.push("privilege");
+        }
+        if expected.registers != actual.registers {
+            differences.push("registers");
+        }
+        if sorted(&expected.csrs) != sorted(&actual.csrs) {
+            differences.push("csrs");
+        }
+        if expected.memory != actual.memory {
+            differences.push("memory");
+        }
+        differences
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "diverged from the reference after {} matching instructions",
+            self.matched
+        )?;
+        for event in &self.context {
+            for line in event.to_string().lines() {
+                writeln!(f, "            {line}")?;
+            }
+        }
+        match &self.expected {
+            Expected::Commit(expected) => writeln!(f, "expected:   {expected}")?,
+            Expected::End => writeln!(f, "expected:   the end of the reference")?,
+            Expected::Error(err) => {
+                writeln!(f, "expected:   (failed to read the reference: {err})")?
+            }
+        }
+        write!(f, "actual:     {}", self.actual)?;
+        let differences = self.differences();
+        if !differences.is_empty() {
+            write!(f, "\ndifferences: {}", differences.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Cosim {
+    reference: Box<dyn Reference>,
+    /// Whether the reference has reached the emulator's starting pc.
+    synced: bool,
+    matched: u64,
+    recent: VecDeque<Event>,
+    divergence: Option<Box<Divergence>>,
+}
+
+impl Cosim {
+    /// Compare an entry logged by the emulator with the reference. Nothing more is compared once
+    /// they have diverged.
+    pub(crate) fn compare(&mut self, event: Event) {
+        if self.divergence.is_some() {
+            return;
+        }
+        let Event::Commit(actual) = event else {
+            self.remember(event);
+            return;
+        };
+        let expected = self.next_commit(actual.pc);
+        let divergence = Divergence {
+            matched: self.matched,
+            context: Vec::new(),
+            actual,
+            expected,
+        };
+        if matches!(divergence.expected, Expected::Commit(_)) && divergence.differences().is_empty()
+        {
+            self.matched += 1;
+            self.remember(Event::Commit(divergence.actual));
+        } else {
+            self.divergence = Some(Box::new(Divergence {
+                context: self.recent.drain(..).collect(),
+                ..divergence
+            }));
+        }
+    }
+
+    /// The next instruction retired by the reference, skipping its traps, and everything before
+    /// `start` until it gets there.
+    fn next_commit(&mut self, start: u64) -> Expected {
+        loop {
+            match self.reference.next_event() {
+                Ok(Some(Event::Commit(commit))) if self.synced || commit.pc == start => {
+                    self.synced = true;
+                    return Expected::Commit(commit);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => return Expected::End,
+                Err(err) => return Expected::Error(err.to_string()),
+            }
+        }
+    }
+
+    fn remember(&mut self, event: Event) {
+        if self.recent.len() == CONTEXT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event);
+    }
+}
+
+impl Emulator {
+    /// Compare every instruction retired from now on with `reference`, replacing any comparison
+    /// in progress. While comparing, everything is executed in the interpreter.
+    pub fn cosimulate(&mut self, reference: impl Reference + 'static) {
+        self.cosim = Some(Box::new(Cosim {
+            reference: Box::new(reference),
+            synced: false,
+            matched: 0,
+            recent: VecDeque::with_capacity(CONTEXT),
+            divergence: None,
+        }));
+    }
+
+    /// Where the emulator first diverged from the reference, if it has.
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.cosim.as_ref()?.divergence.as_deref()
+    }
+
+    /// The pc of the instruction at which the emulator diverged from the reference.
+    pub(crate) fn cosim_divergence(&self) -> Option<u64> {
+        self.divergence().map(|divergence| divergence.actual.pc)
+    }
+}
@@ -1,8 +1,21 @@
-use crate::{instructions::Extension, Emulator, Instruction, Privilege};
+//! Control and status registers.
+//!
+//! Every csr is described by a single entry in a table of [`Csr`]s. An entry declares where the
+//! csr lives, who may access it, which bits software can write, its reset value, which extension
+//! it belongs to and how reads and writes reach the state in [`MachineCsrs`]. Access checks and
+//! illegal instruction traps are derived from the table, so adding a csr is a matter of adding an
+//! entry. The table is indexed by address at compile time, which also rejects two entries for the
+//! same address.
 
-#[derive(Debug)]
+use crate::{
+    hpm::{HpmEvent, HPM_COUNTERS},
+    isa::Extension,
+    trap::Interrupt,
+    Emulator, Instruction, Privilege, Trap,
+};
+
+#[derive(Debug, Clone, Default)]
 pub struct MachineCsrs {
-    pub misa: u64,
     pub mstatus: u64,
     pub mtvec: u64,
     pub mip: u64,
@@ -11,196 +24,459 @@ pub struct MachineCsrs {
     pub minstret: u64,
     pub mcounteren: u32,
     pub mcountinhibit: u32,
+    pub mhpmcounter: [u64; HPM_COUNTERS],
+    pub mhpmevent: [u64; HPM_COUNTERS],
+    /// For each [`HpmEvent`], a bitmask of the counters selecting it, using the same bit layout as
+    /// `mcountinhibit`.
+    pub hpm_listeners: [u32; HpmEvent::ALL.len()],
     pub mscratch: u64,
     pub mepc: u64,
     pub mcause: u64,
     pub mtval: u64,
     pub menvcfg: u64,
     pub mseccfg: u64,
-    pub mtime: u64,
-    pub mtimecmp: u64,
 }
 
-impl Default for MachineCsrs {
-    fn default() -> Self {
-        Self {
-            misa: 2 << 62 | 0b00000100000001000100000101,
-            mstatus: 0,
-            mtvec: 0,
-            mip: 0,
-            mie: 0,
-            mcycle: 0,
-            minstret: 0,
-            mcounteren: 0,
-            mcountinhibit: 0,
-            mscratch: 0,
-            mepc: 0,
-            mcause: 0,
-            mtval: 0,
-            menvcfg: 0,
-            mseccfg: 0,
-            mtime: 0,
-            mtimecmp: 0,
+/// A description of a single csr.
+pub struct Csr {
+    pub addr: u16,
+    pub name: &'static str,
+    /// The lowest privilege level that may access the csr. By default this comes from bits [9:8]
+    /// of the address.
+    pub min_privilege: u8,
+    /// Whether the csr is read only. By default this is the case when bits [11:10] of the address
+    /// are both set.
+    pub read_only: bool,
+    /// The bits that software is able to write. Writes leave all other bits unchanged.
+    pub write_mask: u64,
+    /// The value the csr takes when the core is reset.
+    pub reset: u64,
+    /// The extension that must be implemented for this csr to exist.
+    pub extension: Option<Extension>,
+    read: fn(&Emulator, u16) -> u64,
+    /// Stores an already masked value, applying any side effects of the write.
+    write: fn(&mut Emulator, u16, u64),
+    /// Any extra condition for the current privilege mode to be able to access the csr.
+    accessible: fn(&Emulator, u16) -> bool,
+}
+
+impl Csr {
+    const fn new(addr: u16, name: &'static str) -> Csr {
+        Csr {
+            addr,
+            name,
+            min_privilege: (addr >> 8 & 3) as u8,
+            read_only: addr >> 10 & 3 == 3,
+            write_mask: !0,
+            reset: 0,
+            extension: None,
+            read: |_, _| 0,
+            write: |_, _, _| {},
+            accessible: |_, _| true,
         }
     }
-}
 
-impl MachineCsrs {
-    /// Determine the index into the `misa` bitfield an instruction indexes into.
-    fn misa_index(instruction: Extension) -> u64 {
-        match instruction {
-            Extension::Atomic => 0,
-            Extension::BitManip => 1,
-            Extension::Compressed => 2,
-            Extension::Double => 3,
-            Extension::Embedded => 4,
-            Extension::Float => 5,
-            Extension::Hypervisor => 7,
-            Extension::Base => 8,
-            Extension::Multiply => 12,
-            Extension::Quad => 16,
-            Extension::Supervisor => 18,
-            Extension::User => 20,
-            Extension::Vector => 21,
+    const fn mask(self, write_mask: u64) -> Csr {
+        Csr { write_mask, ..self }
+    }
+
+    const fn reset(self, reset: u64) -> Csr {
+        Csr { reset, ..self }
+    }
+
+    const fn extension(self, extension: Extension) -> Csr {
+        Csr {
+            extension: Some(extension),
+            ..self
         }
     }
 
-    /// Determines whether an instruction is enabled in the `misa` csr.
+    const fn read(self, read: fn(&Emulator, u16) -> u64) -> Csr {
+        Csr { read, ..self }
+    }
+
+    const fn write(self, write: fn(&mut Emulator, u16, u64)) -> Csr {
+        Csr { write, ..self }
+    }
+
+    const fn accessible(self, accessible: fn(&Emulator, u16) -> bool) -> Csr {
+        Csr { accessible, ..self }
+    }
+}
+
+/// User mode counters are only accessible when the matching bit of `mcounteren` is set.
+fn counter_enabled(emu: &Emulator, addr: u16) -> bool {
+    emu.privilege == Privilege::Machine || emu.machine_csrs.mcounteren & 1 << (addr & 0x1f) != 0
+}
+
+macro_rules! hpm_csrs {
+    ($($n:literal),*) => {
+        [
+            $(Csr::new(0xB00 + $n, concat!("mhpmcounter", $n))
+                .read(|emu, _| emu.machine_csrs.mhpmcounter[$n - 3])
+                .write(|emu, _, val| emu.machine_csrs.mhpmcounter[$n - 3] = val),)*
+            $(Csr::new(0x320 + $n, concat!("mhpmevent", $n))
+                .read(|emu, _| emu.machine_csrs.mhpmevent[$n - 3])
+                .write(|emu, _, val| emu.write_mhpmevent($n, val)),)*
+            $(Csr::new(0xC00 + $n, concat!("hpmcounter", $n))
+                .extension(Extension::Zihpm)
+                .accessible(counter_enabled)
+                .read(|emu, _| emu.machine_csrs.mhpmcounter[$n - 3]),)*
+        ]
+    };
+}
+
+static CSRS: &[Csr] = &[
+    Csr::new(0xF11, "mvendorid"),
+    Csr::new(0xF12, "marchid"),
+    Csr::new(0xF13, "mimpid"),
+    Csr::new(0xF14, "mhartid"),
+    Csr::new(0xF15, "mconfigptr"),
+    // MPP resets to M, since that is legal whether or not U is implemented
+    Csr::new(0x300, "mstatus")
+        .mask(!0x7fffffc0ff800015) // WPRI fields
+        .reset(3 << 11)
+        .read(|emu, _| emu.machine_csrs.mstatus)
+        .write(Emulator::write_mstatus),
+    // Don't allow modification of allowed extensions for simplicity (might change later)
+    Csr::new(0x301, "misa")
+        .mask(0)
+        .read(|emu, _| emu.isa.misa()),
+    // MSIE, MTIE, MEIE, LCOFIE and the platform interrupts. The S mode bits are read only zero
+    // since S is not implemented.
+    Csr::new(0x304, "mie")
+        .mask(!0xffff | 0x2888)
+        .read(|emu, _| emu.machine_csrs.mie)
+        .write(|emu, _, val| emu.machine_csrs.mie = val & emu.lcofi_mask()),
+    // We assume mtvec is always in direct mode
+    Csr::new(0x305, "mtvec")
+        .mask(!3)
+        .read(|emu, _| emu.machine_csrs.mtvec)
+        .write(|emu, _, val| emu.machine_csrs.mtvec = val),
+    Csr::new(0x306, "mcounteren")
+        .extension(Extension::User)
+        .mask(0xffffffff)
+        .read(|emu, _| emu.machine_csrs.mcounteren as u64)
+        .write(|emu, _, val| emu.machine_csrs.mcounteren = val as u32),
+    Csr::new(0x30A, "menvcfg") // TODO
+        .read(|emu, _| emu.machine_csrs.menvcfg)
+        .write(|emu, _, val| emu.machine_csrs.menvcfg = val),
+    // Bit 1 would inhibit time, which is not a counter of the hart
+    Csr::new(0x320, "mcountinhibit")
+        .mask(0xfffffffd)
+        .read(|emu, _| emu.machine_csrs.mcountinhibit as u64)
+        .write(|emu, _, val| emu.machine_csrs.mcountinhibit = val as u32),
+    Csr::new(0x340, "mscratch")
+        .read(|emu, _| emu.machine_csrs.mscratch)
+        .write(|emu, _, val| emu.machine_csrs.mscratch = val),
+    Csr::new(0x341, "mepc")
+        .mask(!1)
+        .read(|emu, _| emu.machine_csrs.mepc)
+        .write(|emu, _, val| {
+            // Bit 1 is only writable when IALIGN=16
+            let mask = if emu.isa.has(Extension::Compressed) {
+                !1
+            } else {
+                !3
+            };
+            emu.machine_csrs.mepc = val & mask;
+        }),
+    Csr::new(0x342, "mcause")
+        .read(|emu, _| emu.machine_csrs.mcause)
+        .write(|emu, _, val| emu.machine_csrs.mcause = val),
+    Csr::new(0x343, "mtval")
+        .read(|emu, _| emu.machine_csrs.mtval)
+        .write(|emu, _, val| emu.machine_csrs.mtval = val),
+    // For us everything in the bottom 16 bits of mip is read only, except for LCOFIP which
+    // software clears after handling an overflow.
+    Csr::new(0x344, "mip")
+        .mask(!0xffff | 0x2000)
+        .read(|emu, _| emu.machine_csrs.mip)
+        .write(|emu, _, val| emu.machine_csrs.mip = val & emu.lcofi_mask()),
+    Csr::new(0x747, "mseccfg") // TODO?
+        .read(|emu, _| emu.machine_csrs.mseccfg)
+        .write(|emu, _, val| emu.machine_csrs.mseccfg = val),
+    Csr::new(0xB00, "mcycle")
+        .read(|emu, _| emu.machine_csrs.mcycle)
+        .write(|emu, _, val| emu.machine_csrs.mcycle = val),
+    Csr::new(0xB02, "minstret")
+        .read(|emu, _| emu.machine_csrs.minstret)
+        .write(|emu, _, val| emu.machine_csrs.minstret = val),
+    Csr::new(0xC00, "cycle")
+        .extension(Extension::Zicntr)
+        .accessible(counter_enabled)
+        .read(|emu, _| emu.machine_csrs.mcycle),
+    Csr::new(0xC01, "time")
+        .extension(Extension::Zicntr)
+        .accessible(counter_enabled)
+        .read(|emu, _| emu.clint.borrow().mtime),
+    Csr::new(0xC02, "instret")
+        .extension(Extension::Zicntr)
+        .accessible(counter_enabled)
+        .read(|emu, _| emu.machine_csrs.minstret),
+    // This would be filtered by mcounteren in S mode, but from M mode every bit is visible
+    Csr::new(0xDA0, "scountovf")
+        .extension(Extension::Sscofpmf)
+        .read(|emu, _| emu.counter_overflows()),
+];
+
+static HPM_CSRS: [Csr; 87] = hpm_csrs!(
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 29, 30, 31
+);
+
+/// For every csr address, one more than the position of its entry in [`CSRS`] followed by
+/// [`HPM_CSRS`], or 0 if there is no csr at that address.
+static CSR_INDEX: [u16; 4096] = {
+    let mut index = [0; 4096];
+    let mut idx = 0;
+    while idx < CSRS.len() + HPM_CSRS.len() {
+        let csr = if idx < CSRS.len() {
+            &CSRS[idx]
+        } else {
+            &HPM_CSRS[idx - CSRS.len()]
+        };
+        assert!(
+            index[csr.addr as usize] == 0,
+            "two csrs have the same address"
+        );
+        index[csr.addr as usize] = idx as u16 + 1;
+        idx += 1;
+    }
+    index
+};
+
+/// Every csr in the tables, whether or not this core implements it.
+fn all_csrs() -> impl Iterator<Item = &'static Csr> {
+    CSRS.iter().chain(HPM_CSRS.iter())
+}
+
+/// The csr at `addr`, whether or not this core implements it.
+fn csr_at(addr: u16) -> Option<&'static Csr> {
+    let idx = usize::from(*CSR_INDEX.get(usize::from(addr))?).checked_sub(1)?;
+    CSRS.get(idx).or_else(|| HPM_CSRS.get(idx - CSRS.len()))
+}
+
+/// The name of the csr at `addr`, whether or not this core implements it.
+pub(crate) fn csr_name(addr: u16) -> Option<&'static str> {
+    csr_at(addr).map(|csr| csr.name)
+}
+
+impl Emulator {
+    /// Determines whether an instruction belongs to an extension implemented by this core.
     pub fn can_exec(&self, instruction: &Instruction) -> bool {
         instruction
             .extension()
-            .is_none_or(|extension| self.misa & 1 << MachineCsrs::misa_index(extension) != 0)
+            .is_none_or(|extension| self.isa.has(extension))
     }
-}
 
-impl Emulator {
-    pub fn get_csr(&mut self, csr: u32, _read: bool) -> Option<u64> {
-        if self.privilege >= Privilege::Machine {
-            let val = match csr {
-                0x301 => Some(self.machine_csrs.misa),                 // misa
-                0xF11 => Some(0),                                      // mvendorid
-                0xF12 => Some(0),                                      // marchid
-                0xF13 => Some(0),                                      // mimpid
-                0xF14 => Some(0),                                      // mhartid
-                0x300 => Some(self.machine_csrs.mstatus),              // mstatus
-                0x305 => Some(self.machine_csrs.mtvec),                // mtvec
-                0x344 => Some(0),                                      // mip
-                0x304 => Some(0),                                      // mie
-                0xB00 => Some(self.machine_csrs.mcycle),               // mcycle
-                0xB02 => Some(self.machine_csrs.minstret),             // minstret
-                0xB04..=0xB1F => Some(0), // mhpmcounterN (unimplemented)
-                0x323..=0x33F => Some(0), // mhpmeventN (unimplemented)
-                0x306 => Some(self.machine_csrs.mcounteren as u64), // mcounteren
-                0x320 => Some(self.machine_csrs.mcountinhibit as u64), // mcountinhibit
-                0x340 => Some(self.machine_csrs.mscratch), // mscratch
-                0x341 => Some(self.machine_csrs.mepc), // mepc
-                0x342 => Some(self.machine_csrs.mcause), // mcause
-                0x343 => Some(self.machine_csrs.mtval), // mtval
-                0xF15 => Some(0),         // mconfigptr
-                0x30A => Some(self.machine_csrs.menvcfg), // menvcfg
-                0x747 => Some(self.machine_csrs.mseccfg), // mseccfg
-                _ => None,
-            };
-            if val.is_some() {
-                return val;
-            }
+    /// Iterate over every csr that exists on this core.
+    fn implemented_csrs(&self) -> impl Iterator<Item = &'static Csr> + '_ {
+        all_csrs().filter(|csr| self.implements(csr))
+    }
+
+    /// Whether the extension `csr` belongs to is implemented.
+    fn implements(&self, csr: &Csr) -> bool {
+        csr.extension.is_none_or(|ext| self.isa.has(ext))
+    }
+
+    /// Iterate over every csr that exists on this core, along with its current value.
+    pub fn csrs(&self) -> impl Iterator<Item = (&'static Csr, u64)> + '_ {
+        self.implemented_csrs()
+            .map(|csr| (csr, (csr.read)(self, csr.addr)))
+    }
+
+    /// Find the csr with the given name, if it exists on this core.
+    pub fn csr_by_name(&self, name: &str) -> Option<&'static Csr> {
+        self.implemented_csrs().find(|csr| csr.name == name)
+    }
+
+    fn find_csr(&self, addr: u16) -> Option<&'static Csr> {
+        csr_at(addr).filter(|csr| self.implements(csr))
+    }
+
+    /// Put every csr into its reset state.
+    pub(crate) fn reset_csrs(&mut self) {
+        for csr in all_csrs() {
+            (csr.write)(self, csr.addr, csr.reset);
         }
-        if self.privilege == Privilege::User {
-            let val = match csr {
-                0xC00 if self.machine_csrs.mcounteren & 1 != 0 => Some(self.machine_csrs.mcycle),
-                0xC01 if self.machine_csrs.mcounteren & 2 != 0 => Some(self.machine_csrs.mtime),
-                0xC02 if self.machine_csrs.mcounteren & 4 != 0 => Some(self.machine_csrs.minstret),
-                0xC03..=0xC1F if self.machine_csrs.mcounteren & 1 << (csr - 0xC00) != 0 => {
-                    Some(self.machine_csrs.minstret)
-                }
-                _ => None,
-            };
-            if val.is_some() {
-                return val;
-            }
+    }
+
+    /// Look up the csr at `addr`, checking that it can be accessed from the current privilege
+    /// mode, and that it can be written if `write` is set.
+    pub fn csr_access(&self, addr: u16, write: bool) -> Result<&'static Csr, Trap> {
+        let csr = self.find_csr(addr).ok_or(Trap::IllegalInstruction)?;
+        if (self.privilege as u8) < csr.min_privilege
+            || !(csr.accessible)(self, addr)
+            || write && csr.read_only
+        {
+            return Err(Trap::IllegalInstruction);
+        }
+        Ok(csr)
+    }
+
+    /// Read a csr as the currently executing program.
+    pub fn read_csr(&self, addr: u16) -> Result<u64, Trap> {
+        let csr = self.csr_access(addr, false)?;
+        Ok(self.csr_value(csr))
+    }
+
+    /// Write a csr as the currently executing program.
+    pub fn write_csr(&mut self, addr: u16, val: u64) -> Result<(), Trap> {
+        let csr = self.csr_access(addr, true)?;
+        self.set_csr_value(csr, val);
+        Ok(())
+    }
+
+    /// Read a csr without any access checks.
+    pub fn csr_value(&self, csr: &Csr) -> u64 {
+        (csr.read)(self, csr.addr)
+    }
+
+    /// Write a csr without any access checks. Read only bits keep their values.
+    pub fn set_csr_value(&mut self, csr: &Csr, val: u64) {
+        let old = (csr.read)(self, csr.addr);
+        (csr.write)(self, csr.addr, old & !csr.write_mask | val & csr.write_mask);
+    }
+
+    /// Clears the LCOFI bit of `mip` and `mie` when Sscofpmf is not implemented.
+    fn lcofi_mask(&self) -> u64 {
+        if self.isa.has(Extension::Sscofpmf) {
+            !0
+        } else {
+            !Interrupt::CounterOverflow.mask()
         }
-        None
     }
 
-    pub fn set_csr(&mut self, csr: u32, val: u64, write: bool) -> bool {
-        if !write {
-            return true;
+    fn write_mstatus(&mut self, _addr: u16, val: u64) {
+        let old_mpp = self.machine_csrs.mstatus & (3 << 11);
+        self.machine_csrs.mstatus = val;
+        // We don't implement S yet, so keep SPP to 0
+        self.machine_csrs.mstatus &= !0x100;
+        // We want to ensure that MPP only has legal values (we don't implement S yet, and U might
+        // not be implemented either)
+        let mpp = self.machine_csrs.mstatus & (3 << 11);
+        let user = self.isa.has(Extension::User);
+        if !(mpp == 0b11 << 11 || mpp == 0b00 << 11 && user) {
+            self.machine_csrs.mstatus = self.machine_csrs.mstatus & !(3 << 11) | old_mpp;
         }
-        if self.privilege >= Privilege::Machine {
-            match csr {
-                // misa
-                // Don't allow modification of allowed extensions for simplicity
-                // (might change later)
-                0x301 => {}
-                // mstatus (0x7fffffc0ff800015 is the WPRI mask)
-                0x300 => {
-                    let old_mpp = self.machine_csrs.mstatus & (3 << 11);
-                    self.machine_csrs.mstatus = val & !0x7fffffc0ff800015;
-                    // We don't implement S yet, so keep SPP to 0
-                    self.machine_csrs.mstatus &= !0x100;
-                    // We want to ensure that MPP only has legal values (we don't implement S yet)
-                    let mpp = self.machine_csrs.mstatus & (3 << 11);
-                    if mpp != 0b00 << 11 && mpp != 0b11 << 11 {
-                        self.machine_csrs.mstatus =
-                            self.machine_csrs.mstatus & !(3 << 11) | old_mpp;
-                    }
-                    // SXL is read only 0 since we do not implement S yet
-                    self.machine_csrs.mstatus &= !(3 << 34);
-                    // Ensure that UXL stays on 64 bit, since we don't want to allow variable len
-                    self.machine_csrs.mstatus = (self.machine_csrs.mstatus & !(3 << 32)) | 2 << 32;
-                    // MPRIV is read only 0 if U is not implemented
-                    self.machine_csrs.mstatus &= !(1 << 17);
-                    // MXR is read only 0 if S is not implemented
-                    self.machine_csrs.mstatus &= !(1 << 19);
-                    // SUM is read only 0 if S is not implemented
-                    self.machine_csrs.mstatus &= !(1 << 18);
-                    // We only support little endian, so MBE, SBE and UBE are effectively read only 0.
-                    self.machine_csrs.mstatus &= !(1 << 37);
-                    self.machine_csrs.mstatus &= !(1 << 36);
-                    self.machine_csrs.mstatus &= !(1 << 6);
-                    // TVM is read only 0 if S is not implemented
-                    self.machine_csrs.mstatus &= !(1 << 20);
-                    // TW is read only 0 if there are no modes less than M implemented
-                    self.machine_csrs.mstatus &= !(1 << 21);
-                    // TSR is read only 0 if S is not implemented
-                    self.machine_csrs.mstatus &= !(1 << 22);
-                    // For simplicity FS will always say dirty
-                    self.machine_csrs.mstatus |= 3 << 13;
-                    // VS and XS are read only zero as neither V nor X are implemented
-                    self.machine_csrs.mstatus &= !(3 << 9);
-                    self.machine_csrs.mstatus &= !(3 << 15);
-                }
-                0x305 => self.machine_csrs.mtvec = val & !3, // mtvec (we assume always direct)
-                // mip
-                0x344 => {
-                    // For us everything in the bottom 16 bites of mip is read only
-                    self.machine_csrs.mip = val & !0xffff;
-                }
-                // mie
-                0x304 => {
-                    // Zero out the zero bits of mie.
-                    self.machine_csrs.mie = val & !0xd555;
-                    // SEIP, STIP and SSIP are read only zero since S is not implemented
-                    self.machine_csrs.mie &= !(1 << 1);
-                    self.machine_csrs.mie &= !(1 << 5);
-                    self.machine_csrs.mie &= !(1 << 9);
-                    // LCOFIE is read only zero since Sscofpmf is not implemented
-                    self.machine_csrs.mie &= !(1 << 13);
-                }
-                0xB00 => self.machine_csrs.mcycle = val, // mcycle
-                0xB02 => self.machine_csrs.minstret = val, // minstret
-                0x306 => self.machine_csrs.mcounteren = val as u32, // mcounteren
-                0x340 => self.machine_csrs.mscratch = val, // mscratch
-                0x341 => self.machine_csrs.mepc = val & !1, // mepc
-                0x342 => self.machine_csrs.mcause = val, // mcause
-                0x343 => self.machine_csrs.mtval = val,  // mtval
-                0x30A => self.machine_csrs.menvcfg = val, // menvcfg TODO
-                0x747 => self.machine_csrs.mseccfg = val, // mseccfg TODO?
-
-                _ => return false,
-            }
-            return true;
+        // SXL is read only 0 since we do not implement S yet
+        self.machine_csrs.mstatus &= !(3 << 34);
+        // Ensure that UXL stays on 64 bit, since we don't want to allow variable len
+        self.machine_csrs.mstatus = (self.machine_csrs.mstatus & !(3 << 32)) | 2 << 32;
+        // MPRIV is read only 0 if U is not implemented
+        self.machine_csrs.mstatus &= !(1 << 17);
+        // MXR is read only 0 if S is not implemented
+        self.machine_csrs.mstatus &= !(1 << 19);
+        // SUM is read only 0 if S is not implemented
+        self.machine_csrs.mstatus &= !(1 << 18);
+        // We only support little endian, so MBE, SBE and UBE are effectively read only 0.
+        self.machine_csrs.mstatus &= !(1 << 37);
+        self.machine_csrs.mstatus &= !(1 << 36);
+        self.machine_csrs.mstatus &= !(1 << 6);
+        // TVM is read only 0 if S is not implemented
+        self.machine_csrs.mstatus &= !(1 << 20);
+        // TW is read only 0 if there are no modes less than M implemented
+        self.machine_csrs.mstatus &= !(1 << 21);
+        // TSR is read only 0 if S is not implemented
+        self.machine_csrs.mstatus &= !(1 << 22);
+        // For simplicity FS will always say dirty
+        self.machine_csrs.mstatus |= 3 << 13;
+        // VS and XS are read only zero as neither V nor X are implemented
+        self.machine_csrs.mstatus &= !(3 << 9);
+        self.machine_csrs.mstatus &= !(3 << 15);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::{Isa, DEFAULT_ISA};
+
+    #[test]
+    fn csrs_are_found_by_address() {
+        for csr in all_csrs() {
+            assert_eq!(csr_at(csr.addr).map(|found| found.name), Some(csr.name));
+        }
+        assert_eq!(csr_name(0x340), Some("mscratch"));
+        assert_eq!(csr_name(0xB1F), Some("mhpmcounter31"));
+        assert_eq!(csr_name(0x7C0), None);
+        assert_eq!(csr_name(u16::MAX), None);
+
+        // scountovf has a name, but only exists with Sscofpmf
+        let emu = Emulator::new(4096);
+        assert_eq!(csr_name(0xDA0), Some("scountovf"));
+        assert!(emu.find_csr(0xDA0).is_none());
+        assert_eq!(emu.read_csr(0xDA0), Err(Trap::IllegalInstruction));
+        assert!(emu.csrs().all(|(csr, _)| csr.name != "scountovf"));
+        assert_eq!(
+            emu.csr_by_name("mhpmevent3").map(|csr| csr.addr),
+            Some(0x323)
+        );
+    }
+
+    #[test]
+    fn access_and_masks_come_from_the_table() {
+        let mut emu = Emulator::new(4096);
+        // Read only by address, and by a write mask of 0
+        assert_eq!(emu.write_csr(0xF11, 1), Err(Trap::IllegalInstruction));
+        let misa = emu.read_csr(0x301).unwrap();
+        emu.write_csr(0x301, 0).unwrap();
+        assert_eq!(emu.read_csr(0x301), Ok(misa));
+        // Only the writable bits change
+        emu.write_csr(0x305, 0x1003).unwrap();
+        assert_eq!(emu.read_csr(0x305), Ok(0x1000));
+        emu.write_csr(0x340, !0).unwrap();
+        assert_eq!(emu.read_csr(0x340), Ok(!0));
+
+        // Machine csrs aren't accessible from user mode
+        emu.privilege = Privilege::User;
+        assert_eq!(emu.read_csr(0x340), Err(Trap::IllegalInstruction));
+        emu.privilege = Privilege::Machine;
+
+        emu.reset_csrs();
+        assert_eq!(emu.read_csr(0x340), Ok(0));
+        assert_eq!(emu.read_csr(0x305), Ok(0));
+        assert_eq!(emu.machine_csrs.mstatus >> 11 & 3, 3);
+    }
+
+    #[test]
+    fn counters_are_enabled_for_user_mode_by_mcounteren() {
+        let mut emu = Emulator::new(4096);
+        emu.machine_csrs.mcycle = 5;
+        emu.privilege = Privilege::User;
+        for addr in [0xC00, 0xC01, 0xC02, 0xC03, 0xC1F] {
+            assert_eq!(
+                emu.read_csr(addr),
+                Err(Trap::IllegalInstruction),
+                "{addr:#x}"
+            );
         }
-        false
+        emu.machine_csrs.mcounteren = 1 | 1 << 3;
+        assert_eq!(emu.read_csr(0xC00), Ok(5));
+        assert_eq!(emu.read_csr(0xC03), Ok(0));
+        assert_eq!(emu.read_csr(0xC01), Err(Trap::IllegalInstruction));
+        // The counters are read only even when enabled
+        assert_eq!(emu.write_csr(0xC00, 0), Err(Trap::IllegalInstruction));
+
+        // Machine mode can always read them
+        emu.privilege = Privilege::Machine;
+        emu.machine_csrs.mcounteren = 0;
+        assert_eq!(emu.read_csr(0xC00), Ok(5));
+        assert_eq!(emu.read_csr(0xC1F), Ok(0));
+    }
+
+    #[test]
+    fn scountovf_needs_supervisor_privilege() {
+        let isa: Isa = format!("{DEFAULT_ISA}_sscofpmf").parse().unwrap();
+        let mut emu = Emulator::with_isa(4096, isa);
+        emu.machine_csrs.mhpmevent[0] |= crate::hpm::EVENT_OF;
+        emu.machine_csrs.mhpmevent[28] |= crate::hpm::EVENT_OF;
+        assert_eq!(emu.read_csr(0xDA0), Ok(1 << 3 | 1 << 31));
+        assert_eq!(emu.write_csr(0xDA0, 0), Err(Trap::IllegalInstruction));
+        // There is no supervisor mode, so user mode can't read it even with mcounteren set
+        emu.machine_csrs.mcounteren = !0;
+        emu.privilege = Privilege::User;
+        assert_eq!(emu.read_csr(0xDA0), Err(Trap::IllegalInstruction));
     }
 }
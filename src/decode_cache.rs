@@ -0,0 +1,187 @@
+//! A cache of decoded instructions, so that code which runs repeatedly is only decoded once.
+//!
+//! The cache is direct mapped and keyed by the physical address of the instruction. Stores only
+//! need to drop the few entries that could overlap the bytes written, so self modifying code sees
+//! its writes without having to execute a FENCE.I, although FENCE.I does flush everything. Only
+//! instructions fetched from RAM are cached, since a device can return something different every
+//! time it is read.
+
+use crate::instructions::Instruction;
+
+/// The number of entries, which must be a power of two.
+const ENTRIES: usize = 1 << 14;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    pc: u64,
+    instruction: Instruction,
+    opcode: u32,
+}
+
+pub struct DecodeCache {
+    entries: Box<[Option<Entry>]>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            entries: vec![None; ENTRIES].into_boxed_slice(),
+        }
+    }
+
+    fn slot(pc: u64) -> usize {
+        (pc >> 1) as usize & (ENTRIES - 1)
+    }
+
+    /// The decoded instruction at `pc` along with its encoding, if it is cached.
+    pub fn get(&self, pc: u64) -> Option<(Instruction, u32)> {
+        match &self.entries[Self::slot(pc)] {
+            Some(entry) if entry.pc == pc => Some((entry.instruction.clone(), entry.opcode)),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, pc: u64, instruction: Instruction, opcode: u32) {
+        self.entries[Self::slot(pc)] = Some(Entry {
+            pc,
+            instruction,
+            opcode,
+        });
+    }
+
+    /// Drop every instruction which overlaps the `len` bytes at `addr`.
+    pub fn invalidate(&mut self, addr: usize, len: usize) {
+        // Instructions are at least 2 byte aligned and at most 4 bytes long, so the earliest one
+        // which can overlap the write starts up to 2 bytes before it.
+        let start = addr.saturating_sub(2) as u64 & !1;
+        let end = addr.saturating_add(len) as u64;
+        for pc in (start..end).step_by(2) {
+            let slot = &mut self.entries[Self::slot(pc)];
+            if slot.as_ref().is_some_and(|entry| entry.pc == pc) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::ops::Range;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::device::{mask, BusError, Device};
+    use crate::isa::Isa;
+    use crate::mem::{MemoryMap, RegionSpec, RAM_BASE};
+    use crate::Emulator;
+
+    const ADDI_1: u32 = 0x00150513; // addi a0, a0, 1
+    const ADDI_2: u32 = 0x00250513; // addi a0, a0, 2
+    const ADDI_16: u32 = 0x01050513; // addi a0, a0, 16
+
+    /// An emulator at the start of `program` in RAM.
+    fn emulator(program: &[u32]) -> Emulator {
+        let mut emu = Emulator::new(4096);
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu
+    }
+
+    #[test]
+    fn writes_drop_the_instructions_they_overlap() {
+        let mut cache = DecodeCache::new();
+        let decoded = |opcode| crate::decode(opcode).unwrap();
+        cache.insert(0x1000, decoded(ADDI_1), ADDI_1);
+        cache.insert(0x1004, decoded(ADDI_2), ADDI_2);
+        // This shares a slot with 0x1000, so it replaces it
+        let alias = 0x1000 + 2 * ENTRIES as u64;
+        cache.insert(alias, decoded(ADDI_16), ADDI_16);
+        assert!(cache.get(0x1000).is_none());
+        assert_eq!(cache.get(alias).map(|(_, opcode)| opcode), Some(ADDI_16));
+        cache.insert(0x1000, decoded(ADDI_1), ADDI_1);
+
+        // The last byte of the instruction at 0x1000, but nothing at 0x1004
+        cache.invalidate(0x1003, 1);
+        assert!(cache.get(0x1000).is_none());
+        assert_eq!(cache.get(0x1004).map(|(_, opcode)| opcode), Some(ADDI_2));
+        // An instruction in the same slot at another address is left alone
+        cache.insert(alias, decoded(ADDI_16), ADDI_16);
+        cache.invalidate(0x1000, 4);
+        assert!(cache.get(alias).is_some());
+        cache.flush();
+        assert!(cache.get(alias).is_none() && cache.get(0x1004).is_none());
+    }
+
+    #[test]
+    fn stores_to_code_are_seen_without_a_fence() {
+        let mut emu = emulator(&[
+            ADDI_1, 0x00542023, // sw t0, 0(s0)
+            0xff9ff06f, // j -8
+        ]);
+        emu.x[5] = u64::from(ADDI_16);
+        emu.x[8] = RAM_BASE as u64;
+        emu.run(1);
+        assert!(emu.decode_cache.get(RAM_BASE as u64).is_some());
+        emu.run(1);
+        assert!(emu.decode_cache.get(RAM_BASE as u64).is_none());
+        emu.run(2);
+        assert_eq!(emu.x[10], 17);
+    }
+
+    #[test]
+    fn fence_i_flushes_the_cache() {
+        let mut emu = emulator(&[ADDI_1, 0x0000100f]); // fence.i
+        emu.run(1);
+        assert!(emu.decode_cache.get(RAM_BASE as u64).is_some());
+        emu.run(1);
+        assert!(emu.decode_cache.get(RAM_BASE as u64).is_none());
+        assert!(emu.decode_cache.get(RAM_BASE as u64 + 4).is_none());
+    }
+
+    /// A device which returns the same instruction from every word.
+    struct Code(Rc<Cell<u32>>);
+
+    impl Device for Code {
+        fn address_range(&self) -> Range<usize> {
+            0x10000000..0x10000100
+        }
+
+        fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError> {
+            Ok(u64::from(self.0.get()) >> (offset % 4 * 8) & mask(width))
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, _val: u64) -> Result<(), BusError> {
+            Err(BusError)
+        }
+    }
+
+    #[test]
+    fn only_instructions_in_ram_are_cached() {
+        let specs = ["rom:0x1000:4K", "mmio:0x10000000:4K", "ram:0x80000000:4K"];
+        let specs: Vec<RegionSpec> = specs.iter().map(|spec| spec.parse().unwrap()).collect();
+        let mut emu =
+            Emulator::with_memory_map(MemoryMap::from_specs(&specs).unwrap(), Isa::default());
+        let instruction = Rc::new(Cell::new(ADDI_1));
+        emu.add_device(Rc::new(RefCell::new(Code(instruction.clone()))));
+        emu.memory.load(0x1000, &ADDI_1.to_le_bytes()).unwrap();
+
+        emu.set_pc(0x1000);
+        emu.run(1);
+        assert!(emu.decode_cache.get(0x1000).is_none());
+
+        // The device changes what it returns without anything being stored to it
+        emu.set_pc(0x10000000);
+        emu.run(1);
+        instruction.set(ADDI_2);
+        emu.set_pc(0x10000000);
+        emu.run(1);
+        assert!(emu.decode_cache.get(0x10000000).is_none());
+        assert_eq!(emu.x[10], 4);
+    }
+}
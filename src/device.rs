@@ -1,40 +1,114 @@
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+use std::ops::Range;
+
+use crate::mem::MemoryMap;
+use crate::snapshot::SnapshotError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AccessType {
+    Read,
     Write,
+    ReadWrite,
 }
 
 impl AccessType {
     pub fn can_read(&self) -> bool {
         match self {
+            AccessType::Read | AccessType::ReadWrite => true,
             AccessType::Write => false,
         }
     }
 
     pub fn can_write(&self) -> bool {
         match self {
-            AccessType::Write => true,
+            AccessType::Write | AccessType::ReadWrite => true,
+            AccessType::Read => false,
         }
     }
 }
 
+/// An error response from a device, such as an access to a hole in its register map or with a
+/// width it doesn't support. The hart sees this as an access fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusError;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DeviceRegister {
-    /// The address that this register can be accessed at in memory.
-    pub addr: usize,
+    /// The offset of this register from the start of the device's address range.
+    pub offset: usize,
     /// The amount of bytes that this register stores.
     pub size: usize,
     /// The access type of this register
     pub access_type: AccessType,
 }
 
+impl DeviceRegister {
+    /// Find the register in `registers` that an access of `width` bytes at `offset` falls within,
+    /// checking that the register permits the access. Accesses may be narrower than the register
+    /// but not straddle two registers.
+    pub fn decode(
+        registers: &[DeviceRegister],
+        offset: usize,
+        width: usize,
+        write: bool,
+    ) -> Result<&DeviceRegister, BusError> {
+        let register = registers
+            .iter()
+            .find(|reg| (reg.offset..reg.offset + reg.size).contains(&offset))
+            .ok_or(BusError)?;
+        let permitted = if write {
+            register.access_type.can_write()
+        } else {
+            register.access_type.can_read()
+        };
+        if !permitted || offset + width > register.offset + register.size {
+            return Err(BusError);
+        }
+        Ok(register)
+    }
+}
+
+/// The low `width` bytes of a register, for devices whose registers can be accessed in parts.
+pub(crate) fn mask(width: usize) -> u64 {
+    u64::MAX >> (64 - 8 * width)
+}
+
 /// A device which interfaces with the cpu through memory mapped io.
 ///
-/// A device will provide which addresses correspond to readable and writable, along with the size
-/// of the buffer that said address refers to.
+/// A device claims a range of addresses, and every access within that range is passed to the
+/// device as an offset from the start of the range along with the access width in bytes (1, 2, 4
+/// or 8). Values are little endian and occupy the low `width` bytes of the `u64`.
 pub trait Device {
-    /// Returns the list of registers for this device.
-    fn get_registers(&self) -> Vec<DeviceRegister>;
+    /// Returns the range of addresses that this device responds to.
+    fn address_range(&self) -> Range<usize>;
 
-    fn read_bytes(&mut self, addr: usize, size: usize) -> &[u8];
-    fn write_bytes(&mut self, addr: usize, bytes: &[u8]);
+    fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError>;
+    fn write(&mut self, offset: usize, width: usize, val: u64) -> Result<(), BusError>;
+
+    /// Act on guest memory in response to a write, for devices that are handed buffers by the
+    /// guest rather than having everything passed through their registers. This is called after
+    /// every write to the device, and returns the ranges of memory that were written.
+    fn access_memory(&mut self, _memory: &mut MemoryMap) -> Vec<Range<usize>> {
+        Vec::new()
+    }
+
+    /// The code the guest has asked to exit with through this device, if any. This is checked
+    /// after every write to the device.
+    fn exit_code(&self) -> Option<u32> {
+        None
+    }
+
+    /// Serialize the state of the device for a snapshot. Devices without any state can leave
+    /// this empty.
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Return to a state saved by [`Device::save`].
+    fn restore(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err(SnapshotError::Corrupt)
+        }
+    }
 }
@@ -0,0 +1,767 @@
+//! Rendering instructions as assembly, in the syntax GNU objdump uses.
+//!
+//! Registers go by their ABI names and immediates are sign extended. Common idioms are shown as
+//! the pseudo-instructions the assembler accepts for them, such as `li`, `mv`, `ret` or `nop`.
+//! Compressed instructions are shown by their own mnemonics with a `c.` prefix, since the
+//! expanded instruction alone would hide that they were compressed.
+
+use std::fmt::{self, Write as _};
+
+use crate::{
+    csr::csr_name,
+    elf::Elf,
+    instructions::{
+        atomic::{AAmoD, AAmoW, AMem, AOp, AtomicInstruction},
+        base::{
+            BImmediate32, BImmediate64, BLoad, BRegister32, BRegister64, BStore, BaseInstruction,
+            Branch,
+        },
+        bitmanip::{BitManipInstruction, BmImm32, BmImm64, BmReg32, BmReg64, BmUnary32, BmUnary64},
+        machine::MachineInstruction,
+        mul::{MReg32, MReg64, MulInstruction},
+        zicsr::{ZOp, ZicsrInstruction},
+        Instruction,
+    },
+    ABI_NAMES,
+};
+
+/// An instruction at a known address, ready to be displayed.
+pub struct Disassembly<'a> {
+    instruction: &'a Instruction,
+    /// Where the instruction is, for resolving pc relative targets. Without it targets are shown
+    /// relative to `.`.
+    pc: Option<u64>,
+    /// The 16 bit encoding, if the instruction was compressed.
+    compressed: Option<u16>,
+    symbols: Option<&'a Elf>,
+}
+
+/// A decoded instruction in a block of code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u64,
+    /// The encoding, of which only the low 16 bits are used if `len` is 2.
+    pub encoding: u32,
+    pub len: u64,
+    /// The assembly, or an `.insn` directive if the encoding isn't a known instruction.
+    pub text: String,
+}
+
+impl Instruction {
+    /// Display this instruction as it appears at `pc`. `compressed` is the encoding it was
+    /// decoded from if that was 16 bits, and branch targets are labelled with the symbols of
+    /// `symbols`.
+    pub fn disassemble<'a>(
+        &'a self,
+        pc: u64,
+        compressed: Option<u16>,
+        symbols: Option<&'a Elf>,
+    ) -> Disassembly<'a> {
+        Disassembly {
+            instruction: self,
+            pc: Some(pc),
+            compressed,
+            symbols,
+        }
+    }
+}
+
+/// Shows the instruction as if it was not compressed, with targets relative to `.`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Disassembly {
+            instruction: self,
+            pc: None,
+            compressed: None,
+            symbols: None,
+        }
+        .fmt(f)
+    }
+}
+
+/// Disassemble the code in `bytes`, which starts at `addr`.
+pub fn disassemble(bytes: &[u8], addr: u64, symbols: Option<&Elf>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= bytes.len() {
+        let pc = addr + offset as u64;
+        let low = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let line = if low & 0b11 != 0b11 {
+            let text = match Instruction::parse_compressed(low) {
+                Some(instruction) => instruction.disassemble(pc, Some(low), symbols).to_string(),
+                None if low == 0 => "unimp".to_string(),
+                None => format!(".insn\t2, {low:#06x}"),
+            };
+            Line {
+                addr: pc,
+                encoding: low.into(),
+                len: 2,
+                text,
+            }
+        } else if let Some(word) = bytes.get(offset..offset + 4) {
+            let encoding = u32::from_le_bytes(word.try_into().unwrap());
+            let text = match Instruction::parse(encoding) {
+                Some(instruction) => instruction.disassemble(pc, None, symbols).to_string(),
+                None => format!(".insn\t4, {encoding:#010x}"),
+            };
+            Line {
+                addr: pc,
+                encoding,
+                len: 4,
+                text,
+            }
+        } else {
+            break;
+        };
+        offset += line.len as usize;
+        lines.push(line);
+    }
+    lines
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mnemonic, operands) = match self.compressed {
+            Some(encoding) => self.compressed_form(encoding),
+            None => self.form(),
+        };
+        match operands.is_empty() {
+            true => write!(f, "{mnemonic}"),
+            false => write!(f, "{mnemonic}\t{operands}"),
+        }
+    }
+}
+
+impl Disassembly<'_> {
+    /// The mnemonic and operands of an uncompressed instruction.
+    fn form(&self) -> (String, String) {
+        use BaseInstruction as B;
+
+        let (mnemonic, operands): (&str, String) = match self.instruction {
+            Instruction::Base(instruction) => match instruction {
+                B::Lui(u) => ("lui", format!("{},{:#x}", reg(u.rd), u_imm(u.imm))),
+                B::Auipc(u) => ("auipc", format!("{},{:#x}", reg(u.rd), u_imm(u.imm))),
+                B::Jal(j, _) => {
+                    let target = self.target(j_imm(j.imm));
+                    match j.rd {
+                        0 => ("j", target),
+                        1 => ("jal", target),
+                        rd => ("jal", format!("{},{target}", reg(rd))),
+                    }
+                }
+                B::Jalr(i, _) => {
+                    let imm = i_imm(i.imm);
+                    let target = match imm {
+                        0 => reg(i.rs1).to_string(),
+                        imm => format!("{imm}({})", reg(i.rs1)),
+                    };
+                    match (i.rd, i.rs1, imm) {
+                        (0, 1, 0) => ("ret", String::new()),
+                        (0, _, _) => ("jr", target),
+                        (1, _, _) => ("jalr", target),
+                        (rd, _, _) => ("jalr", format!("{},{imm}({})", reg(rd), reg(i.rs1))),
+                    }
+                }
+                B::Branch(branch, b, _) => {
+                    let target = self.target(b_imm(b.imm));
+                    let (rs1, rs2) = (reg(b.rs1), reg(b.rs2));
+                    match (branch, b.rs1, b.rs2) {
+                        (Branch::Eq, _, 0) => ("beqz", format!("{rs1},{target}")),
+                        (Branch::Ne, _, 0) => ("bnez", format!("{rs1},{target}")),
+                        (Branch::Lt, _, 0) => ("bltz", format!("{rs1},{target}")),
+                        (Branch::Ge, _, 0) => ("bgez", format!("{rs1},{target}")),
+                        (Branch::Lt, 0, _) => ("bgtz", format!("{rs2},{target}")),
+                        (Branch::Ge, 0, _) => ("blez", format!("{rs2},{target}")),
+                        (branch, _, _) => {
+                            let mnemonic = match branch {
+                                Branch::Eq => "beq",
+                                Branch::Ne => "bne",
+                                Branch::Lt => "blt",
+                                Branch::Ltu => "bltu",
+                                Branch::Ge => "bge",
+                                Branch::Geu => "bgeu",
+                            };
+                            (mnemonic, format!("{rs1},{rs2},{target}"))
+                        }
+                    }
+                }
+                B::Load(op, i) => {
+                    let mnemonic = match op {
+                        BLoad::B => "lb",
+                        BLoad::H => "lh",
+                        BLoad::W => "lw",
+                        BLoad::D => "ld",
+                        BLoad::Bu => "lbu",
+                        BLoad::Hu => "lhu",
+                        BLoad::Wu => "lwu",
+                    };
+                    (mnemonic, memory(i.rd, i_imm(i.imm), i.rs1))
+                }
+                B::Store(op, s) => {
+                    let mnemonic = match op {
+                        BStore::B => "sb",
+                        BStore::H => "sh",
+                        BStore::W => "sw",
+                        BStore::D => "sd",
+                    };
+                    (mnemonic, memory(s.rs2, i_imm(s.imm), s.rs1))
+                }
+                B::Imm64(op, i) => {
+                    let imm = i_imm(i.imm);
+                    let (rd, rs1) = (reg(i.rd), reg(i.rs1));
+                    match (op, i.rd, i.rs1, imm) {
+                        (BImmediate64::Add, 0, 0, 0) => ("nop", String::new()),
+                        (BImmediate64::Add, _, 0, _) => ("li", format!("{rd},{imm}")),
+                        (BImmediate64::Add, _, _, 0) => ("mv", format!("{rd},{rs1}")),
+                        (BImmediate64::Xor, _, _, -1) => ("not", format!("{rd},{rs1}")),
+                        (BImmediate64::Sltu, _, _, 1) => ("seqz", format!("{rd},{rs1}")),
+                        (BImmediate64::Sll | BImmediate64::Srl | BImmediate64::Sra, _, _, _) => {
+                            let mnemonic = match op {
+                                BImmediate64::Sll => "slli",
+                                BImmediate64::Srl => "srli",
+                                _ => "srai",
+                            };
+                            (mnemonic, format!("{rd},{rs1},{}", i.imm & 0x3f))
+                        }
+                        (op, _, _, _) => {
+                            let mnemonic = match op {
+                                BImmediate64::Add => "addi",
+                                BImmediate64::Slt => "slti",
+                                BImmediate64::Sltu => "sltiu",
+                                BImmediate64::Xor => "xori",
+                                BImmediate64::Or => "ori",
+                                _ => "andi",
+                            };
+                            (mnemonic, format!("{rd},{rs1},{imm}"))
+                        }
+                    }
+                }
+                B::Imm32(op, i) => {
+                    let (rd, rs1) = (reg(i.rd), reg(i.rs1));
+                    match op {
+                        BImmediate32::Add if i.imm == 0 => ("sext.w", format!("{rd},{rs1}")),
+                        BImmediate32::Add => ("addiw", format!("{rd},{rs1},{}", i_imm(i.imm))),
+                        BImmediate32::Sll => ("slliw", format!("{rd},{rs1},{}", i.imm & 0x1f)),
+                        BImmediate32::Srl => ("srliw", format!("{rd},{rs1},{}", i.imm & 0x1f)),
+                        BImmediate32::Sra => ("sraiw", format!("{rd},{rs1},{}", i.imm & 0x1f)),
+                    }
+                }
+                B::Reg64(op, r) => {
+                    let (rd, rs1, rs2) = (reg(r.rd), reg(r.rs1), reg(r.rs2));
+                    match (op, r.rs1, r.rs2) {
+                        (BRegister64::Sub, 0, _) => ("neg", format!("{rd},{rs2}")),
+                        (BRegister64::Sltu, 0, _) => ("snez", format!("{rd},{rs2}")),
+                        (BRegister64::Slt, _, 0) => ("sltz", format!("{rd},{rs1}")),
+                        (BRegister64::Slt, 0, _) => ("sgtz", format!("{rd},{rs2}")),
+                        (op, _, _) => {
+                            let mnemonic = match op {
+                                BRegister64::Add => "add",
+                                BRegister64::Sub => "sub",
+                                BRegister64::Slt => "slt",
+                                BRegister64::Sltu => "sltu",
+                                BRegister64::Xor => "xor",
+                                BRegister64::Or => "or",
+                                BRegister64::And => "and",
+                                BRegister64::Sll => "sll",
+                                BRegister64::Srl => "srl",
+                                BRegister64::Sra => "sra",
+                            };
+                            (mnemonic, format!("{rd},{rs1},{rs2}"))
+                        }
+                    }
+                }
+                B::Reg32(op, r) => {
+                    let (rd, rs1, rs2) = (reg(r.rd), reg(r.rs1), reg(r.rs2));
+                    match (op, r.rs1) {
+                        (BRegister32::Sub, 0) => ("negw", format!("{rd},{rs2}")),
+                        (op, _) => {
+                            let mnemonic = match op {
+                                BRegister32::Add => "addw",
+                                BRegister32::Sub => "subw",
+                                BRegister32::Sll => "sllw",
+                                BRegister32::Srl => "srlw",
+                                BRegister32::Sra => "sraw",
+                            };
+                            (mnemonic, format!("{rd},{rs1},{rs2}"))
+                        }
+                    }
+                }
+                B::Fence(encoding) => fence(*encoding),
+                B::FenceI => ("fence.i", String::new()),
+                B::Ecall => ("ecall", String::new()),
+                B::Ebreak => ("ebreak", String::new()),
+            },
+            Instruction::Machine(MachineInstruction::MRet(_)) => ("mret", String::new()),
+            Instruction::Machine(MachineInstruction::Wfi(_)) => ("wfi", String::new()),
+            Instruction::Zicsr(instruction) => return zicsr(instruction),
+            Instruction::Mul(instruction) => {
+                let (mnemonic, r) = match instruction {
+                    MulInstruction::Reg64(op, r) => {
+                        let mnemonic = match op {
+                            MReg64::Mul => "mul",
+                            MReg64::Mulh => "mulh",
+                            MReg64::Mulhsu => "mulhsu",
+                            MReg64::Mulhu => "mulhu",
+                            MReg64::Div => "div",
+                            MReg64::Divu => "divu",
+                            MReg64::Rem => "rem",
+                            MReg64::Remu => "remu",
+                        };
+                        (mnemonic, r)
+                    }
+                    MulInstruction::Reg32(op, r) => {
+                        let mnemonic = match op {
+                            MReg32::Mul => "mulw",
+                            MReg32::Div => "divw",
+                            MReg32::Divu => "divuw",
+                            MReg32::Rem => "remw",
+                            MReg32::Remu => "remuw",
+                        };
+                        (mnemonic, r)
+                    }
+                };
+                (
+                    mnemonic,
+                    format!("{},{},{}", reg(r.rd), reg(r.rs1), reg(r.rs2)),
+                )
+            }
+            Instruction::Atomic(instruction) => return atomic(instruction),
+            Instruction::BitManip(instruction) => bitmanip(instruction),
+        };
+        (mnemonic.to_string(), operands)
+    }
+
+    /// The mnemonic and operands of a compressed instruction, which are those of the expanded
+    /// instruction with the mnemonic taken from the compressed encoding.
+    fn compressed_form(&self, encoding: u16) -> (String, String) {
+        use BaseInstruction as B;
+
+        let Some(mnemonic) = compressed_mnemonic(encoding) else {
+            return self.form();
+        };
+        let operands = match self.instruction {
+            Instruction::Base(instruction) => match instruction {
+                B::Load(_, i) => memory(i.rd, i_imm(i.imm), i.rs1),
+                B::Store(_, s) => memory(s.rs2, i_imm(s.imm), s.rs1),
+                B::Jal(j, _) => self.target(j_imm(j.imm)),
+                B::Branch(_, b, _) => format!("{},{}", reg(b.rs1), self.target(b_imm(b.imm))),
+                B::Jalr(i, _) => reg(i.rs1).to_string(),
+                B::Reg64(_, r) | B::Reg32(_, r) => format!("{},{}", reg(r.rd), reg(r.rs2)),
+                B::Lui(u) => format!("{},{:#x}", reg(u.rd), u_imm(u.imm)),
+                B::Imm64(_, i) | B::Imm32(_, i) => match mnemonic {
+                    "c.nop" => String::new(),
+                    "c.addi4spn" => format!("{},sp,{}", reg(i.rd), i_imm(i.imm)),
+                    "c.slli" | "c.srli" | "c.srai" => format!("{},{}", reg(i.rd), i.imm & 0x3f),
+                    _ => format!("{},{}", reg(i.rd), i_imm(i.imm)),
+                },
+                _ => String::new(),
+            },
+            _ => return self.form(),
+        };
+        (mnemonic.to_string(), operands)
+    }
+
+    /// Format a pc relative target, which is labelled with a symbol when possible.
+    fn target(&self, offset: i64) -> String {
+        let Some(pc) = self.pc else {
+            return format!(".{offset:+}");
+        };
+        let target = pc.wrapping_add_signed(offset);
+        match self.symbols.and_then(|elf| elf.symbol_at(target as usize)) {
+            Some((name, 0)) => format!("{target:x} <{name}>"),
+            Some((name, offset)) => format!("{target:x} <{name}+{offset:#x}>"),
+            None => format!("{target:x}"),
+        }
+    }
+}
+
+/// The mnemonic of a compressed instruction, given its encoding.
+fn compressed_mnemonic(encoding: u16) -> Option<&'static str> {
+    let funct3 = encoding >> 13;
+    let rd = encoding >> 7 & 0x1f;
+    let rs2 = encoding >> 2 & 0x1f;
+    Some(match (encoding & 0b11, funct3) {
+        (0b00, 0b000) => "c.addi4spn",
+        (0b00, 0b010) => "c.lw",
+        (0b00, 0b011) => "c.ld",
+        (0b00, 0b110) => "c.sw",
+        (0b00, 0b111) => "c.sd",
+        (0b01, 0b000) if rd == 0 => "c.nop",
+        (0b01, 0b000) => "c.addi",
+        (0b01, 0b001) => "c.addiw",
+        (0b01, 0b010) => "c.li",
+        (0b01, 0b011) if rd == 2 => "c.addi16sp",
+        (0b01, 0b011) => "c.lui",
+        (0b01, 0b100) => match (
+            encoding >> 10 & 0b11,
+            encoding >> 12 & 1,
+            encoding >> 5 & 0b11,
+        ) {
+            (0b00, _, _) => "c.srli",
+            (0b01, _, _) => "c.srai",
+            (0b10, _, _) => "c.andi",
+            (_, 0, 0b00) => "c.sub",
+            (_, 0, 0b01) => "c.xor",
+            (_, 0, 0b10) => "c.or",
+            (_, 0, 0b11) => "c.and",
+            (_, 1, 0b00) => "c.subw",
+            (_, 1, 0b01) => "c.addw",
+            _ => None?,
+        },
+        (0b01, 0b101) => "c.j",
+        (0b01, 0b110) => "c.beqz",
+        (0b01, 0b111) => "c.bnez",
+        (0b10, 0b000) => "c.slli",
+        (0b10, 0b010) => "c.lwsp",
+        (0b10, 0b011) => "c.ldsp",
+        (0b10, 0b100) => match (encoding >> 12 & 1, rd, rs2) {
+            (0, _, 0) => "c.jr",
+            (0, _, _) => "c.mv",
+            (1, 0, 0) => "c.ebreak",
+            (1, _, 0) => "c.jalr",
+            _ => "c.add",
+        },
+        (0b10, 0b110) => "c.swsp",
+        (0b10, 0b111) => "c.sdsp",
+        _ => None?,
+    })
+}
+
+fn zicsr(instruction: &ZicsrInstruction) -> (String, String) {
+    let ZicsrInstruction(op, immediate, i) = instruction;
+    let addr = i.imm & 0xfff;
+    let csr = match csr_name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("{addr:#x}"),
+    };
+    // The immediate forms hold the immediate where rs1 would be
+    let source = match immediate {
+        true => i.rs1.to_string(),
+        false => reg(i.rs1).to_string(),
+    };
+    let suffix = if *immediate { "i" } else { "" };
+    let name = match op {
+        ZOp::Csrrw => "w",
+        ZOp::Csrrs => "s",
+        ZOp::Csrrc => "c",
+    };
+    let counter = match addr {
+        0xc00 => Some("rdcycle"),
+        0xc01 => Some("rdtime"),
+        0xc02 => Some("rdinstret"),
+        _ => None,
+    };
+    match (op, immediate, i.rd, i.rs1) {
+        (ZOp::Csrrs, false, rd, 0) => match counter {
+            Some(mnemonic) => (mnemonic.to_string(), reg(rd).to_string()),
+            None => ("csrr".to_string(), format!("{},{csr}", reg(rd))),
+        },
+        (_, _, 0, _) => (format!("csr{name}{suffix}"), format!("{csr},{source}")),
+        (_, _, rd, _) => (
+            format!("csrr{name}{suffix}"),
+            format!("{},{csr},{source}", reg(rd)),
+        ),
+    }
+}
+
+fn atomic(instruction: &AtomicInstruction) -> (String, String) {
+    let AtomicInstruction { aq, rl, op, instr } = instruction;
+    let (rd, rs1, rs2) = (reg(instr.rd), reg(instr.rs1), reg(instr.rs2));
+    let mut mnemonic = match op {
+        AOp::Mem(AMem::LrW) => "lr.w",
+        AOp::Mem(AMem::LrD) => "lr.d",
+        AOp::Mem(AMem::ScW) => "sc.w",
+        AOp::Mem(AMem::ScD) => "sc.d",
+        AOp::AmoW(op) => match op {
+            AAmoW::Swap => "amoswap.w",
+            AAmoW::Add => "amoadd.w",
+            AAmoW::Xor => "amoxor.w",
+            AAmoW::And => "amoand.w",
+            AAmoW::Or => "amoor.w",
+            AAmoW::Min => "amomin.w",
+            AAmoW::Max => "amomax.w",
+            AAmoW::Minu => "amominu.w",
+            AAmoW::Maxu => "amomaxu.w",
+        },
+        AOp::AmoD(op) => match op {
+            AAmoD::Swap => "amoswap.d",
+            AAmoD::Add => "amoadd.d",
+            AAmoD::Xor => "amoxor.d",
+            AAmoD::And => "amoand.d",
+            AAmoD::Or => "amoor.d",
+            AAmoD::Min => "amomin.d",
+            AAmoD::Max => "amomax.d",
+            AAmoD::Minu => "amominu.d",
+            AAmoD::Maxu => "amomaxu.d",
+        },
+    }
+    .to_string();
+    match (aq, rl) {
+        (true, true) => mnemonic += ".aqrl",
+        (true, false) => mnemonic += ".aq",
+        (false, true) => mnemonic += ".rl",
+        (false, false) => {}
+    }
+    let operands = match op {
+        AOp::Mem(AMem::LrW | AMem::LrD) => format!("{rd},({rs1})"),
+        _ => format!("{rd},{rs2},({rs1})"),
+    };
+    (mnemonic, operands)
+}
+
+fn bitmanip(instruction: &BitManipInstruction) -> (&'static str, String) {
+    match instruction {
+        BitManipInstruction::Reg64(op, r) => {
+            let mnemonic = match op {
+                BmReg64::Sh1add => "sh1add",
+                BmReg64::Sh2add => "sh2add",
+                BmReg64::Sh3add => "sh3add",
+                BmReg64::Andn => "andn",
+                BmReg64::Orn => "orn",
+                BmReg64::Xnor => "xnor",
+                BmReg64::Max => "max",
+                BmReg64::Maxu => "maxu",
+                BmReg64::Min => "min",
+                BmReg64::Minu => "minu",
+                BmReg64::Rol => "rol",
+                BmReg64::Ror => "ror",
+                BmReg64::Bclr => "bclr",
+                BmReg64::Bext => "bext",
+                BmReg64::Binv => "binv",
+                BmReg64::Bset => "bset",
+            };
+            (
+                mnemonic,
+                format!("{},{},{}", reg(r.rd), reg(r.rs1), reg(r.rs2)),
+            )
+        }
+        BitManipInstruction::Reg32(BmReg32::AddUw, r) if r.rs2 == 0 => {
+            ("zext.w", format!("{},{}", reg(r.rd), reg(r.rs1)))
+        }
+        BitManipInstruction::Reg32(op, r) => {
+            let mnemonic = match op {
+                BmReg32::AddUw => "add.uw",
+                BmReg32::Sh1addUw => "sh1add.uw",
+                BmReg32::Sh2addUw => "sh2add.uw",
+                BmReg32::Sh3addUw => "sh3add.uw",
+                BmReg32::Rolw => "rolw",
+                BmReg32::Rorw => "rorw",
+            };
+            (
+                mnemonic,
+                format!("{},{},{}", reg(r.rd), reg(r.rs1), reg(r.rs2)),
+            )
+        }
+        BitManipInstruction::Unary64(op, i) => {
+            let mnemonic = match op {
+                BmUnary64::Clz => "clz",
+                BmUnary64::Ctz => "ctz",
+                BmUnary64::Cpop => "cpop",
+                BmUnary64::SextB => "sext.b",
+                BmUnary64::SextH => "sext.h",
+                BmUnary64::ZextH => "zext.h",
+                BmUnary64::OrcB => "orc.b",
+                BmUnary64::Rev8 => "rev8",
+            };
+            (mnemonic, format!("{},{}", reg(i.rd), reg(i.rs1)))
+        }
+        BitManipInstruction::Unary32(op, i) => {
+            let mnemonic = match op {
+                BmUnary32::Clzw => "clzw",
+                BmUnary32::Ctzw => "ctzw",
+                BmUnary32::Cpopw => "cpopw",
+            };
+            (mnemonic, format!("{},{}", reg(i.rd), reg(i.rs1)))
+        }
+        BitManipInstruction::Imm64(op, i) => {
+            let mnemonic = match op {
+                BmImm64::Rori => "rori",
+                BmImm64::Bclri => "bclri",
+                BmImm64::Bexti => "bexti",
+                BmImm64::Binvi => "binvi",
+                BmImm64::Bseti => "bseti",
+            };
+            let operands = format!("{},{},{}", reg(i.rd), reg(i.rs1), i.imm & 0x3f);
+            (mnemonic, operands)
+        }
+        BitManipInstruction::Imm32(op, i) => {
+            let (mnemonic, shamt) = match op {
+                BmImm32::SlliUw => ("slli.uw", i.imm & 0x3f),
+                BmImm32::Roriw => ("roriw", i.imm & 0x1f),
+            };
+            (mnemonic, format!("{},{},{shamt}", reg(i.rd), reg(i.rs1)))
+        }
+    }
+}
+
+fn fence(encoding: u32) -> (&'static str, String) {
+    let set = |bits: u32| {
+        let mut set = String::new();
+        for (bit, name) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')] {
+            if bits & bit != 0 {
+                set.push(name);
+            }
+        }
+        set
+    };
+    let (fm, pred, succ) = (encoding >> 28, encoding >> 24 & 0xf, encoding >> 20 & 0xf);
+    match (fm, pred, succ) {
+        (0b1000, 0b0011, 0b0011) => ("fence.tso", String::new()),
+        (_, 0b1111, 0b1111) => ("fence", String::new()),
+        _ => {
+            let mut operands = set(pred);
+            let _ = write!(operands, ",{}", set(succ));
+            ("fence", operands)
+        }
+    }
+}
+
+/// `reg,offset(base)`, as used by loads and stores.
+fn memory(reg_: usize, offset: i64, base: usize) -> String {
+    format!("{},{offset}({})", reg(reg_), reg(base))
+}
+
+/// The name objdump uses for register `x`, which is `s0` rather than `fp` for x8.
+fn reg(x: usize) -> &'static str {
+    match x {
+        8 => "s0",
+        x => ABI_NAMES[x],
+    }
+}
+
+/// Sign extend a 12 bit immediate.
+fn i_imm(imm: u16) -> i64 {
+    ((imm as i16) << 4 >> 4).into()
+}
+
+fn b_imm(imm: u16) -> i64 {
+    ((imm as i16) << 3 >> 3).into()
+}
+
+fn j_imm(imm: i32) -> i64 {
+    (imm << 11 >> 11).into()
+}
+
+/// The upper immediate as written in assembly, which is the top 20 bits.
+fn u_imm(imm: i32) -> u32 {
+    (imm as u32) >> 12
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of every line of `bytes` disassembled at 0x80000000.
+    fn texts(bytes: &[u8]) -> Vec<String> {
+        disassemble(bytes, 0x80000000, None)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn instructions_are_shown_as_objdump_shows_them() {
+        let samples = [
+            (0x00150513, "addi\ta0,a0,1"),
+            (0xfff00513, "li\ta0,-1"),
+            (0x00050593, "mv\ta1,a0"),
+            (0x00000013, "nop"),
+            (0x00008067, "ret"),
+            (0x12345537, "lui\ta0,0x12345"),
+            (0xfffff297, "auipc\tt0,0xfffff"),
+            (0x00813503, "ld\ta0,8(sp)"),
+            (0xfe113c23, "sd\tra,-8(sp)"),
+            (0x000300e7, "jalr\tt1"),
+            (0x30002573, "csrr\ta0,mstatus"),
+            (0x34051073, "csrw\tmscratch,a0"),
+            (0x30446073, "csrsi\tmie,8"),
+            // A csr without a name is shown by its address
+            (0x7c059573, "csrrw\ta0,0x7c0,a1"),
+            (0x02c58533, "mul\ta0,a1,a2"),
+            (0x00c5853b, "addw\ta0,a1,a2"),
+            (0x4035d51b, "sraiw\ta0,a1,3"),
+            (0x0005851b, "sext.w\ta0,a1"),
+            (0x40b00533, "neg\ta0,a1"),
+            (0x0015b513, "seqz\ta0,a1"),
+            (0x06b6252f, "amoadd.w.aqrl\ta0,a1,(a2)"),
+            (0x1005b52f, "lr.d\ta0,(a1)"),
+            (0x1ac5a52f, "sc.w.rl\ta0,a2,(a1)"),
+            (0x20c5a533, "sh1add\ta0,a1,a2"),
+            (0x60059513, "clz\ta0,a1"),
+            (0x6b85d513, "rev8\ta0,a1"),
+            (0x2bf59513, "bseti\ta0,a1,63"),
+            (0x0805853b, "zext.w\ta0,a1"),
+            (0x0ff0000f, "fence"),
+            (0x0210000f, "fence\tr,w"),
+            (0x0000100f, "fence.i"),
+            (0x00000073, "ecall"),
+            (0x00100073, "ebreak"),
+            (0x30200073, "mret"),
+            (0x10500073, "wfi"),
+            (0xffffffff, ".insn\t4, 0xffffffff"),
+        ];
+        for (encoding, text) in samples {
+            assert_eq!(
+                texts(&u32::to_le_bytes(encoding)),
+                [text],
+                "{encoding:#010x}"
+            );
+        }
+    }
+
+    #[test]
+    fn targets_are_resolved_from_the_address() {
+        let code: Vec<u8> = [0x00b50463u32, 0xfe051ee3, 0x010000ef, 0xff9ff06f]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        assert_eq!(
+            texts(&code),
+            [
+                "beq\ta0,a1,80000008",
+                "bnez\ta0,80000000",
+                "jal\t80000018",
+                "j\t80000004",
+            ]
+        );
+        // Without an address they are relative to the instruction
+        let shown: Vec<String> = [0x00b50463, 0x010000ef, 0xff9ff06f]
+            .into_iter()
+            .map(|encoding| Instruction::parse(encoding).unwrap().to_string())
+            .collect();
+        assert_eq!(shown, ["beq\ta0,a1,.+8", "jal\t.+16", "j\t.-8"]);
+    }
+
+    #[test]
+    fn compressed_instructions_keep_their_mnemonics() {
+        let code = [
+            0x05, 0x05, // c.addi a0, 1
+            0xc8, 0x41, // c.lw a0, 4(a1)
+            0xfd, 0xbf, // c.j -2
+            0x2e, 0x85, // c.mv a0, a1
+            0xa2, 0x60, // c.ldsp ra, 8(sp)
+            0x75, 0x55, // c.li a0, -3
+            0x00, 0x00, // unimp
+            0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+            0x13, // half an instruction
+        ];
+        let lines = disassemble(&code, 0x80000000, None);
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|line| (line.addr, line.len, line.text.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (0x80000000, 2, "c.addi\ta0,1"),
+                (0x80000002, 2, "c.lw\ta0,4(a1)"),
+                (0x80000004, 2, "c.j\t80000002"),
+                (0x80000006, 2, "c.mv\ta0,a1"),
+                (0x80000008, 2, "c.ldsp\tra,8(sp)"),
+                (0x8000000a, 2, "c.li\ta0,-3"),
+                (0x8000000c, 2, "unimp"),
+                (0x8000000e, 4, "addi\ta0,a0,1"),
+            ]
+        );
+    }
+}
@@ -12,6 +12,33 @@ pub struct Elf {
     symbol_table: HashMap<String, Symbol>,
     /// Entry point into the program (as an address)
     entry_point: usize,
+    /// The loadable segments of the program
+    segments: Vec<Segment>,
+    /// The sections of the program with contents in the file
+    sections: Vec<Section>,
+}
+
+/// A segment of the program that should be loaded into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The physical address to load the segment at
+    pub addr: usize,
+    /// The contents of the segment from the file
+    pub data: Vec<u8>,
+    /// The size of the segment in memory. Anything past the end of `data` is zero filled.
+    pub mem_size: usize,
+}
+
+/// A section of the program with contents in the file, such as `.text` or `.data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    /// The address the section is loaded at
+    pub addr: usize,
+    /// The contents of the section
+    pub data: Vec<u8>,
+    /// Whether the section holds code
+    pub executable: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -52,9 +79,53 @@ impl Elf {
             })
             .collect::<Result<_, _>>()?;
 
+        let segments = read_elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.typ == ProgramHeaderType::Load)
+            .map(|ph| {
+                let start = ph.offset as usize;
+                let data = bin
+                    .get(start..start + ph.file_size)
+                    .ok_or(ElfParseError::TooSmall)?;
+                if ph.mem_size < ph.file_size {
+                    return Err(ElfParseError::InvalidProgramHeader);
+                }
+                Ok(Segment {
+                    addr: ph.paddr,
+                    data: data.to_vec(),
+                    mem_size: ph.mem_size,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let sections = read_elf
+            .section_headers
+            .iter()
+            .filter(|sh| sh.typ == SectionHeaderType::ProgramBits)
+            .map(|sh| {
+                let name = read_elf
+                    .read_str_at(sh.name as usize, read_elf.header.sh_str_table_idx)
+                    .map_err(ElfParseError::Utf8Error)?
+                    .to_owned();
+                let data = bin
+                    .get(sh.offset..sh.offset + sh.size)
+                    .ok_or(ElfParseError::TooSmall)?;
+                Ok(Section {
+                    name,
+                    addr: sh.addr,
+                    data: data.to_vec(),
+                    // SHF_EXECINSTR = 0x4
+                    executable: sh.flags & 0x4 != 0,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
         Ok(Elf {
             symbol_table,
             entry_point: read_elf.header.entry,
+            segments,
+            sections,
         })
     }
 
@@ -63,10 +134,41 @@ impl Elf {
         self.entry_point
     }
 
+    /// Returns the segments that make up the program image
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
     /// Get the symbol with the corresponding name `name`, if it exists
     pub fn get_symbol(&self, name: &str) -> Option<&Symbol> {
         self.symbol_table.get(name)
     }
+
+    /// Iterate over every symbol along with its name, in no particular order
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        self.symbol_table
+            .iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
+    }
+
+    /// Returns the sections with contents in the file, in the order of the section header table
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Find the symbol that `addr` is in, returning its name and the offset of `addr` from it.
+    /// This is the closest named symbol at or before `addr`, unless `addr` is past its end.
+    pub fn symbol_at(&self, addr: usize) -> Option<(&str, usize)> {
+        self.symbols()
+            .filter(|(name, symbol)| {
+                !name.is_empty()
+                    && !name.starts_with('$')
+                    && symbol.value <= addr
+                    && (symbol.size == 0 || addr < symbol.value + symbol.size)
+            })
+            .max_by(|(a, x), (b, y)| x.value.cmp(&y.value).then(b.cmp(a)))
+            .map(|(name, symbol)| (name, addr - symbol.value))
+    }
 }
 
 /// Contains all (relevant) information that can be obtained from the ELF header of a binary.
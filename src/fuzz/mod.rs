@@ -0,0 +1,305 @@
+//! Fuzzing the decoder and interpreter with random programs and register states.
+//!
+//! Any sequence of bytes makes a [`Case`]: the first 248 give the values of x1 to x31, a quarter
+//! of which are turned into pointers into RAM so that loads and stores mostly succeed, and the
+//! rest is the program, placed at the start of RAM. The program is executed until it traps or
+//! [`MAX_STEPS`] instructions have been executed, checking after every instruction that:
+//!
+//! - x0 is still zero,
+//! - a trap has a cause the core can raise, and `mepc` holds the pc it was raised at,
+//! - a second emulator executing the instruction by [`Emulator::inject`], with compressed
+//!   instructions replaced by their 32 bit expansions, ends up in the same state, apart from the
+//!   pc and link register being 2 further on,
+//! - with the `fuzz-reference` feature, a simple model of RV64IMAC written separately from the
+//!   emulator agrees on the registers, the pc, traps and memory, for as long as the program only
+//!   uses what the model implements.
+//!
+//! Panics are left for the fuzzer to catch. [`check`] can be called from a `cargo fuzz` target,
+//! and the `fuzz` subcommand calls it with random inputs.
+
+#[cfg(feature = "fuzz-reference")]
+pub(crate) mod reference;
+
+use std::fmt;
+
+use crate::{
+    instructions::Instruction,
+    isa::{Extension, Isa},
+    mem::{MemoryMap, RegionKind},
+    trap::Trap,
+    Emulator,
+};
+
+/// Where RAM starts, and the program with it.
+pub const RAM_BASE: u64 = 0x8000_0000;
+pub const RAM_SIZE: usize = 64 * 1024;
+/// Registers that point into RAM point this far or further into it, clear of the program.
+const DATA_OFFSET: u64 = 0x4000;
+const DATA_SIZE: u64 = 0x8000;
+/// The largest program that is executed, in bytes.
+pub const MAX_PROGRAM: usize = 0x1000;
+/// The most instructions that are executed, as programs can loop.
+pub const MAX_STEPS: usize = 1000;
+
+/// The initial state a program is executed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub x: [u64; 32],
+    pub program: Vec<u8>,
+}
+
+impl Case {
+    /// Make a case out of arbitrary bytes, filling in any missing at the end with zeros.
+    pub fn from_bytes(data: &[u8]) -> Case {
+        let mut bytes = data.iter().copied();
+        let mut x = [0; 32];
+        for reg in &mut x[1..] {
+            let mut word = [0; 8];
+            for byte in &mut word {
+                *byte = bytes.next().unwrap_or(0);
+            }
+            let val = u64::from_le_bytes(word);
+            *reg = if val >> 62 == 0 {
+                RAM_BASE + DATA_OFFSET + val % DATA_SIZE
+            } else {
+                val
+            };
+        }
+        let program = bytes.take(MAX_PROGRAM).collect();
+        Case { x, program }
+    }
+
+    /// An emulator with only RAM mapped, holding the program and about to execute it.
+    fn emulator(&self, isa: &Isa) -> Emulator {
+        let mut memory = MemoryMap::new();
+        memory
+            .add_region("ram", RegionKind::Ram, RAM_BASE as usize, RAM_SIZE)
+            .expect("RAM is the only region");
+        let mut emu = Emulator::with_memory_map(memory, isa.clone());
+        emu.memory
+            .load(RAM_BASE as usize, &self.program)
+            .expect("The program fits in RAM");
+        emu.x = self.x;
+        emu.pc = RAM_BASE;
+        emu
+    }
+}
+
+/// A broken invariant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The number of instructions executed before the one that broke the invariant.
+    pub step: usize,
+    pub pc: u64,
+    /// The encoding of the instruction, or as much of it as was fetched.
+    pub opcode: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {} at pc {:#x} ({:#x}): {}",
+            self.step, self.pc, self.opcode, self.message
+        )
+    }
+}
+
+/// Execute the case made from `data` on a core implementing `isa`, checking the invariants
+/// after every instruction.
+pub fn check(data: &[u8], isa: &Isa) -> Result<(), Violation> {
+    let case = Case::from_bytes(data);
+    let mut emu = case.emulator(isa);
+    let mut shadow = case.emulator(isa);
+    #[cfg(feature = "fuzz-reference")]
+    let mut reference = Some(reference::Hart::new(&case, isa));
+
+    for step in 0..MAX_STEPS {
+        let pc = emu.pc;
+        let fetched = emu.fetch();
+        let opcode = match &fetched {
+            Ok((_, opcode)) => *opcode,
+            Err((_, opcode)) => *opcode as u32,
+        };
+        let violation = |message: String| Violation {
+            step,
+            pc,
+            opcode,
+            message,
+        };
+        // What the shadow executes, and how much further on the emulator's pc and link register
+        // should be than the shadow's
+        let (injected, shortened) = match &fetched {
+            Ok((instruction, opcode))
+                if opcode & 0b11 != 0b11 && isa.has(Extension::Compressed) =>
+            {
+                (instruction.expanded().encode(), 2)
+            }
+            _ => (opcode, 0),
+        };
+        let jumps = fetched
+            .as_ref()
+            .is_ok_and(|(instruction, _)| instruction.control_transfer(pc).is_some());
+        let link = fetched
+            .as_ref()
+            .ok()
+            .filter(|_| jumps)
+            .and_then(|(instruction, _)| instruction.destination());
+        let fetch_fault = matches!(fetched, Err((Trap::InstrAccessFault, _)));
+
+        let trapped = emu.retire(fetched);
+
+        if emu.x[0] != 0 {
+            return Err(violation(format!("x0 was set to {:#x}", emu.x[0])));
+        }
+        if trapped {
+            let cause = emu.machine_csrs.mcause;
+            if !Trap::ALL.iter().any(|trap| trap.to_code() == cause) {
+                return Err(violation(format!("trapped with unknown cause {cause:#x}")));
+            }
+            if emu.machine_csrs.mepc != pc {
+                return Err(violation(format!(
+                    "trapped with mepc {:#x}",
+                    emu.machine_csrs.mepc
+                )));
+            }
+        }
+
+        // The shadow can't be fetched from where the emulator couldn't fetch
+        if !fetch_fault {
+            let trace = shadow.inject(injected);
+            if trace.trap != trapped {
+                return Err(violation(format!(
+                    "{} when executed as {injected:#x}",
+                    if trapped { "didn't trap" } else { "trapped" }
+                )));
+            }
+            let mut expected = shadow.x;
+            let mut expected_pc = shadow.pc;
+            if !trapped {
+                if let Some(rd) = link {
+                    expected[rd] = expected[rd].wrapping_sub(shortened);
+                }
+                // A jump or branch to the next 32 bit instruction goes to the same place either
+                // way, where falling through doesn't
+                let next = pc.wrapping_add(4);
+                if expected_pc == next && !(jumps && emu.pc == next) {
+                    expected_pc = expected_pc.wrapping_sub(shortened);
+                }
+            }
+            if let Some(reg) = (0..32).find(|&reg| emu.x[reg] != expected[reg]) {
+                return Err(violation(format!(
+                    "x{reg} is {:#x}, but {:#x} when executed as {injected:#x}",
+                    emu.x[reg], expected[reg]
+                )));
+            }
+            if emu.pc != expected_pc {
+                return Err(violation(format!(
+                    "pc is {:#x}, but {expected_pc:#x} when executed as {injected:#x}",
+                    emu.pc
+                )));
+            }
+            let (csrs, shadow_csrs) = (&emu.machine_csrs, &shadow.machine_csrs);
+            if (csrs.mcause, csrs.mepc, csrs.mtval, csrs.mstatus)
+                != (
+                    shadow_csrs.mcause,
+                    shadow_csrs.mepc,
+                    shadow_csrs.mtval,
+                    shadow_csrs.mstatus,
+                )
+                || emu.privilege != shadow.privilege
+            {
+                return Err(violation(format!(
+                    "trap state differs when executed as {injected:#x}"
+                )));
+            }
+            if emu.debug_read(RAM_BASE as usize, RAM_SIZE)
+                != shadow.debug_read(RAM_BASE as usize, RAM_SIZE)
+            {
+                return Err(violation(format!(
+                    "memory differs when executed as {injected:#x}"
+                )));
+            }
+            // Carry on from the same state
+            shadow.x = emu.x;
+            shadow.pc = emu.pc;
+        }
+
+        #[cfg(feature = "fuzz-reference")]
+        if let Some(hart) = &mut reference {
+            match hart.compare(&emu, trapped) {
+                Ok(true) => {}
+                // The program did something the model doesn't implement
+                Ok(false) => reference = None,
+                Err(message) => return Err(violation(message)),
+            }
+        }
+
+        if trapped {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The seed that tests start the generator from, so that their failures are reproducible.
+#[cfg(test)]
+pub(crate) const TEST_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Advance the xorshift generator `state`, which must not be zero, returning 32 random bits.
+pub(crate) fn xorshift(state: &mut u64) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 32) as u32
+}
+
+/// Make an input for [`check`] with the xorshift generator `state`. The program is made of
+/// instructions that decode, as random bytes rarely get past the first instruction.
+pub fn random_input(state: &mut u64) -> Vec<u8> {
+    let mut next = || xorshift(state);
+    let mut data = Vec::new();
+    for _ in 1..32 {
+        let val = u64::from(next()) << 32 | u64::from(next());
+        data.extend(val.to_le_bytes());
+    }
+    for _ in 0..next() % 64 + 1 {
+        // A quarter of the instructions are compressed
+        if next() % 4 == 0 {
+            let encoding = loop {
+                let encoding = next() as u16;
+                if encoding & 0b11 != 0b11 && Instruction::parse_compressed(encoding).is_some() {
+                    break encoding;
+                }
+            };
+            data.extend(encoding.to_le_bytes());
+        } else {
+            let encoding = loop {
+                let encoding = next() | 0b11;
+                if Instruction::parse(encoding).is_some() {
+                    break encoding;
+                }
+            };
+            data.extend(encoding.to_le_bytes());
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_programs_keep_invariants() {
+        let isa = Isa::default();
+        let mut state = TEST_SEED;
+        for _ in 0..2000 {
+            let data = random_input(&mut state);
+            if let Err(violation) = check(&data, &isa) {
+                panic!("{violation}\ninput: {data:02x?}");
+            }
+        }
+    }
+}
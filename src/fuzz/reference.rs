@@ -0,0 +1,584 @@
+//! A model of an RV64IMAC hart in M mode, written from the specification rather than from the
+//! emulator, to compare the emulator with while fuzzing.
+//!
+//! It has its own decoder, expands compressed instructions itself, and keeps its own copy of RAM.
+//! Anything outside RV64IMAC, such as CSRs, `mret` and the bit manipulation extensions, isn't
+//! modelled, and neither are misaligned atomics, which the specification leaves to the
+//! implementation. Comparing stops at the first instruction that needs any of them.
+
+use super::{Case, RAM_BASE, RAM_SIZE};
+use crate::{
+    isa::{Extension, Isa},
+    Emulator,
+};
+
+const INSTR_ADDR_MISALIGNED: u64 = 0;
+const INSTR_ACCESS_FAULT: u64 = 1;
+const ILLEGAL_INSTRUCTION: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const LOAD_ACCESS_FAULT: u64 = 5;
+const STORE_ACCESS_FAULT: u64 = 7;
+const ECALL_M: u64 = 11;
+
+/// What executing an instruction did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Retired,
+    /// A trap with the given cause was taken.
+    Trap(u64),
+    /// The instruction isn't modelled.
+    Unsupported,
+}
+
+/// What a compressed instruction expands to.
+pub(crate) enum Expansion {
+    Instruction(u32),
+    /// A reserved encoding.
+    Illegal,
+    /// An encoding from an extension that isn't modelled.
+    Unknown,
+}
+
+pub(crate) struct Hart {
+    x: [u64; 32],
+    pc: u64,
+    ram: Vec<u8>,
+    /// The address and width of the last `lr`, if no `sc` has come since.
+    reservation: Option<(u64, usize)>,
+    multiply: bool,
+    atomic: bool,
+    compressed: bool,
+}
+
+impl Hart {
+    pub(crate) fn new(case: &Case, isa: &Isa) -> Hart {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[..case.program.len()].copy_from_slice(&case.program);
+        Hart {
+            x: case.x,
+            pc: RAM_BASE,
+            ram,
+            reservation: None,
+            multiply: isa.has(Extension::Multiply),
+            atomic: isa.has(Extension::Atomic),
+            compressed: isa.has(Extension::Compressed),
+        }
+    }
+
+    /// Execute the next instruction and compare the result with the emulator, which has just
+    /// executed it and `trapped` or not. Returns whether the instruction was modelled, and so
+    /// whether the next one can be compared.
+    pub(crate) fn compare(&mut self, emu: &Emulator, trapped: bool) -> Result<bool, String> {
+        let cause = match self.step() {
+            Outcome::Retired => None,
+            Outcome::Trap(cause) => Some(cause),
+            Outcome::Unsupported => return Ok(false),
+        };
+        let actual = emu.machine_csrs.mcause;
+        match (cause, trapped) {
+            (None, true) => {
+                return Err(format!(
+                    "trapped with cause {actual:#x}, where the reference model didn't trap"
+                ))
+            }
+            (Some(cause), false) => {
+                return Err(format!(
+                    "didn't trap, where the reference model trapped with cause {cause:#x}"
+                ))
+            }
+            (Some(cause), true) if cause != actual => {
+                return Err(format!(
+                    "trapped with cause {actual:#x}, where the reference model trapped with cause \
+                     {cause:#x}"
+                ))
+            }
+            (Some(_), true) => return Ok(true),
+            (None, false) => {}
+        }
+        if let Some(reg) = (1..32).find(|&reg| emu.x[reg] != self.x[reg]) {
+            return Err(format!(
+                "x{reg} is {:#x}, where the reference model has {:#x}",
+                emu.x[reg], self.x[reg]
+            ));
+        }
+        if emu.pc != self.pc {
+            return Err(format!(
+                "pc is {:#x}, where the reference model has {:#x}",
+                emu.pc, self.pc
+            ));
+        }
+        let ram = emu.debug_read(RAM_BASE as usize, RAM_SIZE);
+        if let Some(offset) = (0..RAM_SIZE).find(|&offset| ram[offset] != self.ram[offset]) {
+            return Err(format!(
+                "memory at {:#x} is {:#x}, where the reference model has {:#x}",
+                RAM_BASE + offset as u64,
+                ram[offset],
+                self.ram[offset]
+            ));
+        }
+        Ok(true)
+    }
+
+    fn step(&mut self) -> Outcome {
+        let Some(low) = self.load(self.pc, 2) else {
+            return Outcome::Trap(INSTR_ACCESS_FAULT);
+        };
+        if low & 0b11 == 0b11 {
+            match self.load(self.pc, 4) {
+                Some(inst) => self.execute(inst as u32, 4),
+                None => Outcome::Trap(INSTR_ACCESS_FAULT),
+            }
+        } else if !self.compressed {
+            Outcome::Trap(ILLEGAL_INSTRUCTION)
+        } else {
+            match expand(low as u32) {
+                Expansion::Instruction(inst) => self.execute(inst, 2),
+                Expansion::Illegal => Outcome::Trap(ILLEGAL_INSTRUCTION),
+                Expansion::Unknown => Outcome::Unsupported,
+            }
+        }
+    }
+
+    /// Execute the 32 bit instruction `inst`, which was encoded in `len` bytes.
+    fn execute(&mut self, inst: u32, len: u64) -> Outcome {
+        let opcode = inst & 0x7f;
+        let rd = (inst >> 7 & 0x1f) as usize;
+        let funct3 = inst >> 12 & 0b111;
+        let rs1 = (inst >> 15 & 0x1f) as usize;
+        let rs2 = (inst >> 20 & 0x1f) as usize;
+        let funct7 = inst >> 25;
+        let (a, b) = (self.x[rs1], self.x[rs2]);
+        let imm_i = (inst as i32 >> 20) as u64;
+        let imm_s = ((inst as i32 >> 20) as u64 & !0x1f) | u64::from(inst >> 7 & 0x1f);
+        let imm_u = (inst & 0xffff_f000) as i32 as u64;
+        let next = self.pc.wrapping_add(len);
+
+        let result = match opcode {
+            // LUI
+            0x37 => imm_u,
+            // AUIPC
+            0x17 => self.pc.wrapping_add(imm_u),
+            // JAL
+            0x6f => {
+                let imm = (inst as i32 >> 31 << 20) as u64
+                    | u64::from(inst & 0xff000)
+                    | u64::from(inst >> 9 & 0x800)
+                    | u64::from(inst >> 20 & 0x7fe);
+                return self.jump(self.pc.wrapping_add(imm), rd, next);
+            }
+            // JALR
+            0x67 if funct3 == 0 => return self.jump(a.wrapping_add(imm_i) & !1, rd, next),
+            // BRANCH
+            0x63 => {
+                let taken = match funct3 {
+                    0b000 => a == b,
+                    0b001 => a != b,
+                    0b100 => (a as i64) < b as i64,
+                    0b101 => a as i64 >= b as i64,
+                    0b110 => a < b,
+                    0b111 => a >= b,
+                    _ => return Outcome::Trap(ILLEGAL_INSTRUCTION),
+                };
+                if !taken {
+                    self.pc = next;
+                    return Outcome::Retired;
+                }
+                let imm = (inst as i32 >> 31 << 12) as u64
+                    | u64::from(inst << 4 & 0x800)
+                    | u64::from(inst >> 20 & 0x7e0)
+                    | u64::from(inst >> 7 & 0x1e);
+                return self.jump(self.pc.wrapping_add(imm), 0, next);
+            }
+            // LOAD
+            0x03 => {
+                if funct3 == 0b111 {
+                    return Outcome::Trap(ILLEGAL_INSTRUCTION);
+                }
+                let width = 1 << (funct3 & 0b11);
+                let Some(val) = self.load(a.wrapping_add(imm_i), width) else {
+                    return Outcome::Trap(LOAD_ACCESS_FAULT);
+                };
+                match funct3 {
+                    0b000 => val as i8 as u64,
+                    0b001 => val as i16 as u64,
+                    0b010 => val as i32 as u64,
+                    _ => val,
+                }
+            }
+            // STORE
+            0x23 => {
+                if funct3 > 0b011 {
+                    return Outcome::Trap(ILLEGAL_INSTRUCTION);
+                }
+                if self.store(a.wrapping_add(imm_s), 1 << funct3, b).is_none() {
+                    return Outcome::Trap(STORE_ACCESS_FAULT);
+                }
+                self.pc = next;
+                return Outcome::Retired;
+            }
+            // OP-IMM
+            0x13 => {
+                let shamt = imm_i & 0x3f;
+                match (funct3, inst >> 26) {
+                    (0b000, _) => a.wrapping_add(imm_i),
+                    (0b010, _) => ((a as i64) < imm_i as i64).into(),
+                    (0b011, _) => (a < imm_i).into(),
+                    (0b100, _) => a ^ imm_i,
+                    (0b110, _) => a | imm_i,
+                    (0b111, _) => a & imm_i,
+                    (0b001, 0) => a << shamt,
+                    (0b101, 0) => a >> shamt,
+                    (0b101, 0x10) => (a as i64 >> shamt) as u64,
+                    _ => return Outcome::Unsupported,
+                }
+            }
+            // OP-IMM-32
+            0x1b => {
+                let shamt = rs2 as u32;
+                let val = match (funct3, funct7) {
+                    (0b000, _) => (a as i32).wrapping_add(imm_i as i32),
+                    (0b001, 0) => (a as i32) << shamt,
+                    (0b101, 0) => (a as u32 >> shamt) as i32,
+                    (0b101, 0x20) => a as i32 >> shamt,
+                    _ => return Outcome::Unsupported,
+                };
+                val as u64
+            }
+            // OP
+            0x33 => match (funct7, funct3) {
+                (0x00, 0b000) => a.wrapping_add(b),
+                (0x20, 0b000) => a.wrapping_sub(b),
+                (0x00, 0b001) => a << (b & 0x3f),
+                (0x00, 0b010) => ((a as i64) < b as i64).into(),
+                (0x00, 0b011) => (a < b).into(),
+                (0x00, 0b100) => a ^ b,
+                (0x00, 0b101) => a >> (b & 0x3f),
+                (0x20, 0b101) => (a as i64 >> (b & 0x3f)) as u64,
+                (0x00, 0b110) => a | b,
+                (0x00, 0b111) => a & b,
+                (0x01, _) if !self.multiply => return Outcome::Trap(ILLEGAL_INSTRUCTION),
+                (0x01, _) => multiply(funct3, a, b),
+                _ => return Outcome::Unsupported,
+            },
+            // OP-32
+            0x3b => {
+                let (a, b) = (a as u32, b as u32);
+                let val = match (funct7, funct3) {
+                    (0x00, 0b000) => a.wrapping_add(b),
+                    (0x20, 0b000) => a.wrapping_sub(b),
+                    (0x00, 0b001) => a << (b & 0x1f),
+                    (0x00, 0b101) => a >> (b & 0x1f),
+                    (0x20, 0b101) => (a as i32 >> (b & 0x1f)) as u32,
+                    (0x01, 0b000 | 0b100..=0b111) if !self.multiply => {
+                        return Outcome::Trap(ILLEGAL_INSTRUCTION)
+                    }
+                    (0x01, 0b000 | 0b100..=0b111) => multiply_word(funct3, a, b),
+                    _ => return Outcome::Unsupported,
+                };
+                val as i32 as u64
+            }
+            // MISC-MEM: FENCE orders nothing on a single hart
+            0x0f if funct3 == 0 => {
+                self.pc = next;
+                return Outcome::Retired;
+            }
+            // SYSTEM
+            0x73 => {
+                return match inst {
+                    0x0000_0073 => Outcome::Trap(ECALL_M),
+                    0x0010_0073 => Outcome::Trap(BREAKPOINT),
+                    _ => Outcome::Unsupported,
+                }
+            }
+            // AMO
+            0x2f if funct3 == 0b010 || funct3 == 0b011 => {
+                if !self.atomic {
+                    return Outcome::Trap(ILLEGAL_INSTRUCTION);
+                }
+                match self.atomic(inst >> 27, funct3, rs2, a, b) {
+                    Ok(val) => val,
+                    Err(outcome) => return outcome,
+                }
+            }
+            _ => return Outcome::Unsupported,
+        };
+        self.write(rd, result);
+        self.pc = next;
+        Outcome::Retired
+    }
+
+    /// Execute the AMO with `funct5` and `funct3` on the address `addr`, returning the value to
+    /// write to rd.
+    fn atomic(
+        &mut self,
+        funct5: u32,
+        funct3: u32,
+        rs2: usize,
+        addr: u64,
+        src: u64,
+    ) -> Result<u64, Outcome> {
+        let width = if funct3 == 0b010 { 4 } else { 8 };
+        let extend = |val: u64| if width == 4 { val as i32 as u64 } else { val };
+        if !addr.is_multiple_of(width as u64) {
+            return Err(Outcome::Unsupported);
+        }
+        match funct5 {
+            // LR
+            0b00010 => {
+                if rs2 != 0 {
+                    return Err(Outcome::Trap(ILLEGAL_INSTRUCTION));
+                }
+                let val = self
+                    .load(addr, width)
+                    .ok_or(Outcome::Trap(LOAD_ACCESS_FAULT))?;
+                self.reservation = Some((addr, width));
+                Ok(extend(val))
+            }
+            // SC, which gives up the reservation whether it succeeds or not
+            0b00011 => {
+                if self.reservation.take() != Some((addr, width)) {
+                    return Ok(1);
+                }
+                self.store(addr, width, src)
+                    .ok_or(Outcome::Trap(STORE_ACCESS_FAULT))?;
+                Ok(0)
+            }
+            _ => {
+                let old = self
+                    .load(addr, width)
+                    .ok_or(Outcome::Trap(STORE_ACCESS_FAULT))?;
+                let (a, b) = (extend(old), extend(src));
+                let new = match funct5 {
+                    0b00001 => b,
+                    0b00000 => a.wrapping_add(b),
+                    0b00100 => a ^ b,
+                    0b01100 => a & b,
+                    0b01000 => a | b,
+                    0b10000 => (a as i64).min(b as i64) as u64,
+                    0b10100 => (a as i64).max(b as i64) as u64,
+                    0b11000 => a.min(b),
+                    0b11100 => a.max(b),
+                    _ => return Err(Outcome::Unsupported),
+                };
+                self.store(addr, width, new)
+                    .ok_or(Outcome::Trap(STORE_ACCESS_FAULT))?;
+                Ok(a)
+            }
+        }
+    }
+
+    /// Jump to `target`, writing the address of the next instruction to rd.
+    fn jump(&mut self, target: u64, rd: usize, next: u64) -> Outcome {
+        if !self.compressed && target & 0b10 != 0 {
+            return Outcome::Trap(INSTR_ADDR_MISALIGNED);
+        }
+        self.write(rd, next);
+        self.pc = target;
+        Outcome::Retired
+    }
+
+    fn write(&mut self, rd: usize, val: u64) {
+        if rd != 0 {
+            self.x[rd] = val;
+        }
+    }
+
+    /// The offset into RAM of the `width` bytes at `addr`, if they are all in RAM.
+    fn offset(addr: u64, width: usize) -> Option<usize> {
+        let offset = usize::try_from(addr.checked_sub(RAM_BASE)?).ok()?;
+        (offset.checked_add(width)? <= RAM_SIZE).then_some(offset)
+    }
+
+    fn load(&self, addr: u64, width: usize) -> Option<u64> {
+        let offset = Self::offset(addr, width)?;
+        let bytes = &self.ram[offset..offset + width];
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |val, &byte| val << 8 | u64::from(byte)),
+        )
+    }
+
+    fn store(&mut self, addr: u64, width: usize, val: u64) -> Option<()> {
+        let offset = Self::offset(addr, width)?;
+        self.ram[offset..offset + width].copy_from_slice(&val.to_le_bytes()[..width]);
+        Some(())
+    }
+}
+
+/// The result of the M extension instruction in OP with `funct3`.
+fn multiply(funct3: u32, a: u64, b: u64) -> u64 {
+    let (sa, sb) = (a as i64, b as i64);
+    match funct3 {
+        0b000 => a.wrapping_mul(b),
+        0b001 => ((i128::from(sa) * i128::from(sb)) >> 64) as u64,
+        0b010 => ((i128::from(sa) * i128::from(b)) >> 64) as u64,
+        0b011 => ((u128::from(a) * u128::from(b)) >> 64) as u64,
+        0b100 if b == 0 => u64::MAX,
+        0b100 => sa.wrapping_div(sb) as u64,
+        0b101 if b == 0 => u64::MAX,
+        0b101 => a / b,
+        0b110 if b == 0 => a,
+        0b110 => sa.wrapping_rem(sb) as u64,
+        _ if b == 0 => a,
+        _ => a % b,
+    }
+}
+
+/// The result of the M extension instruction in OP-32 with `funct3`, before sign extension.
+fn multiply_word(funct3: u32, a: u32, b: u32) -> u32 {
+    let (sa, sb) = (a as i32, b as i32);
+    match funct3 {
+        0b000 => a.wrapping_mul(b),
+        0b100 if b == 0 => u32::MAX,
+        0b100 => sa.wrapping_div(sb) as u32,
+        0b101 if b == 0 => u32::MAX,
+        0b101 => a / b,
+        0b110 if b == 0 => a,
+        0b110 => sa.wrapping_rem(sb) as u32,
+        _ if b == 0 => a,
+        _ => a % b,
+    }
+}
+
+fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: u32) -> u32 {
+    imm << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn r_type(opcode: u32, funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    (imm >> 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+}
+
+fn b_type(funct3: u32, rs1: u32, imm: u32) -> u32 {
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | 0x63
+}
+
+fn j_type(imm: u32) -> u32 {
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm & 0xff000)
+        | 0x6f
+}
+
+/// Sign extend the low `bits` bits of `val`.
+fn sign_extend(val: u32, bits: u32) -> u32 {
+    ((val << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+/// The 32 bit instruction the RV64C instruction `c` expands to.
+pub(crate) fn expand(c: u32) -> Expansion {
+    use Expansion::{Illegal, Instruction, Unknown};
+
+    let bit = |n: u32| c >> n & 1;
+    let bits = |hi: u32, lo: u32| c >> lo & ((1 << (hi - lo + 1)) - 1);
+    let funct3 = bits(15, 13);
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    // The registers x8 to x15 of the three bit register fields
+    let rd_short = 8 + bits(4, 2);
+    let rs1_short = 8 + bits(9, 7);
+    let imm6 = sign_extend(bit(12) << 5 | bits(6, 2), 6) & 0xfff;
+    let shamt = bit(12) << 5 | bits(6, 2);
+    // The offsets of C.LW and C.SW, and of C.LD and C.SD
+    let word_offset = bits(12, 10) << 3 | bit(6) << 2 | bit(5) << 6;
+    let double_offset = bits(12, 10) << 3 | bits(6, 5) << 6;
+
+    Instruction(match (c & 0b11, funct3) {
+        (0b00, 0b000) => {
+            let imm = bits(12, 11) << 4 | bits(10, 7) << 6 | bit(6) << 2 | bit(5) << 3;
+            if imm == 0 {
+                return Illegal;
+            }
+            i_type(0x13, 0b000, rd_short, 2, imm)
+        }
+        (0b00, 0b010) => i_type(0x03, 0b010, rd_short, rs1_short, word_offset),
+        (0b00, 0b011) => i_type(0x03, 0b011, rd_short, rs1_short, double_offset),
+        (0b00, 0b110) => s_type(0b010, rs1_short, rd_short, word_offset),
+        (0b00, 0b111) => s_type(0b011, rs1_short, rd_short, double_offset),
+        (0b01, 0b000) => i_type(0x13, 0b000, rd, rd, imm6),
+        (0b01, 0b001) => {
+            if rd == 0 {
+                return Illegal;
+            }
+            i_type(0x1b, 0b000, rd, rd, imm6)
+        }
+        (0b01, 0b010) => i_type(0x13, 0b000, rd, 0, imm6),
+        (0b01, 0b011) if rd == 2 => {
+            let imm = bit(12) << 9 | bit(6) << 4 | bit(5) << 6 | bits(4, 3) << 7 | bit(2) << 5;
+            if imm == 0 {
+                return Illegal;
+            }
+            i_type(0x13, 0b000, 2, 2, sign_extend(imm, 10) & 0xfff)
+        }
+        (0b01, 0b011) => {
+            if imm6 == 0 {
+                return Illegal;
+            }
+            sign_extend(imm6, 6) << 12 | rd << 7 | 0x37
+        }
+        (0b01, 0b100) => match (bits(11, 10), bit(12), bits(6, 5)) {
+            (0b00, _, _) => i_type(0x13, 0b101, rs1_short, rs1_short, shamt),
+            (0b01, _, _) => i_type(0x13, 0b101, rs1_short, rs1_short, 0x400 | shamt),
+            (0b10, _, _) => i_type(0x13, 0b111, rs1_short, rs1_short, imm6),
+            (0b11, 0, op) => {
+                let (funct7, funct3) =
+                    [(0x20, 0b000), (0, 0b100), (0, 0b110), (0, 0b111)][op as usize];
+                r_type(0x33, funct7, funct3, rs1_short, rs1_short, rd_short)
+            }
+            (0b11, 1, 0b00) => r_type(0x3b, 0x20, 0b000, rs1_short, rs1_short, rd_short),
+            (0b11, 1, 0b01) => r_type(0x3b, 0, 0b000, rs1_short, rs1_short, rd_short),
+            _ => return Unknown,
+        },
+        (0b01, 0b101) => {
+            let imm = bit(12) << 11
+                | bit(11) << 4
+                | bits(10, 9) << 8
+                | bit(8) << 10
+                | bit(7) << 6
+                | bit(6) << 7
+                | bits(5, 3) << 1
+                | bit(2) << 5;
+            j_type(sign_extend(imm, 12))
+        }
+        (0b01, 0b110 | 0b111) => {
+            let imm =
+                bit(12) << 8 | bits(11, 10) << 3 | bits(6, 5) << 6 | bits(4, 3) << 1 | bit(2) << 5;
+            b_type(funct3 & 1, rs1_short, sign_extend(imm, 9))
+        }
+        (0b10, 0b000) => i_type(0x13, 0b001, rd, rd, shamt),
+        (0b10, 0b010 | 0b011) => {
+            if rd == 0 {
+                return Illegal;
+            }
+            let imm = if funct3 == 0b010 {
+                bit(12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6
+            } else {
+                bit(12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6
+            };
+            i_type(0x03, funct3, rd, 2, imm)
+        }
+        (0b10, 0b100) => match (bit(12), rd, rs2) {
+            (0, 0, 0) => return Illegal,
+            (0, _, 0) => i_type(0x67, 0, 0, rd, 0),
+            (0, _, _) => r_type(0x33, 0, 0b000, rd, 0, rs2),
+            (1, 0, 0) => 0x0010_0073,
+            (1, _, 0) => i_type(0x67, 0, 1, rd, 0),
+            (_, _, _) => r_type(0x33, 0, 0b000, rd, rd, rs2),
+        },
+        (0b10, 0b110) => s_type(0b010, 2, rs2, bits(12, 9) << 2 | bits(8, 7) << 6),
+        (0b10, 0b111) => s_type(0b011, 2, rs2, bits(12, 10) << 3 | bits(9, 7) << 6),
+        _ => return Unknown,
+    })
+}
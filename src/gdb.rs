@@ -0,0 +1,766 @@
+//! A server for the GDB remote serial protocol, so that gdb can debug the guest as if it were a
+//! board connected through a debug probe.
+//!
+//! gdb is told about the registers through a target description, which lists the general
+//! purpose registers, the pc, every csr the core implements and the current privilege mode,
+//! numbered the way gdb numbers them for RISC-V. Memory accesses from gdb go straight to RAM and
+//! ROM and never reach devices, as reading a device register can have side effects. Breakpoints
+//! of both kinds and watchpoints use those of the emulator rather than patching the guest, and
+//! the executable is offered to gdb so that it loads the symbols itself.
+
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use crate::{device::AccessType, Emulator, Privilege, StopReason, ABI_NAMES};
+
+/// The number of registers in a `g` packet, which are the general purpose registers and the pc.
+const CORE_REGS: usize = 33;
+/// gdb numbers each csr by its address plus this.
+const FIRST_CSR_REGNUM: usize = 65;
+/// The register gdb shows the current privilege mode in.
+const PRIV_REGNUM: usize = FIRST_CSR_REGNUM + 4096;
+/// How many instructions to execute between checking whether gdb asked to stop.
+const CHUNK: u64 = 100_000;
+/// The largest packet gdb may send.
+const PACKET_SIZE: usize = 0x4000;
+
+/// How a debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// gdb detached or disconnected, leaving the guest to carry on by itself.
+    Detached,
+    /// gdb killed the guest.
+    Killed,
+    /// The guest exited with this code while gdb was attached.
+    Exited(u32),
+}
+
+/// Serve a single gdb connection, reading packets from `input` and replying on `output`, until
+/// gdb goes away or the guest exits. `exec_file` is the path of the executable the guest was
+/// loaded from, which gdb reads symbols from.
+pub fn serve(
+    emu: &mut Emulator,
+    input: impl Read + Send + 'static,
+    output: impl Write,
+    exec_file: Option<&str>,
+) -> io::Result<SessionEnd> {
+    let (send, receive) = mpsc::channel();
+    // A thread reads from gdb so that a Ctrl-C can be noticed while the guest is running
+    std::thread::spawn(move || {
+        let mut input = input;
+        let mut buf = [0; 4096];
+        while let Ok(len @ 1..) = input.read(&mut buf) {
+            if buf[..len].iter().any(|&byte| send.send(byte).is_err()) {
+                break;
+            }
+        }
+    });
+    let mut session = Session {
+        emu,
+        output,
+        input: receive,
+        ack: true,
+        exec_file,
+        sw_breakpoints: BTreeSet::new(),
+        hw_breakpoints: BTreeSet::new(),
+        watchpoints: Vec::new(),
+    };
+    let end = session.run();
+    session.clear_breakpoints();
+    end
+}
+
+struct Session<'a, 'e, W> {
+    emu: &'e mut Emulator,
+    output: W,
+    input: Receiver<u8>,
+    /// Whether packets are acknowledged, which gdb can turn off on reliable connections.
+    ack: bool,
+    exec_file: Option<&'a str>,
+    sw_breakpoints: BTreeSet<u64>,
+    hw_breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<(Range<usize>, AccessType)>,
+}
+
+/// What to do after handling a packet.
+enum Reply {
+    Packet(String),
+    End(SessionEnd),
+}
+
+impl<W: Write> Session<'_, '_, W> {
+    fn run(&mut self) -> io::Result<SessionEnd> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Reply::Packet(reply) => {
+                    self.send(&reply)?;
+                    // The reply itself is still acknowledged
+                    if packet == b"QStartNoAckMode" {
+                        self.ack = false;
+                    }
+                }
+                Reply::End(end) => return Ok(end),
+            }
+        }
+        Ok(SessionEnd::Detached)
+    }
+
+    /// Read the next packet, returning `None` once gdb has disconnected.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Acks, and Ctrl-C while the guest is already stopped, need no response
+            match self.next_byte() {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut packet = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                match self.next_byte() {
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        packet.push(byte);
+                        sum = sum.wrapping_add(byte);
+                    }
+                    None => return Ok(None),
+                }
+            }
+            let (Some(hi), Some(lo)) = (self.next_byte(), self.next_byte()) else {
+                return Ok(None);
+            };
+            let checksum = std::str::from_utf8(&[hi, lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if !self.ack {
+                return Ok(Some(packet));
+            }
+            if checksum == Some(sum) {
+                self.output.write_all(b"+")?;
+                return Ok(Some(packet));
+            }
+            self.output.write_all(b"-")?;
+            self.output.flush()?;
+        }
+    }
+
+    fn next_byte(&self) -> Option<u8> {
+        self.input.recv().ok()
+    }
+
+    /// Whether gdb sent a Ctrl-C, or went away, while the guest was running.
+    fn interrupted(&self) -> bool {
+        loop {
+            match self.input.try_recv() {
+                Ok(0x03) | Err(TryRecvError::Disconnected) => return true,
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => return false,
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, u8::wrapping_add);
+        write!(self.output, "${data}#{sum:02x}")?;
+        self.output.flush()
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Reply {
+        let packet = String::from_utf8_lossy(packet);
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'c') => return self.resume(&packet[1..], false),
+            Some(b's') => return self.resume(&packet[1..], true),
+            Some(b'b') => self.reverse(&packet[1..]),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b'H') => "OK".to_string(),
+            Some(b'D') => {
+                // The reply has to go out before the session ends
+                let _ = self.send("OK");
+                return Reply::End(SessionEnd::Detached);
+            }
+            Some(b'k') => return Reply::End(SessionEnd::Killed),
+            Some(b'q' | b'Q') => self.query(&packet),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            let mut features = format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;\
+                 QStartNoAckMode+"
+            );
+            if self.exec_file.is_some() {
+                features += ";qXfer:exec-file:read+";
+            }
+            if self.emu.history.is_some() {
+                features += ";ReverseStep+;ReverseContinue+";
+            }
+            features
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            transfer(&target_description(self.emu), request)
+        } else if let Some(request) = packet.strip_prefix("qXfer:exec-file:read:") {
+            // The annex is the process, of which there is only one
+            match (self.exec_file, request.split_once(':')) {
+                (Some(path), Some((_, request))) => transfer(&absolute(path), request),
+                _ => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let mut reply = String::new();
+        for regnum in 0..CORE_REGS {
+            reply += &hex_le(self.register(regnum).unwrap_or(0));
+        }
+        reply
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        for (regnum, value) in data.as_bytes().chunks(16).take(CORE_REGS).enumerate() {
+            let Some(value) = std::str::from_utf8(value).ok().and_then(parse_hex_le) else {
+                return "E01".to_string();
+            };
+            self.set_register(regnum, value);
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|regnum| self.register(regnum))
+            .map_or_else(|| "E01".to_string(), hex_le)
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let written = args.split_once('=').and_then(|(regnum, value)| {
+            let regnum = usize::from_str_radix(regnum, 16).ok()?;
+            self.set_register(regnum, parse_hex_le(value)?)
+        });
+        match written {
+            Some(()) => "OK".to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn register(&self, regnum: usize) -> Option<u64> {
+        match regnum {
+            0..32 => Some(self.emu.x[regnum]),
+            32 => Some(self.emu.pc),
+            PRIV_REGNUM => Some(self.emu.privilege.into()),
+            _ => {
+                let addr = regnum.checked_sub(FIRST_CSR_REGNUM)?;
+                let (_, value) = self
+                    .emu
+                    .csrs()
+                    .find(|(csr, _)| usize::from(csr.addr) == addr)?;
+                Some(value)
+            }
+        }
+    }
+
+    fn set_register(&mut self, regnum: usize, value: u64) -> Option<()> {
+        match regnum {
+            0 => {}
+            1..32 => self.emu.x[regnum] = value,
+            32 => self.emu.pc = value,
+            PRIV_REGNUM => {
+                let privilege = Privilege::try_from(value).ok()?;
+                if privilege < self.emu.min_privilege() {
+                    return None;
+                }
+                self.emu.privilege = privilege;
+            }
+            _ => {
+                let addr = regnum.checked_sub(FIRST_CSR_REGNUM)?;
+                let (csr, _) = self
+                    .emu
+                    .csrs()
+                    .find(|(csr, _)| usize::from(csr.addr) == addr)?;
+                self.emu.set_csr_value(csr, value);
+            }
+        }
+        Some(())
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args) else {
+            return "E01".to_string();
+        };
+        // The range may run off the end of a region, in which case gdb takes what there is
+        let bytes = self.emu.debug_read(addr, len);
+        if bytes.is_empty() && len > 0 {
+            return "E01".to_string();
+        }
+        bytes.iter().fold(String::new(), |mut reply, byte| {
+            let _ = write!(reply, "{byte:02x}");
+            reply
+        })
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let written = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = parse_range(range)?;
+            let bytes = parse_hex_bytes(data)?;
+            if bytes.len() != len {
+                return None;
+            }
+            self.emu.debug_write(addr, &bytes).ok()
+        });
+        match written {
+            Some(()) => "OK".to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    /// Continue or single step, from `args` if an address is given.
+    fn resume(&mut self, args: &str, step: bool) -> Reply {
+        if !args.is_empty() {
+            match u64::from_str_radix(args, 16) {
+                Ok(pc) => self.emu.pc = pc,
+                Err(_) => return Reply::Packet("E01".to_string()),
+            }
+        }
+        let reason = if step {
+            self.emu.run(1)
+        } else {
+            loop {
+                match self.emu.run(CHUNK) {
+                    StopReason::Limit => {}
+                    reason => break reason,
+                }
+                if self.interrupted() {
+                    return Reply::Packet("T02".to_string());
+                }
+                // A run steps over a breakpoint it starts at, so look before running again
+                if self.emu.breakpoints.contains(&self.emu.pc) {
+                    break StopReason::Breakpoint(self.emu.pc);
+                }
+            }
+        };
+        match reason {
+            StopReason::Exit(code) => {
+                // The guest is gone, so this is the last packet
+                let _ = self.send(&format!("W{:02x}", code & 0xff));
+                Reply::End(SessionEnd::Exited(code))
+            }
+            reason => Reply::Packet(self.stop_reply(reason)),
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint(pc) if self.sw_breakpoints.contains(&pc) => {
+                "T05swbreak:;".to_string()
+            }
+            StopReason::Breakpoint(_) => "T05hwbreak:;".to_string(),
+            StopReason::Watchpoint { addr, access } => {
+                // gdb expects the kind it inserted, so a read of an access watchpoint is awatch
+                let access = self
+                    .watchpoints
+                    .iter()
+                    .find(|(range, watched)| {
+                        range.contains(&addr)
+                            && (access.can_read() && watched.can_read()
+                                || access.can_write() && watched.can_write())
+                    })
+                    .map_or(access, |(_, watched)| *watched);
+                let kind = match access {
+                    AccessType::Write => "watch",
+                    AccessType::Read => "rwatch",
+                    AccessType::ReadWrite => "awatch",
+                };
+                format!("T05{kind}:{addr:x};")
+            }
+            StopReason::HistoryStart => "T05replaylog:begin;".to_string(),
+            StopReason::BusError(_) => "T0b".to_string(),
+            StopReason::Exit(code) => format!("W{:02x}", code & 0xff),
+            StopReason::Limit
+            | StopReason::Trap(_)
+            | StopReason::WaitingForInterrupt
+            | StopReason::ReplayDiverged(_)
+            | StopReason::CosimDiverged(_) => "T05".to_string(),
+        }
+    }
+
+    /// Reverse step (`bs`) or reverse continue (`bc`).
+    fn reverse(&mut self, args: &str) -> String {
+        if self.emu.history.is_none() {
+            return "E01".to_string();
+        }
+        let reason = match args {
+            "s" => self.emu.reverse_step(),
+            "c" => self.emu.run_reverse(u64::MAX),
+            _ => return String::new(),
+        };
+        self.stop_reply(reason)
+    }
+
+    /// Insert or remove a breakpoint or watchpoint, given as `type,addr,kind`.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(Ok(addr)), Some(Ok(len))) = (
+            fields.next(),
+            fields.next().map(|addr| u64::from_str_radix(addr, 16)),
+            fields.next().map(|len| usize::from_str_radix(len, 16)),
+        ) else {
+            return "E01".to_string();
+        };
+        let access = match kind {
+            "0" | "1" => {
+                let (these, others) = if kind == "0" {
+                    (&mut self.sw_breakpoints, &self.hw_breakpoints)
+                } else {
+                    (&mut self.hw_breakpoints, &self.sw_breakpoints)
+                };
+                if insert {
+                    these.insert(addr);
+                    self.emu.add_breakpoint(addr);
+                } else if these.remove(&addr) && !others.contains(&addr) {
+                    self.emu.remove_breakpoint(addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => AccessType::Write,
+            "3" => AccessType::Read,
+            "4" => AccessType::ReadWrite,
+            _ => return String::new(),
+        };
+        let addr = addr as usize;
+        let range = addr..addr.saturating_add(len);
+        if insert {
+            self.watchpoints.push((range.clone(), access));
+            self.emu.add_watchpoint(range, access);
+        } else if let Some(idx) = self
+            .watchpoints
+            .iter()
+            .position(|watch| *watch == (range.clone(), access))
+        {
+            self.watchpoints.remove(idx);
+            // The emulator removes every watchpoint on the range, so put back the other kinds
+            self.emu.remove_watchpoint(range.clone());
+            for (watched, access) in &self.watchpoints {
+                if *watched == range {
+                    self.emu.add_watchpoint(watched.clone(), *access);
+                }
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Remove everything gdb inserted, so that the guest doesn't stop at them afterwards.
+    fn clear_breakpoints(&mut self) {
+        for &addr in self.sw_breakpoints.union(&self.hw_breakpoints) {
+            self.emu.remove_breakpoint(addr);
+        }
+        for (range, _) in self.watchpoints.drain(..) {
+            self.emu.remove_watchpoint(range);
+        }
+    }
+}
+
+/// Describe the registers of `emu` to gdb.
+fn target_description(emu: &Emulator) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<architecture>riscv:rv64</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (regnum, name) in ABI_NAMES.iter().enumerate() {
+        let ty = match *name {
+            "sp" | "gp" | "tp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        let _ = writeln!(
+            xml,
+            "<reg name=\"{name}\" bitsize=\"64\" type=\"{ty}\" regnum=\"{regnum}\"/>"
+        );
+    }
+    xml += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/>\n</feature>\n\
+            <feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    for (csr, _) in emu.csrs() {
+        let _ = writeln!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\" group=\"csr\"/>",
+            csr.name,
+            FIRST_CSR_REGNUM + usize::from(csr.addr)
+        );
+    }
+    let _ = write!(
+        xml,
+        "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n\
+         <reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{PRIV_REGNUM}\"/>\n\
+         </feature>\n</target>\n"
+    );
+    xml
+}
+
+/// Reply to a `qXfer` read of `offset,length` from `data`.
+fn transfer(data: &str, request: &str) -> String {
+    let Some((offset, len)) = parse_range(request) else {
+        return "E01".to_string();
+    };
+    let data = data.as_bytes();
+    let chunk = &data[offset.min(data.len())..offset.saturating_add(len).min(data.len())];
+    let more = offset.saturating_add(len) < data.len();
+    let mut reply = String::from(if more { "m" } else { "l" });
+    for &byte in chunk {
+        // These bytes have a meaning in packets, so they are escaped
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            reply.push('}');
+            reply.push(char::from(byte ^ 0x20));
+        } else {
+            reply.push(char::from(byte));
+        }
+    }
+    reply
+}
+
+fn absolute(path: &str) -> String {
+    std::fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+/// Parse `addr,length` in hexadecimal.
+fn parse_range(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// Registers are sent as their bytes in target order, which is little endian.
+fn hex_le(value: u64) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+fn parse_hex_le(hex: &str) -> Option<u64> {
+    let bytes = parse_hex_bytes(hex)?;
+    let mut buf = [0; 8];
+    buf.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::RAM_BASE;
+
+    /// An emulator at the start of a loop incrementing the doubleword at s0.
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(1 << 16);
+        let program = [
+            0x00043503u32, // ld a0, 0(s0)
+            0x00150513,    // addi a0, a0, 1
+            0x00a43023,    // sd a0, 0(s0)
+            0xff5ff06f,    // j -12
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        emu.x[8] = RAM_BASE as u64 + 0x1000;
+        emu.set_pc(RAM_BASE as u64);
+        emu
+    }
+
+    /// A session on `emu`, along with where its input comes from. The input stays open for as
+    /// long as the sender is kept.
+    fn session(emu: &mut Emulator) -> (Session<'static, '_, Vec<u8>>, mpsc::Sender<u8>) {
+        let (send, receive) = mpsc::channel();
+        let session = Session {
+            emu,
+            output: Vec::new(),
+            input: receive,
+            ack: true,
+            exec_file: None,
+            sw_breakpoints: BTreeSet::new(),
+            hw_breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        };
+        (session, send)
+    }
+
+    fn framed(data: &str) -> String {
+        let sum = data.bytes().fold(0u8, u8::wrapping_add);
+        format!("${data}#{sum:02x}")
+    }
+
+    /// Handle `packet`, returning the reply to it.
+    fn reply(session: &mut Session<'_, '_, Vec<u8>>, packet: &str) -> String {
+        match session.handle(packet.as_bytes()) {
+            Reply::Packet(reply) => reply,
+            Reply::End(end) => panic!("{packet} ended the session with {end:?}"),
+        }
+    }
+
+    #[test]
+    fn packets_are_checksummed_and_acknowledged() {
+        let mut emu = emulator();
+        let (mut session, send) = session(&mut emu);
+        let input = format!("+$?#00{}{}", framed("QStartNoAckMode"), framed("?"));
+        input.bytes().for_each(|byte| send.send(byte).unwrap());
+        drop(send);
+        assert_eq!(session.run().unwrap(), SessionEnd::Detached);
+        // The bad packet is asked for again, and acks stop after the reply to QStartNoAckMode
+        let expected = format!("-+{}{}", framed("OK"), framed("S05"));
+        assert_eq!(String::from_utf8(session.output).unwrap(), expected);
+    }
+
+    #[test]
+    fn registers_are_read_and_written() {
+        let mut emu = emulator();
+        emu.x[1] = 0x1122334455667788;
+        let (mut session, _send) = session(&mut emu);
+        let registers = reply(&mut session, "g");
+        assert_eq!(registers.len(), CORE_REGS * 16);
+        assert_eq!(&registers[..32], "00000000000000008877665544332211");
+        assert_eq!(&registers[32 * 16..], "0000008000000000");
+
+        assert_eq!(reply(&mut session, "P2=0100000000000000"), "OK");
+        assert_eq!(reply(&mut session, "p2"), "0100000000000000");
+        // x0 stays zero
+        assert_eq!(reply(&mut session, "P0=0100000000000000"), "OK");
+        assert_eq!(reply(&mut session, "p0"), "0000000000000000");
+        // mscratch, and the privilege mode, which can't be set to one that doesn't exist
+        assert_eq!(reply(&mut session, "P381=efbeadde00000000"), "OK");
+        assert_eq!(session.emu.machine_csrs.mscratch, 0xdeadbeef);
+        assert_eq!(reply(&mut session, "p1041"), "0300000000000000");
+        assert_eq!(reply(&mut session, "P1041=0200000000000000"), "E01");
+        // Between the pc and the first csr there are no registers
+        assert_eq!(reply(&mut session, "p40"), "E01");
+        assert_eq!(reply(&mut session, "P40=00"), "E01");
+
+        let mut registers = "00".repeat(8 * 32);
+        registers += "0400008000000000";
+        assert_eq!(reply(&mut session, &format!("G{registers}")), "OK");
+        assert_eq!((session.emu.x[1], session.emu.pc), (0, RAM_BASE as u64 + 4));
+        assert_eq!(reply(&mut session, "Gzz"), "E01");
+    }
+
+    #[test]
+    fn memory_is_read_and_written() {
+        let mut emu = emulator();
+        let (mut session, _send) = session(&mut emu);
+        assert_eq!(reply(&mut session, "M80001000,4:01020304"), "OK");
+        assert_eq!(reply(&mut session, "m80001000,4"), "01020304");
+        // Reads off the end of RAM return what there is
+        assert_eq!(reply(&mut session, "M8000fffe,2:aabb"), "OK");
+        assert_eq!(reply(&mut session, "m8000fffe,4"), "aabb");
+        assert_eq!(reply(&mut session, "m0,4"), "E01");
+        assert_eq!(reply(&mut session, "M80001000,2:01"), "E01");
+        assert_eq!(reply(&mut session, "M0,1:01"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_stop_the_guest() {
+        let mut emu = emulator();
+        let (mut session, _send) = session(&mut emu);
+        assert_eq!(reply(&mut session, "Z0,80000008,4"), "OK");
+        assert_eq!(reply(&mut session, "c"), "T05swbreak:;");
+        assert_eq!(session.emu.pc, RAM_BASE as u64 + 8);
+        // A hardware breakpoint at the same place outlives the software one
+        assert_eq!(reply(&mut session, "Z1,80000008,4"), "OK");
+        assert_eq!(reply(&mut session, "z0,80000008,4"), "OK");
+        assert_eq!(reply(&mut session, "c"), "T05hwbreak:;");
+        assert_eq!(reply(&mut session, "z1,80000008,4"), "OK");
+
+        assert_eq!(reply(&mut session, "Z3,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "c"), "T05rwatch:80001000;");
+        // Removing one kind of watchpoint from a range leaves the others
+        assert_eq!(reply(&mut session, "Z2,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "Z4,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "z3,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "z4,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "c"), "T05watch:80001000;");
+        assert_eq!(reply(&mut session, "z2,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "Z4,80001000,8"), "OK");
+        assert_eq!(reply(&mut session, "c"), "T05awatch:80001000;");
+
+        assert_eq!(reply(&mut session, "Z9,0,0"), "");
+        assert_eq!(reply(&mut session, "Z0,zz,4"), "E01");
+        session.clear_breakpoints();
+        assert!(emu.breakpoints.is_empty() && emu.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn the_target_description_is_read_in_chunks() {
+        let mut emu = emulator();
+        let (mut session, _send) = session(&mut emu);
+        let mut xml = String::new();
+        loop {
+            let request = format!("qXfer:features:read:target.xml:{:x},100", xml.len());
+            let reply = reply(&mut session, &request);
+            let (more, chunk) = reply.split_at(1);
+            assert!(chunk.len() <= 0x100);
+            xml += chunk;
+            if more == "l" {
+                break;
+            }
+            assert_eq!(more, "m");
+        }
+        assert_eq!(xml, target_description(session.emu));
+        assert!(xml.contains("<reg name=\"mscratch\" bitsize=\"64\" type=\"int\" regnum=\"897\""));
+        let end = format!("qXfer:features:read:target.xml:{:x},100", xml.len() + 1);
+        assert_eq!(reply(&mut session, &end), "l");
+    }
+
+    #[test]
+    fn reverse_execution_needs_history() {
+        let mut emu = emulator();
+        let (mut session, _send) = session(&mut emu);
+        assert_eq!(reply(&mut session, "bs"), "E01");
+        session.emu.enable_history(2);
+        assert_eq!(reply(&mut session, "s"), "T05");
+        assert_eq!(reply(&mut session, "s"), "T05");
+        assert_eq!(reply(&mut session, "bs"), "T05");
+        assert_eq!(session.emu.pc, RAM_BASE as u64 + 4);
+        assert_eq!(reply(&mut session, "bc"), "T05replaylog:begin;");
+        assert_eq!(session.emu.pc, RAM_BASE as u64);
+    }
+
+    #[test]
+    fn ctrl_c_stops_a_running_guest() {
+        let mut emu = emulator();
+        let (mut session, send) = session(&mut emu);
+        // The input stays open, so only the Ctrl-C can stop the loop
+        send.send(0x03).unwrap();
+        assert_eq!(reply(&mut session, "c"), "T02");
+        assert!(session.emu.x[10] > 0);
+    }
+}
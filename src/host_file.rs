@@ -0,0 +1,75 @@
+//! The host's files and console as guests reach them, through the HTIF syscall proxy or
+//! semihosting, and the Linux error numbers both report failures with.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+pub(crate) const EBADF: u64 = 9;
+pub(crate) const ENOMEM: u64 = 12;
+pub(crate) const EACCES: u64 = 13;
+pub(crate) const EFAULT: u64 = 14;
+pub(crate) const EBUSY: u64 = 16;
+pub(crate) const EINVAL: u64 = 22;
+pub(crate) const ESPIPE: u64 = 29;
+pub(crate) const ERANGE: u64 = 34;
+pub(crate) const ENAMETOOLONG: u64 = 36;
+pub(crate) const ENOSYS: u64 = 38;
+
+/// The longest path a guest can pass, in bytes.
+pub(crate) const PATH_MAX: u64 = 4096;
+/// The most bytes read or written by one call, which can then return short.
+pub(crate) const MAX_TRANSFER: u64 = 1 << 20;
+
+/// A file opened by the guest, which is either a host file or part of the host's console.
+pub(crate) enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl HostFile {
+    /// Read up to `buf.len()` bytes into `buf`, returning how many were read.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> Result<usize, u64> {
+        match self {
+            HostFile::Stdin => io::stdin().read(buf),
+            HostFile::File(file) => file.read(buf),
+            HostFile::Stdout | HostFile::Stderr => return Err(EBADF),
+        }
+        .map_err(errno)
+    }
+
+    /// Write some of `buf`, returning how many bytes were written.
+    pub(crate) fn write(&mut self, buf: &[u8]) -> Result<usize, u64> {
+        match self {
+            HostFile::Stdout => write_console(&mut io::stdout(), buf),
+            HostFile::Stderr => write_console(&mut io::stderr(), buf),
+            HostFile::File(file) => file.write(buf),
+            HostFile::Stdin => return Err(EBADF),
+        }
+        .map_err(errno)
+    }
+}
+
+/// Write all of `buf` to `console` straight away, as the guest expects to see it.
+pub(crate) fn write_console(console: &mut impl Write, buf: &[u8]) -> io::Result<usize> {
+    console.write_all(buf)?;
+    console.flush()?;
+    Ok(buf.len())
+}
+
+/// The Linux error number of `err`, for guests that are told why their host file access failed.
+pub(crate) fn errno(err: io::Error) -> u64 {
+    // Linux on the host numbers errors as RISC-V Linux does
+    #[cfg(target_os = "linux")]
+    if let Some(code) = err.raw_os_error() {
+        return code as u64;
+    }
+    match err.kind() {
+        io::ErrorKind::NotFound => 2,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => 17,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => 5,
+    }
+}
@@ -0,0 +1,223 @@
+//! Hardware performance monitor counters.
+//!
+//! Each of `mhpmcounter3` to `mhpmcounter31` counts the event selected by the matching
+//! `mhpmevent` csr. The selector is the low 56 bits of `mhpmevent`, holding one of the codes in
+//! [`HpmEvent`]; any other value counts nothing. With Sscofpmf the top bits of `mhpmevent` hold
+//! the overflow flag and the per privilege mode inhibit bits.
+
+use crate::{csr::MachineCsrs, isa::Extension, trap::Interrupt, Emulator, Privilege};
+
+/// The number of programmable counters, `mhpmcounter3` to `mhpmcounter31`.
+pub const HPM_COUNTERS: usize = 29;
+
+/// The bits of `mhpmevent` that select the event.
+pub const EVENT_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+/// Set when the counter overflows, and the interrupt is only raised when this goes from 0 to 1.
+pub const EVENT_OF: u64 = 1 << 63;
+/// Inhibit counting in M mode.
+pub const EVENT_MINH: u64 = 1 << 62;
+/// Inhibit counting in U mode.
+pub const EVENT_UINH: u64 = 1 << 60;
+
+/// The events that can be selected in `mhpmevent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HpmEvent {
+    /// A conditional branch was executed.
+    Branch = 1,
+    /// A conditional branch was taken.
+    TakenBranch = 2,
+    /// A load from memory completed, not counting LR or AMOs.
+    Load = 3,
+    /// A store to memory completed, not counting SC or AMOs.
+    Store = 4,
+    /// An instruction from the A extension completed.
+    Amo = 5,
+    /// An exception was taken.
+    Trap = 6,
+    /// An interrupt was taken.
+    Interrupt = 7,
+    /// There is no MMU, so this never counts.
+    TlbMiss = 8,
+    /// An instruction had to be decoded rather than found in the decode cache.
+    DecodeCacheMiss = 9,
+}
+
+impl HpmEvent {
+    pub const ALL: [HpmEvent; 9] = [
+        HpmEvent::Branch,
+        HpmEvent::TakenBranch,
+        HpmEvent::Load,
+        HpmEvent::Store,
+        HpmEvent::Amo,
+        HpmEvent::Trap,
+        HpmEvent::Interrupt,
+        HpmEvent::TlbMiss,
+        HpmEvent::DecodeCacheMiss,
+    ];
+
+    /// The event with the selector `code`.
+    pub fn from_code(code: u64) -> Option<HpmEvent> {
+        HpmEvent::ALL
+            .into_iter()
+            .find(|event| *event as u64 == code)
+    }
+}
+
+impl MachineCsrs {
+    /// Work out which counters are listening to each event from `mhpmevent`.
+    pub(crate) fn rebuild_hpm_listeners(&mut self) {
+        self.hpm_listeners = [0; HpmEvent::ALL.len()];
+        for (idx, event) in self.mhpmevent.iter().enumerate() {
+            if let Some(event) = HpmEvent::from_code(event & EVENT_MASK) {
+                self.hpm_listeners[event as usize - 1] |= 1 << (idx + 3);
+            }
+        }
+    }
+}
+
+impl Emulator {
+    /// Count one occurrence of `event` in every counter that is selecting it.
+    pub(crate) fn count_event(&mut self, event: HpmEvent) {
+        // Most of the time no counter is listening, so keep this cheap.
+        let counters =
+            self.machine_csrs.hpm_listeners[event as usize - 1] & !self.machine_csrs.mcountinhibit;
+        if counters == 0 {
+            return;
+        }
+        let inhibit = match self.privilege {
+            Privilege::Machine => EVENT_MINH,
+            Privilege::User => EVENT_UINH,
+        };
+        let filter = self.isa.has(Extension::Sscofpmf);
+        for n in 3..32 {
+            if counters & 1 << n == 0 {
+                continue;
+            }
+            let idx = n - 3;
+            if filter && self.machine_csrs.mhpmevent[idx] & inhibit != 0 {
+                continue;
+            }
+            let (count, overflow) = self.machine_csrs.mhpmcounter[idx].overflowing_add(1);
+            self.machine_csrs.mhpmcounter[idx] = count;
+            if overflow && filter && self.machine_csrs.mhpmevent[idx] & EVENT_OF == 0 {
+                self.machine_csrs.mhpmevent[idx] |= EVENT_OF;
+                self.machine_csrs.mip |= Interrupt::CounterOverflow.mask();
+            }
+        }
+    }
+
+    /// Select the event counted by `mhpmevent{n}`.
+    pub(crate) fn write_mhpmevent(&mut self, n: usize, val: u64) {
+        let idx = n - 3;
+        let val = if self.isa.has(Extension::Sscofpmf) {
+            // SINH, VSINH and VUINH are read only zero as neither S nor H are implemented
+            val & (EVENT_MASK | EVENT_OF | EVENT_MINH | EVENT_UINH)
+        } else {
+            val & EVENT_MASK
+        };
+        self.machine_csrs.mhpmevent[idx] = val;
+        for listeners in self.machine_csrs.hpm_listeners.iter_mut() {
+            *listeners &= !(1 << n);
+        }
+        if let Some(event) = HpmEvent::from_code(val & EVENT_MASK) {
+            self.machine_csrs.hpm_listeners[event as usize - 1] |= 1 << n;
+        }
+    }
+
+    /// The overflow flags of every counter, in the layout of `scountovf`.
+    pub(crate) fn counter_overflows(&self) -> u64 {
+        self.machine_csrs
+            .mhpmevent
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| *event & EVENT_OF != 0)
+            .fold(0, |ovf, (idx, _)| ovf | 1 << (idx + 3))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::{Isa, DEFAULT_ISA};
+
+    fn emulator(sscofpmf: bool) -> Emulator {
+        let isa = if sscofpmf {
+            format!("{DEFAULT_ISA}_sscofpmf")
+        } else {
+            DEFAULT_ISA.to_string()
+        };
+        Emulator::with_isa(4096, isa.parse::<Isa>().unwrap())
+    }
+
+    #[test]
+    fn mhpmevent_selects_what_is_counted() {
+        let mut emu = emulator(false);
+        // Without Sscofpmf the top bits are read only zero
+        emu.write_csr(0x323, EVENT_OF | EVENT_MINH | HpmEvent::Branch as u64)
+            .unwrap();
+        assert_eq!(emu.read_csr(0x323), Ok(HpmEvent::Branch as u64));
+        emu.write_csr(0x324, HpmEvent::Branch as u64).unwrap();
+        emu.count_event(HpmEvent::Branch);
+        emu.count_event(HpmEvent::Load);
+        assert_eq!(emu.machine_csrs.mhpmcounter[..2], [1, 1]);
+
+        // Selecting another event stops counting the old one
+        emu.write_csr(0x323, HpmEvent::Load as u64).unwrap();
+        emu.count_event(HpmEvent::Branch);
+        emu.count_event(HpmEvent::Load);
+        assert_eq!(emu.machine_csrs.mhpmcounter[..2], [2, 2]);
+        // As does an unknown event or mcountinhibit
+        emu.write_csr(0x323, 0xff).unwrap();
+        emu.write_csr(0x320, 1 << 4).unwrap();
+        emu.count_event(HpmEvent::Branch);
+        emu.count_event(HpmEvent::Load);
+        assert_eq!(emu.machine_csrs.mhpmcounter[..2], [2, 2]);
+
+        let mut listeners = emu.machine_csrs.hpm_listeners;
+        emu.machine_csrs.rebuild_hpm_listeners();
+        assert_eq!(emu.machine_csrs.hpm_listeners, listeners);
+        listeners[HpmEvent::Branch as usize - 1] = 1 << 4;
+        assert_eq!(emu.machine_csrs.hpm_listeners, listeners);
+    }
+
+    #[test]
+    fn overflow_sets_lcofip_once() {
+        let mut emu = emulator(true);
+        let lcofip = Interrupt::CounterOverflow.mask();
+        emu.write_csr(0x323, HpmEvent::Store as u64).unwrap();
+        emu.write_csr(0xB03, u64::MAX).unwrap();
+        emu.count_event(HpmEvent::Store);
+        assert_eq!(emu.read_csr(0xB03), Ok(0));
+        assert_eq!(emu.read_csr(0x323), Ok(EVENT_OF | HpmEvent::Store as u64));
+        assert_ne!(emu.machine_csrs.mip & lcofip, 0);
+
+        // While the overflow flag is set, overflowing again doesn't raise another interrupt
+        emu.write_csr(0x344, 0).unwrap();
+        emu.write_csr(0xB03, u64::MAX).unwrap();
+        emu.count_event(HpmEvent::Store);
+        assert_eq!(emu.machine_csrs.mip & lcofip, 0);
+        emu.write_csr(0x323, HpmEvent::Store as u64).unwrap();
+        emu.write_csr(0xB03, u64::MAX).unwrap();
+        emu.count_event(HpmEvent::Store);
+        assert_ne!(emu.machine_csrs.mip & lcofip, 0);
+    }
+
+    #[test]
+    fn counting_can_be_inhibited_by_privilege_mode() {
+        let mut emu = emulator(true);
+        emu.write_csr(0x323, EVENT_MINH | HpmEvent::Trap as u64)
+            .unwrap();
+        emu.count_event(HpmEvent::Trap);
+        assert_eq!(emu.machine_csrs.mhpmcounter[0], 0);
+        emu.privilege = Privilege::User;
+        emu.count_event(HpmEvent::Trap);
+        assert_eq!(emu.machine_csrs.mhpmcounter[0], 1);
+
+        // Without Sscofpmf the inhibit bits can't be set
+        let mut emu = emulator(false);
+        emu.write_csr(0x323, EVENT_MINH | HpmEvent::Trap as u64)
+            .unwrap();
+        emu.count_event(HpmEvent::Trap);
+        assert_eq!(emu.machine_csrs.mhpmcounter[0], 1);
+    }
+}
@@ -0,0 +1,408 @@
+//! The host-target interface (HTIF) of Spike and riscv-pk, through which a guest exits, uses the
+//! host's console, and makes system calls on the host.
+//!
+//! The guest writes commands to `tohost`, each made of a device (bits 63 to 56), a command (bits
+//! 55 to 48) and a payload (bits 47 to 0). The host clears `tohost` once it has taken a command,
+//! and answers it through `fromhost` in the same format. The guest clears `fromhost` once it has
+//! read an answer, and the host only writes the next one after that, holding the rest back until
+//! then. The devices are:
+//!
+//! - 0, the syscall proxy. Command 0 with an odd payload exits with the payload shifted right by
+//!   one, as riscv-tests do. With an even payload, the payload is the address of eight 64 bit
+//!   words holding the number and arguments of a riscv-pk system call, which is carried out on
+//!   the host as described in [`syscall`], and is answered with 1 once the result has been
+//!   written over the number.
+//! - 1, the console. Command 0 asks for a character, which is answered with `0x100` or'd with the
+//!   character once one is typed on the host's stdin, and command 1 writes the character in the
+//!   payload to the host's stdout and is answered with the same.
+//!
+//! Command 255 of any device writes the device's name, as a NUL terminated string, to the
+//! address in the payload and is answered with 1. Any other command is dropped.
+//!
+//! Both `tohost` and `fromhost` are devices, each at its own symbol, so that polling `fromhost`
+//! for console input is logged when recording the inputs of a run. Guests that never expect an
+//! answer, such as riscv-tests, can leave `fromhost` out.
+//!
+//! Only what the guest reads back is logged, so when a replay or the debugger's history executes
+//! a command again, its effects on the host, such as console output or writes to files, happen
+//! again too.
+
+mod syscall;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use crate::device::{mask, AccessType, BusError, Device, DeviceRegister};
+use crate::host_file::write_console;
+use crate::mem::MemoryMap;
+use crate::sandbox::Sandbox;
+use crate::snapshot::SnapshotError;
+
+const REGISTERS: &[DeviceRegister] = &[DeviceRegister {
+    offset: 0,
+    size: 8,
+    access_type: AccessType::ReadWrite,
+}];
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CMD_CONSOLE_READ: u64 = 0;
+const CMD_CONSOLE_WRITE: u64 = 1;
+const CMD_IDENTIFY: u64 = 0xff;
+
+/// The host side of the interface, shared by the `tohost` and `fromhost` devices.
+pub struct Htif {
+    tohost: u64,
+    fromhost: u64,
+    /// Whether the guest has a `fromhost` to be answered through.
+    answers: bool,
+    /// Answers held back until the guest clears `fromhost`.
+    pending: VecDeque<u64>,
+    /// The number of characters asked for that haven't been typed yet.
+    console_reads: u64,
+    /// The characters typed on the host, once the guest has asked for any.
+    console_input: Option<mpsc::Receiver<u8>>,
+    proxy: syscall::Proxy,
+    exit_code: Option<u32>,
+}
+
+impl Htif {
+    /// An interface whose guest is passed `args` as its arguments, and can reach the host files
+    /// in `sandbox`, if any.
+    pub fn new(args: Vec<String>, sandbox: Option<Sandbox>) -> Htif {
+        Htif {
+            tohost: 0,
+            fromhost: 0,
+            answers: false,
+            pending: VecDeque::new(),
+            console_reads: 0,
+            console_input: None,
+            proxy: syscall::Proxy::new(args, sandbox),
+            exit_code: None,
+        }
+    }
+
+    /// The devices to add to the emulator for `tohost` and `fromhost` at these addresses, which
+    /// must not overlap.
+    pub fn into_devices(
+        mut self,
+        tohost: usize,
+        fromhost: Option<usize>,
+    ) -> Vec<Rc<RefCell<dyn Device>>> {
+        self.answers = fromhost.is_some();
+        let htif = Rc::new(RefCell::new(self));
+        let mut devices = vec![Rc::new(RefCell::new(Port {
+            htif: htif.clone(),
+            addr: tohost,
+            register: Register::ToHost,
+        })) as Rc<RefCell<dyn Device>>];
+        if let Some(addr) = fromhost {
+            devices.push(Rc::new(RefCell::new(Port {
+                htif,
+                addr,
+                register: Register::FromHost,
+            })));
+        }
+        devices
+    }
+
+    /// Carry out the command in `tohost`, returning the ranges of memory written.
+    fn take_command(&mut self, memory: &mut MemoryMap) -> Vec<Range<usize>> {
+        let command = std::mem::take(&mut self.tohost);
+        let device = command >> 56;
+        let cmd = command >> 48 & 0xff;
+        let payload = command & ((1 << 48) - 1);
+        let mut written = Vec::new();
+        match (device, cmd) {
+            (_, CMD_IDENTIFY) => {
+                let name: &[u8] = match device {
+                    DEVICE_SYSCALL => b"syscall_proxy\0",
+                    DEVICE_CONSOLE => b"bcd\0",
+                    _ => b"\0",
+                };
+                if memory.write_bytes(payload as usize, name).is_ok() {
+                    written.push(payload as usize..payload as usize + name.len());
+                }
+                self.answer(device, cmd, 1);
+            }
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                self.exit_code = Some((payload >> 1) as u32);
+            }
+            (DEVICE_SYSCALL, 0) => match self.proxy.call(memory, payload, &mut written) {
+                Some(code) => self.exit_code = Some(code),
+                None => self.answer(device, cmd, 1),
+            },
+            (DEVICE_CONSOLE, CMD_CONSOLE_READ) => self.console_reads += 1,
+            (DEVICE_CONSOLE, CMD_CONSOLE_WRITE) => {
+                let _ = write_console(&mut io::stdout(), &[payload as u8]);
+                self.answer(device, cmd, 0x100 | payload & 0xff);
+            }
+            _ => {}
+        }
+        written
+    }
+
+    fn answer(&mut self, device: u64, cmd: u64, payload: u64) {
+        if self.answers {
+            self.pending.push_back(device << 56 | cmd << 48 | payload);
+            self.poll();
+        }
+    }
+
+    /// Write the next answer to `fromhost` if the guest has cleared it.
+    fn poll(&mut self) {
+        if self.fromhost != 0 {
+            return;
+        }
+        if let Some(answer) = self.pending.pop_front() {
+            self.fromhost = answer;
+        } else if self.console_reads > 0 {
+            let input = self.console_input.get_or_insert_with(stdin_bytes);
+            if let Ok(byte) = input.try_recv() {
+                self.console_reads -= 1;
+                self.fromhost =
+                    DEVICE_CONSOLE << 56 | CMD_CONSOLE_READ << 48 | 0x100 | u64::from(byte);
+            }
+        }
+    }
+}
+
+/// The bytes typed on the host's stdin, read on a thread of their own so that the guest can poll
+/// for them without blocking.
+fn stdin_bytes() -> mpsc::Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            let Ok(byte) = byte else {
+                return;
+            };
+            if sender.send(byte).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    ToHost,
+    FromHost,
+}
+
+/// One of the two words of the interface.
+struct Port {
+    htif: Rc<RefCell<Htif>>,
+    addr: usize,
+    register: Register,
+}
+
+impl Device for Port {
+    fn address_range(&self) -> Range<usize> {
+        self.addr..self.addr + 8
+    }
+
+    fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError> {
+        DeviceRegister::decode(REGISTERS, offset, width, false)?;
+        let mut htif = self.htif.borrow_mut();
+        let val = match self.register {
+            Register::ToHost => htif.tohost,
+            Register::FromHost => {
+                htif.poll();
+                htif.fromhost
+            }
+        };
+        Ok(val >> (offset * 8) & mask(width))
+    }
+
+    fn write(&mut self, offset: usize, width: usize, val: u64) -> Result<(), BusError> {
+        DeviceRegister::decode(REGISTERS, offset, width, true)?;
+        let mut htif = self.htif.borrow_mut();
+        let word = match self.register {
+            Register::ToHost => &mut htif.tohost,
+            Register::FromHost => &mut htif.fromhost,
+        };
+        let shift = offset * 8;
+        *word = *word & !(mask(width) << shift) | (val & mask(width)) << shift;
+        if self.register == Register::FromHost {
+            htif.poll();
+        }
+        Ok(())
+    }
+
+    fn access_memory(&mut self, memory: &mut MemoryMap) -> Vec<Range<usize>> {
+        let mut htif = self.htif.borrow_mut();
+        // Commands are usually written as a whole, but 32 bit guests write the low half last
+        if self.register == Register::FromHost || htif.tohost == 0 {
+            return Vec::new();
+        }
+        htif.take_command(memory)
+    }
+
+    fn exit_code(&self) -> Option<u32> {
+        self.htif.borrow().exit_code
+    }
+
+    fn save(&self) -> Vec<u8> {
+        if self.register == Register::FromHost {
+            return Vec::new();
+        }
+        let htif = self.htif.borrow();
+        let exit_code = htif.exit_code.map_or(0, |code| 1 << 32 | u64::from(code));
+        [exit_code, htif.tohost, htif.fromhost, htif.console_reads]
+            .into_iter()
+            .chain(htif.pending.iter().copied())
+            .flat_map(u64::to_le_bytes)
+            .collect()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        if self.register == Register::FromHost {
+            return if state.is_empty() {
+                Ok(())
+            } else {
+                Err(SnapshotError::Corrupt)
+            };
+        }
+        if state.len() < 32 || !state.len().is_multiple_of(8) {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut words = state
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+        let mut htif = self.htif.borrow_mut();
+        let exit_code = words.next().unwrap();
+        htif.exit_code = (exit_code >> 32 == 1).then_some(exit_code as u32);
+        htif.tohost = words.next().unwrap();
+        htif.fromhost = words.next().unwrap();
+        htif.console_reads = words.next().unwrap();
+        htif.pending = words.collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_file::EBUSY;
+    use crate::mem::RAM_BASE;
+    use crate::sandbox::tests::TempDir;
+    use crate::Emulator;
+
+    const TOHOST: usize = RAM_BASE + 0x1000;
+    const FROMHOST: usize = TOHOST + 8;
+    /// Where the number and arguments of system calls go.
+    const MAGIC_MEM: usize = RAM_BASE + 0x2000;
+    const NAME: usize = MAGIC_MEM + 0x100;
+    const BUFFER: usize = MAGIC_MEM + 0x200;
+
+    fn emulator(sandbox: Option<Sandbox>, fromhost: Option<usize>) -> Emulator {
+        let mut emu = Emulator::new(1 << 16);
+        let htif = Htif::new(vec!["guest".to_owned()], sandbox);
+        for device in htif.into_devices(TOHOST, fromhost) {
+            emu.add_device(device);
+        }
+        emu
+    }
+
+    fn command(device: u64, cmd: u64, payload: u64) -> u64 {
+        device << 56 | cmd << 48 | payload
+    }
+
+    #[test]
+    fn answers_wait_for_fromhost_to_be_cleared() {
+        let mut emu = emulator(None, Some(FROMHOST));
+        // A space, which hardly shows up in the output of the tests
+        let write = command(DEVICE_CONSOLE, CMD_CONSOLE_WRITE, u64::from(b' '));
+        emu.write_u64(TOHOST, write).unwrap();
+        assert_eq!(emu.read_u64(TOHOST).unwrap(), 0);
+        emu.write_u64(TOHOST, command(DEVICE_CONSOLE, CMD_IDENTIFY, NAME as u64))
+            .unwrap();
+        assert_eq!(emu.memory.read_array(NAME).unwrap(), *b"bcd\0");
+
+        // The console echoes the character or'd with 0x100, and the identification is only
+        // answered once the guest has taken that
+        let echo = command(DEVICE_CONSOLE, CMD_CONSOLE_WRITE, 0x100 | u64::from(b' '));
+        assert_eq!(emu.read_u64(FROMHOST).unwrap(), echo);
+        assert_eq!(emu.read_u64(FROMHOST).unwrap(), echo);
+        emu.write_u64(FROMHOST, 0).unwrap();
+        assert_eq!(
+            emu.read_u64(FROMHOST).unwrap(),
+            command(DEVICE_CONSOLE, CMD_IDENTIFY, 1)
+        );
+        emu.write_u64(FROMHOST, 0).unwrap();
+        assert_eq!(emu.read_u64(FROMHOST).unwrap(), 0);
+    }
+
+    #[test]
+    fn odd_payloads_exit() {
+        let mut emu = emulator(None, None);
+        emu.write_u64(TOHOST, 1).unwrap();
+        assert_eq!(emu.exit_code.take(), Some(0));
+        // 32 bit guests write the high half first, and the command is only taken on the low half
+        let mut emu = emulator(None, None);
+        emu.write_u32(TOHOST + 4, 0).unwrap();
+        assert_eq!(emu.exit_code.take(), None);
+        emu.write_u32(TOHOST, 42 << 1 | 1).unwrap();
+        assert_eq!(emu.exit_code.take(), Some(42));
+    }
+
+    #[test]
+    fn system_calls_are_proxied_to_the_sandbox() {
+        let temp = TempDir::new("htif");
+        let sandbox = Sandbox::new(temp.path()).unwrap();
+        let mut emu = emulator(Some(sandbox), Some(FROMHOST));
+        let call = |emu: &mut Emulator, name: &[u8], args: &[u64]| {
+            emu.write_bytes(NAME, name).unwrap();
+            let mut words = [0; 8];
+            words[..args.len()].copy_from_slice(args);
+            let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+            emu.write_bytes(MAGIC_MEM, &bytes).unwrap();
+            emu.write_u64(TOHOST, command(DEVICE_SYSCALL, 0, MAGIC_MEM as u64))
+                .unwrap();
+            if emu.exit_code.is_none() {
+                let answer = command(DEVICE_SYSCALL, 0, 1);
+                assert_eq!(emu.read_u64(FROMHOST).unwrap(), answer);
+                emu.write_u64(FROMHOST, 0).unwrap();
+            }
+            emu.read_u64(MAGIC_MEM).unwrap()
+        };
+        let (name, buffer) = (NAME as u64, BUFFER as u64);
+        let at_fdcwd = -100i64 as u64;
+
+        emu.write_bytes(BUFFER, b"hello").unwrap();
+        // openat(AT_FDCWD, "out.txt", O_WRONLY | O_CREAT, 0644), then write and close
+        let fd = call(
+            &mut emu,
+            b"out.txt\0",
+            &[56, at_fdcwd, name, 8, 0o101, 0o644],
+        );
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut emu, b"", &[64, fd, buffer, 5]), 5);
+        assert_eq!(call(&mut emu, b"", &[57, fd]), 0);
+        assert_eq!(
+            std::fs::read(temp.path().join("out.txt")).unwrap(),
+            b"hello"
+        );
+
+        // mkdirat and unlinkat with AT_REMOVEDIR, which can't remove the root of the sandbox
+        assert_eq!(call(&mut emu, b"dir\0", &[34, at_fdcwd, name, 4, 0o755]), 0);
+        assert_eq!(call(&mut emu, b"dir\0", &[35, at_fdcwd, name, 4, 0x200]), 0);
+        assert!(!temp.path().join("dir").exists());
+        for root in [&b"/\0"[..], b"out.txt/..\0"] {
+            let len = root.len() as u64;
+            let result = call(&mut emu, root, &[35, at_fdcwd, name, len, 0x200]);
+            assert_eq!(result, EBUSY.wrapping_neg());
+        }
+        assert!(temp.path().is_dir());
+
+        // exit, which isn't answered
+        call(&mut emu, b"", &[93, 3]);
+        assert_eq!(emu.exit_code, Some(3));
+        assert_eq!(emu.read_u64(FROMHOST).unwrap(), 0);
+    }
+}
@@ -0,0 +1,378 @@
+//! The system calls riscv-pk passes on to the host, numbered and laid out as for Linux, except
+//! that every path is followed by its length including the NUL.
+//!
+//! Calls that touch the host's files only reach those inside the sandbox, and fail with `EACCES`
+//! when there is none. Paths relative to a directory other than the working directory aren't
+//! supported, and the working directory is the root of the sandbox, which can't be removed. File
+//! descriptors 0, 1 and 2 are the host's stdin, stdout and stderr. Anything else fails with
+//! `ENOSYS`.
+
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::host_file::{
+    errno, HostFile, EACCES, EBADF, EBUSY, EFAULT, EINVAL, ENAMETOOLONG, ENOMEM, ENOSYS, ERANGE,
+    ESPIPE, MAX_TRANSFER, PATH_MAX,
+};
+use crate::mem::MemoryMap;
+use crate::sandbox::Sandbox;
+
+const SYS_GETCWD: u64 = 17;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_FTRUNCATE: u64 = 46;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_PREAD: u64 = 67;
+const SYS_PWRITE: u64 = 68;
+const SYS_FSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_LSTAT: u64 = 1039;
+const SYS_GETMAINVARS: u64 = 2011;
+
+const AT_FDCWD: u64 = -100i64 as u64;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const W_OK: u64 = 2;
+
+/// The outcome of a call, or the error number it failed with.
+type SysResult = Result<u64, u64>;
+
+pub(crate) struct Proxy {
+    args: Vec<String>,
+    sandbox: Option<Sandbox>,
+    files: Vec<Option<HostFile>>,
+}
+
+impl Proxy {
+    pub(crate) fn new(args: Vec<String>, sandbox: Option<Sandbox>) -> Proxy {
+        Proxy {
+            args,
+            sandbox,
+            files: vec![
+                Some(HostFile::Stdin),
+                Some(HostFile::Stdout),
+                Some(HostFile::Stderr),
+            ],
+        }
+    }
+
+    /// Carry out the call whose number and arguments are at `addr`, writing its result over the
+    /// number and adding the ranges of memory written to `written`. Returns the exit code if the
+    /// guest exited.
+    pub(crate) fn call(
+        &mut self,
+        memory: &mut MemoryMap,
+        addr: u64,
+        written: &mut Vec<Range<usize>>,
+    ) -> Option<u32> {
+        let mut guest = Guest { memory, written };
+        let Ok(words) = guest.read(addr, 64) else {
+            return None;
+        };
+        let mut words = words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+        let n = words.next().unwrap();
+        let mut a = [0; 7];
+        for (arg, word) in a.iter_mut().zip(words) {
+            *arg = word;
+        }
+        if n == SYS_EXIT || n == SYS_EXIT_GROUP {
+            return Some(a[0] as u32);
+        }
+        let result = self
+            .dispatch(&mut guest, n, a)
+            .unwrap_or_else(|errno| errno.wrapping_neg());
+        let _ = guest.write(addr, &result.to_le_bytes());
+        None
+    }
+
+    fn dispatch(&mut self, guest: &mut Guest, n: u64, a: [u64; 7]) -> SysResult {
+        match n {
+            SYS_GETCWD => {
+                if a[1] < 2 {
+                    return Err(ERANGE);
+                }
+                guest.write(a[0], b"/\0")?;
+                Ok(a[0])
+            }
+            SYS_MKDIRAT => {
+                fs::create_dir(self.path(guest, a[0], a[1], a[2])?).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_UNLINKAT => {
+                let path = self.path(guest, a[0], a[1], a[2])?;
+                if a[3] & AT_REMOVEDIR != 0 {
+                    // As for the root of the host's filesystem
+                    let root = self.sandbox.as_ref().map(Sandbox::root);
+                    if path.canonicalize().ok().as_deref() == root {
+                        return Err(EBUSY);
+                    }
+                    fs::remove_dir(path).map_err(errno)?;
+                } else {
+                    fs::remove_file(path).map_err(errno)?;
+                }
+                Ok(0)
+            }
+            SYS_FTRUNCATE => {
+                self.file(a[0])?.set_len(a[1]).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_FACCESSAT => {
+                let metadata = fs::metadata(self.path(guest, a[0], a[1], a[2])?).map_err(errno)?;
+                if a[3] & W_OK != 0 && metadata.permissions().readonly() {
+                    return Err(EACCES);
+                }
+                Ok(0)
+            }
+            SYS_OPENAT => {
+                let path = self.path(guest, a[0], a[1], a[2])?;
+                let flags = a[3];
+                let mut options = OpenOptions::new();
+                options
+                    .read(flags & 3 != 1)
+                    .write(flags & 3 != 0)
+                    .append(flags & O_APPEND != 0)
+                    .truncate(flags & O_TRUNC != 0);
+                if flags & O_EXCL != 0 {
+                    options.create_new(flags & O_CREAT != 0);
+                } else {
+                    options.create(flags & O_CREAT != 0);
+                }
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, a[4] as u32 & 0o7777);
+                let file = options.open(path).map_err(errno)?;
+                let fd = match self.files.iter().position(Option::is_none) {
+                    Some(fd) => fd,
+                    None => {
+                        self.files.push(None);
+                        self.files.len() - 1
+                    }
+                };
+                self.files[fd] = Some(HostFile::File(file));
+                Ok(fd as u64)
+            }
+            SYS_CLOSE => {
+                self.files
+                    .get_mut(a[0] as usize)
+                    .and_then(Option::take)
+                    .ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let pos = match a[2] {
+                    0 => SeekFrom::Start(a[1]),
+                    1 => SeekFrom::Current(a[1] as i64),
+                    2 => SeekFrom::End(a[1] as i64),
+                    _ => return Err(EINVAL),
+                };
+                self.file(a[0])?.seek(pos).map_err(errno)
+            }
+            SYS_READ => {
+                let mut buf = vec![0; a[2].min(MAX_TRANSFER) as usize];
+                let count = self.host_file(a[0])?.read(&mut buf)?;
+                guest.write(a[1], &buf[..count])?;
+                Ok(count as u64)
+            }
+            SYS_WRITE => {
+                let buf = guest.read(a[1], a[2].min(MAX_TRANSFER))?;
+                let count = self.host_file(a[0])?.write(&buf)?;
+                Ok(count as u64)
+            }
+            SYS_PREAD => {
+                let mut buf = vec![0; a[2].min(MAX_TRANSFER) as usize];
+                let count = at_offset(self.file(a[0])?, a[3], |file| file.read(&mut buf))?;
+                guest.write(a[1], &buf[..count])?;
+                Ok(count as u64)
+            }
+            SYS_PWRITE => {
+                let buf = guest.read(a[1], a[2].min(MAX_TRANSFER))?;
+                at_offset(self.file(a[0])?, a[3], |file| file.write(&buf)).map(|count| count as u64)
+            }
+            SYS_FSTATAT => {
+                let path = self.path(guest, a[0], a[1], a[2])?;
+                let metadata = if a[4] & AT_SYMLINK_NOFOLLOW != 0 {
+                    fs::symlink_metadata(path)
+                } else {
+                    fs::metadata(path)
+                };
+                guest.write(a[3], &stat(&metadata.map_err(errno)?))?;
+                Ok(0)
+            }
+            SYS_FSTAT => {
+                let stat = match self.host_file(a[0])? {
+                    HostFile::File(file) => stat(&file.metadata().map_err(errno)?),
+                    _ => console_stat(),
+                };
+                guest.write(a[1], &stat)?;
+                Ok(0)
+            }
+            SYS_LSTAT => {
+                let metadata = fs::symlink_metadata(self.path(guest, AT_FDCWD, a[0], a[1])?);
+                guest.write(a[2], &stat(&metadata.map_err(errno)?))?;
+                Ok(0)
+            }
+            SYS_GETMAINVARS => self.main_vars(guest, a[0], a[1]),
+            _ => Err(ENOSYS),
+        }
+    }
+
+    /// Write the arguments to `buf` as riscv-pk lays them out: the number of them, a pointer to
+    /// each, two null pointers ending the arguments and the empty environment, then the strings.
+    fn main_vars(&self, guest: &mut Guest, buf: u64, limit: u64) -> SysResult {
+        let mut strings = (self.args.len() as u64 + 3) * 8;
+        let mut words = vec![self.args.len() as u64];
+        let mut data = Vec::new();
+        for arg in &self.args {
+            words.push(buf.wrapping_add(strings));
+            strings += arg.len() as u64 + 1;
+            data.extend(arg.as_bytes());
+            data.push(0);
+        }
+        words.extend([0, 0]);
+        if strings > limit {
+            return Err(ENOMEM);
+        }
+        let mut bytes: Vec<u8> = words.into_iter().flat_map(u64::to_le_bytes).collect();
+        bytes.extend(data);
+        guest.write(buf, &bytes)?;
+        Ok(0)
+    }
+
+    /// The host path of the guest path at `addr`, which is `len` bytes long including the NUL.
+    fn path(
+        &self,
+        guest: &Guest,
+        dirfd: u64,
+        addr: u64,
+        len: u64,
+    ) -> Result<std::path::PathBuf, u64> {
+        if len > PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let bytes = guest.read(addr, len)?;
+        let bytes = bytes.split(|&byte| byte == 0).next().unwrap_or_default();
+        let path = std::str::from_utf8(bytes).map_err(|_| EINVAL)?;
+        if !path.starts_with('/') && dirfd != AT_FDCWD {
+            return Err(EBADF);
+        }
+        let sandbox = self.sandbox.as_ref().ok_or(EACCES)?;
+        sandbox.resolve(path).map_err(errno)
+    }
+
+    fn host_file(&mut self, fd: u64) -> Result<&mut HostFile, u64> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+
+    /// The file behind `fd`, which can't be one of the console's.
+    fn file(&mut self, fd: u64) -> Result<&mut File, u64> {
+        match self.host_file(fd)? {
+            HostFile::File(file) => Ok(file),
+            _ => Err(ESPIPE),
+        }
+    }
+}
+
+/// Guest memory, keeping track of what has been written to it.
+struct Guest<'a> {
+    memory: &'a mut MemoryMap,
+    written: &'a mut Vec<Range<usize>>,
+}
+
+impl Guest<'_> {
+    fn read(&self, addr: u64, len: u64) -> Result<Vec<u8>, u64> {
+        self.memory
+            .read_bytes(addr as usize, len as usize)
+            .map(|bytes| bytes.into_owned())
+            .map_err(|_| EFAULT)
+    }
+
+    fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<(), u64> {
+        self.memory
+            .write_bytes(addr as usize, bytes)
+            .map_err(|_| EFAULT)?;
+        self.written
+            .push(addr as usize..addr as usize + bytes.len());
+        Ok(())
+    }
+}
+
+/// Do `transfer` at `offset` into `file`, leaving its position where it was.
+fn at_offset(
+    file: &mut File,
+    offset: u64,
+    transfer: impl FnOnce(&mut File) -> io::Result<usize>,
+) -> Result<usize, u64> {
+    let pos = file.stream_position().map_err(errno)?;
+    file.seek(SeekFrom::Start(offset)).map_err(errno)?;
+    let result = transfer(file);
+    file.seek(SeekFrom::Start(pos)).map_err(errno)?;
+    result.map_err(errno)
+}
+
+/// `metadata` as a Linux `struct stat`.
+fn stat(metadata: &Metadata) -> [u8; 128] {
+    let mut stat = [0; 128];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        stat[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        put(0, &metadata.dev().to_le_bytes());
+        put(8, &metadata.ino().to_le_bytes());
+        put(16, &metadata.mode().to_le_bytes());
+        put(20, &(metadata.nlink() as u32).to_le_bytes());
+        put(24, &metadata.uid().to_le_bytes());
+        put(28, &metadata.gid().to_le_bytes());
+        put(32, &metadata.rdev().to_le_bytes());
+        put(56, &(metadata.blksize() as u32).to_le_bytes());
+        put(64, &metadata.blocks().to_le_bytes());
+        put(72, &metadata.atime().to_le_bytes());
+        put(80, &metadata.atime_nsec().to_le_bytes());
+        put(88, &metadata.mtime().to_le_bytes());
+        put(96, &metadata.mtime_nsec().to_le_bytes());
+        put(104, &metadata.ctime().to_le_bytes());
+        put(112, &metadata.ctime_nsec().to_le_bytes());
+    }
+    #[cfg(not(unix))]
+    {
+        let kind: u32 = if metadata.is_dir() {
+            0o040000
+        } else {
+            0o100000
+        };
+        let permissions = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        put(16, &(kind | permissions).to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+    }
+    put(48, &metadata.len().to_le_bytes());
+    stat
+}
+
+/// The `struct stat` of the console, a character device.
+fn console_stat() -> [u8; 128] {
+    let mut stat = [0; 128];
+    stat[16..20].copy_from_slice(&0o020620u32.to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+    stat
+}
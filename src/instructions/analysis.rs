@@ -0,0 +1,352 @@
+//! What instructions read, write and where they go, for tools that analyse programs without
+//! executing them.
+
+use super::{
+    atomic::{AMem, AOp, AtomicInstruction},
+    base::{BImmediate32, BImmediate64, BaseInstruction},
+    bitmanip::{BitManipInstruction, BmImm32},
+    machine::MachineInstruction,
+    mul::MulInstruction,
+    zicsr::ZicsrInstruction,
+    Instruction,
+};
+
+/// The way an instruction accesses memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    /// Reads memory, including `lr`.
+    Load,
+    /// Writes memory, including `sc`, which only writes if its reservation is still held.
+    Store,
+    /// Reads and then writes memory, atomically.
+    Amo,
+}
+
+/// An access to memory made by an instruction, at `x[base] + offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    /// The number of bytes accessed
+    pub width: usize,
+    /// The register holding the base address
+    pub base: usize,
+    pub offset: i64,
+}
+
+/// A change of control flow made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControlTransfer {
+    /// A conditional branch to `target`.
+    Branch { target: u64 },
+    /// An unconditional jump to `target`, saving the return address in `link` if there is one.
+    Jump { target: u64, link: Option<usize> },
+    /// A jump to `x[base] + offset` with the lowest bit cleared, saving the return address in
+    /// `link` if there is one.
+    IndirectJump {
+        base: usize,
+        offset: i64,
+        link: Option<usize>,
+    },
+    /// A trap into a more privileged mode, by `ecall` or `ebreak`.
+    Trap,
+    /// A return from a trap handler, to the address in `mepc`.
+    TrapReturn,
+}
+
+impl Instruction {
+    /// The registers read by the instruction. x0 is never included, since it always reads zero.
+    pub fn sources(&self) -> impl Iterator<Item = usize> {
+        self.registers().1.into_iter().flatten().filter(|&x| x != 0)
+    }
+
+    /// The register written by the instruction, if any. Writes to x0 are discarded, so this is
+    /// never x0.
+    pub fn destination(&self) -> Option<usize> {
+        self.registers().0.filter(|&x| x != 0)
+    }
+
+    /// The destination and source registers named by the instruction, which may be x0. The
+    /// sources are rs1 and rs2, in that order.
+    pub(crate) fn registers(&self) -> (Option<usize>, [Option<usize>; 2]) {
+        use BaseInstruction as B;
+
+        match self {
+            Instruction::Base(instruction) => match instruction {
+                B::Lui(u) | B::Auipc(u) => (Some(u.rd), [None, None]),
+                B::Jal(j, _) => (Some(j.rd), [None, None]),
+                B::Jalr(i, _) | B::Load(_, i) | B::Imm64(_, i) | B::Imm32(_, i) => {
+                    (Some(i.rd), [Some(i.rs1), None])
+                }
+                B::Branch(_, b, _) => (None, [Some(b.rs1), Some(b.rs2)]),
+                B::Store(_, s) => (None, [Some(s.rs1), Some(s.rs2)]),
+                B::Reg64(_, r) | B::Reg32(_, r) => (Some(r.rd), [Some(r.rs1), Some(r.rs2)]),
+                B::Fence(_) | B::FenceI | B::Ecall | B::Ebreak => (None, [None, None]),
+            },
+            Instruction::Machine(_) => (None, [None, None]),
+            // The immediate forms hold the immediate where rs1 would be
+            Instruction::Zicsr(ZicsrInstruction(_, true, i)) => (Some(i.rd), [None, None]),
+            Instruction::Zicsr(ZicsrInstruction(_, false, i)) => (Some(i.rd), [Some(i.rs1), None]),
+            Instruction::Mul(MulInstruction::Reg64(_, r) | MulInstruction::Reg32(_, r)) => {
+                (Some(r.rd), [Some(r.rs1), Some(r.rs2)])
+            }
+            Instruction::Atomic(AtomicInstruction { op, instr, .. }) => match op {
+                AOp::Mem(AMem::LrW | AMem::LrD) => (Some(instr.rd), [Some(instr.rs1), None]),
+                _ => (Some(instr.rd), [Some(instr.rs1), Some(instr.rs2)]),
+            },
+            Instruction::BitManip(instruction) => match instruction {
+                BitManipInstruction::Reg64(_, r) | BitManipInstruction::Reg32(_, r) => {
+                    (Some(r.rd), [Some(r.rs1), Some(r.rs2)])
+                }
+                BitManipInstruction::Unary64(_, i)
+                | BitManipInstruction::Unary32(_, i)
+                | BitManipInstruction::Imm64(_, i)
+                | BitManipInstruction::Imm32(_, i) => (Some(i.rd), [Some(i.rs1), None]),
+            },
+        }
+    }
+
+    /// The value of the immediate operand, sign extended. For shifts and single bit
+    /// instructions this is the shift amount or bit index, for `lui` and `auipc` it is the value
+    /// added (so a multiple of 4096) and for jumps and branches it is the offset from the
+    /// instruction. The csr instructions with an immediate give the immediate, not the csr.
+    pub fn immediate(&self) -> Option<i64> {
+        use BaseInstruction as B;
+
+        /// Sign extend the low `bits` bits of `imm`.
+        fn sext(imm: i64, bits: u32) -> i64 {
+            imm << (64 - bits) >> (64 - bits)
+        }
+
+        Some(match self {
+            Instruction::Base(instruction) => match instruction {
+                B::Lui(u) | B::Auipc(u) => u.imm.into(),
+                B::Jal(j, _) => sext(j.imm.into(), 21),
+                B::Branch(_, b, _) => sext(b.imm.into(), 13),
+                B::Jalr(i, _) | B::Load(_, i) => sext(i.imm.into(), 12),
+                B::Store(_, s) => sext(s.imm.into(), 12),
+                B::Imm64(op, i) => match op {
+                    BImmediate64::Sll | BImmediate64::Srl | BImmediate64::Sra => {
+                        (i.imm & 0x3f).into()
+                    }
+                    _ => sext(i.imm.into(), 12),
+                },
+                B::Imm32(op, i) => match op {
+                    BImmediate32::Add => sext(i.imm.into(), 12),
+                    _ => (i.imm & 0x1f).into(),
+                },
+                _ => None?,
+            },
+            Instruction::Zicsr(ZicsrInstruction(_, true, i)) => i.rs1 as i64,
+            Instruction::BitManip(BitManipInstruction::Imm64(_, i)) => (i.imm & 0x3f).into(),
+            Instruction::BitManip(BitManipInstruction::Imm32(op, i)) => match op {
+                BmImm32::SlliUw => (i.imm & 0x3f).into(),
+                BmImm32::Roriw => (i.imm & 0x1f).into(),
+            },
+            _ => None?,
+        })
+    }
+
+    /// The csr accessed by the instruction, if it is one of the csr instructions.
+    pub fn csr(&self) -> Option<u16> {
+        match self {
+            Instruction::Zicsr(ZicsrInstruction(_, _, i)) => Some(i.imm & 0xfff),
+            _ => None,
+        }
+    }
+
+    /// The memory accessed by the instruction, if it is a load, store or atomic.
+    ///
+    /// ```
+    /// use riscv::instructions::{AccessKind, Instruction, MemoryAccess};
+    ///
+    /// // lw a0, -4(s0)
+    /// let load = Instruction::parse(0xffc42503).unwrap();
+    /// let access = MemoryAccess {
+    ///     kind: AccessKind::Load,
+    ///     width: 4,
+    ///     base: 8,
+    ///     offset: -4,
+    /// };
+    /// assert_eq!(load.memory_access(), Some(access));
+    /// ```
+    pub fn memory_access(&self) -> Option<MemoryAccess> {
+        match self {
+            Instruction::Base(BaseInstruction::Load(op, i)) => Some(MemoryAccess {
+                kind: AccessKind::Load,
+                width: op.width(),
+                base: i.rs1,
+                offset: self.immediate()?,
+            }),
+            Instruction::Base(BaseInstruction::Store(op, s)) => Some(MemoryAccess {
+                kind: AccessKind::Store,
+                width: op.width(),
+                base: s.rs1,
+                offset: self.immediate()?,
+            }),
+            Instruction::Atomic(AtomicInstruction { op, instr, .. }) => {
+                let (kind, width) = match op {
+                    AOp::Mem(AMem::LrW) => (AccessKind::Load, 4),
+                    AOp::Mem(AMem::LrD) => (AccessKind::Load, 8),
+                    AOp::Mem(AMem::ScW) => (AccessKind::Store, 4),
+                    AOp::Mem(AMem::ScD) => (AccessKind::Store, 8),
+                    AOp::AmoW(_) => (AccessKind::Amo, 4),
+                    AOp::AmoD(_) => (AccessKind::Amo, 8),
+                };
+                Some(MemoryAccess {
+                    kind,
+                    width,
+                    base: instr.rs1,
+                    offset: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The change of control flow made by the instruction at `pc`, if it is a jump, branch or
+    /// trap.
+    ///
+    /// ```
+    /// use riscv::instructions::{ControlTransfer, Instruction};
+    ///
+    /// // c.beqz a0, -4
+    /// let branch = Instruction::parse_compressed(0xdd75).unwrap();
+    /// let target = 0x8000_0000 - 4;
+    /// assert_eq!(
+    ///     branch.control_transfer(0x8000_0000),
+    ///     Some(ControlTransfer::Branch { target })
+    /// );
+    /// ```
+    pub fn control_transfer(&self, pc: u64) -> Option<ControlTransfer> {
+        use BaseInstruction as B;
+
+        let link = |rd: usize| (rd != 0).then_some(rd);
+        Some(match self {
+            Instruction::Base(instruction) => match instruction {
+                B::Jal(j, _) => ControlTransfer::Jump {
+                    target: pc.wrapping_add_signed(self.immediate()?),
+                    link: link(j.rd),
+                },
+                B::Jalr(i, _) => ControlTransfer::IndirectJump {
+                    base: i.rs1,
+                    offset: self.immediate()?,
+                    link: link(i.rd),
+                },
+                B::Branch(..) => ControlTransfer::Branch {
+                    target: pc.wrapping_add_signed(self.immediate()?),
+                },
+                B::Ecall | B::Ebreak => ControlTransfer::Trap,
+                _ => None?,
+            },
+            Instruction::Machine(MachineInstruction::MRet(_)) => ControlTransfer::TrapReturn,
+            _ => None?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isa::Extension;
+
+    /// The instruction encoded in the low 16 bits of `encoding` if they are a compressed
+    /// instruction, or else in all 32.
+    fn decode(encoding: u32) -> Instruction {
+        if encoding & 0b11 == 0b11 {
+            Instruction::parse(encoding)
+        } else {
+            Instruction::parse_compressed(encoding as u16)
+        }
+        .unwrap_or_else(|| panic!("{encoding:#x} doesn't decode"))
+    }
+
+    #[test]
+    fn sources_and_destination() {
+        let cases: [(u32, &[usize], Option<usize>); 13] = [
+            (0x00c58533, &[11, 12], Some(10)), // add a0, a1, a2
+            (0x00a13423, &[2, 10], None),      // sd a0, 8(sp)
+            (0x00b6252f, &[12, 11], Some(10)), // amoadd.w a0, a1, (a2)
+            (0x1005b52f, &[11], Some(10)),     // lr.d a0, (a1)
+            (0x00000013, &[], None),           // nop
+            (0x3002d573, &[], Some(10)),       // csrrwi a0, mstatus, 5
+            (0x3005a573, &[11], Some(10)),     // csrrs a0, mstatus, a1
+            (0xff1ff0ef, &[], Some(1)),        // jal -16
+            (0x852e, &[11], Some(10)),         // c.mv a0, a1
+            (0x157d, &[10], Some(10)),         // c.addi a0, -1
+            (0xe42a, &[2, 10], None),          // c.sdsp a0, 8(sp)
+            (0x9502, &[10], Some(1)),          // c.jalr a0
+            (0x657d, &[], Some(10)),           // c.lui a0, 31
+        ];
+        for (encoding, sources, destination) in cases {
+            let instruction = decode(encoding);
+            assert_eq!(
+                instruction.sources().collect::<Vec<_>>(),
+                sources,
+                "{encoding:#x}"
+            );
+            assert_eq!(instruction.destination(), destination, "{encoding:#x}");
+        }
+    }
+
+    #[test]
+    fn memory_accesses() {
+        let access = |kind, width, base, offset| {
+            Some(MemoryAccess {
+                kind,
+                width,
+                base,
+                offset,
+            })
+        };
+        let cases = [
+            (0xffc42503, access(AccessKind::Load, 4, 8, -4)), // lw a0, -4(s0)
+            (0x00a13423, access(AccessKind::Store, 8, 2, 8)), // sd a0, 8(sp)
+            (0x00b6252f, access(AccessKind::Amo, 4, 12, 0)),  // amoadd.w a0, a1, (a2)
+            (0x1005b52f, access(AccessKind::Load, 8, 11, 0)), // lr.d a0, (a1)
+            (0x18c5a52f, access(AccessKind::Store, 4, 11, 0)), // sc.w a0, a2, (a1)
+            (0x00c58533, None),                               // add a0, a1, a2
+            (0x41c8, access(AccessKind::Load, 4, 11, 4)),     // c.lw a0, 4(a1)
+            (0x6542, access(AccessKind::Load, 8, 2, 16)),     // c.ldsp a0, 16(sp)
+            (0xe42a, access(AccessKind::Store, 8, 2, 8)),     // c.sdsp a0, 8(sp)
+        ];
+        for (encoding, access) in cases {
+            assert_eq!(decode(encoding).memory_access(), access, "{encoding:#x}");
+        }
+    }
+
+    #[test]
+    fn control_transfers() {
+        use ControlTransfer::{Branch, Trap, TrapReturn};
+
+        let jump = |target, link| Some(ControlTransfer::Jump { target, link });
+        let indirect =
+            |base, offset, link| Some(ControlTransfer::IndirectJump { base, offset, link });
+        let pc = 0x1000;
+        let cases = [
+            (0xff1ff0ef, jump(0xff0, Some(1))),            // jal -16
+            (0x00408067, indirect(1, 4, None)),            // jr 4(ra)
+            (0x00b50663, Some(Branch { target: 0x100c })), // beq a0, a1, 12
+            (0x00000073, Some(Trap)),                      // ecall
+            (0x30200073, Some(TrapReturn)),                // mret
+            (0x00c58533, None),                            // add a0, a1, a2
+            (0xbfed, jump(0xffa, None)),                   // c.j -6
+            (0x9502, indirect(10, 0, Some(1))),            // c.jalr a0
+            (0x8082, indirect(1, 0, None)),                // c.jr ra
+            (0xdd75, Some(Branch { target: 0xffc })),      // c.beqz a0, -4
+            (0x9002, Some(Trap)),                          // c.ebreak
+        ];
+        for (encoding, transfer) in cases {
+            assert_eq!(
+                decode(encoding).control_transfer(pc),
+                transfer,
+                "{encoding:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_instructions_report_the_extension_of_their_expansion() {
+        assert_eq!(decode(0x41c8).extension(), Some(Extension::Base)); // c.lw a0, 4(a1)
+        assert_eq!(decode(0x9002).extension(), Some(Extension::Base)); // c.ebreak
+    }
+}
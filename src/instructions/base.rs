@@ -29,6 +29,30 @@ pub enum BStore {
     D,
 }
 
+impl BLoad {
+    /// The number of bytes loaded.
+    pub fn width(&self) -> usize {
+        match self {
+            BLoad::B | BLoad::Bu => 1,
+            BLoad::H | BLoad::Hu => 2,
+            BLoad::W | BLoad::Wu => 4,
+            BLoad::D => 8,
+        }
+    }
+}
+
+impl BStore {
+    /// The number of bytes stored.
+    pub fn width(&self) -> usize {
+        match self {
+            BStore::B => 1,
+            BStore::H => 2,
+            BStore::W => 4,
+            BStore::D => 8,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BImmediate64 {
     Add,
@@ -87,6 +111,7 @@ pub enum BaseInstruction {
     Reg64(BRegister64, RType),
     Reg32(BRegister32, RType),
     Fence(u32),
+    FenceI,
     Ecall,
     Ebreak,
 }
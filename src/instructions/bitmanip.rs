@@ -0,0 +1,103 @@
+use super::{Extension, IType, RType};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmReg64 {
+    Sh1add,
+    Sh2add,
+    Sh3add,
+    Andn,
+    Orn,
+    Xnor,
+    Max,
+    Maxu,
+    Min,
+    Minu,
+    Rol,
+    Ror,
+    Bclr,
+    Bext,
+    Binv,
+    Bset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmReg32 {
+    AddUw,
+    Sh1addUw,
+    Sh2addUw,
+    Sh3addUw,
+    Rolw,
+    Rorw,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmUnary64 {
+    Clz,
+    Ctz,
+    Cpop,
+    SextB,
+    SextH,
+    ZextH,
+    OrcB,
+    Rev8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmUnary32 {
+    Clzw,
+    Ctzw,
+    Cpopw,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmImm64 {
+    Rori,
+    Bclri,
+    Bexti,
+    Binvi,
+    Bseti,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BmImm32 {
+    SlliUw,
+    Roriw,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BitManipInstruction {
+    Reg64(BmReg64, RType),
+    Reg32(BmReg32, RType),
+    // Unary instructions only use rd and rs1, so the immediate field holds the raw encoding.
+    Unary64(BmUnary64, IType),
+    Unary32(BmUnary32, IType),
+    Imm64(BmImm64, IType),
+    Imm32(BmImm32, IType),
+}
+
+impl BitManipInstruction {
+    /// The sub-extension of B that this instruction belongs to.
+    pub fn extension(&self) -> Extension {
+        match self {
+            BitManipInstruction::Reg64(op, _) => match op {
+                BmReg64::Sh1add | BmReg64::Sh2add | BmReg64::Sh3add => Extension::Zba,
+                BmReg64::Bclr | BmReg64::Bext | BmReg64::Binv | BmReg64::Bset => Extension::Zbs,
+                _ => Extension::Zbb,
+            },
+            BitManipInstruction::Reg32(op, _) => match op {
+                BmReg32::Rolw | BmReg32::Rorw => Extension::Zbb,
+                _ => Extension::Zba,
+            },
+            BitManipInstruction::Unary64(_, _) => Extension::Zbb,
+            BitManipInstruction::Unary32(_, _) => Extension::Zbb,
+            BitManipInstruction::Imm64(op, _) => match op {
+                BmImm64::Rori => Extension::Zbb,
+                _ => Extension::Zbs,
+            },
+            BitManipInstruction::Imm32(op, _) => match op {
+                BmImm32::SlliUw => Extension::Zba,
+                BmImm32::Roriw => Extension::Zbb,
+            },
+        }
+    }
+}
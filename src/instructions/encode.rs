@@ -0,0 +1,716 @@
+use super::{
+    atomic::{AAmoD, AAmoW, AMem, AOp, AtomicInstruction},
+    base::{
+        BImmediate32, BImmediate64, BLoad, BRegister32, BRegister64, BStore, BaseInstruction,
+        Branch,
+    },
+    bitmanip::{BitManipInstruction, BmImm32, BmImm64, BmReg32, BmReg64, BmUnary32, BmUnary64},
+    machine::MachineInstruction,
+    mul::{MReg32, MReg64, MulInstruction},
+    zicsr::{ZOp, ZicsrInstruction},
+    BType, IType, Instruction, JType, RType, SType, UType,
+};
+
+impl RType {
+    fn encode(&self, opcode: u32, funct3: u32, funct7: u32) -> u32 {
+        funct7 << 25
+            | (self.rs2 as u32) << 20
+            | (self.rs1 as u32) << 15
+            | funct3 << 12
+            | (self.rd as u32) << 7
+            | opcode
+    }
+}
+
+impl IType {
+    fn encode(&self, opcode: u32, funct3: u32) -> u32 {
+        self.encode_with(opcode, funct3, self.imm.into())
+    }
+
+    /// Encode with `imm` in place of the immediate, for instructions that split it into a
+    /// shift amount and funct bits.
+    fn encode_with(&self, opcode: u32, funct3: u32, imm: u32) -> u32 {
+        (imm & 0xfff) << 20
+            | (self.rs1 as u32) << 15
+            | funct3 << 12
+            | (self.rd as u32) << 7
+            | opcode
+    }
+}
+
+impl SType {
+    fn encode(&self, opcode: u32, funct3: u32) -> u32 {
+        let imm = u32::from(self.imm);
+        (imm >> 5 & 0x7f) << 25
+            | (self.rs2 as u32) << 20
+            | (self.rs1 as u32) << 15
+            | funct3 << 12
+            | (imm & 0x1f) << 7
+            | opcode
+    }
+}
+
+impl BType {
+    fn encode(&self, opcode: u32, funct3: u32) -> u32 {
+        let imm = u32::from(self.imm);
+        (imm >> 12 & 1) << 31
+            | (imm >> 5 & 0x3f) << 25
+            | (self.rs2 as u32) << 20
+            | (self.rs1 as u32) << 15
+            | funct3 << 12
+            | (imm >> 1 & 0xf) << 8
+            | (imm >> 11 & 1) << 7
+            | opcode
+    }
+}
+
+impl UType {
+    fn encode(&self, opcode: u32) -> u32 {
+        self.imm as u32 & 0xfffff000 | (self.rd as u32) << 7 | opcode
+    }
+}
+
+impl JType {
+    fn encode(&self, opcode: u32) -> u32 {
+        let imm = self.imm as u32;
+        (imm >> 20 & 1) << 31
+            | (imm >> 1 & 0x3ff) << 21
+            | (imm >> 11 & 1) << 20
+            | imm & 0xff000
+            | (self.rd as u32) << 7
+            | opcode
+    }
+}
+
+impl Instruction {
+    /// Returns the canonical encoding of the instruction, which [`Instruction::parse`] decodes
+    /// back into it.
+    ///
+    /// Jumps and branches decoded from a compressed instruction only have a 16 bit encoding,
+    /// which is returned in the low half. These can be told apart by their low two bits not being
+    /// `0b11`, and panic if the instruction can't be compressed.
+    pub fn encode(&self) -> u32 {
+        use BaseInstruction as B;
+
+        match self {
+            Instruction::Base(B::Jal(_, true) | B::Jalr(_, true) | B::Branch(_, _, true)) => {
+                match self.encode_compressed() {
+                    Some(encoding) => encoding.into(),
+                    None => panic!("{self:?} has no compressed encoding"),
+                }
+            }
+            Instruction::Base(instruction) => match instruction {
+                B::Lui(u) => u.encode(0b0110111),
+                B::Auipc(u) => u.encode(0b0010111),
+                B::Jal(j, _) => j.encode(0b1101111),
+                B::Jalr(i, _) => i.encode(0b1100111, 0b000),
+                B::Branch(branch, b, _) => {
+                    let funct3 = match branch {
+                        Branch::Eq => 0b000,
+                        Branch::Ne => 0b001,
+                        Branch::Lt => 0b100,
+                        Branch::Ge => 0b101,
+                        Branch::Ltu => 0b110,
+                        Branch::Geu => 0b111,
+                    };
+                    b.encode(0b1100011, funct3)
+                }
+                B::Load(op, i) => {
+                    let funct3 = match op {
+                        BLoad::B => 0b000,
+                        BLoad::H => 0b001,
+                        BLoad::W => 0b010,
+                        BLoad::D => 0b011,
+                        BLoad::Bu => 0b100,
+                        BLoad::Hu => 0b101,
+                        BLoad::Wu => 0b110,
+                    };
+                    i.encode(0b0000011, funct3)
+                }
+                B::Store(op, s) => {
+                    let funct3 = match op {
+                        BStore::B => 0b000,
+                        BStore::H => 0b001,
+                        BStore::W => 0b010,
+                        BStore::D => 0b011,
+                    };
+                    s.encode(0b0100011, funct3)
+                }
+                B::Imm64(op, i) => {
+                    let shamt = u32::from(i.imm) & 0x3f;
+                    match op {
+                        BImmediate64::Add => i.encode(0b0010011, 0b000),
+                        BImmediate64::Slt => i.encode(0b0010011, 0b010),
+                        BImmediate64::Sltu => i.encode(0b0010011, 0b011),
+                        BImmediate64::Xor => i.encode(0b0010011, 0b100),
+                        BImmediate64::Or => i.encode(0b0010011, 0b110),
+                        BImmediate64::And => i.encode(0b0010011, 0b111),
+                        BImmediate64::Sll => i.encode_with(0b0010011, 0b001, shamt),
+                        BImmediate64::Srl => i.encode_with(0b0010011, 0b101, shamt),
+                        BImmediate64::Sra => i.encode_with(0b0010011, 0b101, 0x400 | shamt),
+                    }
+                }
+                B::Imm32(op, i) => {
+                    let shamt = u32::from(i.imm) & 0x1f;
+                    match op {
+                        BImmediate32::Add => i.encode(0b0011011, 0b000),
+                        BImmediate32::Sll => i.encode_with(0b0011011, 0b001, shamt),
+                        BImmediate32::Srl => i.encode_with(0b0011011, 0b101, shamt),
+                        BImmediate32::Sra => i.encode_with(0b0011011, 0b101, 0x400 | shamt),
+                    }
+                }
+                B::Reg64(op, r) => {
+                    let (funct3, funct7) = match op {
+                        BRegister64::Add => (0b000, 0b0000000),
+                        BRegister64::Sub => (0b000, 0b0100000),
+                        BRegister64::Sll => (0b001, 0b0000000),
+                        BRegister64::Slt => (0b010, 0b0000000),
+                        BRegister64::Sltu => (0b011, 0b0000000),
+                        BRegister64::Xor => (0b100, 0b0000000),
+                        BRegister64::Srl => (0b101, 0b0000000),
+                        BRegister64::Sra => (0b101, 0b0100000),
+                        BRegister64::Or => (0b110, 0b0000000),
+                        BRegister64::And => (0b111, 0b0000000),
+                    };
+                    r.encode(0b0110011, funct3, funct7)
+                }
+                B::Reg32(op, r) => {
+                    let (funct3, funct7) = match op {
+                        BRegister32::Add => (0b000, 0b0000000),
+                        BRegister32::Sub => (0b000, 0b0100000),
+                        BRegister32::Sll => (0b001, 0b0000000),
+                        BRegister32::Srl => (0b101, 0b0000000),
+                        BRegister32::Sra => (0b101, 0b0100000),
+                    };
+                    r.encode(0b0111011, funct3, funct7)
+                }
+                B::Fence(encoding) => *encoding,
+                B::FenceI => 0x0000100f,
+                B::Ecall => 0x00000073,
+                B::Ebreak => 0x00100073,
+            },
+            Instruction::Machine(MachineInstruction::MRet(_)) => 0x30200073,
+            Instruction::Machine(MachineInstruction::Wfi(_)) => 0x10500073,
+            Instruction::Zicsr(ZicsrInstruction(op, immediate, i)) => {
+                let funct3 = match op {
+                    ZOp::Csrrw => 0b001,
+                    ZOp::Csrrs => 0b010,
+                    ZOp::Csrrc => 0b011,
+                };
+                let funct3 = if *immediate { funct3 | 0b100 } else { funct3 };
+                i.encode(0b1110011, funct3)
+            }
+            Instruction::Mul(instruction) => match instruction {
+                MulInstruction::Reg64(op, r) => {
+                    let funct3 = match op {
+                        MReg64::Mul => 0b000,
+                        MReg64::Mulh => 0b001,
+                        MReg64::Mulhsu => 0b010,
+                        MReg64::Mulhu => 0b011,
+                        MReg64::Div => 0b100,
+                        MReg64::Divu => 0b101,
+                        MReg64::Rem => 0b110,
+                        MReg64::Remu => 0b111,
+                    };
+                    r.encode(0b0110011, funct3, 0b0000001)
+                }
+                MulInstruction::Reg32(op, r) => {
+                    let funct3 = match op {
+                        MReg32::Mul => 0b000,
+                        MReg32::Div => 0b100,
+                        MReg32::Divu => 0b101,
+                        MReg32::Rem => 0b110,
+                        MReg32::Remu => 0b111,
+                    };
+                    r.encode(0b0111011, funct3, 0b0000001)
+                }
+            },
+            Instruction::Atomic(AtomicInstruction { aq, rl, op, instr }) => {
+                let (funct5, funct3) = match op {
+                    AOp::Mem(AMem::LrW) => (0b00010, 0b010),
+                    AOp::Mem(AMem::LrD) => (0b00010, 0b011),
+                    AOp::Mem(AMem::ScW) => (0b00011, 0b010),
+                    AOp::Mem(AMem::ScD) => (0b00011, 0b011),
+                    AOp::AmoW(op) => {
+                        let funct5 = match op {
+                            AAmoW::Add => 0b00000,
+                            AAmoW::Swap => 0b00001,
+                            AAmoW::Xor => 0b00100,
+                            AAmoW::Or => 0b01000,
+                            AAmoW::And => 0b01100,
+                            AAmoW::Min => 0b10000,
+                            AAmoW::Max => 0b10100,
+                            AAmoW::Minu => 0b11000,
+                            AAmoW::Maxu => 0b11100,
+                        };
+                        (funct5, 0b010)
+                    }
+                    AOp::AmoD(op) => {
+                        let funct5 = match op {
+                            AAmoD::Add => 0b00000,
+                            AAmoD::Swap => 0b00001,
+                            AAmoD::Xor => 0b00100,
+                            AAmoD::Or => 0b01000,
+                            AAmoD::And => 0b01100,
+                            AAmoD::Min => 0b10000,
+                            AAmoD::Max => 0b10100,
+                            AAmoD::Minu => 0b11000,
+                            AAmoD::Maxu => 0b11100,
+                        };
+                        (funct5, 0b011)
+                    }
+                };
+                let funct7 = funct5 << 2 | u32::from(*aq) << 1 | u32::from(*rl);
+                instr.encode(0b0101111, funct3, funct7)
+            }
+            Instruction::BitManip(instruction) => instruction.encode(),
+        }
+    }
+
+    /// The 32 bit instruction that an instruction decoded from a compressed one expands to, which
+    /// [`Instruction::encode`] gives a 32 bit encoding for. Other instructions are returned as
+    /// they are.
+    pub fn expanded(&self) -> Instruction {
+        use BaseInstruction as B;
+
+        // Compressed immediates are sign extended to the width of their field, where 32 bit
+        // immediates are left as the bits of the encoding
+        let mut instruction = self.clone();
+        if let Instruction::Base(base) = &mut instruction {
+            match base {
+                B::Jal(j, compressed) => {
+                    j.imm &= 0x1fffff;
+                    *compressed = false;
+                }
+                B::Jalr(_, compressed) => *compressed = false,
+                B::Branch(_, b, compressed) => {
+                    b.imm &= 0x1fff;
+                    *compressed = false;
+                }
+                B::Load(_, i) | B::Imm64(_, i) | B::Imm32(_, i) => i.imm &= 0xfff,
+                B::Store(_, s) => s.imm &= 0xfff,
+                _ => {}
+            }
+        }
+        instruction
+    }
+
+    /// Returns the 16 bit encoding of the instruction, if it has one. This is the encoding that
+    /// [`Instruction::parse_compressed`] decodes into the same instruction, where there is a
+    /// choice of more than one.
+    pub fn encode_compressed(&self) -> Option<u16> {
+        use BaseInstruction as B;
+
+        /// The 3 bit field for one of x8 to x15.
+        fn creg(x: usize) -> Option<u16> {
+            (8..16).contains(&x).then(|| x as u16 - 8)
+        }
+        /// A register in the 5 bit field at bit 7 or bit 2.
+        fn reg(x: usize) -> u16 {
+            x as u16
+        }
+        /// The value of a 12 bit immediate, which compressed instructions decode already sign
+        /// extended to 16 bits.
+        fn simm(imm: u16) -> i32 {
+            i32::from((imm as i16) << 4 >> 4)
+        }
+        /// Whether `imm` fits in `bits` signed bits.
+        fn fits(imm: i32, bits: u32) -> bool {
+            (-(1 << (bits - 1))..1 << (bits - 1)).contains(&imm)
+        }
+        /// Whether `imm` is a multiple of `scale` below `limit`.
+        fn scaled(imm: i32, scale: i32, limit: i32) -> bool {
+            (0..limit).contains(&imm) && imm % scale == 0
+        }
+        /// The CI format, with a 6 bit immediate split into bit 12 and bits 6 to 2.
+        fn ci(funct3: u16, rd: usize, imm: u16, op: u16) -> u16 {
+            funct3 << 13 | (imm >> 5 & 1) << 12 | reg(rd) << 7 | (imm & 0x1f) << 2 | op
+        }
+        /// The CL and CS formats, given the immediate bits at 12 to 10 and 6 to 5.
+        fn cl(funct3: u16, rs1: u16, rd: u16, high: u16, low: u16) -> u16 {
+            funct3 << 13 | high << 10 | rs1 << 7 | low << 5 | rd << 2
+        }
+
+        Some(match self {
+            Instruction::Base(instruction) => match instruction {
+                B::Load(op @ (BLoad::W | BLoad::D), i) => {
+                    let imm = simm(i.imm);
+                    let imm16 = imm as u16;
+                    match (op, i.rs1, creg(i.rd), creg(i.rs1)) {
+                        (BLoad::W, 2, _, _) if i.rd != 0 && scaled(imm, 4, 256) => {
+                            let bits = (imm16 >> 2 & 7) << 2 | imm16 >> 6 & 3;
+                            ci(0b010, i.rd, (imm16 >> 5 & 1) << 5 | bits, 0b10)
+                        }
+                        (BLoad::D, 2, _, _) if i.rd != 0 && scaled(imm, 8, 512) => {
+                            let bits = (imm16 >> 3 & 3) << 3 | imm16 >> 6 & 7;
+                            ci(0b011, i.rd, (imm16 >> 5 & 1) << 5 | bits, 0b10)
+                        }
+                        (BLoad::W, _, Some(rd), Some(rs1)) if scaled(imm, 4, 128) => {
+                            let low = (imm16 >> 2 & 1) << 1 | imm16 >> 6 & 1;
+                            cl(0b010, rs1, rd, imm16 >> 3 & 7, low)
+                        }
+                        (BLoad::D, _, Some(rd), Some(rs1)) if scaled(imm, 8, 256) => {
+                            cl(0b011, rs1, rd, imm16 >> 3 & 7, imm16 >> 6 & 3)
+                        }
+                        _ => None?,
+                    }
+                }
+                B::Store(op @ (BStore::W | BStore::D), s) => {
+                    let imm = simm(s.imm);
+                    let imm16 = imm as u16;
+                    match (op, s.rs1, creg(s.rs2), creg(s.rs1)) {
+                        (BStore::W, 2, _, _) if scaled(imm, 4, 256) => {
+                            let bits = (imm16 >> 2 & 0xf) << 2 | imm16 >> 6 & 3;
+                            0b110 << 13 | bits << 7 | reg(s.rs2) << 2 | 0b10
+                        }
+                        (BStore::D, 2, _, _) if scaled(imm, 8, 512) => {
+                            let bits = (imm16 >> 3 & 7) << 3 | imm16 >> 6 & 7;
+                            0b111 << 13 | bits << 7 | reg(s.rs2) << 2 | 0b10
+                        }
+                        (BStore::W, _, Some(rs2), Some(rs1)) if scaled(imm, 4, 128) => {
+                            let low = (imm16 >> 2 & 1) << 1 | imm16 >> 6 & 1;
+                            cl(0b110, rs1, rs2, imm16 >> 3 & 7, low)
+                        }
+                        (BStore::D, _, Some(rs2), Some(rs1)) if scaled(imm, 8, 256) => {
+                            cl(0b111, rs1, rs2, imm16 >> 3 & 7, imm16 >> 6 & 3)
+                        }
+                        _ => None?,
+                    }
+                }
+                B::Imm64(BImmediate64::Add, i) => {
+                    let imm = simm(i.imm);
+                    let imm16 = imm as u16;
+                    match (i.rd, i.rs1) {
+                        // c.nop and its hints are c.addi with rd = 0, which is also c.li
+                        (0, 0) if fits(imm, 6) => ci(0b000, 0, imm16, 0b01),
+                        (rd, 0) if fits(imm, 6) => ci(0b010, rd, imm16, 0b01),
+                        (rd, rs1) if rd == rs1 && fits(imm, 6) => ci(0b000, rd, imm16, 0b01),
+                        (2, 2) if imm != 0 && fits(imm, 10) && imm % 16 == 0 => {
+                            let bits = (imm16 >> 4 & 1) << 4
+                                | (imm16 >> 6 & 1) << 3
+                                | (imm16 >> 7 & 3) << 1
+                                | imm16 >> 5 & 1;
+                            ci(0b011, 2, (imm16 >> 9 & 1) << 5 | bits, 0b01)
+                        }
+                        (rd, 2) if imm != 0 && scaled(imm, 4, 1024) => {
+                            let bits = (imm16 >> 4 & 3) << 6
+                                | (imm16 >> 6 & 0xf) << 2
+                                | (imm16 >> 2 & 1) << 1
+                                | imm16 >> 3 & 1;
+                            bits << 5 | creg(rd)? << 2
+                        }
+                        _ => None?,
+                    }
+                }
+                B::Imm64(op @ (BImmediate64::Srl | BImmediate64::Sra), i) if i.rd == i.rs1 => {
+                    let funct2 = match op {
+                        BImmediate64::Srl => 0b00,
+                        _ => 0b01,
+                    };
+                    let shamt = i.imm & 0x3f;
+                    ci(0b100, usize::from(creg(i.rd)? | funct2 << 3), shamt, 0b01)
+                }
+                B::Imm64(BImmediate64::And, i) if i.rd == i.rs1 && fits(simm(i.imm), 6) => {
+                    ci(0b100, usize::from(creg(i.rd)? | 0b10 << 3), i.imm, 0b01)
+                }
+                B::Imm64(BImmediate64::Sll, i) if i.rd == i.rs1 => {
+                    ci(0b000, i.rd, i.imm & 0x3f, 0b10)
+                }
+                B::Imm32(BImmediate32::Add, i) if i.rd == i.rs1 && i.rd != 0 => {
+                    let imm = simm(i.imm);
+                    if !fits(imm, 6) {
+                        None?
+                    }
+                    ci(0b001, i.rd, imm as u16, 0b01)
+                }
+                B::Lui(u) if u.rd != 2 => {
+                    let imm = u.imm >> 12;
+                    if imm == 0 || !fits(imm, 6) {
+                        None?
+                    }
+                    ci(0b011, u.rd, imm as u16, 0b01)
+                }
+                B::Reg64(BRegister64::Add, r) if r.rs2 != 0 => match (r.rd, r.rs1) {
+                    (rd, 0) => 0b1000 << 12 | reg(rd) << 7 | reg(r.rs2) << 2 | 0b10,
+                    (rd, rs1) if rd == rs1 => 0b1001 << 12 | reg(rd) << 7 | reg(r.rs2) << 2 | 0b10,
+                    _ => None?,
+                },
+                B::Reg64(_, r) | B::Reg32(_, r) if r.rd == r.rs1 => {
+                    let (funct1, funct2) = match instruction {
+                        B::Reg64(BRegister64::Sub, _) => (0, 0b00),
+                        B::Reg64(BRegister64::Xor, _) => (0, 0b01),
+                        B::Reg64(BRegister64::Or, _) => (0, 0b10),
+                        B::Reg64(BRegister64::And, _) => (0, 0b11),
+                        B::Reg32(BRegister32::Sub, _) => (1, 0b00),
+                        B::Reg32(BRegister32::Add, _) => (1, 0b01),
+                        _ => None?,
+                    };
+                    0b100 << 13
+                        | funct1 << 12
+                        | 0b11 << 10
+                        | creg(r.rd)? << 7
+                        | funct2 << 5
+                        | creg(r.rs2)? << 2
+                        | 0b01
+                }
+                B::Jal(j, true) if j.rd == 0 && fits(j.imm << 11 >> 11, 12) => {
+                    let imm = j.imm as u16;
+                    let bits = (imm >> 11 & 1) << 10
+                        | (imm >> 4 & 1) << 9
+                        | (imm >> 8 & 3) << 7
+                        | (imm >> 10 & 1) << 6
+                        | (imm >> 6 & 1) << 5
+                        | (imm >> 7 & 1) << 4
+                        | (imm >> 1 & 7) << 1
+                        | imm >> 5 & 1;
+                    0b101 << 13 | bits << 2 | 0b01
+                }
+                B::Branch(branch @ (Branch::Eq | Branch::Ne), b, true) if b.rs2 == 0 => {
+                    let imm = i32::from((b.imm as i16) << 3 >> 3);
+                    if !fits(imm, 9) {
+                        None?
+                    }
+                    let imm = imm as u16;
+                    let funct3 = match branch {
+                        Branch::Eq => 0b110,
+                        _ => 0b111,
+                    };
+                    let high = (imm >> 8 & 1) << 2 | imm >> 3 & 3;
+                    let low = (imm >> 6 & 3) << 3 | (imm >> 1 & 3) << 1 | imm >> 5 & 1;
+                    funct3 << 13 | high << 10 | creg(b.rs1)? << 7 | low << 2 | 0b01
+                }
+                B::Jalr(i, true) if i.imm == 0 && i.rs1 != 0 => match i.rd {
+                    0 => 0b1000 << 12 | reg(i.rs1) << 7 | 0b10,
+                    1 => 0b1001 << 12 | reg(i.rs1) << 7 | 0b10,
+                    _ => None?,
+                },
+                B::Ebreak => 0x9002,
+                _ => None?,
+            },
+            _ => None?,
+        })
+    }
+}
+
+impl BitManipInstruction {
+    fn encode(&self) -> u32 {
+        match self {
+            BitManipInstruction::Reg64(op, r) => {
+                let (funct3, funct7) = match op {
+                    BmReg64::Sh1add => (0b010, 0b0010000),
+                    BmReg64::Sh2add => (0b100, 0b0010000),
+                    BmReg64::Sh3add => (0b110, 0b0010000),
+                    BmReg64::Andn => (0b111, 0b0100000),
+                    BmReg64::Orn => (0b110, 0b0100000),
+                    BmReg64::Xnor => (0b100, 0b0100000),
+                    BmReg64::Max => (0b110, 0b0000101),
+                    BmReg64::Maxu => (0b111, 0b0000101),
+                    BmReg64::Min => (0b100, 0b0000101),
+                    BmReg64::Minu => (0b101, 0b0000101),
+                    BmReg64::Rol => (0b001, 0b0110000),
+                    BmReg64::Ror => (0b101, 0b0110000),
+                    BmReg64::Bclr => (0b001, 0b0100100),
+                    BmReg64::Bext => (0b101, 0b0100100),
+                    BmReg64::Binv => (0b001, 0b0110100),
+                    BmReg64::Bset => (0b001, 0b0010100),
+                };
+                r.encode(0b0110011, funct3, funct7)
+            }
+            BitManipInstruction::Reg32(op, r) => {
+                let (funct3, funct7) = match op {
+                    BmReg32::AddUw => (0b000, 0b0000100),
+                    BmReg32::Sh1addUw => (0b010, 0b0010000),
+                    BmReg32::Sh2addUw => (0b100, 0b0010000),
+                    BmReg32::Sh3addUw => (0b110, 0b0010000),
+                    BmReg32::Rolw => (0b001, 0b0110000),
+                    BmReg32::Rorw => (0b101, 0b0110000),
+                };
+                r.encode(0b0111011, funct3, funct7)
+            }
+            // These are encoded as immediate instructions with the operation in the immediate
+            BitManipInstruction::Unary64(op, i) => match op {
+                BmUnary64::Clz => i.encode_with(0b0010011, 0b001, 0x600),
+                BmUnary64::Ctz => i.encode_with(0b0010011, 0b001, 0x601),
+                BmUnary64::Cpop => i.encode_with(0b0010011, 0b001, 0x602),
+                BmUnary64::SextB => i.encode_with(0b0010011, 0b001, 0x604),
+                BmUnary64::SextH => i.encode_with(0b0010011, 0b001, 0x605),
+                BmUnary64::OrcB => i.encode_with(0b0010011, 0b101, 0x287),
+                BmUnary64::Rev8 => i.encode_with(0b0010011, 0b101, 0x6b8),
+                // zext.h is pack with rs2 = 0
+                BmUnary64::ZextH => i.encode_with(0b0111011, 0b100, 0x080),
+            },
+            BitManipInstruction::Unary32(op, i) => match op {
+                BmUnary32::Clzw => i.encode_with(0b0011011, 0b001, 0x600),
+                BmUnary32::Ctzw => i.encode_with(0b0011011, 0b001, 0x601),
+                BmUnary32::Cpopw => i.encode_with(0b0011011, 0b001, 0x602),
+            },
+            BitManipInstruction::Imm64(op, i) => {
+                let funct6 = match op {
+                    BmImm64::Bseti => 0b001010,
+                    BmImm64::Bclri | BmImm64::Bexti => 0b010010,
+                    BmImm64::Rori => 0b011000,
+                    BmImm64::Binvi => 0b011010,
+                };
+                let funct3 = match op {
+                    BmImm64::Rori | BmImm64::Bexti => 0b101,
+                    _ => 0b001,
+                };
+                i.encode_with(0b0010011, funct3, funct6 << 6 | u32::from(i.imm) & 0x3f)
+            }
+            BitManipInstruction::Imm32(op, i) => match op {
+                BmImm32::SlliUw => {
+                    i.encode_with(0b0011011, 0b001, 0b000010 << 6 | u32::from(i.imm) & 0x3f)
+                }
+                BmImm32::Roriw => {
+                    i.encode_with(0b0011011, 0b101, 0b0110000 << 5 | u32::from(i.imm) & 0x1f)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fuzz::{xorshift, TEST_SEED};
+
+    /// The major opcodes of the instructions we decode.
+    const OPCODES: [u32; 14] = [
+        0b0000011, 0b0001111, 0b0010011, 0b0010111, 0b0011011, 0b0100011, 0b0101111, 0b0110011,
+        0b0110111, 0b0111011, 0b1100011, 0b1100111, 0b1101111, 0b1110011,
+    ];
+
+    /// Whether a compressed encoding decodes to the same instruction as another, so that it
+    /// isn't the one it is re-encoded as.
+    fn is_alias(encoding: u16) -> bool {
+        let rd = encoding >> 7 & 0x1f;
+        let rs2 = encoding >> 2 & 0x1f;
+        match (encoding & 0b11, encoding >> 13) {
+            // c.li x0 is the hint c.addi x0
+            (0b01, 0b010) => rd == 0,
+            // c.addi16sp with an immediate that fits c.addi sp
+            (0b01, 0b011) if rd == 2 => {
+                let bits = encoding >> 2 & 0x10
+                    | encoding << 3 & 0x20
+                    | encoding << 1 & 0x40
+                    | encoding << 4 & 0x180
+                    | encoding >> 3 & 0x200;
+                (-32..32).contains(&((bits as i16) << 6 >> 6))
+            }
+            // c.add x0 is the hint c.mv x0
+            (0b10, 0b100) => encoding >> 12 & 1 == 1 && rd == 0 && rs2 != 0,
+            _ => false,
+        }
+    }
+
+    /// The bits of a 32 bit encoding that don't affect what it decodes to, which are encoded as
+    /// zeros.
+    fn dont_care(encoding: u32) -> u32 {
+        match (encoding & 0x7f, encoding >> 12 & 0b111) {
+            // fence.i ignores its immediate, rs1 and rd
+            (0b0001111, 0b001) => 0xfff0_0000 | 0x1f << 15 | 0x1f << 7,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let mut state = TEST_SEED;
+        for _ in 0..200_000 {
+            let bits = xorshift(&mut state) & !0x7f;
+            for opcode in OPCODES {
+                let encoding = bits | opcode;
+                let Some(instruction) = Instruction::parse(encoding) else {
+                    continue;
+                };
+                assert_eq!(
+                    Instruction::parse(instruction.encode()).as_ref(),
+                    Some(&instruction),
+                    "{encoding:#010x} re-encoded as {:#010x}",
+                    instruction.encode(),
+                );
+                // Anything else would mean the decoder dropped some bits of the encoding
+                assert_eq!(
+                    instruction.encode(),
+                    encoding & !dont_care(encoding),
+                    "{encoding:#010x} decoded to {instruction:?}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encode_compressed_round_trips() {
+        for encoding in 0..=u16::MAX {
+            if encoding & 0b11 == 0b11 {
+                continue;
+            }
+            let Some(instruction) = Instruction::parse_compressed(encoding) else {
+                continue;
+            };
+            let compressed = instruction.encode_compressed();
+            assert!(
+                compressed.is_some(),
+                "{encoding:#06x} decoded to {instruction:?}, which has no compressed encoding",
+            );
+            assert_eq!(
+                compressed.and_then(Instruction::parse_compressed).as_ref(),
+                Some(&instruction),
+                "{encoding:#06x} re-encoded as {compressed:#06x?}",
+            );
+            // Anything else would mean the decoder dropped some bits of the encoding
+            assert!(
+                compressed == Some(encoding) || is_alias(encoding),
+                "{encoding:#06x} decoded to {instruction:?}, which encodes as {compressed:#06x?}",
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_expands_to_32_bit_equivalent() {
+        for encoding in 0..=u16::MAX {
+            if encoding & 0b11 == 0b11 {
+                continue;
+            }
+            let Some(instruction) = Instruction::parse_compressed(encoding) else {
+                continue;
+            };
+            let expanded = instruction.expanded();
+            assert_eq!(
+                Instruction::parse(expanded.encode()).as_ref(),
+                Some(&expanded),
+                "{encoding:#06x} expanded to {:#010x}",
+                expanded.encode(),
+            );
+        }
+    }
+    /// Compare with the expansion of the reference model, which is written separately from the
+    /// decoder.
+    #[cfg(feature = "fuzz-reference")]
+    #[test]
+    fn compressed_expands_as_the_reference_model_does() {
+        use crate::fuzz::reference::{expand, Expansion};
+
+        let mut compared = 0;
+        for encoding in 0..=u16::MAX {
+            if encoding & 0b11 == 0b11 {
+                continue;
+            }
+            let instruction = Instruction::parse_compressed(encoding);
+            match expand(u32::from(encoding)) {
+                Expansion::Instruction(reference) => {
+                    assert_eq!(
+                        instruction.as_ref().map(Instruction::expanded),
+                        Instruction::parse(reference),
+                        "{encoding:#06x} expanded differently from {reference:#010x}",
+                    );
+                    compared += 1;
+                }
+                Expansion::Illegal => assert_eq!(instruction, None, "{encoding:#06x}"),
+                Expansion::Unknown => {}
+            }
+        }
+        // Everything but the floating point loads and stores and the reserved encodings
+        assert!(compared > 38_000, "only {compared} encodings were compared");
+    }
+}
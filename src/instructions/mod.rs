@@ -1,15 +1,23 @@
+//! Decoding and encoding instructions, and analysing what they do.
+
 #![allow(dead_code)]
 
+mod analysis;
 pub mod atomic;
 pub mod base;
+pub mod bitmanip;
+mod encode;
 pub mod machine;
 pub mod mul;
 pub mod zicsr;
 
+pub use analysis::{AccessKind, ControlTransfer, MemoryAccess};
+
 use atomic::{AAmoD, AAmoW, AMem, AOp, AtomicInstruction};
 use base::{
     BImmediate32, BImmediate64, BLoad, BRegister32, BRegister64, BStore, BaseInstruction, Branch,
 };
+use bitmanip::{BitManipInstruction, BmImm32, BmImm64, BmReg32, BmReg64, BmUnary32, BmUnary64};
 use machine::MachineInstruction;
 use mul::{MReg32, MReg64, MulInstruction};
 use zicsr::{ZOp, ZicsrInstruction};
@@ -220,7 +228,9 @@ impl JType {
                 | instruction << 1 & 0x80
                 | instruction >> 1 & 0x300
                 | instruction << 2 & 0x400
-                | instruction >> 1 & 0x800) as i16) << 4 >> 4) as i32,
+                | instruction >> 1 & 0x800) as i16)
+                << 4
+                >> 4) as i32,
         }
     }
 }
@@ -232,8 +242,11 @@ pub enum Instruction {
     Zicsr(ZicsrInstruction),
     Mul(MulInstruction),
     Atomic(AtomicInstruction),
+    BitManip(BitManipInstruction),
 }
 
+/// An ISA extension. The single letter extensions are listed first, in the order of their bits in
+/// `misa`, followed by the multi-letter extensions.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Extension {
     Atomic,
@@ -249,16 +262,32 @@ pub enum Extension {
     Supervisor,
     User,
     Vector,
+    Zicsr,
+    Zifencei,
+    Zicntr,
+    Zihpm,
+    Zba,
+    Zbb,
+    Zbs,
+    Sscofpmf,
 }
 
 impl Instruction {
+    /// The extension the instruction belongs to, or `None` for the privileged instructions, which
+    /// every core has.
+    ///
+    /// Decoded instructions don't record whether they were compressed, so one decoded from a
+    /// compressed encoding reports the extension of the 32 bit instruction it expands to. Whether
+    /// it also needs [`Extension::Compressed`] follows from the length of its encoding.
     pub fn extension(&self) -> Option<Extension> {
         match self {
+            Instruction::Base(BaseInstruction::FenceI) => Some(Extension::Zifencei),
             Instruction::Base(_) => Some(Extension::Base),
             Instruction::Machine(_) => None,
-            Instruction::Zicsr(_) => None,
+            Instruction::Zicsr(_) => Some(Extension::Zicsr),
             Instruction::Mul(_) => Some(Extension::Multiply),
             Instruction::Atomic(_) => Some(Extension::Atomic),
+            Instruction::BitManip(instr) => Some(instr.extension()),
         }
     }
 
@@ -305,17 +334,20 @@ impl Instruction {
                             instruction >> 7 & 0x38 | instruction << 1 & 0xc0,
                         ),
                     ))),
-                    // C.ADDI4SPN
-                    0b000 => Some(Instruction::Base(BaseInstruction::Imm64(
-                        BImmediate64::Add,
-                        IType::ciw(
-                            instruction,
-                            instruction >> 4 & 0x4
-                                | instruction >> 2 & 0x8
-                                | instruction >> 7 & 0x30
-                                | instruction >> 1 & 0x3c0,
-                        ),
-                    ))),
+                    // C.ADDI4SPN (nzuimm = 0 is reserved)
+                    0b000 => {
+                        let imm = instruction >> 4 & 0x4
+                            | instruction >> 2 & 0x8
+                            | instruction >> 7 & 0x30
+                            | instruction >> 1 & 0x3c0;
+                        if imm == 0 {
+                            return None;
+                        }
+                        Some(Instruction::Base(BaseInstruction::Imm64(
+                            BImmediate64::Add,
+                            IType::ciw(instruction, imm),
+                        )))
+                    }
                     _ => None,
                 }
             }
@@ -350,6 +382,8 @@ impl Instruction {
                             instr,
                         )))
                     }
+                    // C.ADDI16SP and C.LUI with a zero immediate are reserved
+                    0b011 if instruction & 0x107c == 0 => None,
                     0b011 => {
                         let rd = (instruction >> 7 & 0x1f) as usize;
                         // C.ADDI16SP
@@ -390,7 +424,8 @@ impl Instruction {
                                 >> 10) as u16,
                         ),
                     ))),
-                    // C.ADDIW
+                    // C.ADDIW (rd = 0 is reserved)
+                    0b001 if instruction >> 7 & 0x1f == 0 => None,
                     0b001 => Some(Instruction::Base(BaseInstruction::Imm32(
                         BImmediate32::Add,
                         IType::ci(
@@ -457,6 +492,8 @@ impl Instruction {
 
             0b10 => {
                 match funct3 {
+                    // C.LWSP and C.LDSP (rd = 0 is reserved)
+                    0b010 | 0b011 if instruction >> 7 & 0x1f == 0 => None,
                     // C.LWSP
                     0b010 => Some(Instruction::Base(BaseInstruction::Load(
                         BLoad::W,
@@ -548,6 +585,7 @@ impl Instruction {
         let funct7 = instruction >> 25 & 0x7f;
 
         use BaseInstruction as B;
+        use BitManipInstruction as BM;
         use Instruction as I;
         use MachineInstruction as MA;
         use MulInstruction as M;
@@ -568,17 +606,44 @@ impl Instruction {
                 0b111 => I::Base(B::Imm64(Bimm64::And, IType::new(instruction))),
                 0b001 => {
                     let upper = instruction >> 26 & 0x3f;
-                    match upper {
-                        0b000000 => I::Base(B::Imm64(Bimm64::Sll, IType::new(instruction))),
+                    let shamt = instruction & 0x3ffffff;
+                    match (upper, instruction >> 20) {
+                        (0b000000, _) => I::Base(B::Imm64(Bimm64::Sll, IType::new(instruction))),
+                        (_, 0x600) => {
+                            I::BitManip(BM::Unary64(BmUnary64::Clz, IType::new(instruction)))
+                        }
+                        (_, 0x601) => {
+                            I::BitManip(BM::Unary64(BmUnary64::Ctz, IType::new(instruction)))
+                        }
+                        (_, 0x602) => {
+                            I::BitManip(BM::Unary64(BmUnary64::Cpop, IType::new(instruction)))
+                        }
+                        (_, 0x604) => {
+                            I::BitManip(BM::Unary64(BmUnary64::SextB, IType::new(instruction)))
+                        }
+                        (_, 0x605) => {
+                            I::BitManip(BM::Unary64(BmUnary64::SextH, IType::new(instruction)))
+                        }
+                        (0b010010, _) => I::BitManip(BM::Imm64(BmImm64::Bclri, IType::new(shamt))),
+                        (0b011010, _) => I::BitManip(BM::Imm64(BmImm64::Binvi, IType::new(shamt))),
+                        (0b001010, _) => I::BitManip(BM::Imm64(BmImm64::Bseti, IType::new(shamt))),
                         _ => None?,
                     }
                 }
                 0b101 => {
                     let upper = instruction >> 26 & 0x3f;
-                    let instruction = instruction & 0x3ffffff;
-                    match upper {
-                        0b0000000 => I::Base(B::Imm64(Bimm64::Srl, IType::new(instruction))),
-                        0b010000 => I::Base(B::Imm64(Bimm64::Sra, IType::new(instruction))),
+                    let shamt = instruction & 0x3ffffff;
+                    match (upper, instruction >> 20) {
+                        (_, 0x287) => {
+                            I::BitManip(BM::Unary64(BmUnary64::OrcB, IType::new(instruction)))
+                        }
+                        (_, 0x6b8) => {
+                            I::BitManip(BM::Unary64(BmUnary64::Rev8, IType::new(instruction)))
+                        }
+                        (0b000000, _) => I::Base(B::Imm64(Bimm64::Srl, IType::new(shamt))),
+                        (0b010000, _) => I::Base(B::Imm64(Bimm64::Sra, IType::new(shamt))),
+                        (0b011000, _) => I::BitManip(BM::Imm64(BmImm64::Rori, IType::new(shamt))),
+                        (0b010010, _) => I::BitManip(BM::Imm64(BmImm64::Bexti, IType::new(shamt))),
                         _ => None?,
                     }
                 }
@@ -589,8 +654,22 @@ impl Instruction {
                 0b000 => I::Base(B::Imm32(Bimm32::Add, IType::new(instruction))),
                 0b001 => {
                     let upper = instruction >> 25 & 0x7f;
-                    match upper {
-                        0b0000000 => I::Base(B::Imm32(Bimm32::Sll, IType::new(instruction))),
+                    match (upper, instruction >> 20) {
+                        (0b0000000, _) => I::Base(B::Imm32(Bimm32::Sll, IType::new(instruction))),
+                        (_, 0x600) => {
+                            I::BitManip(BM::Unary32(BmUnary32::Clzw, IType::new(instruction)))
+                        }
+                        (_, 0x601) => {
+                            I::BitManip(BM::Unary32(BmUnary32::Ctzw, IType::new(instruction)))
+                        }
+                        (_, 0x602) => {
+                            I::BitManip(BM::Unary32(BmUnary32::Cpopw, IType::new(instruction)))
+                        }
+                        // slli.uw has a 6 bit shift amount, so only the top 6 bits are the funct
+                        (0b0000100 | 0b0000101, _) => I::BitManip(BM::Imm32(
+                            BmImm32::SlliUw,
+                            IType::new(instruction & 0x3ffffff),
+                        )),
                         _ => None?,
                     }
                 }
@@ -599,6 +678,10 @@ impl Instruction {
                     match upper {
                         0b0000000 => I::Base(B::Imm32(Bimm32::Srl, IType::new(instruction))),
                         0b0100000 => I::Base(B::Imm32(Bimm32::Sra, IType::new(instruction))),
+                        0b0110000 => I::BitManip(BM::Imm32(
+                            BmImm32::Roriw,
+                            IType::new(instruction & 0x1ffffff),
+                        )),
                         _ => None?,
                     }
                 }
@@ -628,6 +711,44 @@ impl Instruction {
                 (0b101, 0b0000001) => I::Mul(M::Reg64(MReg64::Divu, RType::new(instruction))),
                 (0b110, 0b0000001) => I::Mul(M::Reg64(MReg64::Rem, RType::new(instruction))),
                 (0b111, 0b0000001) => I::Mul(M::Reg64(MReg64::Remu, RType::new(instruction))),
+                (0b010, 0b0010000) => {
+                    I::BitManip(BM::Reg64(BmReg64::Sh1add, RType::new(instruction)))
+                }
+                (0b100, 0b0010000) => {
+                    I::BitManip(BM::Reg64(BmReg64::Sh2add, RType::new(instruction)))
+                }
+                (0b110, 0b0010000) => {
+                    I::BitManip(BM::Reg64(BmReg64::Sh3add, RType::new(instruction)))
+                }
+                (0b111, 0b0100000) => {
+                    I::BitManip(BM::Reg64(BmReg64::Andn, RType::new(instruction)))
+                }
+                (0b110, 0b0100000) => I::BitManip(BM::Reg64(BmReg64::Orn, RType::new(instruction))),
+                (0b100, 0b0100000) => {
+                    I::BitManip(BM::Reg64(BmReg64::Xnor, RType::new(instruction)))
+                }
+                (0b110, 0b0000101) => I::BitManip(BM::Reg64(BmReg64::Max, RType::new(instruction))),
+                (0b111, 0b0000101) => {
+                    I::BitManip(BM::Reg64(BmReg64::Maxu, RType::new(instruction)))
+                }
+                (0b100, 0b0000101) => I::BitManip(BM::Reg64(BmReg64::Min, RType::new(instruction))),
+                (0b101, 0b0000101) => {
+                    I::BitManip(BM::Reg64(BmReg64::Minu, RType::new(instruction)))
+                }
+                (0b001, 0b0110000) => I::BitManip(BM::Reg64(BmReg64::Rol, RType::new(instruction))),
+                (0b101, 0b0110000) => I::BitManip(BM::Reg64(BmReg64::Ror, RType::new(instruction))),
+                (0b001, 0b0100100) => {
+                    I::BitManip(BM::Reg64(BmReg64::Bclr, RType::new(instruction)))
+                }
+                (0b101, 0b0100100) => {
+                    I::BitManip(BM::Reg64(BmReg64::Bext, RType::new(instruction)))
+                }
+                (0b001, 0b0110100) => {
+                    I::BitManip(BM::Reg64(BmReg64::Binv, RType::new(instruction)))
+                }
+                (0b001, 0b0010100) => {
+                    I::BitManip(BM::Reg64(BmReg64::Bset, RType::new(instruction)))
+                }
                 _ => None?,
             },
 
@@ -642,6 +763,28 @@ impl Instruction {
                 (0b101, 0b0000001) => I::Mul(M::Reg32(MReg32::Divu, RType::new(instruction))),
                 (0b110, 0b0000001) => I::Mul(M::Reg32(MReg32::Rem, RType::new(instruction))),
                 (0b111, 0b0000001) => I::Mul(M::Reg32(MReg32::Remu, RType::new(instruction))),
+                (0b000, 0b0000100) => {
+                    I::BitManip(BM::Reg32(BmReg32::AddUw, RType::new(instruction)))
+                }
+                (0b010, 0b0010000) => {
+                    I::BitManip(BM::Reg32(BmReg32::Sh1addUw, RType::new(instruction)))
+                }
+                (0b100, 0b0010000) => {
+                    I::BitManip(BM::Reg32(BmReg32::Sh2addUw, RType::new(instruction)))
+                }
+                (0b110, 0b0010000) => {
+                    I::BitManip(BM::Reg32(BmReg32::Sh3addUw, RType::new(instruction)))
+                }
+                (0b001, 0b0110000) => {
+                    I::BitManip(BM::Reg32(BmReg32::Rolw, RType::new(instruction)))
+                }
+                (0b101, 0b0110000) => {
+                    I::BitManip(BM::Reg32(BmReg32::Rorw, RType::new(instruction)))
+                }
+                // zext.h is the rs2 = 0 encoding of the RV32 pack instruction
+                (0b100, 0b0000100) if instruction >> 20 & 0x1f == 0 => {
+                    I::BitManip(BM::Unary64(BmUnary64::ZextH, IType::new(instruction)))
+                }
                 _ => None?,
             },
 
@@ -681,7 +824,11 @@ impl Instruction {
                 _ => None?,
             },
 
-            0b0001111 => I::Base(B::Fence(instruction)),
+            0b0001111 => match funct3 {
+                0b000 => I::Base(B::Fence(instruction)),
+                0b001 => I::Base(B::FenceI),
+                _ => None?,
+            },
 
             0b1110011 => {
                 if funct3 == 0 {
@@ -1,4 +1,5 @@
 use crate::{
+    device::AccessType,
     instructions::atomic::{AAmoD, AAmoW, AMem, AOp, AtomicInstruction},
     Emulator, Trap,
 };
@@ -27,6 +28,7 @@ impl Emulator {
                         .map_err(|f| f.trap())?;
                     self.x[instr.rd] = val;
                     self.reservation.store(addr | 0b01, Ordering::Relaxed);
+                    self.watch(addr, 4, AccessType::Read);
                 }
                 AMem::LrD => {
                     if instr.rs2 != 0 {
@@ -35,14 +37,18 @@ impl Emulator {
                     let val = self.read_u64(addr).map_err(|f| f.trap())?;
                     self.x[instr.rd] = val;
                     self.reservation.store(addr | 0b10, Ordering::Relaxed);
+                    self.watch(addr, 8, AccessType::Read);
                 }
                 AMem::ScW => {
                     if self.reservation.load(Ordering::Acquire) == addr | 0b01 {
                         self.write_u32(addr, self.x[instr.rs2] as u32)
                             .map_err(|f| f.trap())?;
+                        self.watch(addr, 4, AccessType::Write);
                         self.reservation.store(0, Ordering::Release);
                         self.x[instr.rd] = 0;
                     } else {
+                        // A failed SC gives up the reservation too
+                        self.reservation.store(0, Ordering::Release);
                         self.x[instr.rd] = 1;
                     }
                 }
@@ -50,15 +56,18 @@ impl Emulator {
                     if self.reservation.load(Ordering::Acquire) == addr | 0b10 {
                         self.write_u64(addr, self.x[instr.rs2])
                             .map_err(|f| f.trap())?;
+                        self.watch(addr, 8, AccessType::Write);
                         self.reservation.store(0, Ordering::Release);
                         self.x[instr.rd] = 0;
                     } else {
+                        self.reservation.store(0, Ordering::Release);
                         self.x[instr.rd] = 1;
                     }
                 }
             },
             AOp::AmoW(op) => {
-                let inp = self.read_u32(addr).map_err(|f| f.trap())?;
+                // AMOs raise store faults even when it is the read that fails
+                let inp = self.read_u32(addr).map_err(|_| Trap::StoreAccessFault)?;
                 let inp2 = self.x[instr.rs2] as u32;
                 let out = match op {
                     AAmoW::Swap => inp2,
@@ -73,9 +82,10 @@ impl Emulator {
                 };
                 self.x[instr.rd] = inp as i32 as i64 as u64;
                 self.write_u32(addr, out).map_err(|f| f.trap())?;
+                self.watch(addr, 4, AccessType::ReadWrite);
             }
             AOp::AmoD(op) => {
-                let inp = self.read_u64(addr).map_err(|f| f.trap())?;
+                let inp = self.read_u64(addr).map_err(|_| Trap::StoreAccessFault)?;
                 let inp2 = self.x[instr.rs2];
                 let out = match op {
                     AAmoD::Swap => inp2,
@@ -90,8 +100,57 @@ impl Emulator {
                 };
                 self.x[instr.rd] = inp;
                 self.write_u64(addr, out).map_err(|f| f.trap())?;
+                self.watch(addr, 8, AccessType::ReadWrite);
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{mem::RAM_BASE, Emulator, Trap};
+
+    const LR_W_A0_S0: u32 = 0x1004252f;
+    const SC_W_A1_A2_S0: u32 = 0x18c425af;
+    const SC_W_A1_A2_S1: u32 = 0x18c4a5af;
+
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(4096);
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = RAM_BASE as u64 + 0x100;
+        emu.x[9] = RAM_BASE as u64 + 0x200;
+        emu
+    }
+
+    #[test]
+    fn failed_sc_gives_up_the_reservation() {
+        let mut emu = emulator();
+        emu.inject(LR_W_A0_S0);
+        // A store conditional to another address fails, and so does the next one to the
+        // reserved address
+        emu.inject(SC_W_A1_A2_S1);
+        assert_eq!(emu.x[11], 1);
+        emu.inject(SC_W_A1_A2_S0);
+        assert_eq!(emu.x[11], 1);
+
+        emu.inject(LR_W_A0_S0);
+        emu.inject(SC_W_A1_A2_S0);
+        assert_eq!(emu.x[11], 0);
+    }
+
+    #[test]
+    fn amo_read_faults_are_store_faults() {
+        for amoadd in [0x00b4252f, 0x00b4352f] {
+            let mut emu = emulator();
+            // Nothing is mapped at address 0
+            emu.x[8] = 0;
+            assert!(emu.inject(amoadd).trap);
+            assert_eq!(
+                emu.machine_csrs.mcause,
+                Trap::StoreAccessFault.to_code(),
+                "{amoadd:#010x}"
+            );
+        }
+    }
+}
@@ -1,12 +1,24 @@
 use crate::{
+    device::AccessType,
+    hpm::HpmEvent,
     instructions::base::{
         BImmediate32, BImmediate64, BLoad, BRegister32, BRegister64, BStore, BaseInstruction,
         Branch,
     },
+    isa::Extension,
     Emulator, Privilege, Trap,
 };
 
 impl Emulator {
+    /// The alignment in bytes that jump and branch targets must have.
+    pub(crate) fn instruction_alignment(&self) -> i64 {
+        if self.isa.has(Extension::Compressed) {
+            2
+        } else {
+            4
+        }
+    }
+
     pub fn execute_base(&mut self, instruction: BaseInstruction) -> Result<(), Trap> {
         match instruction {
             BaseInstruction::Lui(i) => self.x[i.rd] = i.imm as i64 as u64,
@@ -14,7 +26,7 @@ impl Emulator {
             BaseInstruction::Jal(i, compressed) => {
                 let offset = (i.imm << 11 >> 11) as i64;
                 let instroff = if compressed { 2 } else { 4 };
-                if offset % 2 != 0 {
+                if offset % self.instruction_alignment() != 0 {
                     // We are jumping to a misaligned address, so we throw an instruction address
                     // misaligned trap
                     return Err(Trap::InstrAddrMisaligned);
@@ -27,9 +39,11 @@ impl Emulator {
                 let offset = ((i.imm as i32) << 20 >> 20) as i64 as u64;
                 let instroff = if compressed { 2 } else { 4 };
                 let tmp = self.x[i.rs1].wrapping_add(offset) & !1;
-                // We ignore the lowest bit, and since we support compressed mode we will always
-                // jump to a legal address. Thus we do not need to throw an instruction address
-                // misaligned trap.
+                // We ignore the lowest bit, so when compressed mode is supported we will always
+                // jump to a legal address. Otherwise the target must be 4 byte aligned.
+                if tmp as i64 % self.instruction_alignment() != 0 {
+                    return Err(Trap::InstrAddrMisaligned);
+                }
                 self.x[i.rd] = self.pc.wrapping_add(instroff);
                 self.pc = tmp.wrapping_sub(instroff);
             }
@@ -45,8 +59,13 @@ impl Emulator {
                     Branch::Geu => self.x[i.rs1] >= self.x[i.rs2],
                 };
                 if taken {
+                    if offset as i64 % self.instruction_alignment() != 0 {
+                        return Err(Trap::InstrAddrMisaligned);
+                    }
                     self.pc = self.pc.wrapping_add(offset).wrapping_sub(instroff);
+                    self.count_event(HpmEvent::TakenBranch);
                 }
+                self.count_event(HpmEvent::Branch);
             }
             BaseInstruction::Load(op, i) => {
                 let offset = ((i.imm as i64) << 52 >> 52) as u64;
@@ -66,6 +85,8 @@ impl Emulator {
                         return Err(Trap::LoadAccessFault);
                     }
                 }
+                self.watch(addr, op.width(), AccessType::Read);
+                self.count_event(HpmEvent::Load);
             }
             BaseInstruction::Store(op, i) => {
                 let offset = ((i.imm as i64) << 52 >> 52) as u64;
@@ -80,6 +101,8 @@ impl Emulator {
                 if res.is_err() {
                     return Err(Trap::StoreAccessFault);
                 }
+                self.watch(addr, op.width(), AccessType::Write);
+                self.count_event(HpmEvent::Store);
             }
             BaseInstruction::Imm64(op, i) => {
                 let imm = ((i.imm as i64) << 52 >> 52) as u64;
@@ -134,6 +157,7 @@ impl Emulator {
                 } as i64 as u64;
             }
             BaseInstruction::Fence(_) => (),
+            BaseInstruction::FenceI => self.flush_decoded(),
             BaseInstruction::Ecall => {
                 self.machine_csrs.minstret = self.machine_csrs.minstret.wrapping_sub(1);
                 match self.privilege {
@@ -142,7 +166,9 @@ impl Emulator {
                 }
             }
             BaseInstruction::Ebreak => {
-                return Err(Trap::Breakpoint);
+                if !self.semihosting_call() {
+                    return Err(Trap::Breakpoint);
+                }
             }
         }
         Ok(())
@@ -11,15 +11,17 @@ impl Emulator {
                 self.pc = self.machine_csrs.mepc.wrapping_sub(4);
                 // Set MIE to MPIE
                 self.machine_csrs.mstatus =
-                    (self.machine_csrs.mstatus & !0x8) | (self.machine_csrs.mstatus & !0x80) >> 4;
-                // Set privilege to the value in MPP
+                    (self.machine_csrs.mstatus & !0x8) | (self.machine_csrs.mstatus & 0x80) >> 4;
+                // Set privilege to the value in MPP, which writes to mstatus keep legal, but a
+                // restored snapshot might not have
                 self.privilege = (self.machine_csrs.mstatus >> 11 & 0x3)
                     .try_into()
-                    .expect("An illegal MPP value was written to mstatus.");
+                    .unwrap_or_else(|_| self.min_privilege());
                 // Set MPIE to 1
                 self.machine_csrs.mstatus |= 0x80;
-                // Set MPP to user mode
-                self.machine_csrs.mstatus &= !(3 << 11);
+                // Set MPP to the least privileged mode
+                self.machine_csrs.mstatus = (self.machine_csrs.mstatus & !(3 << 11))
+                    | u64::from(self.min_privilege()) << 11;
                 // If we are not in machine mode, set MPRV to 0
                 if self.privilege != Privilege::Machine {
                     self.machine_csrs.mstatus &= !(1 << 17);
@@ -32,3 +34,28 @@ impl Emulator {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{mem::RAM_BASE, Emulator, Privilege};
+
+    #[test]
+    fn mret_to_an_illegal_mpp_goes_to_the_least_privileged_mode() {
+        for (isa, privilege) in [
+            ("rv64imac_zicsr", Privilege::Machine),
+            ("rv64imacu_zicsr", Privilege::User),
+        ] {
+            let mut emu = Emulator::with_isa(4096, isa.parse().unwrap());
+            emu.set_pc(RAM_BASE as u64);
+            emu.machine_csrs.mepc = RAM_BASE as u64 + 0x100;
+            // MPP of 2 is reserved, which a restored snapshot might still hold
+            emu.machine_csrs.mstatus = emu.machine_csrs.mstatus & !(3 << 11) | 2 << 11;
+            assert!(!emu.inject(0x30200073).trap);
+            assert_eq!(
+                (emu.pc, emu.privilege),
+                (RAM_BASE as u64 + 0x100, privilege),
+                "{isa}"
+            );
+        }
+    }
+}
@@ -1,30 +1,51 @@
 #![allow(dead_code)]
 
-use super::{instructions::Instruction, Emulator, Trap};
+use super::{hpm::HpmEvent, instructions::Instruction, Emulator, Trap};
 
 mod atomic;
 mod base;
+mod bitmanip;
 mod machine;
 mod mul;
 mod zicsr;
 
 impl Emulator {
     pub fn execute(&mut self, instruction: Instruction, opcode: u64) {
-        self.x[0] = 0;
-
-        let trap = if self.machine_csrs.can_exec(&instruction) {
+        let trap = if self.can_exec(&instruction) {
             match instruction {
                 Instruction::Base(instr) => self.execute_base(instr),
                 Instruction::Machine(instr) => self.execute_machine(instr),
                 Instruction::Zicsr(instr) => self.execute_zicsr(instr),
                 Instruction::Mul(instr) => self.execute_mul(instr),
-                Instruction::Atomic(instr) => self.execute_atomic(instr),
+                Instruction::Atomic(instr) => self
+                    .execute_atomic(instr)
+                    .inspect(|_| self.count_event(HpmEvent::Amo)),
+                Instruction::BitManip(instr) => self.execute_bitmanip(instr),
             }
         } else {
             Err(Trap::IllegalInstruction)
         };
+        // Instructions write x0 like any other register, so whatever was written is discarded
+        self.x[0] = 0;
         if let Err(trap) = trap {
             self.set_trap(trap, opcode);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{mem::RAM_BASE, Emulator};
+
+    #[test]
+    fn writes_to_x0_are_discarded() {
+        let mut emu = Emulator::new(4096);
+        emu.set_pc(RAM_BASE as u64);
+        // li zero, 5 and lui zero, 1, after which x0 must already read zero
+        for opcode in [0x00500013, 0x00001037] {
+            let trace = emu.inject(opcode);
+            assert!(!trace.trap);
+            assert_eq!(emu.x[0], 0, "{opcode:#010x}");
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Configuration of the set of extensions implemented by the emulated core.
+//!
+//! An [`Isa`] is built from an ISA string such as `rv64imac_zicsr_zifencei_zba_zbb`. Both the
+//! lowercase form used by compilers and the riscv-config form used in
+//! `riscof/test_dut/test_dut_isa.yaml` (e.g. `RV64IMCZicsr_Zifencei`) are accepted, so the string
+//! in the yaml can be passed straight to `--isa`.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+pub use crate::instructions::Extension;
+
+/// The ISA used when none is specified. This matches what the emulator has always implemented.
+pub const DEFAULT_ISA: &str = "rv64imacu_zicsr_zifencei_zicntr_zihpm";
+
+/// The single letter extensions in canonical ISA string order.
+const SINGLE_LETTER: &[Extension] = &[
+    Extension::Base,
+    Extension::Embedded,
+    Extension::Multiply,
+    Extension::Atomic,
+    Extension::Float,
+    Extension::Double,
+    Extension::Quad,
+    Extension::Compressed,
+    Extension::BitManip,
+    Extension::Vector,
+    Extension::Hypervisor,
+    Extension::Supervisor,
+    Extension::User,
+];
+
+/// The multi-letter extensions in canonical ISA string order.
+const MULTI_LETTER: &[Extension] = &[
+    Extension::Zicsr,
+    Extension::Zifencei,
+    Extension::Zicntr,
+    Extension::Zihpm,
+    Extension::Zba,
+    Extension::Zbb,
+    Extension::Zbs,
+    Extension::Sscofpmf,
+];
+
+/// Extensions which this emulator is able to execute.
+const SUPPORTED: &[Extension] = &[
+    Extension::Base,
+    Extension::Multiply,
+    Extension::Atomic,
+    Extension::Compressed,
+    Extension::BitManip,
+    Extension::User,
+    Extension::Zicsr,
+    Extension::Zifencei,
+    Extension::Zicntr,
+    Extension::Zihpm,
+    Extension::Zba,
+    Extension::Zbb,
+    Extension::Zbs,
+    Extension::Sscofpmf,
+];
+
+/// Pairs of extensions where the first requires the second to also be present.
+const REQUIREMENTS: &[(Extension, Extension)] = &[
+    (Extension::Double, Extension::Float),
+    (Extension::Quad, Extension::Double),
+    (Extension::User, Extension::Zicsr),
+    (Extension::Supervisor, Extension::User),
+    (Extension::Zicntr, Extension::Zicsr),
+    (Extension::Zihpm, Extension::Zicsr),
+    (Extension::Sscofpmf, Extension::Zihpm),
+];
+
+/// Pairs of extensions that cannot both be present.
+const CONFLICTS: &[(Extension, Extension)] = &[(Extension::Base, Extension::Embedded)];
+
+impl Extension {
+    /// The name of this extension as it appears in an ISA string.
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::Atomic => "a",
+            Extension::BitManip => "b",
+            Extension::Compressed => "c",
+            Extension::Double => "d",
+            Extension::Embedded => "e",
+            Extension::Float => "f",
+            Extension::Hypervisor => "h",
+            Extension::Base => "i",
+            Extension::Multiply => "m",
+            Extension::Quad => "q",
+            Extension::Supervisor => "s",
+            Extension::User => "u",
+            Extension::Vector => "v",
+            Extension::Zicsr => "zicsr",
+            Extension::Zifencei => "zifencei",
+            Extension::Zicntr => "zicntr",
+            Extension::Zihpm => "zihpm",
+            Extension::Zba => "zba",
+            Extension::Zbb => "zbb",
+            Extension::Zbs => "zbs",
+            Extension::Sscofpmf => "sscofpmf",
+        }
+    }
+
+    /// Look up an extension by its (lowercase) name in an ISA string.
+    pub fn from_name(name: &str) -> Option<Extension> {
+        SINGLE_LETTER
+            .iter()
+            .chain(MULTI_LETTER)
+            .copied()
+            .find(|ext| ext.name() == name)
+    }
+
+    /// The bit this extension occupies in `misa`, if it is a single letter extension.
+    pub fn misa_bit(self) -> Option<u32> {
+        let name = self.name().as_bytes();
+        (name.len() == 1).then(|| (name[0] - b'a') as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsaParseError {
+    /// The string did not start with `rv32` or `rv64` followed by a base ISA.
+    MissingBase,
+    /// Only RV64 is implemented.
+    UnsupportedXlen(u32),
+    /// An extension name that isn't known at all.
+    UnknownExtension(String),
+    /// A known extension which this emulator does not implement.
+    UnsupportedExtension(Extension),
+    DuplicateExtension(Extension),
+    Conflict(Extension, Extension),
+    /// The first extension requires the second, which was not given.
+    MissingDependency(Extension, Extension),
+}
+
+impl fmt::Display for IsaParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsaParseError::MissingBase => {
+                write!(f, "ISA string must start with rv64 followed by i, e or g")
+            }
+            IsaParseError::UnsupportedXlen(xlen) => {
+                write!(f, "rv{xlen} is not supported, only rv64 is implemented")
+            }
+            IsaParseError::UnknownExtension(name) => write!(f, "unknown extension '{name}'"),
+            IsaParseError::UnsupportedExtension(ext) => {
+                write!(f, "extension '{}' is not implemented", ext.name())
+            }
+            IsaParseError::DuplicateExtension(ext) => {
+                write!(f, "extension '{}' is given more than once", ext.name())
+            }
+            IsaParseError::Conflict(a, b) => write!(
+                f,
+                "extensions '{}' and '{}' cannot be used together",
+                a.name(),
+                b.name()
+            ),
+            IsaParseError::MissingDependency(ext, dep) => write!(
+                f,
+                "extension '{}' requires extension '{}'",
+                ext.name(),
+                dep.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IsaParseError {}
+
+/// The set of extensions implemented by the emulated core. The XLEN is always 64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Isa {
+    extensions: BTreeSet<Extension>,
+}
+
+impl Isa {
+    /// Whether the extension `ext` is implemented.
+    pub fn has(&self, ext: Extension) -> bool {
+        self.extensions.contains(&ext)
+    }
+
+    /// Iterate over every implemented extension.
+    pub fn extensions(&self) -> impl Iterator<Item = Extension> + '_ {
+        self.extensions.iter().copied()
+    }
+
+    /// The value of the `misa` csr for this ISA.
+    pub fn misa(&self) -> u64 {
+        self.extensions
+            .iter()
+            .filter_map(|ext| ext.misa_bit())
+            .fold(2 << 62, |misa, bit| misa | 1 << bit)
+    }
+
+    fn validate(&self) -> Result<(), IsaParseError> {
+        for &(a, b) in CONFLICTS {
+            if self.has(a) && self.has(b) {
+                return Err(IsaParseError::Conflict(a, b));
+            }
+        }
+        for &(ext, dep) in REQUIREMENTS {
+            if self.has(ext) && !self.has(dep) {
+                return Err(IsaParseError::MissingDependency(ext, dep));
+            }
+        }
+        // Report the first in canonical order, so that rv64g is rejected for F rather than D
+        if let Some(&ext) = SINGLE_LETTER
+            .iter()
+            .chain(MULTI_LETTER)
+            .find(|ext| self.has(**ext) && !SUPPORTED.contains(ext))
+        {
+            return Err(IsaParseError::UnsupportedExtension(ext));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        DEFAULT_ISA
+            .parse()
+            .expect("The default ISA string is valid")
+    }
+}
+
+impl FromStr for Isa {
+    type Err = IsaParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let rest = s.strip_prefix("rv").ok_or(IsaParseError::MissingBase)?;
+        let xlen_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let xlen = rest[..xlen_len]
+            .parse::<u32>()
+            .map_err(|_| IsaParseError::MissingBase)?;
+        if xlen != 64 {
+            return Err(IsaParseError::UnsupportedXlen(xlen));
+        }
+        let rest = &rest[xlen_len..];
+        if !rest.starts_with(['i', 'e', 'g']) {
+            return Err(IsaParseError::MissingBase);
+        }
+
+        let mut names = Vec::new();
+        for token in rest.split('_').filter(|token| !token.is_empty()) {
+            // A multi-letter extension starting with s must be separated by an underscore, since
+            // otherwise it can't be told apart from the single letter S extension.
+            if token.starts_with('s') && token.len() > 1 {
+                names.push(strip_version(token));
+                continue;
+            }
+            let mut token = token;
+            // Single letter extensions can be followed by a multi-letter extension with no
+            // separator in between (e.g. `imczicsr`), so split the token at the first z or x.
+            while let Some(c) = token.chars().next() {
+                if matches!(c, 'z' | 'x') {
+                    names.push(strip_version(token));
+                    break;
+                }
+                let len = c.len_utf8();
+                names.push(&token[..len]);
+                token = &token[len + version_len(&token[len..])..];
+            }
+        }
+
+        // Extensions implied by a shorthand can also be given on their own, as in the common
+        // `rv64gc_zicsr_zifencei`, but any extension given explicitly twice is a mistake
+        let mut given = BTreeSet::new();
+        let mut extensions = BTreeSet::new();
+        for name in names {
+            match name {
+                // G is shorthand for IMAFD_Zicsr_Zifencei
+                "g" => extensions.extend([
+                    Extension::Base,
+                    Extension::Multiply,
+                    Extension::Atomic,
+                    Extension::Float,
+                    Extension::Double,
+                    Extension::Zicsr,
+                    Extension::Zifencei,
+                ]),
+                // B is shorthand for Zba_Zbb_Zbs, but unlike those it also has a bit in misa.
+                "b" => extensions.extend([
+                    Extension::BitManip,
+                    Extension::Zba,
+                    Extension::Zbb,
+                    Extension::Zbs,
+                ]),
+                _ => {
+                    let ext = Extension::from_name(name)
+                        .ok_or_else(|| IsaParseError::UnknownExtension(name.to_owned()))?;
+                    if !given.insert(ext) {
+                        return Err(IsaParseError::DuplicateExtension(ext));
+                    }
+                    extensions.insert(ext);
+                }
+            }
+        }
+
+        let isa = Isa { extensions };
+        isa.validate()?;
+        Ok(isa)
+    }
+}
+
+/// The length of the version number (such as `2p0` or `2`) at the start of `s`.
+fn version_len(s: &str) -> usize {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let major = digits(s);
+    if major == 0 {
+        return 0;
+    }
+    match s[major..].strip_prefix('p').map(digits) {
+        Some(minor) if minor > 0 => major + 1 + minor,
+        _ => major,
+    }
+}
+
+/// Remove a trailing version number such as `2p0` from a multi-letter extension name.
+fn strip_version(name: &str) -> &str {
+    (1..name.len())
+        .filter(|&i| name.is_char_boundary(i))
+        .find(|&i| {
+            name[..i].ends_with(|c: char| !c.is_ascii_digit())
+                && version_len(&name[i..]) == name.len() - i
+        })
+        .map_or(name, |i| &name[..i])
+}
+
+impl fmt::Display for Isa {
+    /// Formats the ISA as a canonical lowercase ISA string.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv64")?;
+        for ext in SINGLE_LETTER.iter().filter(|ext| self.has(**ext)) {
+            write!(f, "{}", ext.name())?;
+        }
+        for ext in MULTI_LETTER.iter().filter(|ext| self.has(**ext)) {
+            write!(f, "_{}", ext.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Isa, IsaParseError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_compiler_and_riscv_config_forms() {
+        let isa = parse("rv64imac_zicsr_zifencei_zba_zbb").unwrap();
+        assert_eq!(isa.to_string(), "rv64imac_zicsr_zifencei_zba_zbb");
+        assert_eq!(
+            parse("RV64IMCZicsr_Zifencei").unwrap().to_string(),
+            "rv64imc_zicsr_zifencei"
+        );
+        assert_eq!(
+            parse("rv64i2p0m2p0_zicsr2p0").unwrap().to_string(),
+            "rv64im_zicsr"
+        );
+        assert_eq!(parse(DEFAULT_ISA).unwrap(), Isa::default());
+    }
+
+    #[test]
+    fn shorthands_overlap_with_explicit_extensions() {
+        let isa = parse("rv64ib_zba_zbs").unwrap();
+        assert!(isa.has(Extension::BitManip) && isa.has(Extension::Zba));
+        assert_eq!(isa.to_string(), "rv64ib_zba_zbb_zbs");
+        // G is rejected for the extensions it brings that aren't implemented, not for repeating
+        // those that are
+        assert_eq!(
+            parse("rv64gc_zicsr_zifencei"),
+            Err(IsaParseError::UnsupportedExtension(Extension::Float))
+        );
+    }
+
+    #[test]
+    fn reports_each_error() {
+        assert_eq!(parse("imac"), Err(IsaParseError::MissingBase));
+        assert_eq!(parse("rv64"), Err(IsaParseError::MissingBase));
+        assert_eq!(parse("rvimac"), Err(IsaParseError::MissingBase));
+        assert_eq!(parse("rv32imac"), Err(IsaParseError::UnsupportedXlen(32)));
+        assert_eq!(
+            parse("rv64i_zfoo"),
+            Err(IsaParseError::UnknownExtension("zfoo".to_owned()))
+        );
+        assert_eq!(
+            parse("rv64iv"),
+            Err(IsaParseError::UnsupportedExtension(Extension::Vector))
+        );
+        assert_eq!(
+            parse("rv64imm"),
+            Err(IsaParseError::DuplicateExtension(Extension::Multiply))
+        );
+        assert_eq!(
+            parse("rv64i_zicsr_zicsr"),
+            Err(IsaParseError::DuplicateExtension(Extension::Zicsr))
+        );
+        assert_eq!(
+            parse("rv64ie"),
+            Err(IsaParseError::Conflict(
+                Extension::Base,
+                Extension::Embedded
+            ))
+        );
+        assert_eq!(
+            parse("rv64iu"),
+            Err(IsaParseError::MissingDependency(
+                Extension::User,
+                Extension::Zicsr
+            ))
+        );
+    }
+
+    #[test]
+    fn misa_has_a_bit_per_single_letter_extension() {
+        let isa = parse("rv64imacu_zicsr").unwrap();
+        let letters = |misa: u64| {
+            (0..26)
+                .filter(|bit| misa >> bit & 1 == 1)
+                .map(|bit| (b'a' + bit as u8) as char)
+                .collect::<String>()
+        };
+        assert_eq!(isa.misa() >> 62, 2);
+        assert_eq!(letters(isa.misa()), "acimu");
+    }
+}
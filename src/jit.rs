@@ -0,0 +1,766 @@
+//! Translation of hot blocks of RISC-V code into x86-64 code.
+//!
+//! Only integer register instructions are translated, along with branches and JAL which end a
+//! block. A block also ends at the first instruction that can't be translated, which is then left
+//! to the interpreter, so the interpreter stays the reference implementation of everything.
+//! Translated instructions can't trap, so a block always runs to completion, and traps,
+//! interrupts and the counters only need to be dealt with between blocks.
+//!
+//! Generated code is called with a pointer to the integer registers in `rdi`, and returns the pc
+//! of the next instruction in `rax`. It only uses `rax`, `rcx` and `rdx` as scratch registers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+#[cfg(not(feature = "jit-lockstep"))]
+use crate::hpm::HpmEvent;
+use crate::{
+    instructions::{
+        base::{BImmediate32, BImmediate64, BRegister32, BRegister64, BaseInstruction, Branch},
+        Instruction,
+    },
+    isa::Extension,
+    Emulator,
+};
+
+/// How many times a pc has to be reached by the interpreter before a block is translated there.
+const HOT_THRESHOLD: u32 = 64;
+/// The most instructions that are translated into a single block.
+const MAX_BLOCK_LEN: usize = 64;
+/// The most bytes of guest code a block can span.
+const MAX_BLOCK_BYTES: u64 = MAX_BLOCK_LEN as u64 * 4;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut u64) -> u64;
+
+/// How a block ends, which decides the hpm events it counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The block ran into an instruction that couldn't be translated.
+    Fallthrough,
+    Jump,
+    /// A conditional branch, which wasn't taken if the block returns `not_taken`.
+    Branch {
+        not_taken: u64,
+    },
+}
+
+/// A page of host memory holding the code for a block.
+struct ExecutableCode {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl ExecutableCode {
+    fn new(code: &[u8]) -> Option<ExecutableCode> {
+        // SAFETY: We map a fresh private anonymous region which nothing else refers to, and only
+        // make it executable after writing the code to it.
+        unsafe {
+            let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let len = code.len().next_multiple_of(page);
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            let code_mem = ExecutableCode { ptr, len };
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(code_mem)
+        }
+    }
+}
+
+impl Drop for ExecutableCode {
+    fn drop(&mut self) {
+        // SAFETY: The region was mapped by us in `new`, and blocks are never run after they are
+        // dropped.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+pub struct Block {
+    code: ExecutableCode,
+    /// The bytes of guest code that the block was translated from.
+    pub span: Range<u64>,
+    /// The number of instructions in the block.
+    pub len: u64,
+    pub exit: Exit,
+}
+
+impl Block {
+    /// Run the block on the integer registers `x`, returning the pc of the next instruction. `x0`
+    /// is never read or written.
+    pub fn run(&self, x: &mut [u64; 32]) -> u64 {
+        // SAFETY: The code was generated by `translate`, and only accesses the 32 registers.
+        unsafe {
+            let entry: BlockFn = std::mem::transmute(self.code.ptr);
+            entry(x.as_mut_ptr())
+        }
+    }
+}
+
+pub enum Lookup<'a> {
+    Run(&'a Block),
+    /// The pc has become hot, so a block should be translated there.
+    Translate,
+    Interpret,
+}
+
+enum Slot {
+    Cold(u32),
+    Hot(Block),
+    /// The instruction at the pc can't be translated.
+    Never,
+}
+
+#[derive(Default)]
+pub struct Jit {
+    slots: HashMap<u64, Slot>,
+    /// Maps the start of every translated block to its end, for invalidation.
+    blocks: BTreeMap<u64, u64>,
+}
+
+impl Jit {
+    pub fn lookup(&mut self, pc: u64) -> Lookup<'_> {
+        match self.slots.entry(pc).or_insert(Slot::Cold(0)) {
+            Slot::Hot(block) => Lookup::Run(block),
+            Slot::Cold(count) => {
+                *count += 1;
+                if *count >= HOT_THRESHOLD {
+                    Lookup::Translate
+                } else {
+                    Lookup::Interpret
+                }
+            }
+            Slot::Never => Lookup::Interpret,
+        }
+    }
+
+    /// Translate the block at `pc` made of `instructions`, which are paired with their length in
+    /// bytes. An empty block marks the pc as untranslatable.
+    pub fn translate(&mut self, pc: u64, instructions: &[(Instruction, u64)]) {
+        let block = (!instructions.is_empty())
+            .then(|| compile(pc, instructions))
+            .flatten();
+        match block {
+            Some(block) => {
+                self.blocks.insert(block.span.start, block.span.end);
+                self.slots.insert(pc, Slot::Hot(block));
+            }
+            None => {
+                self.slots.insert(pc, Slot::Never);
+            }
+        }
+    }
+
+    /// Drop every block translated from any of the `len` bytes at `addr`.
+    pub fn invalidate(&mut self, addr: u64, len: u64) {
+        let end = addr.saturating_add(len);
+        let stale: Vec<u64> = self
+            .blocks
+            .range(addr.saturating_sub(MAX_BLOCK_BYTES)..end)
+            .filter(|(_, &block_end)| block_end > addr)
+            .map(|(&start, _)| start)
+            .collect();
+        for start in stale {
+            self.blocks.remove(&start);
+            self.slots.remove(&start);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.slots.clear();
+        self.blocks.clear();
+    }
+}
+
+/// Whether `instruction` can be part of a block, given the alignment in bytes that jump targets
+/// must have.
+pub fn translatable(instruction: &Instruction, alignment: i64) -> bool {
+    match instruction {
+        Instruction::Base(instr) => match instr {
+            BaseInstruction::Lui(_)
+            | BaseInstruction::Auipc(_)
+            | BaseInstruction::Imm64(_, _)
+            | BaseInstruction::Imm32(_, _)
+            | BaseInstruction::Reg64(_, _)
+            | BaseInstruction::Reg32(_, _) => true,
+            // Misaligned targets trap, which is left to the interpreter
+            BaseInstruction::Jal(i, _) => jal_offset(i.imm) % alignment == 0,
+            BaseInstruction::Branch(_, i, _) => branch_offset(i.imm) as i64 % alignment == 0,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether `instruction` has to be the last in its block.
+pub fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Base(BaseInstruction::Jal(_, _) | BaseInstruction::Branch(_, _, _))
+    )
+}
+
+fn jal_offset(imm: i32) -> i64 {
+    (imm << 11 >> 11) as i64
+}
+
+fn branch_offset(imm: u16) -> u64 {
+    ((imm as i32) << 19 >> 19) as u64
+}
+
+fn sign_extend_12(imm: u16) -> u64 {
+    ((imm as i64) << 52 >> 52) as u64
+}
+
+/// The x86-64 registers used by generated code, numbered as in their encodings.
+#[derive(Clone, Copy)]
+enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+}
+
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Load guest register `x` into `reg`, where x0 always reads as zero.
+    fn load(&mut self, reg: Reg, x: usize) {
+        if x == 0 {
+            // xor reg32, reg32
+            self.emit(&[0x31, 0xc0 | (reg as u8) << 3 | reg as u8]);
+        } else {
+            // mov reg, [rdi + 8 * x]
+            self.emit(&[0x48, 0x8b, 0x87 | (reg as u8) << 3]);
+            self.emit(&(x as u32 * 8).to_le_bytes());
+        }
+    }
+
+    /// Store rax into guest register `x`, where writes to x0 are dropped.
+    fn store(&mut self, x: usize) {
+        if x != 0 {
+            // mov [rdi + 8 * x], rax
+            self.emit(&[0x48, 0x89, 0x87]);
+            self.emit(&(x as u32 * 8).to_le_bytes());
+        }
+    }
+
+    fn mov_imm(&mut self, reg: Reg, imm: u64) {
+        // movabs reg, imm64
+        self.emit(&[0x48, 0xb8 | reg as u8]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// Apply `op` to rax and rcx, leaving the result in rax.
+    fn alu(&mut self, op: Alu, wide: bool) {
+        let rex: &[u8] = if wide { &[0x48] } else { &[] };
+        self.emit(rex);
+        match op {
+            Alu::Add => self.emit(&[0x01, 0xc8]),
+            Alu::Sub => self.emit(&[0x29, 0xc8]),
+            Alu::And => self.emit(&[0x21, 0xc8]),
+            Alu::Or => self.emit(&[0x09, 0xc8]),
+            Alu::Xor => self.emit(&[0x31, 0xc8]),
+            Alu::Sll => self.emit(&[0xd3, 0xe0]),
+            Alu::Srl => self.emit(&[0xd3, 0xe8]),
+            Alu::Sra => self.emit(&[0xd3, 0xf8]),
+            Alu::Slt | Alu::Sltu => {
+                // cmp rax, rcx; setcc al; movzx eax, al
+                self.emit(&[0x39, 0xc8, 0x0f]);
+                self.emit(&[if op == Alu::Slt { 0x9c } else { 0x92 }, 0xc0]);
+                self.emit(&[0x0f, 0xb6, 0xc0]);
+            }
+        }
+        if !wide {
+            // movsxd rax, eax
+            self.emit(&[0x48, 0x63, 0xc0]);
+        }
+    }
+
+    fn ret(&mut self) {
+        self.emit(&[0xc3]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alu {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+    Slt,
+    Sltu,
+}
+
+fn compile(start: u64, instructions: &[(Instruction, u64)]) -> Option<Block> {
+    let mut asm = Assembler::default();
+    let mut pc = start;
+    let mut exit = Exit::Fallthrough;
+    for (instruction, len) in instructions {
+        let Instruction::Base(instruction) = instruction else {
+            return None;
+        };
+        let next = pc.wrapping_add(*len);
+        match instruction {
+            BaseInstruction::Lui(i) => {
+                asm.mov_imm(Reg::Rax, i.imm as i64 as u64);
+                asm.store(i.rd);
+            }
+            BaseInstruction::Auipc(i) => {
+                asm.mov_imm(Reg::Rax, pc.wrapping_add(i.imm as i64 as u64));
+                asm.store(i.rd);
+            }
+            BaseInstruction::Imm64(op, i) => {
+                let imm = sign_extend_12(i.imm);
+                let (op, operand) = match op {
+                    BImmediate64::Add => (Alu::Add, imm),
+                    BImmediate64::Slt => (Alu::Slt, imm),
+                    BImmediate64::Sltu => (Alu::Sltu, imm),
+                    BImmediate64::Xor => (Alu::Xor, imm),
+                    BImmediate64::Or => (Alu::Or, imm),
+                    BImmediate64::And => (Alu::And, imm),
+                    BImmediate64::Sll => (Alu::Sll, imm & 0x3f),
+                    BImmediate64::Srl => (Alu::Srl, imm & 0x3f),
+                    BImmediate64::Sra => (Alu::Sra, imm & 0x3f),
+                };
+                asm.load(Reg::Rax, i.rs1);
+                asm.mov_imm(Reg::Rcx, operand);
+                asm.alu(op, true);
+                asm.store(i.rd);
+            }
+            BaseInstruction::Imm32(op, i) => {
+                let (op, operand) = match op {
+                    BImmediate32::Add => (Alu::Add, sign_extend_12(i.imm)),
+                    BImmediate32::Sll => (Alu::Sll, i.imm as u64 & 0x1f),
+                    BImmediate32::Srl => (Alu::Srl, i.imm as u64 & 0x1f),
+                    BImmediate32::Sra => (Alu::Sra, i.imm as u64 & 0x1f),
+                };
+                asm.load(Reg::Rax, i.rs1);
+                asm.mov_imm(Reg::Rcx, operand);
+                asm.alu(op, false);
+                asm.store(i.rd);
+            }
+            BaseInstruction::Reg64(op, i) => {
+                let op = match op {
+                    BRegister64::Add => Alu::Add,
+                    BRegister64::Sub => Alu::Sub,
+                    BRegister64::Slt => Alu::Slt,
+                    BRegister64::Sltu => Alu::Sltu,
+                    BRegister64::Xor => Alu::Xor,
+                    BRegister64::Or => Alu::Or,
+                    BRegister64::And => Alu::And,
+                    BRegister64::Sll => Alu::Sll,
+                    BRegister64::Srl => Alu::Srl,
+                    BRegister64::Sra => Alu::Sra,
+                };
+                asm.load(Reg::Rax, i.rs1);
+                asm.load(Reg::Rcx, i.rs2);
+                asm.alu(op, true);
+                asm.store(i.rd);
+            }
+            BaseInstruction::Reg32(op, i) => {
+                let op = match op {
+                    BRegister32::Add => Alu::Add,
+                    BRegister32::Sub => Alu::Sub,
+                    BRegister32::Sll => Alu::Sll,
+                    BRegister32::Srl => Alu::Srl,
+                    BRegister32::Sra => Alu::Sra,
+                };
+                asm.load(Reg::Rax, i.rs1);
+                asm.load(Reg::Rcx, i.rs2);
+                asm.alu(op, false);
+                asm.store(i.rd);
+            }
+            BaseInstruction::Jal(i, _) => {
+                asm.mov_imm(Reg::Rax, next);
+                asm.store(i.rd);
+                asm.mov_imm(Reg::Rax, pc.wrapping_add(jal_offset(i.imm) as u64));
+                asm.ret();
+                exit = Exit::Jump;
+            }
+            BaseInstruction::Branch(cond, i, _) => {
+                asm.load(Reg::Rax, i.rs1);
+                asm.load(Reg::Rcx, i.rs2);
+                // cmp rax, rcx
+                asm.emit(&[0x48, 0x39, 0xc8]);
+                asm.mov_imm(Reg::Rax, next);
+                asm.mov_imm(Reg::Rdx, pc.wrapping_add(branch_offset(i.imm)));
+                let cc = match cond {
+                    Branch::Eq => 0x44,
+                    Branch::Ne => 0x45,
+                    Branch::Lt => 0x4c,
+                    Branch::Ge => 0x4d,
+                    Branch::Ltu => 0x42,
+                    Branch::Geu => 0x43,
+                };
+                // cmovcc rax, rdx
+                asm.emit(&[0x48, 0x0f, cc, 0xc2]);
+                asm.ret();
+                exit = Exit::Branch { not_taken: next };
+            }
+            _ => return None,
+        }
+        pc = next;
+        if exit != Exit::Fallthrough {
+            break;
+        }
+    }
+    if exit == Exit::Fallthrough {
+        asm.mov_imm(Reg::Rax, pc);
+        asm.ret();
+    }
+    Some(Block {
+        code: ExecutableCode::new(&asm.code)?,
+        span: start..pc,
+        len: instructions.len() as u64,
+        exit,
+    })
+}
+
+impl Emulator {
+    /// Enable or disable translating hot code into host code. Disabling drops every translated
+    /// block.
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit = enabled.then(Jit::default);
+    }
+
+    /// Run the translated block at the pc, translating one first if the pc has become hot,
+    /// returning the number of instructions executed. Returns `None` if the instruction at the pc
+    /// should be interpreted instead, which is also the case if the block is longer than `budget`
+    /// or would run past a breakpoint.
+    pub(crate) fn run_block(&mut self, budget: u64) -> Option<u64> {
+        let pc = self.pc;
+        let jit = self.jit.as_mut()?;
+        let block = match jit.lookup(pc) {
+            Lookup::Run(block) => block,
+            Lookup::Translate => {
+                let instructions = self.block_at(pc);
+                if let Some(jit) = &mut self.jit {
+                    jit.translate(pc, &instructions);
+                }
+                return None;
+            }
+            Lookup::Interpret => return None,
+        };
+        let len = block.len;
+        if len > budget
+            || self
+                .breakpoints
+                .range(pc + 1..block.span.end)
+                .next()
+                .is_some()
+        {
+            return None;
+        }
+
+        #[cfg(feature = "jit-lockstep")]
+        {
+            let mut x = self.x;
+            let next = block.run(&mut x);
+            let (span, exit) = (block.span.clone(), block.exit);
+            for _ in 0..len {
+                self.step();
+            }
+            if x[1..] != self.x[1..] || next != self.pc {
+                panic!(
+                    "jit and interpreter disagree after block {span:#x?} ending in {exit:x?}\n\
+                     jit: pc {next:#x} x {x:x?}\n\
+                     interpreter: pc {:#x} x {:x?}",
+                    self.pc, self.x
+                );
+            }
+        }
+
+        #[cfg(not(feature = "jit-lockstep"))]
+        {
+            let exit = block.exit;
+            self.pc = block.run(&mut self.x);
+            // Every instruction in a block retires, so the counters are exact at its end
+            if self.machine_csrs.mcountinhibit & 1 == 0 {
+                self.machine_csrs.mcycle += len;
+            }
+            if self.machine_csrs.mcountinhibit & 4 == 0 {
+                self.machine_csrs.minstret += len;
+            }
+            if let Exit::Branch { not_taken } = exit {
+                if self.pc != not_taken {
+                    self.count_event(HpmEvent::TakenBranch);
+                }
+                self.count_event(HpmEvent::Branch);
+            }
+        }
+        Some(len)
+    }
+
+    /// Decode the longest run of translatable instructions starting at `pc`, ending at the first
+    /// that isn't in RAM or ROM, since reading anything else could have side effects.
+    fn block_at(&self, mut pc: u64) -> Vec<(Instruction, u64)> {
+        let alignment = self.instruction_alignment();
+        let mut instructions = Vec::new();
+        while instructions.len() < MAX_BLOCK_LEN {
+            let Some((instruction, opcode)) = self.decode_ahead(pc) else {
+                break;
+            };
+            let len = if opcode & 0b11 == 0b11 { 4 } else { 2 };
+            if len == 2 && !self.isa.has(Extension::Compressed)
+                || !self.can_exec(&instruction)
+                || !translatable(&instruction, alignment)
+            {
+                break;
+            }
+            let last = ends_block(&instruction);
+            instructions.push((instruction, len));
+            if last {
+                break;
+            }
+            pc = pc.wrapping_add(len);
+        }
+        instructions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::ops::Range;
+    use std::rc::Rc;
+
+    use crate::device::{BusError, Device};
+    use crate::mem::RAM_BASE;
+    use crate::{Emulator, StopReason};
+
+    /// Every ALU instruction that is translated, mixed into s2 by 300 iterations of a loop that
+    /// also takes and skips each kind of branch and calls with `jal`, then sets a0 and spins at
+    /// `done`. Assembled by llvm-mc without relaxation.
+    const ALU: &[u32] = &[
+        0x00254437, // lui s0, 596
+        0x5f54041b, // addiw s0, s0, 1525
+        0x00c41413, // slli s0, s0, 12
+        0x91540413, // addi s0, s0, -1771
+        0x00f41413, // slli s0, s0, 15
+        0xb6740413, // addi s0, s0, -1177
+        0x00d41413, // slli s0, s0, 13
+        0xd1d40413, // addi s0, s0, -739
+        0x12c00493, // li s1, 300
+        0x00000913, // li s2, 0
+        0x00d41293, // slli t0, s0, 13
+        0x00544433, // xor s0, s0, t0
+        0x00745293, // srli t0, s0, 7
+        0x00544433, // xor s0, s0, t0
+        0x01141293, // slli t0, s0, 17
+        0x00544433, // xor s0, s0, t0
+        0x01240333, // add t1, s0, s2
+        0x412403b3, // sub t2, s0, s2
+        0x00941e33, // sll t3, s0, s1
+        0x00945eb3, // srl t4, s0, s1
+        0x40945f33, // sra t5, s0, s1
+        0x01242fb3, // slt t6, s0, s2
+        0x01243533, // sltu a0, s0, s2
+        0x006475b3, // and a1, s0, t1
+        0x00746633, // or a2, s0, t2
+        0x00c5c6b3, // xor a3, a1, a2
+        0x01c4073b, // addw a4, s0, t3
+        0x41d407bb, // subw a5, s0, t4
+        0x0094183b, // sllw a6, s0, s1
+        0x009458bb, // srlw a7, s0, s1
+        0x409459bb, // sraw s3, s0, s1
+        0x80040a13, // addi s4, s0, -2048
+        0x06442a93, // slti s5, s0, 100
+        0xfff43b13, // sltiu s6, s0, -1
+        0x55544b93, // xori s7, s0, 1365
+        0xd5646c13, // ori s8, s0, -682
+        0x7f047c93, // andi s9, s0, 2032
+        0x42145d13, // srai s10, s0, 33
+        0x7ff40d9b, // addiw s11, s0, 2047
+        0x01f4129b, // slliw t0, s0, 31
+        0x0014531b, // srliw t1, s0, 1
+        0x41f4539b, // sraiw t2, s0, 31
+        0xfffffe37, // lui t3, 1048575
+        0x12345e97, // auipc t4, 74565
+        0x00590933, // add s2, s2, t0
+        0x00690933, // add s2, s2, t1
+        0x00790933, // add s2, s2, t2
+        0x01c90933, // add s2, s2, t3
+        0x01d90933, // add s2, s2, t4
+        0x01e90933, // add s2, s2, t5
+        0x01f90933, // add s2, s2, t6
+        0x00a90933, // add s2, s2, a0
+        0x00d90933, // add s2, s2, a3
+        0x00e90933, // add s2, s2, a4
+        0x00f90933, // add s2, s2, a5
+        0x01090933, // add s2, s2, a6
+        0x01190933, // add s2, s2, a7
+        0x01390933, // add s2, s2, s3
+        0x01490933, // add s2, s2, s4
+        0x01590933, // add s2, s2, s5
+        0x01690933, // add s2, s2, s6
+        0x01790933, // add s2, s2, s7
+        0x01890933, // add s2, s2, s8
+        0x01990933, // add s2, s2, s9
+        0x01a90933, // add s2, s2, s10
+        0x01b90933, // add s2, s2, s11
+        0x00147293, // andi t0, s0, 1
+        0x00028463, // beqz t0, loop+0xec
+        0x00390913, // addi s2, s2, 3
+        0x01244463, // blt s0, s2, loop+0xf4
+        0x01194913, // xori s2, s2, 17
+        0x01247463, // bgeu s0, s2, loop+0xfc
+        0xffb90913, // addi s2, s2, -5
+        0x00895463, // bge s2, s0, loop+0x104
+        0x00191913, // slli s2, s2, 1
+        0x00896463, // bltu s2, s0, loop+0x10c
+        0x00195913, // srli s2, s2, 1
+        0x01240463, // beq s0, s2, loop+0x114
+        0x00790913, // addi s2, s2, 7
+        0x014000ef, // jal mix
+        0xfff48493, // addi s1, s1, -1
+        0xee0492e3, // bnez s1, loop
+        0x00100513, // li a0, 1
+        0x0000006f, // j done
+        0x00194933, // xor s2, s2, ra
+        0x00008067, // ret
+    ];
+    /// The start of `done` in [`ALU`].
+    const ALU_DONE: u64 = 83 * 4;
+
+    /// The translated compressed instructions, mixed into a0 by 248 iterations of a loop, which
+    /// then spins at its last instruction.
+    const COMPRESSED: &[u16] = &[
+        0x4435, // li s0, 13
+        0x44fd, // li s1, 31
+        0x048e, // slli s1, s1, 3
+        0x4501, // li a0, 0
+        0x85a2, // mv a1, s0
+        0x058e, // slli a1, a1, 3
+        0x8c2d, // xor s0, s0, a1
+        0x85a2, // mv a1, s0
+        0x8195, // srli a1, a1, 5
+        0x8c2d, // xor s0, s0, a1
+        0x85a2, // mv a1, s0
+        0x059e, // slli a1, a1, 7
+        0x8c2d, // xor s0, s0, a1
+        0x9522, // add a0, a0, s0
+        0x8d0d, // sub a0, a0, a1
+        0x8dc1, // or a1, a1, s0
+        0x8de9, // and a1, a1, a0
+        0x9d2d, // addw a0, a0, a1
+        0x9d01, // subw a0, a0, s0
+        0x8505, // srai a0, a0, 1
+        0x99f5, // andi a1, a1, -3
+        0x0515, // addi a0, a0, 5
+        0x357d, // addiw a0, a0, -1
+        0x667d, // lui a2, 31
+        0x9532, // add a0, a0, a2
+        0x8622, // mv a2, s0
+        0x8a05, // andi a2, a2, 1
+        0xc211, // beqz a2, loop+0x32
+        0x0525, // addi a0, a0, 9
+        0xe211, // bnez a2, loop+0x36
+        0x8d21, // xor a0, a0, s0
+        0x14fd, // addi s1, s1, -1
+        0xf4e1, // bnez s1, loop
+        0xa001, // j done
+    ];
+
+    /// An emulator running `program` from the start of RAM, with or without the JIT.
+    fn emulator(program: &[u8], jit: bool) -> Emulator {
+        let mut emu = Emulator::new(1 << 16);
+        emu.write_bytes(RAM_BASE, program).unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.set_jit(jit);
+        emu
+    }
+
+    /// Run `program` for `limit` instructions both translated and interpreted, which with
+    /// `jit-lockstep` also checks every block against the interpreter as it runs, and check that
+    /// they end up in the same state, returning it.
+    fn run_both(program: &[u8], limit: u64) -> Emulator {
+        let mut jitted = emulator(program, true);
+        let mut interpreted = emulator(program, false);
+        assert_eq!(jitted.run(limit), StopReason::Limit);
+        assert_eq!(interpreted.run(limit), StopReason::Limit);
+        assert!(!jitted.jit.as_ref().unwrap().blocks.is_empty());
+        assert_eq!(jitted.pc, interpreted.pc);
+        assert_eq!(jitted.x, interpreted.x);
+        jitted
+    }
+
+    #[test]
+    fn alu_branches_and_jal() {
+        let program: Vec<u8> = ALU.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let emu = run_both(&program, 50_000);
+        assert_eq!(emu.pc, RAM_BASE as u64 + ALU_DONE);
+        assert_eq!(emu.x[10], 1);
+    }
+
+    #[test]
+    fn compressed() {
+        let program: Vec<u8> = COMPRESSED
+            .iter()
+            .flat_map(|half| half.to_le_bytes())
+            .collect();
+        let emu = run_both(&program, 20_000);
+        assert_eq!(emu.pc, RAM_BASE as u64 + program.len() as u64 - 2);
+    }
+
+    /// A device that counts its reads, all of which return `jalr x0, 0(s0)`.
+    struct Jump {
+        range: Range<usize>,
+        reads: Rc<Cell<u32>>,
+    }
+
+    impl Device for Jump {
+        fn address_range(&self) -> Range<usize> {
+            self.range.clone()
+        }
+
+        fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError> {
+            self.reads.set(self.reads.get() + 1);
+            Ok(0x00040067 >> (offset * 8) & (u64::MAX >> (64 - 8 * width)))
+        }
+
+        fn write(&mut self, _: usize, _: usize, _: u64) -> Result<(), BusError> {
+            Err(BusError)
+        }
+    }
+
+    #[test]
+    fn blocks_end_before_devices() {
+        // A loop of `addi a0, a0, 1` in RAM followed by a jump back from a device
+        let reads = |jit| {
+            let mut emu = emulator(&0x00150513u32.to_le_bytes(), jit);
+            let reads = Rc::new(Cell::new(0));
+            emu.add_device(Rc::new(RefCell::new(Jump {
+                range: RAM_BASE + 4..RAM_BASE + 8,
+                reads: reads.clone(),
+            })));
+            emu.x[8] = RAM_BASE as u64;
+            assert_eq!(emu.run(1000), StopReason::Limit);
+            assert_eq!(emu.x[10], 500);
+            reads.get()
+        };
+        assert_eq!(reads(true), reads(false));
+    }
+}
@@ -1,22 +1,53 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
+use std::ops::Range;
 use std::rc::Rc;
 use std::sync::atomic::AtomicUsize;
 
+mod clint;
+pub mod commit_log;
+pub mod cosim;
 mod csr;
+mod decode_cache;
 pub mod device;
+pub mod disasm;
 pub mod elf;
-mod instructions;
+pub mod fuzz;
+pub mod gdb;
+mod host_file;
+pub mod hpm;
+pub mod htif;
+pub mod instructions;
 mod interpret;
-mod mem;
-pub mod tester;
+pub mod isa;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod mem;
+pub mod monitor;
+mod replay;
+mod reverse;
+mod run;
+pub mod rvfi;
+pub mod sandbox;
+pub mod semihosting;
+pub mod snapshot;
+pub mod suite;
 mod trap;
 
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the jit feature is only supported on x86-64 Linux hosts");
+
+pub use csr::Csr;
 use csr::MachineCsrs;
-use device::{Device, DeviceRegister};
-use mem::Memory;
-use trap::Trap;
+use decode_cache::DecodeCache;
+use device::{AccessType, Device};
+use hpm::HpmEvent;
+use isa::{Extension, Isa};
+use mem::{MemoryMap, RegionKind, CLINT_BASE};
+pub use replay::ReplayError;
+pub use run::StopReason;
+use trap::{Interrupt, Trap};
 
 use instructions::Instruction;
 
@@ -47,11 +78,20 @@ impl From<Privilege> for u64 {
     }
 }
 
+/// The names the calling convention gives the general purpose registers, by register number.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
 // TODO
 // enums for CSRs ?!
 
 pub struct Emulator {
-    memory: Memory,
+    memory: MemoryMap,
+
+    isa: Isa,
 
     x: [u64; 32],
 
@@ -74,13 +114,79 @@ pub struct Emulator {
     reservation: AtomicUsize,
 
     devices: Vec<Rc<RefCell<dyn Device>>>,
-    device_map: BTreeMap<usize, (usize, DeviceRegister)>,
+    /// Maps the start of each device's address range to the end of the range and the index of
+    /// the device.
+    device_map: BTreeMap<usize, (usize, usize)>,
+    /// The smallest range of addresses covering every device.
+    device_span: Range<usize>,
+
+    decode_cache: DecodeCache,
+
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<(Range<usize>, AccessType)>,
+    trap_breakpoints: BTreeSet<u64>,
+    /// The last access to hit a watchpoint since `run` checked.
+    watch_hit: Option<(usize, AccessType)>,
+    /// The cause of the last trap to hit a trap breakpoint since `run` checked.
+    trap_hit: Option<u64>,
+    /// The exit code the guest asked for through a device since `run` checked.
+    exit_code: Option<u32>,
+    /// The nondeterministic inputs being recorded or replayed.
+    input_log: Option<RefCell<replay::InputLog>>,
+    /// What is needed to execute backwards, when enabled.
+    history: Option<RefCell<reverse::History>>,
+    /// Where retired instructions are logged, when enabled.
+    commit_log: Option<commit_log::CommitLog>,
+    /// The reference retired instructions are compared with, when co-simulating.
+    cosim: Option<Box<cosim::Cosim>>,
+    /// What services semihosting calls, when enabled.
+    semihosting: Option<Box<semihosting::Semihosting>>,
+    /// The machine timer, which is also a device when the CLINT is mapped.
+    clint: Rc<RefCell<clint::Clint>>,
+
+    /// Translated blocks, when the JIT is enabled.
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+}
+
+/// Decode an instruction, which is compressed unless the low bits of `opcode` are `0b11`. On
+/// failure this returns the illegal instruction trap along with the encoding.
+fn decode(opcode: u32) -> Result<Instruction, (Trap, u64)> {
+    let instruction = if opcode & 0b11 == 0b11 {
+        Instruction::parse(opcode)
+    } else {
+        Instruction::parse_compressed(opcode as u16)
+    };
+    instruction.ok_or((Trap::IllegalInstruction, opcode as u64))
 }
 
 impl Emulator {
+    /// Create an emulator with the default ISA and memory map, with `mem_size` bytes of RAM.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mem_size` is zero or too large for the address space, as
+    /// [`MemoryMap::with_ram`] rejects it.
     pub fn new(mem_size: usize) -> Self {
-        Emulator {
-            memory: Memory::new(mem_size),
+        Emulator::with_isa(mem_size, Isa::default())
+    }
+
+    /// Create an emulator implementing the extensions in `isa`, with the default memory map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mem_size` is zero or too large for the address space, as
+    /// [`MemoryMap::with_ram`] rejects it.
+    pub fn with_isa(mem_size: usize, isa: Isa) -> Self {
+        let memory = MemoryMap::with_ram(mem_size).expect("The RAM size is valid");
+        Emulator::with_memory_map(memory, isa)
+    }
+
+    /// Create an emulator implementing the extensions in `isa`, with the physical address space
+    /// laid out by `memory`.
+    pub fn with_memory_map(memory: MemoryMap, isa: Isa) -> Self {
+        let mut emu = Emulator {
+            memory,
 
             x: [0; 32],
 
@@ -88,6 +194,8 @@ impl Emulator {
 
             machine_csrs: MachineCsrs::default(),
 
+            isa,
+
             waiting: false,
 
             privilege: Privilege::Machine,
@@ -98,16 +206,59 @@ impl Emulator {
 
             devices: Vec::new(),
             device_map: BTreeMap::new(),
+            device_span: 0..0,
+
+            decode_cache: DecodeCache::new(),
+
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            trap_breakpoints: BTreeSet::new(),
+            watch_hit: None,
+            trap_hit: None,
+            exit_code: None,
+            input_log: None,
+            history: None,
+            commit_log: None,
+            cosim: None,
+            semihosting: None,
+            clint: Rc::new(RefCell::new(clint::Clint::default())),
+
+            #[cfg(feature = "jit")]
+            jit: None,
+        };
+        emu.reset_csrs();
+        let clint_mapped = emu
+            .memory
+            .region_at(CLINT_BASE)
+            .is_some_and(|region| region.kind == RegionKind::Mmio);
+        if clint_mapped {
+            emu.add_device(emu.clint.clone());
         }
+        emu
     }
 
     pub fn load_binary(&mut self, file_name: &str) -> std::io::Result<elf::Elf> {
         let mut file = std::fs::File::open(file_name)?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
-        // TODO handle this error better
-        let elf = elf::Elf::new(&buf).unwrap();
-        self.write_bytes(mem::RAM_BASE, &buf[0x1000..]).unwrap();
+        let elf = elf::Elf::new(&buf).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("not an executable RISC-V ELF file ({err:?})"),
+            )
+        })?;
+        for segment in elf.segments() {
+            // Anything past the end of the file contents (such as .bss) is zero filled
+            let mut bytes = segment.data.clone();
+            bytes.resize(segment.mem_size, 0);
+            self.memory.load(segment.addr, &bytes).map_err(|_| {
+                std::io::Error::other(format!(
+                    "segment at {:#x} of size {:#x} is not in RAM or ROM",
+                    segment.addr, segment.mem_size
+                ))
+            })?;
+        }
+        self.flush_decoded();
         self.pc = elf.get_entry() as u64;
         // mtvec can be anything so I shall make it what I need to make these darn riscof tests
         // work!
@@ -116,23 +267,51 @@ impl Emulator {
         Ok(elf)
     }
 
+    /// The address of the next instruction to execute.
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    /// The layout of the physical address space.
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory
+    }
+
+    /// The ISA implemented by this emulator.
+    pub fn isa(&self) -> &Isa {
+        &self.isa
+    }
+
+    /// The least privileged mode that is implemented.
+    fn min_privilege(&self) -> Privilege {
+        if self.isa.has(Extension::User) {
+            Privilege::User
+        } else {
+            Privilege::Machine
+        }
+    }
+
     pub fn debug(&self) {
         println!("{:x}", self.pc);
 
         // copy pasted
-        let instruction = if let Ok(opcode) = self.read_u16(self.pc as usize) {
+        let (instruction, compressed) = if let Ok(opcode) = self.read_u16(self.pc as usize) {
             if opcode & 0b11 == 0b11 {
                 let Ok(opcode) = self.read_u32(self.pc as usize) else {
                     return;
                 };
 
                 match Instruction::parse(opcode) {
-                    Some(instruction) => instruction,
+                    Some(instruction) => (instruction, None),
                     None => return,
                 }
             } else {
                 match Instruction::parse_compressed(opcode) {
-                    Some(instruction) => instruction,
+                    Some(instruction) => (instruction, Some(opcode)),
                     None => return,
                 }
             }
@@ -140,22 +319,52 @@ impl Emulator {
             return;
         };
         println!("{:?}", self.privilege);
-        println!("{:?}", instruction);
+        println!("{}", instruction.disassemble(self.pc, compressed, None));
         println!("{:?}", self.x);
-        println!("{:?}", self.machine_csrs);
+        for (csr, val) in self.csrs().filter(|(_, val)| *val != 0) {
+            print!("{}={:#x} ", csr.name, val);
+        }
+        println!();
     }
 
+    /// Attach a device, which then handles every access to its address range. Devices take
+    /// priority over the memory map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device's range is empty or overlaps a device that was already added, which
+    /// includes the CLINT when the memory map has it.
     pub fn add_device(&mut self, device: Rc<RefCell<dyn Device>>) {
         let idx = self.devices.len();
-        for register in device.borrow().get_registers() {
-            self.device_map.insert(register.addr, (idx, register));
-        }
+        let range = device.borrow().address_range();
+        assert!(!range.is_empty(), "Devices must claim at least one address");
+        let overlaps = self
+            .device_map
+            .range(..range.end)
+            .next_back()
+            .is_some_and(|(_, &(end, _))| end > range.start);
+        assert!(!overlaps, "Device ranges must not overlap");
+        self.device_map.insert(range.start, (range.end, idx));
+        self.device_span = if self.device_span.is_empty() {
+            range
+        } else {
+            self.device_span.start.min(range.start)..self.device_span.end.max(range.end)
+        };
 
         self.devices.push(device);
     }
 
     fn handle_traps(&mut self, pc: u64) {
         if let Some(trap) = self.trap {
+            if self.trap_breakpoints.contains(&trap) {
+                self.trap_hit = Some(trap);
+            }
+            if trap >> 63 == 1 {
+                self.count_event(HpmEvent::Interrupt);
+            } else {
+                self.count_event(HpmEvent::Trap);
+            }
+            self.log_trap(trap, pc);
             self.machine_csrs.mepc = pc;
             self.machine_csrs.mcause = trap;
             self.pc = self.machine_csrs.mtvec;
@@ -202,36 +411,177 @@ impl Emulator {
         }
     }
 
+    /// Take the highest priority interrupt that is both pending and enabled, returning whether
+    /// one was taken.
+    fn take_interrupt(&mut self) -> bool {
+        let pending = self.machine_csrs.mip & self.machine_csrs.mie;
+        if pending == 0 {
+            return false;
+        }
+        // A pending interrupt wakes the hart from WFI, even if interrupts are globally disabled
+        self.waiting = false;
+        // Interrupts to M mode are always globally enabled when running in a lower privilege mode
+        if self.privilege == Privilege::Machine && self.machine_csrs.mstatus & 0x8 == 0 {
+            return false;
+        }
+        let Some(interrupt) = Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| pending & interrupt.mask() != 0)
+        else {
+            return false;
+        };
+        self.trap = Some(1 << 63 | interrupt.to_code());
+        self.machine_csrs.mtval = 0;
+        self.handle_traps(self.pc);
+        true
+    }
+
+    /// Fetch and decode the instruction at the pc, going through the decode cache. On failure this
+    /// returns the trap along with the (possibly partial) encoding of the instruction.
+    fn fetch(&mut self) -> Result<(Instruction, u32), (Trap, u64)> {
+        if let Some(hit) = self.decode_cache.get(self.pc) {
+            return Ok(hit);
+        }
+        self.count_event(HpmEvent::DecodeCacheMiss);
+        self.decode_at(self.pc)
+    }
+
+    /// Decode the instruction at `pc`, adding it to the decode cache if it is in RAM. Anything
+    /// else is decoded afresh every time, as a device can return a different instruction on
+    /// every fetch without a store that would invalidate it.
+    fn decode_at(&mut self, pc: u64) -> Result<(Instruction, u32), (Trap, u64)> {
+        let pc = pc as usize;
+        let low = self.read_u16(pc).map_err(|_| (Trap::InstrAccessFault, 0))?;
+        let (opcode, len) = if low & 0b11 == 0b11 {
+            let opcode = self.read_u32(pc).map_err(|_| (Trap::InstrAccessFault, 0))?;
+            (opcode, 4)
+        } else {
+            (low as u32, 2)
+        };
+        let instruction = decode(opcode)?;
+        let in_ram = self
+            .memory
+            .region_at(pc)
+            .is_some_and(|region| region.kind == RegionKind::Ram);
+        if in_ram && self.device_access(pc, len).is_none() {
+            self.decode_cache
+                .insert(pc as u64, instruction.clone(), opcode);
+        }
+        Ok((instruction, opcode))
+    }
+
+    /// Decode the instruction at `pc` for looking ahead of the pc, which unlike a fetch must have
+    /// no side effects. Only RAM and ROM are read, so this returns `None` for an instruction
+    /// that is anywhere else, including on a device.
+    #[cfg(feature = "jit")]
+    fn decode_ahead(&self, pc: u64) -> Option<(Instruction, u32)> {
+        let addr = pc as usize;
+        let in_memory = |len| self.device_access(addr, len).is_none();
+        let low = u16::from_le_bytes(self.memory.read_array(addr).ok().filter(|_| in_memory(2))?);
+        let opcode = if low & 0b11 == 0b11 {
+            u32::from_le_bytes(self.memory.read_array(addr).ok().filter(|_| in_memory(4))?)
+        } else {
+            low as u32
+        };
+        decode(opcode).ok().map(|instruction| (instruction, opcode))
+    }
+
+    /// Drop everything decoded or translated from the `len` bytes at `addr`.
+    fn invalidate_decoded(&mut self, addr: usize, len: usize) {
+        self.decode_cache.invalidate(addr, len);
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.invalidate(addr as u64, len as u64);
+        }
+    }
+
+    /// Drop everything that has been decoded or translated.
+    fn flush_decoded(&mut self) {
+        self.decode_cache.flush();
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.flush();
+        }
+    }
+
     pub fn cycle(&mut self) {
-        let mut offset = 0;
-        if let Ok(opcode) = self.read_u16(self.pc as usize) {
-            if opcode & 0b11 == 0b11 {
-                let Ok(opcode) = self.read_u32(self.pc as usize) else {
-                    self.set_trap(Trap::InstrAccessFault, 0);
-                    self.machine_csrs.mtval = 0;
-                    return;
-                };
+        self.advance(u64::MAX);
+    }
 
-                match Instruction::parse(opcode) {
-                    Some(instruction) => self.execute(instruction, opcode as u64),
-                    None => self.set_trap(Trap::IllegalInstruction, opcode as u64),
+    /// Take an interrupt, or execute at most `budget` (which must be at least 1) instructions,
+    /// returning how many were executed.
+    fn advance(&mut self, budget: u64) -> u64 {
+        let mut budget = budget.min(self.replay_host_inputs());
+        if self.history.is_some() {
+            // Every instruction is a separate step through history
+            budget = 1;
+            self.begin_step();
+        }
+        let executed = self.execute_next(budget);
+        self.end_step();
+        executed
+    }
+
+    /// Take an interrupt, or execute at most `budget` instructions in the JIT or the interpreter.
+    fn execute_next(&mut self, budget: u64) -> u64 {
+        if self.take_interrupt() {
+            return 0;
+        }
+        // Translated blocks can't log what each instruction did
+        #[cfg(feature = "jit")]
+        if !self.observing_commits() {
+            if let Some(len) = self.run_block(budget) {
+                return len;
+            }
+        }
+        #[cfg(not(feature = "jit"))]
+        let _ = budget;
+        self.step();
+        1
+    }
+
+    /// Execute the instruction at the pc in the interpreter.
+    fn step(&mut self) {
+        let fetched = self.fetch();
+        self.retire(fetched);
+    }
+
+    /// Execute a fetched instruction, or take the trap raised fetching it, and move on to the
+    /// next instruction. Returns whether a trap was taken.
+    fn retire(&mut self, fetched: Result<(Instruction, u32), (Trap, u64)>) -> bool {
+        let offset = match fetched {
+            Ok((instruction, opcode)) => {
+                let compressed = opcode & 0b11 != 0b11;
+                if compressed && !self.isa.has(Extension::Compressed) {
+                    self.set_trap(Trap::IllegalInstruction, opcode as u64);
+                } else {
+                    let retiring = self.begin_commit(&instruction, opcode);
+                    self.execute(instruction, opcode as u64);
+                    if let Some(retiring) = retiring {
+                        self.log_commit(retiring);
+                    }
                 }
-                offset = 4;
-            } else {
-                match Instruction::parse_compressed(opcode) {
-                    Some(instruction) => self.execute(instruction, opcode as u64),
-                    None => self.set_trap(Trap::IllegalInstruction, opcode as u64),
+                self.increment_counters();
+                if compressed {
+                    2
+                } else {
+                    4
                 }
-
-                offset = 2;
             }
-            self.increment_counters();
-        } else {
-            self.set_trap(Trap::InstrAccessFault, 0);
+            Err((trap, opcode)) => {
+                // Illegal instructions were still fetched, so they count towards the counters
+                if trap == Trap::IllegalInstruction {
+                    self.increment_counters();
+                }
+                self.set_trap(trap, opcode);
+                0
+            }
         };
         let pc = self.pc;
         self.pc = self.pc.wrapping_add(offset);
+        let trapped = self.trap.is_some();
         self.handle_traps(pc);
+        trapped
     }
 
     /// Write a signature file at the specified path, given that the signature sits between the
@@ -239,18 +589,22 @@ impl Emulator {
     ///
     /// The signature format is specified [here](https://github.com/riscv/riscv-arch-test/blob/master/spec/TestFormatSpec.adoc#36-the-test-signature).
     pub fn write_signature(&self, path: &str, start: usize, end: usize) -> std::io::Result<()> {
-        // TODO should I perhaps not be passing a &str and instead be passing a File ?
         // TODO unwrap sorry :(((
-        use std::io::prelude::*;
-        let mut file = std::fs::File::create(path)?;
-        let bytes = self.read_bytes(start, end - start).unwrap();
+        std::fs::write(path, self.signature(start, end).unwrap())
+    }
+
+    /// The memory from `start` to `end` as a riscof signature, one 32 bit word per line in hex,
+    /// or `None` if it isn't all in RAM or ROM.
+    pub fn signature(&self, start: usize, end: usize) -> Option<String> {
+        use std::fmt::Write;
+        let bytes = self.read_bytes(start, end.checked_sub(start)?).ok()?;
+        let mut signature = String::new();
         for line in bytes.chunks(4) {
-            assert!(line.len() <= 4);
             for i in (0..4).rev() {
-                write!(&mut file, "{:02x}", line.get(i).unwrap_or(&0))?;
+                let _ = write!(signature, "{:02x}", line.get(i).unwrap_or(&0));
             }
-            writeln!(&mut file)?;
+            signature.push('\n');
         }
-        Ok(())
+        Some(signature)
     }
 }
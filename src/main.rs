@@ -1,92 +1,654 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use clap::{Parser, Subcommand};
 
-use clap::Parser;
+use riscv::{
+    cosim::CommitLogReader,
+    elf::Elf,
+    gdb::SessionEnd,
+    htif::Htif,
+    isa::{Isa, DEFAULT_ISA},
+    mem::{MemoryMap, RegionSpec},
+    monitor::{Flow, Monitor},
+    sandbox::Sandbox,
+    semihosting::Semihosting,
+    snapshot::Snapshot,
+    suite, Emulator, StopReason,
+};
 
-use riscv::{device::Device, tester::Tester, Emulator};
+/// The number of instructions between checkpoints kept for stepping backwards while debugging.
+const DEBUG_CHECKPOINT_INTERVAL: u64 = 10_000;
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Path to the executable to run
-    executable: String,
-    /// Path to a signature file to output (when running riscof tests)
+    #[arg(required = true)]
+    executable: Option<String>,
+    /// Arguments passed to the executable, which gets them through the HTIF syscall proxy
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    program_args: Vec<String>,
+    /// Path to a signature file to output (when running riscof tests). Without it, the exit code
+    /// of the executable is the exit code of the emulator.
     #[arg(long)]
-    signature: String,
-    /// Run debug mode, where each cycle is  stepped through manually
-    #[arg(short, long)]
+    signature: Option<String>,
+    /// Let the executable reach the host files in this directory through the HTIF syscall proxy,
+    /// as if it were the root of the filesystem (it can't reach any otherwise)
+    #[arg(long, value_name = "DIR")]
+    htif_root: Option<String>,
+    /// Service RISC-V semihosting calls made by the executable, rather than raising breakpoint
+    /// exceptions for them
+    #[arg(long)]
+    semihosting: bool,
+    /// Let the executable open the host files in this directory through semihosting, as if it
+    /// were the root of the filesystem (it can't open any otherwise)
+    #[arg(long, value_name = "DIR", requires = "semihosting")]
+    semihosting_root: Option<String>,
+    /// Run debug mode, where the executable is stepped through from a command line monitor (enter
+    /// `help` to list its commands)
+    #[arg(short, long, conflicts_with = "max_instructions")]
     debug: bool,
+    /// ISA string describing the extensions of the emulated core, e.g.
+    /// `rv64imac_zicsr_zifencei_zba_zbb` (the `ISA` field of a riscv-config yaml is also accepted)
+    #[arg(long, default_value = DEFAULT_ISA)]
+    isa: Isa,
+    /// Add a region to the memory map, in the form `KIND:BASE:SIZE[:NAME]` where KIND is ram, rom
+    /// or mmio, e.g. `--region rom:0x1000:64K:boot --region ram:0x20000000:256K:sram`. If no
+    /// regions are given, there is 128M of RAM at 0x80000000 and a CLINT at 0x2000000.
+    #[arg(long = "region", value_name = "REGION")]
+    regions: Vec<RegionSpec>,
+    /// Give up after executing this many instructions, so that a hung test fails
+    #[arg(long, value_name = "COUNT")]
+    max_instructions: Option<u64>,
+    /// Save a snapshot of the emulator to this path once `--snapshot-after` instructions have
+    /// been executed
+    #[arg(long, value_name = "PATH")]
+    save_snapshot: Option<String>,
+    /// The number of instructions to execute before saving a snapshot
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 0,
+        requires = "save_snapshot"
+    )]
+    snapshot_after: u64,
+    /// Restore a snapshot taken with `--save-snapshot` of the same executable before running
+    #[arg(long, value_name = "PATH")]
+    restore: Option<String>,
+    /// Log every nondeterministic input, such as device reads, to this path
+    #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+    record: Option<String>,
+    /// Replay the inputs logged by `--record`, to reproduce a run exactly
+    #[arg(long, value_name = "PATH")]
+    replay: Option<String>,
+    /// Log every retired instruction and trap in the format of Spike's `--log-commits`, to this
+    /// path (given as `--log-commits=PATH`) or to stderr
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-"
+    )]
+    log_commits: Option<String>,
+    /// Compare every retired instruction with a commit log recorded by Spike's `--log-commits`
+    /// (or by `--log-commits`), stopping at the first one that differs
+    #[arg(long, value_name = "PATH")]
+    cosim: Option<String>,
+    /// Wait for gdb to connect on this TCP port (or `HOST:PORT`), or Unix socket path, and let it
+    /// debug the executable
+    #[arg(
+        long,
+        value_name = "PORT|SOCKET",
+        conflicts_with_all = ["debug", "max_instructions"]
+    )]
+    gdb: Option<String>,
+    /// Translate hot code into host code rather than interpreting it
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    jit: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Disassemble the code of an executable, in the format of objdump
+    Disasm {
+        /// Path to the executable to disassemble
+        executable: String,
+        /// Only disassemble this section (every executable section by default)
+        #[arg(long = "section", short = 'j', value_name = "NAME")]
+        sections: Vec<String>,
+    },
+    /// Serve TestRIG's RVFI-DII protocol on a TCP port (or `HOST:PORT`), or Unix socket path,
+    /// executing the instructions it injects and answering with their RVFI traces
+    RvfiDii {
+        /// Where to wait for TestRIG to connect
+        #[arg(value_name = "PORT|SOCKET")]
+        address: String,
+        /// ISA string describing the extensions of the emulated core
+        #[arg(long, default_value = DEFAULT_ISA)]
+        isa: Isa,
+        /// Add a region to the memory map, as for running an executable
+        #[arg(long = "region", value_name = "REGION")]
+        regions: Vec<RegionSpec>,
+    },
+    /// Execute random programs from random register states, checking the invariants of the
+    /// decoder and interpreter after every instruction
+    Fuzz {
+        /// The number of programs to execute
+        #[arg(long, default_value_t = 100_000)]
+        iterations: u64,
+        /// Seed for the random programs (taken from the clock by default)
+        #[arg(long)]
+        seed: Option<u64>,
+        /// ISA string describing the extensions of the emulated core
+        #[arg(long, default_value = DEFAULT_ISA)]
+        isa: Isa,
+    },
+    /// Run a suite of test executables, such as riscv-tests or riscv-arch-test, in parallel and
+    /// report which pass, comparing signatures with golden signatures where there are any
+    Test {
+        /// Executables, directories to search for executables, or globs such as
+        /// `isa/rv64ui-p-*`
+        #[arg(required = true, value_name = "PATH|GLOB")]
+        tests: Vec<String>,
+        /// ISA string describing the extensions of the emulated core
+        #[arg(long, default_value = DEFAULT_ISA)]
+        isa: Isa,
+        /// Add a region to the memory map, as for running an executable
+        #[arg(long = "region", value_name = "REGION")]
+        regions: Vec<RegionSpec>,
+        /// The number of tests to run at once (the number of CPUs by default)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Fail a test that runs for longer than this many seconds
+        #[arg(long, value_name = "SECONDS", default_value_t = 30.0)]
+        timeout: f64,
+        /// Fail a test that executes more than this many instructions
+        #[arg(long, value_name = "COUNT")]
+        max_instructions: Option<u64>,
+        /// Also look for golden signatures named after the tests in this directory, e.g.
+        /// `add-01.signature` for `add-01.elf`
+        #[arg(long, value_name = "DIR")]
+        golden: Option<std::path::PathBuf>,
+        /// Write a JUnit XML report of the results to this path
+        #[arg(long, value_name = "PATH")]
+        junit: Option<String>,
+        /// Translate hot code into host code rather than interpreting it
+        #[cfg(feature = "jit")]
+        #[arg(long)]
+        jit: bool,
+    },
 }
 
 fn main() {
     let args = Args::parse();
 
-    let path = args.executable;
+    if let Some(Command::Disasm {
+        executable,
+        sections,
+    }) = &args.command
+    {
+        if let Err(err) = disassemble(executable, sections) {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+        return;
+    }
 
-    let mut emu = Emulator::new(128 * 1024 * 1024);
+    if let Some(Command::RvfiDii {
+        address,
+        isa,
+        regions,
+    }) = &args.command
+    {
+        let memory = memory_map(regions);
+        let reset = || Emulator::with_memory_map(memory.clone(), isa.clone());
+        let served = accept(address, "TestRIG")
+            .and_then(|(input, output)| riscv::rvfi::serve(reset, input, output));
+        if let Err(err) = served {
+            eprintln!("error: RVFI-DII connection on {address} failed: {err}");
+            std::process::exit(2);
+        }
+        return;
+    }
 
-    let elf = emu.load_binary(&path).unwrap();
+    if let Some(Command::Fuzz {
+        iterations,
+        seed,
+        isa,
+    }) = &args.command
+    {
+        if !fuzz(*iterations, *seed, isa) {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let tester_addr = elf.get_symbol("tohost").unwrap().value;
-    let signature_start = elf.get_symbol("begin_signature").unwrap().value;
-    let signature_end = elf.get_symbol("end_signature").unwrap().value;
+    if let Some(Command::Test {
+        tests,
+        isa,
+        regions,
+        jobs,
+        timeout,
+        max_instructions,
+        golden,
+        junit,
+        #[cfg(feature = "jit")]
+        jit,
+    }) = &args.command
+    {
+        let options = suite::Options {
+            isa: isa.clone(),
+            memory: memory_map(regions),
+            timeout: std::time::Duration::from_secs_f64(*timeout),
+            max_instructions: *max_instructions,
+            golden: golden.clone(),
+            #[cfg(feature = "jit")]
+            jit: *jit,
+        };
+        if !run_suite(tests, &options, *jobs, junit.as_deref()) {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let tester = Rc::new(RefCell::new(Tester::new(tester_addr)));
+    let path = args.executable.unwrap();
 
-    emu.add_device(tester.clone() as Rc<RefCell<dyn Device>>);
+    let memory = memory_map(&args.regions);
+    let mut emu = Emulator::with_memory_map(memory, args.isa);
+    #[cfg(feature = "jit")]
+    emu.set_jit(args.jit);
 
-    loop {
-        if args.debug {
-            emu.debug();
+    let elf = emu.load_binary(&path).unwrap();
 
-            use std::io::BufRead;
-            let mut b = String::new();
-            let mut h = std::io::stdin().lock();
-            h.read_line(&mut b).unwrap();
+    let symbol = |name: &str| match elf.get_symbol(name) {
+        Some(symbol) => symbol.value,
+        None => {
+            eprintln!("error: {path} has no {name} symbol");
+            std::process::exit(2);
         }
+    };
+    let signature = args.signature.as_ref().map(|signature| {
+        (
+            signature,
+            symbol("begin_signature"),
+            symbol("end_signature"),
+        )
+    });
 
-        emu.cycle();
-        if let Some(code) = tester.borrow().get_exit_code() {
-            println!("{code}");
-            emu.write_signature(&args.signature, signature_start, signature_end)
-                .unwrap();
-            break;
+    let sandbox = |root: &Option<String>, name: &str| {
+        root.as_ref().map(|root| {
+            Sandbox::new(root).unwrap_or_else(|err| {
+                eprintln!("error: can't use {root} as the {name} root: {err}");
+                std::process::exit(2);
+            })
+        })
+    };
+    if args.semihosting {
+        emu.enable_semihosting(Semihosting::new(sandbox(
+            &args.semihosting_root,
+            "semihosting",
+        )));
+    }
+    let tohost = match elf.get_symbol("tohost") {
+        Some(symbol) => Some(symbol.value),
+        // Semihosted programs can exit without it
+        None if args.semihosting => None,
+        None => Some(symbol("tohost")),
+    };
+    if let Some(tohost) = tohost {
+        let argv = std::iter::once(path.clone())
+            .chain(args.program_args.iter().cloned())
+            .collect();
+        let fromhost = elf.get_symbol("fromhost").map(|symbol| symbol.value);
+        let htif = Htif::new(argv, sandbox(&args.htif_root, "HTIF"));
+        for device in htif.into_devices(tohost, fromhost) {
+            emu.add_device(device);
         }
     }
 
-    /*
-    for entry in std::fs::read_dir(path).unwrap().flatten() {
-        let name = entry.file_name();
-        let name = name.to_str().unwrap();
+    if let Some(path) = &args.restore {
+        let restored = std::fs::File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| Snapshot::read_from(std::io::BufReader::new(file)))
+            .and_then(|snapshot| emu.restore(&snapshot));
+        if let Err(err) = restored {
+            eprintln!("error: failed to restore {path}: {err}");
+            std::process::exit(2);
+        }
+    }
 
-        if !name.starts_with("rv64mi-p-") || name.ends_with(".dump") {
-            continue;
+    if let Some(path) = &args.record {
+        let recording = std::fs::File::create(path)
+            .and_then(|file| emu.record_inputs(std::io::BufWriter::new(file)));
+        if let Err(err) = recording {
+            eprintln!("error: failed to record to {path}: {err}");
+            std::process::exit(2);
+        }
+    }
+    if let Some(path) = &args.replay {
+        let replaying = std::fs::File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| emu.replay_inputs(std::io::BufReader::new(file)));
+        if let Err(err) = replaying {
+            eprintln!("error: failed to replay {path}: {err}");
+            std::process::exit(2);
         }
+    }
 
-        let path = entry.path();
-        let path = path.to_str().unwrap();
+    match args.log_commits.as_deref() {
+        Some("-") => emu.log_commits(std::io::BufWriter::new(std::io::stderr())),
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => emu.log_commits(std::io::BufWriter::new(file)),
+            Err(err) => {
+                eprintln!("error: failed to log commits to {path}: {err}");
+                std::process::exit(2);
+            }
+        },
+        None => {}
+    }
 
-        let mut emu = Emulator::new(128 * 1024 * 1024);
+    if let Some(path) = &args.cosim {
+        match std::fs::File::open(path) {
+            Ok(file) => emu.cosimulate(CommitLogReader::new(std::io::BufReader::new(file))),
+            Err(err) => {
+                eprintln!("error: failed to open {path}: {err}");
+                std::process::exit(2);
+            }
+        }
+    }
 
-        let tester_addr = match name {
-            "rv64ui-p-ma_data" => 0x3000,
-            "rv64uc-p-rvc" => 0x4000,
-            _ => 0x2000,
-        };
+    let mut remaining = args.max_instructions.unwrap_or(u64::MAX);
+    let mut stopped = None;
+    if let Some(path) = &args.save_snapshot {
+        let after = args.snapshot_after.min(remaining);
+        match emu.run(after) {
+            StopReason::Limit => {
+                remaining -= after;
+                let saved = std::fs::File::create(path).and_then(|file| {
+                    let mut file = std::io::BufWriter::new(file);
+                    emu.snapshot().write_to(&mut file)?;
+                    std::io::Write::flush(&mut file)
+                });
+                if let Err(err) = saved {
+                    eprintln!("error: failed to save {path}: {err}");
+                    std::process::exit(2);
+                }
+            }
+            reason => stopped = Some(reason),
+        }
+    }
+
+    if let (Some(address), None) = (&args.gdb, &stopped) {
+        emu.enable_history(DEBUG_CHECKPOINT_INTERVAL);
+        match debug_with_gdb(&mut emu, address, &path) {
+            Ok(SessionEnd::Exited(code)) => stopped = Some(StopReason::Exit(code)),
+            Ok(SessionEnd::Detached) => emu.disable_history(),
+            Ok(SessionEnd::Killed) => {
+                eprintln!("error: killed by gdb");
+                std::process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("error: gdb connection on {address} failed: {err}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let reason = match stopped {
+        Some(reason) => reason,
+        None if args.debug => {
+            emu.enable_history(DEBUG_CHECKPOINT_INTERVAL);
+            match debug_with_monitor(&mut emu, elf) {
+                Some(code) => StopReason::Exit(code),
+                None => std::process::exit(1),
+            }
+        }
+        None => emu.run(remaining),
+    };
+    if let Err(err) = emu.finish_recording() {
+        eprintln!("error: failed to record inputs: {err}");
+    }
+    if let Err(err) = emu.finish_commit_log() {
+        eprintln!("error: failed to log commits: {err}");
+    }
+    match reason {
+        StopReason::Exit(code) => match signature {
+            Some((signature, start, end)) => {
+                println!("{code}");
+                emu.write_signature(signature, start, end).unwrap();
+            }
+            None => std::process::exit(code as i32),
+        },
+        reason => {
+            if let Some(divergence) = emu.divergence() {
+                eprintln!("{divergence}");
+            }
+            eprintln!("error: stopped at pc {:#x}: {reason}", emu.pc());
+            std::process::exit(1);
+        }
+    }
+}
 
-        let tester = Rc::new(RefCell::new(Tester::new(tester_addr)));
+/// Print the disassembly of `sections` of the executable at `path`, or of all its executable
+/// sections if none are given.
+fn disassemble(path: &str, sections: &[String]) -> Result<(), String> {
+    use std::io::Write;
 
-        emu.load_binary(path, 0x1000).unwrap();
+    let bin = std::fs::read(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+    let elf = Elf::new(&bin).map_err(|err| format!("failed to parse {path}: {err:?}"))?;
+    if let Some(missing) = sections
+        .iter()
+        .find(|name| !elf.sections().iter().any(|section| &section.name == *name))
+    {
+        return Err(format!("no section named {missing} in {path}"));
+    }
 
-        emu.add_device(tester.clone() as Rc<RefCell<dyn Device>>);
+    // Label each instruction that starts a symbol, as objdump does
+    let mut labels = std::collections::BTreeMap::new();
+    for (name, symbol) in elf.symbols() {
+        if !name.is_empty() && !name.starts_with('$') {
+            labels
+                .entry(symbol.value as u64)
+                .and_modify(|label: &mut &str| *label = name.min(label))
+                .or_insert(name);
+        }
+    }
 
-        loop {
-            emu.cycle();
-            if let Some(code) = tester.borrow().get_exit_code() {
-                println!("{name}: {code}");
-                break;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let written = (|| {
+        write!(out, "\n{path}:     file format elf64-littleriscv\n\n")?;
+        for section in elf.sections() {
+            let wanted = match sections.is_empty() {
+                true => section.executable,
+                false => sections.contains(&section.name),
+            };
+            if !wanted {
+                continue;
+            }
+            write!(out, "\nDisassembly of section {}:\n", section.name)?;
+            let lines = riscv::disasm::disassemble(&section.data, section.addr as u64, Some(&elf));
+            for line in lines {
+                if let Some(label) = labels.get(&line.addr) {
+                    write!(out, "\n{:016x} <{label}>:\n", line.addr)?;
+                }
+                // objdump leaves room for the bytes of an instruction to be shown
+                let encoding = match line.len {
+                    2 => format!("{:04x}{:16}", line.encoding, ""),
+                    _ => format!("{:08x}{:10}", line.encoding, ""),
+                };
+                writeln!(out, "    {:8x}:\t{encoding}\t{}", line.addr, line.text)?;
             }
         }
+        out.flush()
+    })();
+    match written {
+        // Output piped into something like `head` is allowed to stop early
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Run the tests found from `patterns`, printing each result and then a summary, and writing a
+/// JUnit report to `junit`. Returns whether every test passed.
+fn run_suite(
+    patterns: &[String],
+    options: &suite::Options,
+    jobs: Option<usize>,
+    junit: Option<&str>,
+) -> bool {
+    let tests = match suite::find_tests(patterns) {
+        Ok(tests) => tests,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+    };
+    let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+    let results = suite::run_all(&tests, options, jobs, |result| println!("{result}"));
+
+    let passed = results
+        .iter()
+        .filter(|result| result.outcome == suite::Outcome::Passed)
+        .count();
+    println!(
+        "{} tests: {passed} passed, {} failed",
+        results.len(),
+        results.len() - passed
+    );
+    if let Some(path) = junit {
+        let written = std::fs::File::create(path).and_then(|file| {
+            let mut file = std::io::BufWriter::new(file);
+            suite::write_junit(&results, &mut file)?;
+            std::io::Write::flush(&mut file)
+        });
+        if let Err(err) = written {
+            eprintln!("error: failed to write {path}: {err}");
+            std::process::exit(2);
+        }
+    }
+    passed == results.len()
+}
+
+/// Check `iterations` random inputs, returning whether they all kept the invariants. The first
+/// input that doesn't is printed in hex, to be passed to [`riscv::fuzz::check`] again.
+fn fuzz(iterations: u64, seed: Option<u64>, isa: &Isa) -> bool {
+    let seed = seed.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |time| time.as_nanos() as u64)
+    });
+    eprintln!("fuzzing with seed {seed}");
+    // The xorshift generator gets stuck at zero
+    let mut state = seed.max(1);
+    for iteration in 0..iterations {
+        let data = riscv::fuzz::random_input(&mut state);
+        let checked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            riscv::fuzz::check(&data, isa)
+        }));
+        let failure = match checked {
+            Ok(Ok(())) => continue,
+            Ok(Err(violation)) => violation.to_string(),
+            Err(_) => "panicked".to_string(),
+        };
+        let hex: String = data.iter().map(|byte| format!("{byte:02x}")).collect();
+        eprintln!("input {iteration} {failure}");
+        eprintln!("input: {hex}");
+        return false;
     }
-    */
+    eprintln!("{iterations} inputs kept the invariants");
+    true
+}
+
+/// Run the monitor until the guest exits, returning its exit code, or the user quits. Command
+/// history is kept in `~/.riscv_history`.
+fn debug_with_monitor(emu: &mut Emulator, elf: Elf) -> Option<u32> {
+    let mut editor = rustyline::DefaultEditor::new().ok()?;
+    let history =
+        std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".riscv_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    let mut monitor = Monitor::new(Some(elf));
+    let mut out = std::io::stdout();
+    let _ = monitor.show_pc(emu, &mut out);
+    let flow = loop {
+        let line = match editor.readline("(riscv) ") {
+            Ok(line) => line,
+            Err(rustyline::error::ReadlineError::Interrupted) => continue,
+            Err(_) => break Flow::Quit,
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        match monitor.execute(emu, &line, &mut out) {
+            Ok(Flow::Prompt) => {}
+            Ok(flow) => break flow,
+            Err(_) => break Flow::Quit,
+        }
+    };
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    match flow {
+        Flow::Exited(code) => Some(code),
+        Flow::Prompt | Flow::Quit => None,
+    }
+}
+
+/// The memory map laid out by `--region`, or the default one if there are none.
+fn memory_map(regions: &[RegionSpec]) -> MemoryMap {
+    if regions.is_empty() {
+        return MemoryMap::with_ram(128 * 1024 * 1024).expect("128M of RAM fits");
+    }
+    match MemoryMap::from_specs(regions) {
+        Ok(memory) => memory,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Wait for a single connection from `peer` on a TCP port (or `HOST:PORT`), or Unix socket path.
+fn accept(
+    address: &str,
+    peer: &str,
+) -> std::io::Result<(Box<dyn std::io::Read + Send>, Box<dyn std::io::Write>)> {
+    if address.parse::<u16>().is_ok() || address.contains(':') {
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("127.0.0.1:{address}")
+        };
+        let listener = std::net::TcpListener::bind(&address)?;
+        eprintln!("waiting for {peer} on {address}");
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    } else {
+        let listener = std::os::unix::net::UnixListener::bind(address)?;
+        eprintln!("waiting for {peer} on {address}");
+        let (stream, _) = listener.accept()?;
+        // Only one connection is served, so the socket isn't needed anymore
+        let _ = std::fs::remove_file(address);
+        Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+}
+
+/// Wait for gdb to connect to `address`, which is a TCP port, `HOST:PORT` or the path of a Unix
+/// socket, and serve it until it is done.
+fn debug_with_gdb(
+    emu: &mut Emulator,
+    address: &str,
+    exec_file: &str,
+) -> std::io::Result<SessionEnd> {
+    let (input, output) = accept(address, "gdb")?;
+    riscv::gdb::serve(emu, input, output, Some(exec_file))
 }
@@ -1,33 +1,374 @@
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use crate::{Emulator, Trap};
+use crate::{device::BusError, replay::DeviceReadKey, Emulator, Trap};
 
-pub struct Memory {
-    bytes: Box<[u8]>,
-    size: usize,
+/// The base address of RAM in the default memory map.
+pub const RAM_BASE: usize = 0x80000000;
+
+/// The CLINT in the default memory map. It is added as a device whenever an mmio region covers
+/// [`CLINT_BASE`].
+pub const CLINT_BASE: usize = 0x2000000;
+pub const CLINT_SIZE: usize = 0xC000;
+
+/// The granularity at which memory is allocated.
+pub const PAGE_SIZE: usize = 4096;
+
+pub(crate) type Page = [u8; PAGE_SIZE];
+
+/// What pages that have never been written contain.
+static ZERO_PAGE: Page = [0; PAGE_SIZE];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionKind {
+    Ram,
+    /// Read only memory. It can only be written when loading a program.
+    Rom,
+    /// Address space for memory mapped devices. Accesses which aren't handled by a device fault.
+    Mmio,
 }
 
-pub const RAM_BASE: usize = 0x80000000;
+impl RegionKind {
+    fn name(self) -> &'static str {
+        match self {
+            RegionKind::Ram => "ram",
+            RegionKind::Rom => "rom",
+            RegionKind::Mmio => "mmio",
+        }
+    }
+}
+
+/// A named, contiguous region of the physical address space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub kind: RegionKind,
+    pub base: usize,
+    pub size: usize,
+    /// The contents of the region, split into pages which are only allocated once they are first
+    /// written to. Pages are shared with snapshots, and copied when written while shared. This is
+    /// empty for mmio regions.
+    pages: Vec<Option<Arc<Page>>>,
+}
+
+impl Region {
+    /// The address one past the end of the region.
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    /// Get the `count` bytes at `addr`, as long as they lie entirely within this region.
+    fn range(&self, addr: usize, count: usize) -> Option<std::ops::Range<usize>> {
+        let start = addr - self.base;
+        let end = start.checked_add(count)?;
+        (end <= self.size).then_some(start..end)
+    }
+
+    /// Read the bytes at `range`, which is relative to the start of the region. This only copies
+    /// when the range crosses a page boundary.
+    fn read(&self, range: std::ops::Range<usize>) -> Cow<'_, [u8]> {
+        let offset = range.start % PAGE_SIZE;
+        if offset + range.len() <= PAGE_SIZE {
+            let page = self.pages[range.start / PAGE_SIZE]
+                .as_deref()
+                .unwrap_or(&ZERO_PAGE);
+            return Cow::from(&page[offset..offset + range.len()]);
+        }
+        let mut buf = vec![0; range.len()];
+        self.read_into(range.start, &mut buf);
+        Cow::from(buf)
+    }
+
+    /// Fill `buf` with the bytes at `start`, which is relative to the start of the region.
+    fn read_into(&self, start: usize, mut buf: &mut [u8]) {
+        let mut addr = start;
+        while !buf.is_empty() {
+            let offset = addr % PAGE_SIZE;
+            let len = (PAGE_SIZE - offset).min(buf.len());
+            let page = self.pages[addr / PAGE_SIZE]
+                .as_deref()
+                .unwrap_or(&ZERO_PAGE);
+            let (chunk, rest) = buf.split_at_mut(len);
+            chunk.copy_from_slice(&page[offset..offset + len]);
+            addr += len;
+            buf = rest;
+        }
+    }
+
+    /// Write `bytes` at `start`, which is relative to the start of the region.
+    fn write(&mut self, start: usize, mut bytes: &[u8]) {
+        let mut addr = start;
+        while !bytes.is_empty() {
+            let offset = addr % PAGE_SIZE;
+            let len = (PAGE_SIZE - offset).min(bytes.len());
+            let (chunk, rest) = bytes.split_at(len);
+            let page = &mut self.pages[addr / PAGE_SIZE];
+            // Writing zeros to a page that was never touched doesn't change anything, which keeps
+            // loading large .bss sections cheap.
+            if page.is_some() || chunk.iter().any(|&b| b != 0) {
+                let page = Arc::make_mut(page.get_or_insert_with(|| Arc::new([0; PAGE_SIZE])));
+                page[offset..offset + len].copy_from_slice(chunk);
+            }
+            addr += len;
+            bytes = rest;
+        }
+    }
+
+    /// The amount of host memory allocated to hold the contents of this region.
+    pub fn allocated_bytes(&self) -> usize {
+        self.pages.iter().flatten().count() * PAGE_SIZE
+    }
+
+    /// Iterate over the pages that have been written, along with their index in the region.
+    pub(crate) fn allocated_pages(&self) -> impl Iterator<Item = (usize, &Arc<Page>)> {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, page)| Some((idx, page.as_ref()?)))
+    }
+
+    /// Replace the contents of the region with `pages`, leaving every other page zeroed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a page index is out of range.
+    pub(crate) fn set_pages(&mut self, pages: impl IntoIterator<Item = (usize, Arc<Page>)>) {
+        self.pages.fill(None);
+        for (idx, page) in pages {
+            self.pages[idx] = Some(page);
+        }
+    }
+}
+
+/// A description of a region, as given on the command line in the form
+/// `KIND:BASE:SIZE[:NAME]`, e.g. `rom:0x1000:64K:boot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionSpec {
+    pub name: String,
+    pub kind: RegionKind,
+    pub base: usize,
+    pub size: usize,
+}
 
-impl Memory {
-    pub fn new(size: usize) -> Memory {
-        Memory {
-            bytes: vec![0; size].into_boxed_slice(),
+impl FromStr for RegionSpec {
+    type Err = MemoryMapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MemoryMapError::InvalidSpec(s.to_owned());
+        let mut parts = s.split(':');
+        let kind = match parts.next().ok_or_else(invalid)? {
+            "ram" => RegionKind::Ram,
+            "rom" => RegionKind::Rom,
+            "mmio" => RegionKind::Mmio,
+            _ => return Err(invalid()),
+        };
+        let base = parts.next().and_then(parse_size).ok_or_else(invalid)?;
+        let size = parts.next().and_then(parse_size).ok_or_else(invalid)?;
+        let name = parts.next().unwrap_or(kind.name()).to_owned();
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(RegionSpec {
+            name,
+            kind,
+            base,
             size,
+        })
+    }
+}
+
+/// Parse a number in decimal or hex (with a `0x` prefix), optionally followed by a K, M or G
+/// suffix.
+fn parse_size(s: &str) -> Option<usize> {
+    let (s, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    val.checked_mul(1 << shift)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The two named regions overlap.
+    Overlap(String, String),
+    /// The region has a size of zero or extends past the end of the address space.
+    InvalidSize(String),
+    /// A region description that couldn't be parsed.
+    InvalidSpec(String),
+}
+
+impl fmt::Display for MemoryMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryMapError::Overlap(a, b) => write!(f, "regions '{a}' and '{b}' overlap"),
+            MemoryMapError::InvalidSize(name) => write!(f, "region '{name}' has an invalid size"),
+            MemoryMapError::InvalidSpec(spec) => write!(
+                f,
+                "invalid region '{spec}', expected KIND:BASE:SIZE[:NAME] where KIND is ram, rom or mmio"
+            ),
         }
     }
+}
+
+impl std::error::Error for MemoryMapError {}
+
+/// The physical address space of the emulated machine.
+///
+/// An access must lie entirely within a single region, otherwise it faults, as do accesses to
+/// addresses that aren't mapped at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// Sorted by base address.
+    regions: Vec<Region>,
+    /// The index of the region that was last accessed.
+    last: Cell<usize>,
+}
+
+impl MemoryMap {
+    /// An empty memory map.
+    pub fn new() -> MemoryMap {
+        MemoryMap::default()
+    }
+
+    /// The default memory map, with `ram_size` bytes of RAM at [`RAM_BASE`] and the CLINT at
+    /// [`CLINT_BASE`]. This fails if `ram_size` is zero or the RAM would run past the end of the
+    /// address space.
+    pub fn with_ram(ram_size: usize) -> Result<MemoryMap, MemoryMapError> {
+        let mut map = MemoryMap::new();
+        map.add_region("clint", RegionKind::Mmio, CLINT_BASE, CLINT_SIZE)?;
+        map.add_region("ram", RegionKind::Ram, RAM_BASE, ram_size)?;
+        Ok(map)
+    }
 
-    pub fn read_bytes(&self, addr: usize, count: usize) -> Result<&[u8], AccessFault> {
-        self.bytes.get(addr..addr + count).ok_or(AccessFault::Load)
+    /// Build a memory map from a list of region descriptions.
+    pub fn from_specs(specs: &[RegionSpec]) -> Result<MemoryMap, MemoryMapError> {
+        let mut map = MemoryMap::new();
+        for spec in specs {
+            map.add_region(&spec.name, spec.kind, spec.base, spec.size)?;
+        }
+        Ok(map)
     }
 
-    pub fn write_bytes(&mut self, mut addr: usize, bytes: &[u8]) -> Result<(), AccessFault> {
-        // TODO bounds check before writing anything maybe
-        for &b in bytes {
-            *self.bytes.get_mut(addr).ok_or(AccessFault::Store)? = b;
-            addr += 1;
-            addr %= self.size;
+    pub fn add_region(
+        &mut self,
+        name: &str,
+        kind: RegionKind,
+        base: usize,
+        size: usize,
+    ) -> Result<(), MemoryMapError> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(MemoryMapError::InvalidSize(name.to_owned()));
+        }
+        let idx = self.regions.partition_point(|region| region.base < base);
+        // Only the neighbours on either side can overlap the new region
+        let prev = idx.checked_sub(1).and_then(|i| self.regions.get(i));
+        let next = self.regions.get(idx);
+        if let Some(prev) = prev.filter(|prev| prev.end() > base) {
+            return Err(MemoryMapError::Overlap(prev.name.clone(), name.to_owned()));
+        }
+        if let Some(next) = next.filter(|next| next.base < base + size) {
+            return Err(MemoryMapError::Overlap(name.to_owned(), next.name.clone()));
         }
+        let pages = match kind {
+            RegionKind::Mmio => Vec::new(),
+            RegionKind::Ram | RegionKind::Rom => vec![None; size.div_ceil(PAGE_SIZE)],
+        };
+        self.regions.insert(
+            idx,
+            Region {
+                name: name.to_owned(),
+                kind,
+                base,
+                size,
+                pages,
+            },
+        );
+        Ok(())
+    }
+
+    /// Iterate over the regions in order of address.
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    pub(crate) fn regions_mut(&mut self) -> impl Iterator<Item = &mut Region> {
+        self.regions.iter_mut()
+    }
+
+    fn region_index(&self, addr: usize) -> Option<usize> {
+        // Almost every access hits the same region as the one before it
+        let last = self.last.get();
+        if let Some(region) = self.regions.get(last) {
+            if region.base <= addr && addr < region.end() {
+                return Some(last);
+            }
+        }
+        let idx = self
+            .regions
+            .partition_point(|region| region.base <= addr)
+            .checked_sub(1)?;
+        if addr >= self.regions[idx].end() {
+            return None;
+        }
+        self.last.set(idx);
+        Some(idx)
+    }
+
+    /// The region containing `addr`, if it is mapped.
+    pub fn region_at(&self, addr: usize) -> Option<&Region> {
+        self.region_index(addr).map(|idx| &self.regions[idx])
+    }
+
+    pub fn read_bytes(&self, addr: usize, count: usize) -> Result<Cow<'_, [u8]>, AccessFault> {
+        let region = self.region_at(addr).ok_or(AccessFault::Load)?;
+        if region.kind == RegionKind::Mmio {
+            return Err(AccessFault::Load);
+        }
+        let range = region.range(addr, count).ok_or(AccessFault::Load)?;
+        Ok(region.read(range))
+    }
+
+    /// Read `N` bytes at `addr`. Unlike [`MemoryMap::read_bytes`] this never allocates.
+    pub fn read_array<const N: usize>(&self, addr: usize) -> Result<[u8; N], AccessFault> {
+        let region = self.region_at(addr).ok_or(AccessFault::Load)?;
+        if region.kind == RegionKind::Mmio {
+            return Err(AccessFault::Load);
+        }
+        let range = region.range(addr, N).ok_or(AccessFault::Load)?;
+        let mut buf = [0; N];
+        region.read_into(range.start, &mut buf);
+        Ok(buf)
+    }
+
+    pub fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), AccessFault> {
+        self.store(addr, bytes, false)
+    }
+
+    /// Write to memory as a program loader would, which unlike a normal store is allowed to
+    /// write to ROM.
+    pub fn load(&mut self, addr: usize, bytes: &[u8]) -> Result<(), AccessFault> {
+        self.store(addr, bytes, true)
+    }
+
+    fn store(&mut self, addr: usize, bytes: &[u8], rom: bool) -> Result<(), AccessFault> {
+        let idx = self.region_index(addr).ok_or(AccessFault::Store)?;
+        let region = &mut self.regions[idx];
+        match region.kind {
+            RegionKind::Ram => {}
+            RegionKind::Rom if rom => {}
+            RegionKind::Rom | RegionKind::Mmio => return Err(AccessFault::Store),
+        }
+        let range = region.range(addr, bytes.len()).ok_or(AccessFault::Store)?;
+        region.write(range.start, bytes);
         Ok(())
     }
 }
@@ -48,87 +389,139 @@ impl AccessFault {
 }
 
 impl Emulator {
+    /// Find the device that an access of `count` bytes at `addr` touches, returning it along with
+    /// the offset of the access into the device's range. This fails if the access touches a
+    /// device but doesn't lie entirely within it, or has a width a device can't be accessed with.
+    pub(crate) fn device_access(
+        &self,
+        addr: usize,
+        count: usize,
+    ) -> Option<Result<(usize, usize), ()>> {
+        let end = addr.checked_add(count)?;
+        // Most accesses are nowhere near a device, so avoid searching the map for them
+        if end <= self.device_span.start || addr >= self.device_span.end {
+            return None;
+        }
+        let (&start, &(device_end, idx)) = self.device_map.range(..end).next_back()?;
+        if device_end <= addr {
+            return None;
+        }
+        if addr < start || end > device_end || !matches!(count, 1 | 2 | 4 | 8) {
+            return Some(Err(()));
+        }
+        Some(Ok((idx, addr - start)))
+    }
+
     pub fn read_bytes(&self, addr: usize, count: usize) -> Result<Cow<'_, [u8]>, AccessFault> {
-        if let Some((idx, reg)) = self.device_map.get(&addr) {
-            if !reg.access_type.can_read() {
-                return Err(AccessFault::Load);
+        match self.device_access(addr, count) {
+            Some(Ok((idx, offset))) => {
+                let val = self
+                    .read_device(idx, offset, count)
+                    .map_err(|_| AccessFault::Load)?;
+                Ok(Cow::from(val.to_le_bytes()[..count].to_vec()))
             }
-            let bytes = self.devices[*idx]
-                .borrow_mut()
-                .read_bytes(addr, count)
-                .to_vec();
-            Ok(Cow::from(bytes))
-        } else {
-            match addr {
-                // CLINT
-                0x2000000..=0x200BFFF => match addr - 0x2000000 {
-                    0x0 => todo!("msip"),
-                    _ => todo!("Slave bus error on invalid access or misaligned read"),
+            Some(Err(())) => Err(AccessFault::Load),
+            None => self.memory.read_bytes(addr, count),
+        }
+    }
+
+    /// Read from a device, which goes through the input log when one is being recorded or
+    /// replayed, and through history when a step is being executed again.
+    fn read_device(&self, idx: usize, offset: usize, width: usize) -> Result<u64, BusError> {
+        let live = || self.devices[idx].borrow_mut().read(offset, width);
+        let logged = || match &self.input_log {
+            None => live(),
+            Some(log) => log.borrow_mut().device_read(
+                DeviceReadKey {
+                    minstret: self.machine_csrs.minstret,
+                    device: idx,
+                    offset,
+                    width,
                 },
-                RAM_BASE.. => Ok(Cow::from(self.memory.read_bytes(addr - RAM_BASE, count)?)),
-                _ => Err(AccessFault::Load),
+                live,
+            ),
+        };
+        match &self.history {
+            None => logged(),
+            Some(history) => history.borrow_mut().device_read(logged),
+        }
+    }
+
+    /// Read `N` bytes at `addr` without allocating, where `N` is at most 8.
+    fn read_array<const N: usize>(&self, addr: usize) -> Result<[u8; N], AccessFault> {
+        match self.device_access(addr, N) {
+            Some(Ok((idx, offset))) => {
+                let val = self
+                    .read_device(idx, offset, N)
+                    .map_err(|_| AccessFault::Load)?;
+                let mut buf = [0; N];
+                buf.copy_from_slice(&val.to_le_bytes()[..N]);
+                Ok(buf)
             }
+            Some(Err(())) => Err(AccessFault::Load),
+            None => self.memory.read_array(addr),
         }
     }
 
     pub fn read_u8(&self, addr: usize) -> Result<u8, AccessFault> {
-        let bytes = self.read_bytes(addr, 1)?;
-        let mut buf = [0; 1];
-        buf.copy_from_slice(&bytes);
-        Ok(u8::from_le_bytes(buf))
+        self.read_array(addr).map(u8::from_le_bytes)
     }
 
     pub fn read_u16(&self, addr: usize) -> Result<u16, AccessFault> {
-        let bytes = self.read_bytes(addr, 2)?;
-        let mut buf = [0; 2];
-        buf.copy_from_slice(&bytes);
-        Ok(u16::from_le_bytes(buf))
+        self.read_array(addr).map(u16::from_le_bytes)
     }
 
     pub fn read_u32(&self, addr: usize) -> Result<u32, AccessFault> {
-        let bytes = self.read_bytes(addr, 4)?;
-        let mut buf = [0; 4];
-        buf.copy_from_slice(&bytes);
-        Ok(u32::from_le_bytes(buf))
+        self.read_array(addr).map(u32::from_le_bytes)
     }
 
     pub fn read_u64(&self, addr: usize) -> Result<u64, AccessFault> {
-        match addr {
-            // CLINT
-            0x2000000..=0x200BFFF => match addr - 0x2000000 {
-                0x4000 => Ok(self.machine_csrs.mtimecmp),
-                0x8000 => Ok(self.machine_csrs.mtime),
-                _ => todo!("Slave bus error on invalid access or misaligned read"),
-            },
-            _ => {
-                let bytes = self.read_bytes(addr, 8)?;
-                let mut buf = [0; 8];
-                buf.copy_from_slice(&bytes);
-                Ok(u64::from_le_bytes(buf))
-            }
-        }
+        self.read_array(addr).map(u64::from_le_bytes)
     }
 
     pub fn write_bytes(&mut self, addr: usize, bytes: &[u8]) -> Result<(), AccessFault> {
-        if let Some((idx, reg)) = self.device_map.get(&addr) {
-            if !reg.access_type.can_write() {
-                return Err(AccessFault::Store);
-            }
-            self.devices[*idx].borrow_mut().write_bytes(addr, bytes);
-        } else {
-            match addr {
-                // CLINT
-                0x2000000..=0x200BFFF => {
-                    let addr = addr - 0x2000000;
-                    match addr {
-                        0x0 => todo!("msip"),
-                        _ => todo!("Slave bus error on invalid access or misaligned write"),
-                    }
+        match self.device_access(addr, bytes.len()) {
+            Some(Ok((idx, offset))) => {
+                let mut buf = [0; 8];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                let mut device = self.devices[idx].borrow_mut();
+                device
+                    .write(offset, bytes.len(), u64::from_le_bytes(buf))
+                    .map_err(|_| AccessFault::Store)?;
+                let written = device.access_memory(&mut self.memory);
+                if let Some(code) = device.exit_code() {
+                    self.exit_code = Some(code);
                 }
-                RAM_BASE.. => self.memory.write_bytes(addr - RAM_BASE, bytes)?,
-                _ => Err(AccessFault::Store)?,
+                drop(device);
+                for range in written {
+                    self.invalidate_decoded(range.start, range.len());
+                }
+                Ok(())
             }
+            Some(Err(())) => Err(AccessFault::Store),
+            None => {
+                self.memory.write_bytes(addr, bytes)?;
+                self.invalidate_decoded(addr, bytes.len());
+                Ok(())
+            }
+        }
+    }
+
+    /// Read up to `count` bytes at `addr` for a debugger, stopping early at the first byte that
+    /// isn't in RAM or ROM. Devices aren't read, since that can have side effects.
+    pub(crate) fn debug_read(&self, addr: usize, count: usize) -> Vec<u8> {
+        match self.memory.read_bytes(addr, count) {
+            Ok(bytes) => bytes.into_owned(),
+            Err(_) => (addr..addr.saturating_add(count))
+                .map_while(|addr| self.memory.read_array(addr).ok().map(|[byte]| byte))
+                .collect(),
         }
+    }
+
+    /// Write `bytes` at `addr` for a debugger, which like a loader may write to ROM.
+    pub(crate) fn debug_write(&mut self, addr: usize, bytes: &[u8]) -> Result<(), AccessFault> {
+        self.memory.load(addr, bytes)?;
+        self.invalidate_decoded(addr, bytes.len());
         Ok(())
     }
 
@@ -145,18 +538,161 @@ impl Emulator {
     }
 
     pub fn write_u64(&mut self, addr: usize, val: u64) -> Result<(), AccessFault> {
-        match addr {
-            // CLINT
-            0x2000000..=0x200BFFF => {
-                let addr = addr - 0x2000000;
-                match addr {
-                    0x4000 => self.machine_csrs.mtimecmp = val,
-                    0x8000 => self.machine_csrs.mtime = val,
-                    _ => todo!("Slave bus error on invalid access or misaligned write"),
-                }
-                Ok(())
-            }
-            _ => self.write_bytes(addr, &val.to_le_bytes()),
+        self.write_bytes(addr, &val.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::ops::Range;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::isa::Isa;
+
+    /// Where [`Probe`] is mapped, in the mmio region of [`map`].
+    const PROBE: usize = 0x10000000;
+
+    /// A ROM, some RAM and a page of mmio.
+    fn map() -> MemoryMap {
+        let specs = [
+            "rom:0x1000:4K:boot",
+            "mmio:0x10000000:4K",
+            "ram:0x80000000:12K",
+        ];
+        let specs: Vec<RegionSpec> = specs.iter().map(|spec| spec.parse().unwrap()).collect();
+        MemoryMap::from_specs(&specs).unwrap()
+    }
+
+    #[test]
+    fn default_map_rejects_invalid_ram_sizes() {
+        let invalid = Err(MemoryMapError::InvalidSize("ram".to_owned()));
+        assert_eq!(MemoryMap::with_ram(0), invalid);
+        assert_eq!(MemoryMap::with_ram(usize::MAX - RAM_BASE + 1), invalid);
+
+        let map = MemoryMap::with_ram(4096).unwrap();
+        let ram = map.region_at(RAM_BASE + 4095).unwrap();
+        assert_eq!((ram.name.as_str(), ram.kind), ("ram", RegionKind::Ram));
+        assert!(map.region_at(RAM_BASE + 4096).is_none());
+        assert!(map.region_at(CLINT_BASE).is_some());
+    }
+
+    #[test]
+    fn regions_are_found_by_address() {
+        let map = map();
+        let name = |addr| map.region_at(addr).map(|region| region.name.as_str());
+        for (addr, region) in [
+            (0xfff, None),
+            (0x1000, Some("boot")),
+            (0x1fff, Some("boot")),
+            (0x2000, None),
+            (0x10000fff, Some("mmio")),
+            (RAM_BASE, Some("ram")),
+            // After the last region, and back to the first, which isn't the one last looked up
+            (RAM_BASE + 0x3000, None),
+            (0x1800, Some("boot")),
+        ] {
+            assert_eq!(name(addr), region, "{addr:#x}");
         }
+
+        let mut map = map;
+        assert_eq!(
+            map.add_region("more", RegionKind::Ram, 0x1800, 0x1000),
+            Err(MemoryMapError::Overlap(
+                "boot".to_owned(),
+                "more".to_owned()
+            ))
+        );
+        assert_eq!(
+            map.add_region("more", RegionKind::Ram, 0x800, 0x1000),
+            Err(MemoryMapError::Overlap(
+                "more".to_owned(),
+                "boot".to_owned()
+            ))
+        );
+        assert_eq!(
+            map.add_region("more", RegionKind::Ram, 0x2000, 0x1000),
+            Ok(())
+        );
+        assert_eq!(
+            "flash:0x1000:4K".parse::<RegionSpec>(),
+            Err(MemoryMapError::InvalidSpec("flash:0x1000:4K".to_owned()))
+        );
+    }
+
+    #[test]
+    fn pages_are_allocated_when_first_written() {
+        let mut map = map();
+        let ram = |map: &MemoryMap| map.region_at(RAM_BASE).unwrap().allocated_bytes();
+        map.write_bytes(RAM_BASE + 0x10, &[0; 64]).unwrap();
+        assert_eq!(ram(&map), 0);
+        assert_eq!(map.read_array(RAM_BASE + 0x10).unwrap(), [0; 8]);
+
+        // A write across a page boundary allocates both pages, and only those
+        map.write_bytes(RAM_BASE + PAGE_SIZE - 2, &[1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(ram(&map), 2 * PAGE_SIZE);
+        assert_eq!(
+            map.read_bytes(RAM_BASE + PAGE_SIZE - 3, 6).unwrap()[..],
+            [0, 1, 2, 3, 4, 0]
+        );
+        assert_eq!(map.read_array(RAM_BASE + 2 * PAGE_SIZE).unwrap(), [0; 4]);
+
+        // Accesses must lie within a single region
+        assert!(map.read_array::<4>(RAM_BASE + 3 * PAGE_SIZE - 2).is_err());
+        assert!(map.write_bytes(0x1ffe, &[1; 4]).is_err());
+        // ROM is only written by loading, and mmio has no memory at all
+        assert!(map.write_bytes(0x1000, &[1]).is_err());
+        map.load(0x1000, &[1]).unwrap();
+        assert_eq!(map.read_array(0x1000).unwrap(), [1]);
+        assert!(map.read_array::<1>(PROBE).is_err());
+        assert!(map.load(PROBE, &[1]).is_err());
+    }
+
+    /// A device that answers reads with their width and offset, and remembers the last write.
+    struct Probe {
+        written: Option<(usize, usize, u64)>,
+    }
+
+    impl Device for Probe {
+        fn address_range(&self) -> Range<usize> {
+            PROBE..PROBE + 16
+        }
+
+        fn read(&mut self, offset: usize, width: usize) -> Result<u64, BusError> {
+            Ok((width << 8 | offset) as u64)
+        }
+
+        fn write(&mut self, offset: usize, width: usize, val: u64) -> Result<(), BusError> {
+            self.written = Some((offset, width, val));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn accesses_to_devices_are_dispatched_to_them() {
+        let mut emu = Emulator::with_memory_map(map(), Isa::default());
+        let probe = Rc::new(RefCell::new(Probe { written: None }));
+        emu.add_device(probe.clone());
+
+        assert_eq!(emu.read_u8(PROBE + 3).unwrap(), 0x03);
+        assert_eq!(emu.read_u16(PROBE + 4).unwrap(), 0x0204);
+        assert_eq!(emu.read_u32(PROBE + 8).unwrap(), 0x0408);
+        assert_eq!(emu.read_u64(PROBE + 8).unwrap(), 0x0808);
+        assert_eq!(emu.read_bytes(PROBE + 1, 2).unwrap()[..], [0x01, 0x02]);
+        emu.write_u16(PROBE + 6, 0xbeef).unwrap();
+        assert_eq!(probe.borrow().written, Some((6, 2, 0xbeef)));
+        emu.write_bytes(PROBE + 12, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(probe.borrow().written, Some((12, 4, 0x04030201)));
+
+        // Accesses that run off the end of the device or have an odd width fault
+        assert!(emu.read_u64(PROBE + 12).is_err());
+        assert!(emu.read_bytes(PROBE, 3).is_err());
+        assert!(emu.write_bytes(PROBE + 4, &[0; 16]).is_err());
+        // As does the rest of the mmio region, where there is no device
+        assert!(emu.read_u32(PROBE + 16).is_err());
+        assert_eq!(probe.borrow().written, Some((12, 4, 0x04030201)));
     }
 }
@@ -0,0 +1,698 @@
+//! A command line monitor, for quick triage of a guest without attaching gdb.
+//!
+//! Wherever a command takes an address or value, it may be a number (decimal, or hexadecimal
+//! with `0x`), a symbol from the executable or a register such as `$a0` or `$pc`, and these can
+//! be added and subtracted, e.g. `x/4xg $sp+16` or `break main+8`. Like the rest of the
+//! debugging tools, memory is read and written directly and devices are never touched.
+//!
+//! Changing registers or memory with `set` restarts the history that `reverse-step` and
+//! `reverse-continue` go back through, since the steps before it no longer lead to the changed
+//! state.
+
+use std::io::{self, Write};
+
+use crate::{device::AccessType, disasm, elf::Elf, Emulator, Privilege, StopReason, ABI_NAMES};
+
+/// The number of instructions `disas` shows when not told otherwise.
+const DISAS_COUNT: usize = 8;
+/// The number of bytes `watch` covers when not told otherwise.
+const WATCH_LEN: usize = 8;
+/// The most units `x` or instructions `disas` shows at once.
+const MAX_COUNT: usize = 1 << 16;
+
+const HELP: &str = "\
+step [N]                 execute N instructions (s)
+continue                 run until something stops the guest (c)
+reverse-step             go back one instruction (rs)
+reverse-continue         run backwards to the previous breakpoint or watchpoint (rc)
+break [LOC]              stop before executing LOC, or list breakpoints (b)
+delete LOC               remove the breakpoint at LOC (d)
+watch ADDR [LEN]         stop after a store to ADDR (also rwatch and awatch)
+unwatch ADDR [LEN]       remove the watchpoints on ADDR
+trap-break [CAUSE]       stop when a trap with CAUSE is taken, or list trap breakpoints
+trap-delete CAUSE        remove a trap breakpoint
+regs                     show the general purpose registers, pc and privilege mode
+csr [NAME]               show a csr, or every csr that isn't zero
+x/NFU ADDR               examine N units of memory, where F is x, d, u or i, and U is b, h, w or g
+disas [ADDR] [N]         disassemble N instructions, from the pc by default
+set reg NAME VALUE       write a register or csr
+set mem[/U] ADDR VALUE   write a unit of memory
+quit                     stop debugging (q)
+An empty line repeats the previous command.";
+
+/// Trap causes by name, as accepted by `trap-break`.
+const TRAP_CAUSES: [(&str, u64); 14] = [
+    ("instruction-misaligned", 0),
+    ("instruction-fault", 1),
+    ("illegal-instruction", 2),
+    ("breakpoint", 3),
+    ("load-misaligned", 4),
+    ("load-fault", 5),
+    ("store-misaligned", 6),
+    ("store-fault", 7),
+    ("ecall-u", 8),
+    ("ecall-m", 11),
+    ("software-interrupt", 1 << 63 | 3),
+    ("timer-interrupt", 1 << 63 | 7),
+    ("external-interrupt", 1 << 63 | 11),
+    ("counter-overflow-interrupt", 1 << 63 | 13),
+];
+
+/// What the driver of the monitor should do after a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Read another command.
+    Prompt,
+    /// The guest exited with this code.
+    Exited(u32),
+    /// The user is done debugging.
+    Quit,
+}
+
+pub struct Monitor {
+    elf: Option<Elf>,
+    /// The last command, which an empty line repeats.
+    last: String,
+}
+
+impl Monitor {
+    /// Create a monitor resolving symbols through `elf`, if there is one.
+    pub fn new(elf: Option<Elf>) -> Self {
+        Monitor {
+            elf,
+            last: String::new(),
+        }
+    }
+
+    /// Execute a line of input, writing anything it prints to `out`. Mistakes in the command are
+    /// reported to `out` as well.
+    pub fn execute(
+        &mut self,
+        emu: &mut Emulator,
+        line: &str,
+        out: &mut impl Write,
+    ) -> io::Result<Flow> {
+        let line = match line.trim() {
+            "" => std::mem::take(&mut self.last),
+            line => line.to_string(),
+        };
+        let result = self.command(emu, &line, out);
+        self.last = line;
+        match result {
+            Ok(flow) => Ok(flow),
+            Err(Error::Io(err)) => Err(err),
+            Err(Error::Command(message)) => {
+                writeln!(out, "error: {message}")?;
+                Ok(Flow::Prompt)
+            }
+        }
+    }
+
+    /// Show where the hart is and the instruction it executes next.
+    pub fn show_pc(&self, emu: &Emulator, out: &mut impl Write) -> io::Result<()> {
+        self.disassemble(emu, emu.pc, 1, out)
+    }
+
+    fn command(
+        &mut self,
+        emu: &mut Emulator,
+        line: &str,
+        out: &mut impl Write,
+    ) -> Result<Flow, Error> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Flow::Prompt);
+        };
+        let args: Vec<&str> = words.collect();
+        match (command, args.as_slice()) {
+            ("help" | "h", []) => writeln!(out, "{HELP}")?,
+            ("step" | "s", []) => return self.resume(emu, 1, out),
+            ("step" | "s", [count]) => {
+                let count = self.value(emu, count)?;
+                return self.resume(emu, count, out);
+            }
+            ("continue" | "c", []) => return self.resume(emu, u64::MAX, out),
+            ("reverse-step" | "rs", []) => {
+                require_history(emu)?;
+                let reason = emu.reverse_step();
+                self.stopped(emu, reason, out)?;
+            }
+            ("reverse-continue" | "rc", []) => {
+                require_history(emu)?;
+                let reason = emu.run_reverse(u64::MAX);
+                self.stopped(emu, reason, out)?;
+            }
+            ("break" | "b", []) => {
+                for &pc in &emu.breakpoints {
+                    writeln!(out, "{}", self.location(pc))?;
+                }
+            }
+            ("break" | "b", [location]) => {
+                let pc = self.value(emu, location)?;
+                emu.add_breakpoint(pc);
+                writeln!(out, "breakpoint at {}", self.location(pc))?;
+            }
+            ("delete" | "d", [location]) => {
+                let pc = self.value(emu, location)?;
+                emu.remove_breakpoint(pc);
+            }
+            ("watch" | "rwatch" | "awatch", [addr, len @ ..]) if len.len() <= 1 => {
+                let access = match command {
+                    "watch" => AccessType::Write,
+                    "rwatch" => AccessType::Read,
+                    _ => AccessType::ReadWrite,
+                };
+                let range = self.range(emu, addr, len.first())?;
+                emu.add_watchpoint(range, access);
+            }
+            ("unwatch", [addr, len @ ..]) if len.len() <= 1 => {
+                let range = self.range(emu, addr, len.first())?;
+                emu.remove_watchpoint(range);
+            }
+            ("trap-break", []) => {
+                for &cause in &emu.trap_breakpoints {
+                    writeln!(out, "{}", cause_name(cause))?;
+                }
+            }
+            ("trap-break", [cause]) => emu.add_trap_breakpoint(self.cause(emu, cause)?),
+            ("trap-delete", [cause]) => emu.remove_trap_breakpoint(self.cause(emu, cause)?),
+            ("regs", []) => self.show_registers(emu, out)?,
+            ("csr", []) => {
+                for (csr, value) in emu.csrs().filter(|(_, value)| *value != 0) {
+                    writeln!(out, "{:<14}{value:#x}", csr.name)?;
+                }
+            }
+            ("csr", [name]) => {
+                let csr = emu
+                    .csr_by_name(name)
+                    .ok_or_else(|| Error::Command(format!("no csr named {name}")))?;
+                writeln!(out, "{name} = {:#x}", emu.csr_value(csr))?;
+            }
+            ("disas", []) => self.disassemble(emu, emu.pc, DISAS_COUNT, out)?,
+            ("disas", [addr]) => {
+                let addr = self.value(emu, addr)?;
+                self.disassemble(emu, addr, DISAS_COUNT, out)?;
+            }
+            ("disas", [addr, count]) => {
+                let addr = self.value(emu, addr)?;
+                let count = check_count(self.value(emu, count)?)?;
+                self.disassemble(emu, addr, count, out)?;
+            }
+            ("set", ["reg", name, value]) => {
+                let value = self.value(emu, value)?;
+                set_register(emu, name, value)?;
+                restart_history(emu, out)?;
+            }
+            ("set", [mem, addr, value]) if mem.split('/').next() == Some("mem") => {
+                let unit = match mem.split_once('/') {
+                    Some((_, unit)) => unit_size(unit)?,
+                    None => 4,
+                };
+                let addr = self.value(emu, addr)? as usize;
+                let value = self.value(emu, value)?;
+                emu.debug_write(addr, &value.to_le_bytes()[..unit])
+                    .map_err(|_| Error::Command(format!("can't write memory at {addr:#x}")))?;
+                restart_history(emu, out)?;
+            }
+            ("quit" | "q", []) => return Ok(Flow::Quit),
+            (examine, [addr]) if examine.starts_with("x/") || examine == "x" => {
+                let addr = self.value(emu, addr)?;
+                self.examine(emu, &examine[1..], addr, out)?;
+            }
+            _ => {
+                return Err(Error::Command(format!(
+                    "can't understand '{line}', try 'help'"
+                )))
+            }
+        }
+        Ok(Flow::Prompt)
+    }
+
+    /// Run for at most `limit` instructions and show where the hart stopped.
+    fn resume(&self, emu: &mut Emulator, limit: u64, out: &mut impl Write) -> Result<Flow, Error> {
+        let reason = emu.run(limit);
+        if let StopReason::Exit(code) = reason {
+            writeln!(out, "{reason}")?;
+            return Ok(Flow::Exited(code));
+        }
+        self.stopped(emu, reason, out)?;
+        Ok(Flow::Prompt)
+    }
+
+    fn stopped(&self, emu: &Emulator, reason: StopReason, out: &mut impl Write) -> io::Result<()> {
+        if reason != StopReason::Limit {
+            writeln!(out, "{reason}")?;
+        }
+        if let (StopReason::CosimDiverged(_), Some(divergence)) = (&reason, emu.divergence()) {
+            writeln!(out, "{divergence}")?;
+        }
+        self.show_pc(emu, out)
+    }
+
+    fn show_registers(&self, emu: &Emulator, out: &mut impl Write) -> io::Result<()> {
+        for row in ABI_NAMES.chunks(4).enumerate() {
+            let (row, names) = row;
+            for (col, name) in names.iter().enumerate() {
+                let value = emu.x[row * 4 + col];
+                write!(out, "{name:<5}{value:#018x}  ")?;
+            }
+            writeln!(out)?;
+        }
+        let privilege = match emu.privilege {
+            Privilege::User => "user",
+            Privilege::Machine => "machine",
+        };
+        writeln!(out, "pc   {:#018x}  {privilege} mode", emu.pc)
+    }
+
+    /// Examine memory as `NFU`, like gdb.
+    fn examine(
+        &self,
+        emu: &Emulator,
+        format: &str,
+        addr: u64,
+        out: &mut impl Write,
+    ) -> Result<(), Error> {
+        let format = format.strip_prefix('/').unwrap_or(format);
+        let digits = format
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(format.len());
+        let count = match &format[..digits] {
+            "" => 1,
+            count => check_count(count.parse().map_err(|_| bad_format(format))?)?,
+        };
+        let mut kind = 'x';
+        let mut unit = 4;
+        for letter in format[digits..].chars() {
+            match letter {
+                'x' | 'd' | 'u' | 'i' => kind = letter,
+                'b' | 'h' | 'w' | 'g' => unit = unit_size(&letter.to_string())?,
+                _ => return Err(bad_format(format)),
+            }
+        }
+        if kind == 'i' {
+            self.disassemble(emu, addr, count, out)?;
+            return Ok(());
+        }
+        let per_line = 16 / unit.max(2);
+        // The count is capped, so this can't overflow
+        let bytes = emu.debug_read(addr as usize, count * unit);
+        for (line, units) in bytes.chunks(unit * per_line).enumerate() {
+            let line_addr = addr.wrapping_add((line * unit * per_line) as u64);
+            write!(out, "{}:", self.location(line_addr))?;
+            for bytes in units.chunks_exact(unit) {
+                let mut buf = [0; 8];
+                buf[..unit].copy_from_slice(bytes);
+                let value = u64::from_le_bytes(buf);
+                let shift = 64 - 8 * unit as u32;
+                match kind {
+                    'd' => write!(out, " {}", (value << shift) as i64 >> shift)?,
+                    'u' => write!(out, " {value}")?,
+                    _ => write!(out, " {value:#0width$x}", width = 2 + 2 * unit)?,
+                }
+            }
+            writeln!(out)?;
+        }
+        if bytes.len() < count * unit {
+            let end = addr.wrapping_add(bytes.len() as u64);
+            return Err(Error::Command(format!("can't read memory at {end:#x}")));
+        }
+        Ok(())
+    }
+
+    fn disassemble(
+        &self,
+        emu: &Emulator,
+        mut addr: u64,
+        count: usize,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        for _ in 0..count {
+            let location = self.location(addr);
+            let marker = if addr == emu.pc { "=>" } else { "  " };
+            // Reading goes around devices and the decode cache, so nothing is disturbed
+            let bytes = emu.debug_read(addr as usize, 4);
+            match disasm::disassemble(&bytes, addr, self.elf.as_ref()).first() {
+                Some(line) => {
+                    writeln!(out, "{marker} {location}:\t{}", line.text)?;
+                    addr = addr.wrapping_add(line.len);
+                }
+                None => {
+                    writeln!(out, "{marker} {location}: (unreadable)")?;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Format `addr` along with the symbol it falls in, if any.
+    fn location(&self, addr: u64) -> String {
+        match self
+            .elf
+            .as_ref()
+            .and_then(|elf| elf.symbol_at(addr as usize))
+        {
+            Some((name, 0)) => format!("{addr:#x} <{name}>"),
+            Some((name, offset)) => format!("{addr:#x} <{name}+{offset}>"),
+            None => format!("{addr:#x}"),
+        }
+    }
+
+    /// Evaluate a sum of numbers, symbols and registers.
+    fn value(&self, emu: &Emulator, expr: &str) -> Result<u64, Error> {
+        let mut total = 0u64;
+        let mut negate = false;
+        let mut start = 0;
+        // A term ends at a + or -, other than one starting the expression
+        let ends = expr
+            .char_indices()
+            .filter(|&(idx, c)| idx > 0 && matches!(c, '+' | '-'))
+            .map(|(idx, _)| idx)
+            .chain([expr.len()]);
+        for end in ends {
+            let term = expr[start..end].trim();
+            let value = self.term(emu, term)?;
+            total = if negate {
+                total.wrapping_sub(value)
+            } else {
+                total.wrapping_add(value)
+            };
+            negate = expr[end..].starts_with('-');
+            start = end + 1;
+        }
+        Ok(total)
+    }
+
+    fn term(&self, emu: &Emulator, term: &str) -> Result<u64, Error> {
+        let unknown = || Error::Command(format!("can't evaluate '{term}'"));
+        if let Some(name) = term.strip_prefix('$') {
+            return register(emu, name).ok_or_else(unknown);
+        }
+        if let Some(value) = parse_number(term) {
+            return Ok(value);
+        }
+        self.elf
+            .as_ref()
+            .and_then(|elf| elf.get_symbol(term))
+            .map(|symbol| symbol.value as u64)
+            .ok_or_else(unknown)
+    }
+
+    fn range(
+        &self,
+        emu: &Emulator,
+        addr: &str,
+        len: Option<&&str>,
+    ) -> Result<std::ops::Range<usize>, Error> {
+        let addr = self.value(emu, addr)? as usize;
+        let len = match len {
+            Some(len) => self.value(emu, len)? as usize,
+            None => WATCH_LEN,
+        };
+        Ok(addr..addr.saturating_add(len))
+    }
+
+    fn cause(&self, emu: &Emulator, cause: &str) -> Result<u64, Error> {
+        match TRAP_CAUSES.iter().find(|(name, _)| *name == cause) {
+            Some(&(_, code)) => Ok(code),
+            None => self.value(emu, cause),
+        }
+    }
+}
+
+enum Error {
+    Io(io::Error),
+    /// The command was wrong, which is reported to the user.
+    Command(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+fn bad_format(format: &str) -> Error {
+    Error::Command(format!("invalid format '{format}'"))
+}
+
+/// Check that `count` units or instructions can be shown at once.
+fn check_count(count: u64) -> Result<usize, Error> {
+    usize::try_from(count)
+        .ok()
+        .filter(|&count| count <= MAX_COUNT)
+        .ok_or_else(|| Error::Command(format!("can't show more than {MAX_COUNT} at once")))
+}
+
+fn require_history(emu: &Emulator) -> Result<(), Error> {
+    match emu.history_position() {
+        Some(_) => Ok(()),
+        None => Err(Error::Command("history isn't enabled".to_string())),
+    }
+}
+
+/// Restart history after the state was changed by hand, since going back to the steps before
+/// would lose the change.
+fn restart_history(emu: &mut Emulator, out: &mut impl Write) -> io::Result<()> {
+    let Some(step) = emu.history_position() else {
+        return Ok(());
+    };
+    emu.restart_history();
+    if step > 0 {
+        writeln!(
+            out,
+            "history now starts here, as the steps before no longer lead here"
+        )?;
+    }
+    Ok(())
+}
+
+fn unit_size(unit: &str) -> Result<usize, Error> {
+    match unit {
+        "b" => Ok(1),
+        "h" => Ok(2),
+        "w" => Ok(4),
+        "g" => Ok(8),
+        _ => Err(bad_format(unit)),
+    }
+}
+
+fn cause_name(cause: u64) -> String {
+    match TRAP_CAUSES.iter().find(|&&(_, code)| code == cause) {
+        Some((name, _)) => name.to_string(),
+        None => format!("{cause:#x}"),
+    }
+}
+
+fn parse_number(number: &str) -> Option<u64> {
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, number),
+    };
+    let value = match number.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => number.parse().ok()?,
+    };
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// The number of a general purpose register, by ABI name or as `xN`.
+fn register_number(name: &str) -> Option<usize> {
+    ABI_NAMES
+        .iter()
+        .position(|abi| *abi == name)
+        .or_else(|| match name {
+            "s0" => Some(8),
+            _ => name.strip_prefix('x')?.parse().ok().filter(|&idx| idx < 32),
+        })
+}
+
+fn register(emu: &Emulator, name: &str) -> Option<u64> {
+    if name == "pc" {
+        return Some(emu.pc);
+    }
+    if let Some(idx) = register_number(name) {
+        return Some(emu.x[idx]);
+    }
+    emu.csr_by_name(name).map(|csr| emu.csr_value(csr))
+}
+
+fn set_register(emu: &mut Emulator, name: &str, value: u64) -> Result<(), Error> {
+    if name == "pc" {
+        emu.pc = value;
+    } else if let Some(idx) = register_number(name) {
+        if idx != 0 {
+            emu.x[idx] = value;
+        }
+    } else if let Some(csr) = emu.csr_by_name(name) {
+        emu.set_csr_value(csr, value);
+    } else {
+        return Err(Error::Command(format!("no register named {name}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::RAM_BASE;
+
+    /// An emulator at the start of a loop counting in a0.
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(1 << 16);
+        let program = [
+            0x00150513u32, // addi a0, a0, 1
+            0xffdff06f,    // j -4
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu
+    }
+
+    /// Execute `line`, returning what it printed.
+    fn execute(monitor: &mut Monitor, emu: &mut Emulator, line: &str) -> String {
+        let mut out = Vec::new();
+        let flow = monitor.execute(emu, line, &mut out).unwrap();
+        assert_eq!(flow, Flow::Prompt);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn expressions_sum_numbers_and_registers() {
+        let monitor = Monitor::new(None);
+        let mut emu = emulator();
+        emu.x[10] = 0x100;
+        emu.machine_csrs.mscratch = 7;
+        let value = |expr| monitor.value(&emu, expr).ok();
+        assert_eq!(value("$a0+0x10-4"), Some(0x10c));
+        assert_eq!(value("-8+$a0"), Some(0xf8));
+        assert_eq!(value("$pc + 2"), Some(RAM_BASE as u64 + 2));
+        assert_eq!(value("$mscratch+$x10"), Some(0x107));
+        assert_eq!(value("0-1"), Some(u64::MAX));
+        assert_eq!(value("main"), None);
+        assert_eq!(value("$t7"), None);
+    }
+
+    #[test]
+    fn examines_memory() {
+        let mut monitor = Monitor::new(None);
+        let mut emu = emulator();
+        let bytes: Vec<u8> = (1..=0x18).collect();
+        emu.write_bytes(RAM_BASE + 0x100, &bytes).unwrap();
+        emu.write_bytes(RAM_BASE + 0x200, &[0xff, 0xff]).unwrap();
+        let mut x = |line| execute(&mut monitor, &mut emu, line);
+        assert_eq!(
+            x("x/4xw 0x80000100"),
+            "0x80000100: 0x04030201 0x08070605 0x0c0b0a09 0x100f0e0d\n"
+        );
+        assert_eq!(
+            x("x/3xg 0x80000100"),
+            "0x80000100: 0x0807060504030201 0x100f0e0d0c0b0a09\n\
+             0x80000110: 0x1817161514131211\n"
+        );
+        assert_eq!(x("x/3ub 0x80000100"), "0x80000100: 1 2 3\n");
+        assert_eq!(x("x/dh 0x80000200"), "0x80000200: -1\n");
+        assert_eq!(x("x/uh 0x80000200"), "0x80000200: 65535\n");
+        assert_eq!(
+            x("x/2i 0x80000000"),
+            "=> 0x80000000:\taddi\ta0,a0,1\n   0x80000004:\tj\t80000000\n"
+        );
+
+        assert_eq!(x("x/4q 0x80000100"), "error: invalid format '4q'\n");
+        assert_eq!(
+            x("x/2xg 0x8000fff8"),
+            "0x8000fff8: 0x0000000000000000\nerror: can't read memory at 0x80010000\n"
+        );
+        // Counts that would overflow are refused rather than wrapping
+        for line in [
+            "x/65537xb 0",
+            "x/2305843009213693952xg 0",
+            "disas 0 0x10001",
+        ] {
+            assert_eq!(x(line), "error: can't show more than 65536 at once\n");
+        }
+        assert_eq!(
+            x("x/99999999999999999999xg 0"),
+            "error: invalid format '99999999999999999999xg'\n"
+        );
+    }
+
+    #[test]
+    fn sets_registers_and_memory() {
+        let mut monitor = Monitor::new(None);
+        let mut emu = emulator();
+        for line in [
+            "set reg a0 5",
+            "set reg x0 1",
+            "set reg mscratch 0x20",
+            "set reg pc $pc+4",
+            "set mem/b 0x80000100 0x1ff",
+            "set mem 0x80000104 0x12345678",
+        ] {
+            assert_eq!(execute(&mut monitor, &mut emu, line), "");
+        }
+        assert_eq!((emu.x[10], emu.x[0]), (5, 0));
+        assert_eq!(emu.machine_csrs.mscratch, 0x20);
+        assert_eq!(emu.pc, RAM_BASE as u64 + 4);
+        assert_eq!(
+            emu.memory.read_array(RAM_BASE + 0x100).unwrap(),
+            [0xff, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0]
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut emu, "set reg t7 1"),
+            "error: no register named t7\n"
+        );
+        assert_eq!(
+            execute(&mut monitor, &mut emu, "set mem 0x100 1"),
+            "error: can't write memory at 0x100\n"
+        );
+    }
+
+    #[test]
+    fn setting_state_restarts_history() {
+        let mut monitor = Monitor::new(None);
+        let mut emu = emulator();
+        assert_eq!(
+            execute(&mut monitor, &mut emu, "rs"),
+            "error: history isn't enabled\n"
+        );
+
+        emu.enable_history(4);
+        execute(&mut monitor, &mut emu, "step 10");
+        assert_eq!(emu.x[10], 5);
+        assert_eq!(
+            execute(&mut monitor, &mut emu, "set reg a0 100"),
+            "history now starts here, as the steps before no longer lead here\n"
+        );
+        assert_eq!(emu.history_position(), Some(0));
+        // Going back doesn't lose the change
+        execute(&mut monitor, &mut emu, "step 4");
+        execute(&mut monitor, &mut emu, "rc");
+        assert_eq!((emu.history_position(), emu.x[10]), (Some(0), 100));
+    }
+
+    #[test]
+    fn empty_lines_repeat_the_last_command() {
+        let mut monitor = Monitor::new(None);
+        let mut emu = emulator();
+        execute(&mut monitor, &mut emu, "s");
+        execute(&mut monitor, &mut emu, "");
+        execute(&mut monitor, &mut emu, "  ");
+        assert_eq!(emu.x[10], 2);
+        assert_eq!(
+            execute(&mut monitor, &mut emu, "frobnicate 3"),
+            "error: can't understand 'frobnicate 3', try 'help'\n"
+        );
+        let mut out = Vec::new();
+        assert_eq!(
+            monitor.execute(&mut emu, "q", &mut out).unwrap(),
+            Flow::Quit
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! Recording the inputs that make a run nondeterministic, and replaying them to reproduce it.
+//!
+//! Everything the hart does follows from its state except for values read from devices, the
+//! inputs the host drives through [`Emulator::set_external_interrupt`] and
+//! [`Emulator::set_mtime`], and the results of semihosting calls. While recording, each of these
+//! is written to a log along with the value of `minstret` at the time. While replaying, device
+//! reads return the logged values without reaching the device, the host driven inputs are
+//! applied at the same instruction counts as they were recorded, ignoring the host, and
+//! semihosting calls return their logged results without reaching the host.
+//!
+//! The log is plain text. After a `riscv-input-log 1` header, every line is one of:
+//!
+//! ```text
+//! <minstret> read <device> <offset> <width> <value>|fault
+//! <minstret> irq 0|1
+//! <minstret> mtime <value>
+//! <minstret> semihosting <value> [exit <code>] [write <address> <bytes>]...
+//! ```
+//!
+//! where devices are numbered in the order they were added, starting with the CLINT if it is
+//! mapped, values and addresses are hexadecimal, and the bytes a semihosting call wrote to memory
+//! are two hexadecimal digits each. Since the log is keyed by `minstret`, replays are only exact
+//! as long as the guest doesn't inhibit it around host driven inputs.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::{device::BusError, semihosting::CallResult, trap::Interrupt, Emulator};
+
+const HEADER: &str = "riscv-input-log 1";
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The log doesn't start with a header this version understands.
+    BadHeader,
+    /// A line of the log couldn't be parsed.
+    InvalidLine(usize),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "{err}"),
+            ReplayError::BadHeader => write!(f, "not an input log, expected '{HEADER}'"),
+            ReplayError::InvalidLine(line) => write!(f, "invalid input log entry on line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeviceRead {
+    minstret: u64,
+    device: usize,
+    offset: usize,
+    width: usize,
+    value: Result<u64, BusError>,
+}
+
+/// An input driven by the host, which arrives between instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostInput {
+    ExternalInterrupt(bool),
+    Mtime(u64),
+    /// The result of the semihosting call made by the next instruction.
+    Semihosting(CallResult),
+}
+
+/// A line of the log.
+enum Entry {
+    Read(DeviceRead),
+    Host(u64, HostInput),
+}
+
+pub(crate) enum InputLog {
+    Record {
+        out: Box<dyn Write>,
+        /// The first error writing to `out`, which is reported by `finish_recording`.
+        error: Option<io::Error>,
+    },
+    Replay {
+        reads: VecDeque<DeviceRead>,
+        inputs: VecDeque<(u64, HostInput)>,
+        /// The count at which the run stopped matching the log.
+        diverged: Option<u64>,
+    },
+}
+
+impl InputLog {
+    fn record(&mut self, args: fmt::Arguments) {
+        if let InputLog::Record { out, error } = self {
+            if error.is_none() {
+                *error = writeln!(out, "{args}").err();
+            }
+        }
+    }
+
+    /// Read from a device with `live`, unless the read is being replayed.
+    pub(crate) fn device_read(
+        &mut self,
+        read: DeviceReadKey,
+        live: impl FnOnce() -> Result<u64, BusError>,
+    ) -> Result<u64, BusError> {
+        let DeviceReadKey {
+            minstret,
+            device,
+            offset,
+            width,
+        } = read;
+        match self {
+            InputLog::Record { .. } => {
+                let value = live();
+                match value {
+                    Ok(val) => self.record(format_args!(
+                        "{minstret} read {device} {offset:#x} {width} {val:#x}"
+                    )),
+                    Err(BusError) => self.record(format_args!(
+                        "{minstret} read {device} {offset:#x} {width} fault"
+                    )),
+                }
+                value
+            }
+            InputLog::Replay {
+                reads, diverged, ..
+            } => match reads.pop_front() {
+                Some(logged)
+                    if (logged.minstret, logged.device, logged.offset, logged.width)
+                        == (minstret, device, offset, width) =>
+                {
+                    logged.value
+                }
+                // Past the end of the log the run carries on with live inputs
+                None => live(),
+                Some(_) => {
+                    diverged.get_or_insert(minstret);
+                    live()
+                }
+            },
+        }
+    }
+}
+
+/// Identifies a device read in the log.
+pub(crate) struct DeviceReadKey {
+    pub minstret: u64,
+    pub device: usize,
+    pub offset: usize,
+    pub width: usize,
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    let mut words = line.split_whitespace();
+    let minstret = words.next()?.parse().ok()?;
+    let entry = match (words.next()?, words.next()?) {
+        ("read", device) => {
+            let device = device.parse().ok()?;
+            let offset = hex(words.next()?)? as usize;
+            let width = words.next()?.parse().ok()?;
+            let value = match words.next()? {
+                "fault" => Err(BusError),
+                val => Ok(hex(val)?),
+            };
+            Entry::Read(DeviceRead {
+                minstret,
+                device,
+                offset,
+                width,
+                value,
+            })
+        }
+        ("irq", "0") => Entry::Host(minstret, HostInput::ExternalInterrupt(false)),
+        ("irq", "1") => Entry::Host(minstret, HostInput::ExternalInterrupt(true)),
+        ("mtime", val) => Entry::Host(minstret, HostInput::Mtime(hex(val)?)),
+        ("semihosting", val) => {
+            let mut result = CallResult {
+                value: hex(val)?,
+                ..CallResult::default()
+            };
+            while let Some(word) = words.next() {
+                match word {
+                    "exit" => result.exit = Some(words.next()?.parse().ok()?),
+                    "write" => {
+                        let addr = hex(words.next()?)?;
+                        let bytes = words.next()?;
+                        let bytes = (0..bytes.len())
+                            .step_by(2)
+                            .map(|idx| u8::from_str_radix(bytes.get(idx..idx + 2)?, 16).ok())
+                            .collect::<Option<_>>()?;
+                        result.writes.push((addr, bytes));
+                    }
+                    _ => return None,
+                }
+            }
+            Entry::Host(minstret, HostInput::Semihosting(result))
+        }
+        _ => return None,
+    };
+    words.next().is_none().then_some(entry)
+}
+
+impl Emulator {
+    /// Start logging every nondeterministic input to `out`, replacing any recording or replay
+    /// that was in progress.
+    pub fn record_inputs(&mut self, mut out: impl Write + 'static) -> io::Result<()> {
+        writeln!(out, "{HEADER}")?;
+        self.input_log = Some(RefCell::new(InputLog::Record {
+            out: Box::new(out),
+            error: None,
+        }));
+        Ok(())
+    }
+
+    /// Stop recording, flushing the log and reporting any error that occurred while writing it.
+    pub fn finish_recording(&mut self) -> io::Result<()> {
+        match self.input_log.take().map(RefCell::into_inner) {
+            Some(InputLog::Record { mut out, error }) => match error {
+                Some(err) => Err(err),
+                None => out.flush(),
+            },
+            log => {
+                self.input_log = log.map(RefCell::new);
+                Ok(())
+            }
+        }
+    }
+
+    /// Feed the inputs logged by [`Emulator::record_inputs`] back in. The emulator must be in
+    /// the same state as when recording started.
+    pub fn replay_inputs(&mut self, log: impl BufRead) -> Result<(), ReplayError> {
+        let mut lines = log.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(ReplayError::BadHeader);
+        }
+        let mut reads = VecDeque::new();
+        let mut inputs = VecDeque::new();
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The header is line 1
+            match parse_line(&line).ok_or(ReplayError::InvalidLine(idx + 2))? {
+                Entry::Read(read) => reads.push_back(read),
+                Entry::Host(minstret, input) => inputs.push_back((minstret, input)),
+            }
+        }
+        self.input_log = Some(RefCell::new(InputLog::Replay {
+            reads,
+            inputs,
+            diverged: None,
+        }));
+        Ok(())
+    }
+
+    /// Raise or lower the machine external interrupt. This is ignored while replaying, or while
+    /// executing steps again after going back in history, as the interrupt then follows the
+    /// log.
+    pub fn set_external_interrupt(&mut self, pending: bool) {
+        if self.host_input(&HostInput::ExternalInterrupt(pending)) {
+            self.apply_host_input(HostInput::ExternalInterrupt(pending));
+        }
+    }
+
+    /// Set `mtime`, such as to follow the time on the host. This is ignored while replaying, or
+    /// while executing steps again after going back in history, as `mtime` then follows the
+    /// log.
+    pub fn set_mtime(&mut self, mtime: u64) {
+        if self.host_input(&HostInput::Mtime(mtime)) {
+            self.apply_host_input(HostInput::Mtime(mtime));
+        }
+    }
+
+    /// Log an input from the host, returning whether it should take effect.
+    fn host_input(&mut self, input: &HostInput) -> bool {
+        if let Some(history) = &self.history {
+            if !history.borrow().accepts_host_input() {
+                return false;
+            }
+        }
+        if let Some(InputLog::Replay { .. }) = self.input_log.as_mut().map(RefCell::get_mut) {
+            return false;
+        }
+        self.log_host_input(input);
+        true
+    }
+
+    /// Write an input from the host to the log, if recording.
+    pub(crate) fn log_host_input(&mut self, input: &HostInput) {
+        let minstret = self.machine_csrs.minstret;
+        let Some(log) = self.input_log.as_mut().map(RefCell::get_mut) else {
+            return;
+        };
+        match input {
+            HostInput::ExternalInterrupt(pending) => {
+                log.record(format_args!("{minstret} irq {}", *pending as u8))
+            }
+            HostInput::Mtime(mtime) => log.record(format_args!("{minstret} mtime {mtime:#x}")),
+            HostInput::Semihosting(result) => {
+                let mut line = format!("{minstret} semihosting {:#x}", result.value);
+                if let Some(code) = result.exit {
+                    line += &format!(" exit {code}");
+                }
+                for (addr, bytes) in &result.writes {
+                    line += &format!(" write {addr:#x} ");
+                    line.extend(bytes.iter().map(|byte| format!("{byte:02x}")));
+                }
+                log.record(format_args!("{line}"))
+            }
+        }
+    }
+
+    pub(crate) fn apply_host_input(&mut self, input: HostInput) {
+        if let Some(history) = &mut self.history {
+            history.get_mut().record_host_input(input.clone());
+        }
+        match input {
+            HostInput::ExternalInterrupt(true) => {
+                self.machine_csrs.mip |= Interrupt::MachineExternal.mask()
+            }
+            HostInput::ExternalInterrupt(false) => {
+                self.machine_csrs.mip &= !Interrupt::MachineExternal.mask()
+            }
+            HostInput::Mtime(mtime) => self.clint.borrow_mut().mtime = mtime,
+            HostInput::Semihosting(result) => {
+                if let Some(semihosting) = &mut self.semihosting {
+                    semihosting.feed(result);
+                }
+            }
+        }
+    }
+
+    /// Apply every replayed host input that is due, returning how many instructions can run
+    /// before the next one is.
+    pub(crate) fn replay_host_inputs(&mut self) -> u64 {
+        let minstret = self.machine_csrs.minstret;
+        loop {
+            let Some(InputLog::Replay {
+                inputs, diverged, ..
+            }) = self.input_log.as_mut().map(RefCell::get_mut)
+            else {
+                return u64::MAX;
+            };
+            match inputs.front() {
+                Some(&(at, _)) if at == minstret => {
+                    let (_, input) = inputs.pop_front().unwrap();
+                    self.apply_host_input(input);
+                }
+                Some(&(at, _)) if at < minstret => {
+                    diverged.get_or_insert(minstret);
+                    inputs.pop_front();
+                }
+                Some(&(at, _)) => return at - minstret,
+                None => return u64::MAX,
+            }
+        }
+    }
+
+    /// The value of `minstret` at which a replayed run stopped matching its log.
+    pub(crate) fn replay_divergence(&self) -> Option<u64> {
+        match &*self.input_log.as_ref()?.borrow() {
+            InputLog::Replay { diverged, .. } => *diverged,
+            InputLog::Record { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::ops::Range;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::device::Device;
+    use crate::isa::Isa;
+    use crate::mem::{MemoryMap, RegionSpec, RAM_BASE};
+    use crate::sandbox::{tests::TempDir, Sandbox};
+    use crate::semihosting::Semihosting;
+    use crate::StopReason;
+
+    /// Where the device is, which the program has in s3.
+    const DEVICE: usize = 0x10000000;
+    /// Where the arguments of the semihosting calls are, which the program has in s0.
+    const ARGS: usize = RAM_BASE + 0x1000;
+    const NAME: usize = ARGS + 0x100;
+    const BUFFER: usize = ARGS + 0x120;
+
+    /// A device that counts up from where it starts every time it is read.
+    struct Counter(u64);
+
+    impl Device for Counter {
+        fn address_range(&self) -> Range<usize> {
+            DEVICE..DEVICE + 8
+        }
+
+        fn read(&mut self, _offset: usize, _width: usize) -> Result<u64, BusError> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, _val: u64) -> Result<(), BusError> {
+            Ok(())
+        }
+    }
+
+    /// A log being written, which can still be read once the emulator has it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An emulator with a [`Counter`] starting at `count` and semihosting in `dir`, which reads
+    /// the device, reads the time, opens in.txt and reads 8 bytes of it, reads the device again
+    /// and then loops forever.
+    fn emulator(count: u64, dir: &Path) -> Emulator {
+        let program: [u32; 16] = [
+            0x0009a283, // lw t0, 0(s3)
+            0xc0102373, // rdtime t1
+            0x00100513, // li a0, 1
+            0x00040593, // mv a1, s0
+            0x020000ef, // jal semihost
+            0x02a43023, // sd a0, 32(s0)
+            0x00600513, // li a0, 6
+            0x02040593, // addi a1, s0, 32
+            0x010000ef, // jal semihost
+            0x00050493, // mv s1, a0
+            0x0009a383, // lw t2, 0(s3)
+            0x0000006f, // j .
+            0x01f01013, // slli zero, zero, 31
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x00008067, // ret
+        ];
+        let specs = ["mmio:0x10000000:4K", "ram:0x80000000:64K"];
+        let specs: Vec<RegionSpec> = specs.iter().map(|spec| spec.parse().unwrap()).collect();
+        let mut emu =
+            Emulator::with_memory_map(MemoryMap::from_specs(&specs).unwrap(), Isa::default());
+        emu.add_device(Rc::new(RefCell::new(Counter(count))));
+        emu.enable_semihosting(Semihosting::new(Some(Sandbox::new(dir).unwrap())));
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        // SYS_OPEN of in.txt with "r", then SYS_READ of 8 bytes from handle 1
+        for (idx, word) in [NAME as u64, 0, 6, 0, 1, BUFFER as u64, 8]
+            .iter()
+            .enumerate()
+        {
+            emu.write_u64(ARGS + idx * 8, *word).unwrap();
+        }
+        emu.write_bytes(NAME, b"in.txt").unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = ARGS as u64;
+        emu.x[19] = DEVICE as u64;
+        emu
+    }
+
+    #[test]
+    fn a_recorded_run_is_replayed() {
+        let recorded = TempDir::new("replay-recorded");
+        fs::write(recorded.path().join("in.txt"), b"recorded").unwrap();
+        let mut emu = emulator(7, recorded.path());
+        let log = Shared::default();
+        emu.record_inputs(log.clone()).unwrap();
+        assert_eq!(emu.run(1), StopReason::Limit);
+        emu.set_mtime(0x1234);
+        emu.set_external_interrupt(true);
+        assert_eq!(emu.run(30), StopReason::Limit);
+        emu.finish_recording().unwrap();
+        assert_eq!((emu.x[5], emu.x[6], emu.x[7]), (8, 0x1234, 9));
+        assert_eq!(emu.x[9], 0);
+
+        let log = String::from_utf8(log.0.take()).unwrap();
+        let expected = format!(
+            "{HEADER}\n0 read 0 0x0 4 0x8\n1 mtime 0x1234\n1 irq 1\n6 semihosting 0x1\n\
+             14 semihosting 0x0 write {BUFFER:#x} 7265636f72646564\n18 read 0 0x0 4 0x9\n"
+        );
+        assert_eq!(log, expected);
+
+        // Neither the device, the file nor the host give what they did when recording
+        let replayed = TempDir::new("replay-replayed");
+        fs::write(replayed.path().join("in.txt"), b"other").unwrap();
+        let mut replay = emulator(100, replayed.path());
+        replay.replay_inputs(log.as_bytes()).unwrap();
+        assert_eq!(replay.run(1), StopReason::Limit);
+        replay.set_mtime(0x5678);
+        assert_eq!(replay.run(30), StopReason::Limit);
+        assert_eq!((replay.pc, replay.x), (emu.pc, emu.x));
+        assert_eq!(replay.machine_csrs.mip, emu.machine_csrs.mip);
+        assert_eq!(replay.memory.read_array(BUFFER).unwrap(), *b"recorded");
+        assert_eq!(replay.replay_divergence(), None);
+    }
+
+    #[test]
+    fn replays_that_stop_following_the_log_diverge() {
+        let dir = TempDir::new("replay-diverged");
+        let mut emu = emulator(0, dir.path());
+        // The second read is logged as 8 bytes wide rather than 4
+        let log = format!("{HEADER}\n0 read 0 0x0 4 0x8\n18 read 0 0x0 8 0x9\n");
+        emu.replay_inputs(log.as_bytes()).unwrap();
+        assert_eq!(emu.run(30), StopReason::ReplayDiverged(18));
+        assert_eq!(emu.x[5], 8);
+
+        let invalid = format!("{HEADER}\n0 read 0 0x0 4 0x8\n\n1 mtime\n");
+        assert!(matches!(
+            emu.replay_inputs(invalid.as_bytes()),
+            Err(ReplayError::InvalidLine(4))
+        ));
+        assert!(matches!(
+            emu.replay_inputs(&b"0 mtime 0x1\n"[..]),
+            Err(ReplayError::BadHeader)
+        ));
+    }
+}
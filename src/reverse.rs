@@ -0,0 +1,344 @@
+//! Reverse execution, by going back to a checkpoint and deterministically executing forwards.
+//!
+//! While history is enabled every instruction (or interrupt taken) is one step, and a snapshot is
+//! taken every so many steps. Snapshots share the pages of memory that haven't been written since,
+//! so a checkpoint only costs a copy of the pages written after it is taken. Device reads and
+//! inputs from the host are logged by step, so that executing a step again reads the same values
+//! and sees the same inputs, no matter what the devices or the host would do now. Going back to
+//! step `n` restores the latest checkpoint before it and executes forwards to `n`.
+//!
+//! Devices only take part if they implement [`Device::save`](crate::device::Device::save) and
+//! [`Device::restore`](crate::device::Device::restore), and see writes again as steps are
+//! executed again.
+
+use std::cell::RefCell;
+
+use crate::{device::BusError, replay::HostInput, snapshot::Snapshot, Emulator, StopReason};
+
+/// The most checkpoints that are kept. When there are more, every other one is dropped and the
+/// interval between them doubles, so the whole history stays reachable.
+const MAX_CHECKPOINTS: usize = 64;
+
+pub(crate) struct History {
+    /// The number of steps between checkpoints.
+    interval: u64,
+    /// The number of steps executed since history was enabled.
+    step: u64,
+    /// The furthest step reached. Steps before this are executed again from the log.
+    horizon: u64,
+    /// Sorted by step, and the first is always at step 0.
+    checkpoints: Vec<(u64, Snapshot)>,
+    /// Every device read, by the step it was made in.
+    reads: Vec<(u64, Result<u64, BusError>)>,
+    next_read: usize,
+    /// Every input from the host, by the step it arrived before.
+    inputs: Vec<(u64, HostInput)>,
+    next_input: usize,
+}
+
+impl History {
+    /// Read from a device with `live`, unless the step is being executed again.
+    pub(crate) fn device_read(
+        &mut self,
+        live: impl FnOnce() -> Result<u64, BusError>,
+    ) -> Result<u64, BusError> {
+        if self.step < self.horizon {
+            if let Some(&(step, value)) = self.reads.get(self.next_read) {
+                if step == self.step {
+                    self.next_read += 1;
+                    return value;
+                }
+            }
+        }
+        let value = live();
+        if self.step >= self.horizon {
+            self.reads.push((self.step, value));
+            self.next_read = self.reads.len();
+        }
+        value
+    }
+
+    /// Whether an input from the host should take effect, which is only the case at the end of
+    /// history.
+    pub(crate) fn accepts_host_input(&self) -> bool {
+        self.step >= self.horizon
+    }
+
+    /// Whether the current step was executed before, and is being executed again.
+    pub(crate) fn is_repeating(&self) -> bool {
+        self.step < self.horizon
+    }
+
+    /// Log an input from the host that is taking effect.
+    pub(crate) fn record_host_input(&mut self, input: HostInput) {
+        if self.step >= self.horizon {
+            self.inputs.push((self.step, input));
+            self.next_input = self.inputs.len();
+        }
+    }
+}
+
+impl Emulator {
+    /// Start keeping history so that execution can be reversed, with a checkpoint every
+    /// `interval` steps. History starts at the current state, and any previous history is
+    /// dropped.
+    ///
+    /// Reversing is only exact if the state of the emulator isn't changed other than by
+    /// executing it, such as with [`Emulator::restore`] or [`Emulator::set_pc`].
+    pub fn enable_history(&mut self, interval: u64) {
+        self.history = Some(RefCell::new(History {
+            interval: interval.max(1),
+            step: 0,
+            horizon: 0,
+            checkpoints: vec![(0, self.snapshot())],
+            reads: Vec::new(),
+            next_read: 0,
+            inputs: Vec::new(),
+            next_input: 0,
+        }));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// Drop the history before the current step and keep history from here, with checkpoints as
+    /// far apart as before. This is for when the state has been changed other than by executing,
+    /// which the steps before it no longer lead to.
+    pub fn restart_history(&mut self) {
+        if let Some(history) = &self.history {
+            let interval = history.borrow().interval;
+            self.enable_history(interval);
+        }
+    }
+
+    /// The number of steps executed since history was enabled.
+    pub fn history_position(&self) -> Option<u64> {
+        Some(self.history.as_ref()?.borrow().step)
+    }
+
+    /// Execute backwards by at most `limit` steps, stopping at the latest breakpoint or
+    /// watchpoint before the current step. A breakpoint stops with the pc at it, and a
+    /// watchpoint stops just before the instruction that made the access.
+    ///
+    /// # Panics
+    ///
+    /// Panics if history isn't enabled.
+    pub fn run_reverse(&mut self, limit: u64) -> StopReason {
+        let current = self.history_position().expect("history must be enabled");
+        let floor = current.saturating_sub(limit);
+        let mut end = current;
+        while end > floor {
+            let start = self.seek_checkpoint(end - 1);
+            let mut found = None;
+            while self.history_position() < Some(end) {
+                let step = self.history_position().unwrap();
+                if step >= floor && self.breakpoints.contains(&self.pc) {
+                    found = Some((step, StopReason::Breakpoint(self.pc)));
+                }
+                self.advance(1);
+                if let Some((addr, access)) = self.watch_hit.take() {
+                    if step >= floor {
+                        found = Some((step, StopReason::Watchpoint { addr, access }));
+                    }
+                }
+            }
+            if let Some((step, reason)) = found {
+                self.seek(step);
+                return reason;
+            }
+            end = start;
+        }
+        self.seek(floor);
+        if floor == 0 && limit > current {
+            StopReason::HistoryStart
+        } else {
+            StopReason::Limit
+        }
+    }
+
+    /// Go back a single step.
+    ///
+    /// # Panics
+    ///
+    /// Panics if history isn't enabled.
+    pub fn reverse_step(&mut self) -> StopReason {
+        if self.history_position() == Some(0) {
+            return StopReason::HistoryStart;
+        }
+        let step = self.history_position().expect("history must be enabled") - 1;
+        self.seek(step);
+        StopReason::Limit
+    }
+
+    /// Restore the latest checkpoint at or before `step`, returning the step it was taken at.
+    fn seek_checkpoint(&mut self, step: u64) -> u64 {
+        let history = self.history.take().expect("history must be enabled");
+        let start = {
+            let h = history.borrow();
+            let idx = h.checkpoints.partition_point(|(at, _)| *at <= step) - 1;
+            let (start, snapshot) = &h.checkpoints[idx];
+            self.restore(snapshot)
+                .expect("checkpoints are of this emulator");
+            *start
+        };
+        {
+            let mut h = history.borrow_mut();
+            h.step = start;
+            h.next_read = h.reads.partition_point(|(at, _)| *at < start);
+            h.next_input = h.inputs.partition_point(|(at, _)| *at < start);
+        }
+        self.history = Some(history);
+        start
+    }
+
+    /// Go to `step`, which must be at or before the end of history.
+    fn seek(&mut self, step: u64) {
+        self.seek_checkpoint(step);
+        while self.history_position() < Some(step) {
+            self.advance(1);
+        }
+        self.watch_hit = None;
+        self.trap_hit = None;
+        self.exit_code = None;
+    }
+
+    /// Apply the logged host inputs for the coming step, and take a checkpoint if one is due.
+    pub(crate) fn begin_step(&mut self) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        let history = history.get_mut();
+        let step = history.step;
+        let mut due = Vec::new();
+        if step < history.horizon {
+            while let Some((at, input)) = history.inputs.get(history.next_input) {
+                if *at != step {
+                    break;
+                }
+                due.push(input.clone());
+                history.next_input += 1;
+            }
+        }
+        let checkpoint = step % history.interval == 0
+            && history.checkpoints.last().is_some_and(|(at, _)| *at < step);
+        for input in due {
+            self.apply_host_input(input);
+        }
+        if checkpoint {
+            let snapshot = self.snapshot();
+            let history = self.history.as_mut().unwrap().get_mut();
+            history.checkpoints.push((step, snapshot));
+            if history.checkpoints.len() > MAX_CHECKPOINTS {
+                let mut idx = 0;
+                history.checkpoints.retain(|_| {
+                    idx += 1;
+                    idx % 2 == 1
+                });
+                history.interval *= 2;
+            }
+        }
+    }
+
+    pub(crate) fn end_step(&mut self) {
+        if let Some(history) = &mut self.history {
+            let history = history.get_mut();
+            history.step += 1;
+            history.horizon = history.horizon.max(history.step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::AccessType;
+    use crate::mem::RAM_BASE;
+    use crate::{Emulator, StopReason};
+
+    /// Where the loop stores its counter, on a page of its own.
+    const COUNTER: usize = RAM_BASE + 0x1000;
+    /// The pc of the store.
+    const STORE: u64 = RAM_BASE as u64 + 4;
+
+    /// An emulator with history every other step, running a loop that counts in a0 and stores
+    /// the count to [`COUNTER`].
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(1 << 16);
+        let program = [
+            0x00150513u32, // addi a0, a0, 1
+            0x00a43023,    // sd a0, 0(s0)
+            0xff9ff06f,    // j -8
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = COUNTER as u64;
+        emu.enable_history(2);
+        emu
+    }
+
+    fn counter(emu: &Emulator) -> u64 {
+        u64::from_le_bytes(emu.memory.read_array(COUNTER).unwrap())
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut emu = emulator();
+        // Enough steps to thin out the checkpoints a couple of times
+        let mut states = vec![(emu.pc, emu.x, counter(&emu))];
+        for _ in 0..600 {
+            assert_eq!(emu.run(1), StopReason::Limit);
+            states.push((emu.pc, emu.x, counter(&emu)));
+        }
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert_eq!(emu.reverse_step(), StopReason::Limit);
+            assert_eq!(emu.history_position(), Some(states.len() as u64));
+            assert_eq!((emu.pc, emu.x, counter(&emu)), state);
+        }
+        assert_eq!(emu.reverse_step(), StopReason::HistoryStart);
+    }
+
+    #[test]
+    fn reverse_continue_to_breakpoint() {
+        let mut emu = emulator();
+        assert_eq!(emu.run(600), StopReason::Limit);
+        emu.add_breakpoint(STORE);
+        // The store of the last of the 200 iterations, and the one before it
+        for count in [200, 199] {
+            assert_eq!(emu.run_reverse(u64::MAX), StopReason::Breakpoint(STORE));
+            assert_eq!(emu.history_position(), Some(count * 3 - 2));
+            assert_eq!(
+                (emu.pc, emu.x[10], counter(&emu)),
+                (STORE, count, count - 1)
+            );
+        }
+        // The limit stops it short of the store before that
+        assert_eq!(emu.run_reverse(2), StopReason::Limit);
+        assert_eq!(emu.history_position(), Some(593));
+        assert_eq!(emu.run_reverse(u64::MAX), StopReason::Breakpoint(STORE));
+        assert_eq!(emu.history_position(), Some(592));
+    }
+
+    #[test]
+    fn reverse_continue_to_watchpoint() {
+        let mut emu = emulator();
+        assert_eq!(emu.run(600), StopReason::Limit);
+        emu.add_watchpoint(COUNTER..COUNTER + 8, AccessType::Write);
+        let watchpoint = StopReason::Watchpoint {
+            addr: COUNTER,
+            access: AccessType::Write,
+        };
+        for count in [200, 199] {
+            assert_eq!(emu.run_reverse(u64::MAX), watchpoint);
+            assert_eq!(emu.history_position(), Some(count * 3 - 2));
+            assert_eq!((emu.pc, counter(&emu)), (STORE, count - 1));
+        }
+        emu.remove_watchpoint(COUNTER..COUNTER + 8);
+        assert_eq!(emu.run_reverse(u64::MAX), StopReason::HistoryStart);
+        assert_eq!((emu.pc, emu.x[10], counter(&emu)), (RAM_BASE as u64, 0, 0));
+        // Forwards again from the start of history, the stores happen as before
+        assert_eq!(emu.run(600), StopReason::Limit);
+        assert_eq!(counter(&emu), 200);
+    }
+}
@@ -0,0 +1,163 @@
+//! Running the hart until something needs the attention of whoever is driving it.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::{device::AccessType, trap::Trap, Emulator};
+
+/// Why [`Emulator::run`] returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The instruction budget was used up.
+    Limit,
+    /// The pc reached a breakpoint, which has not been executed yet.
+    Breakpoint(u64),
+    /// The guest asked to exit with a code through a device, such as `tohost`.
+    Exit(u32),
+    /// The hart executed a WFI with no interrupt pending, so it would never wake up.
+    WaitingForInterrupt,
+    /// Fetching from the trap vector at this address faulted, so every trap would fault again.
+    BusError(u64),
+    /// A load or store touched a watched address. The instruction has completed.
+    Watchpoint { addr: usize, access: AccessType },
+    /// The hart took a trap with a cause passed to [`Emulator::add_trap_breakpoint`]. The pc is
+    /// at the trap handler, which has not been executed yet.
+    Trap(u64),
+    /// Executing backwards reached the start of history.
+    HistoryStart,
+    /// A replayed run stopped matching its input log when `minstret` had this value.
+    ReplayDiverged(u64),
+    /// The instruction at this pc retired differently in the reference model, as reported by
+    /// [`Emulator::divergence`].
+    CosimDiverged(u64),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Limit => write!(f, "instruction limit reached"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {pc:#x}"),
+            StopReason::Exit(code) => write!(f, "exited with code {code}"),
+            StopReason::WaitingForInterrupt => {
+                write!(f, "waiting for an interrupt that can't arrive")
+            }
+            StopReason::BusError(addr) => {
+                write!(f, "bus error fetching the trap vector at {addr:#x}")
+            }
+            StopReason::Watchpoint { addr, access } => {
+                write!(f, "watchpoint hit by {access:?} access at {addr:#x}")
+            }
+            StopReason::Trap(cause) => write!(f, "took a trap with mcause {cause:#x}"),
+            StopReason::HistoryStart => write!(f, "reached the start of history"),
+            StopReason::ReplayDiverged(minstret) => {
+                write!(
+                    f,
+                    "replay diverged from the input log at minstret {minstret}"
+                )
+            }
+            StopReason::CosimDiverged(pc) => {
+                write!(f, "diverged from the reference model at pc {pc:#x}")
+            }
+        }
+    }
+}
+
+impl Emulator {
+    /// Execute at most `limit` instructions, stopping early for any of the reasons in
+    /// [`StopReason`]. A breakpoint at the pc when this is called is stepped over, so running
+    /// again after a breakpoint makes progress.
+    ///
+    /// Taking an interrupt doesn't count towards the limit, but an instruction that traps does.
+    pub fn run(&mut self, limit: u64) -> StopReason {
+        self.exit_code = None;
+        self.watch_hit = None;
+        self.trap_hit = None;
+        let mut executed = 0;
+        let mut first = true;
+        loop {
+            if executed >= limit {
+                return StopReason::Limit;
+            }
+            let pc = self.pc;
+            if !first && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+            first = false;
+            executed += self.advance(limit - executed);
+            if let Some(code) = self.exit_code.take() {
+                return StopReason::Exit(code);
+            }
+            if let Some(minstret) = self.replay_divergence() {
+                return StopReason::ReplayDiverged(minstret);
+            }
+            if let Some(pc) = self.cosim_divergence() {
+                return StopReason::CosimDiverged(pc);
+            }
+            if let Some((addr, access)) = self.watch_hit.take() {
+                return StopReason::Watchpoint { addr, access };
+            }
+            if let Some(cause) = self.trap_hit.take() {
+                return StopReason::Trap(cause);
+            }
+            if self.waiting && self.machine_csrs.mip & self.machine_csrs.mie == 0 {
+                // Carry on past the WFI if run again, which is a legal implementation of it
+                self.waiting = false;
+                return StopReason::WaitingForInterrupt;
+            }
+            if self.pc == pc
+                && pc == self.machine_csrs.mtvec
+                && self.machine_csrs.mepc == pc
+                && self.machine_csrs.mcause == Trap::InstrAccessFault.to_code()
+            {
+                return StopReason::BusError(pc);
+            }
+        }
+    }
+
+    /// Stop [`Emulator::run`] before executing the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Stop [`Emulator::run`] when the hart takes a trap with this `mcause`, which has the top bit
+    /// set for interrupts.
+    pub fn add_trap_breakpoint(&mut self, cause: u64) {
+        self.trap_breakpoints.insert(cause);
+    }
+
+    pub fn remove_trap_breakpoint(&mut self, cause: u64) {
+        self.trap_breakpoints.remove(&cause);
+    }
+
+    /// Stop [`Emulator::run`] after any load or store permitted by `access` which touches
+    /// `range`.
+    pub fn add_watchpoint(&mut self, range: Range<usize>, access: AccessType) {
+        self.watchpoints.push((range, access));
+    }
+
+    /// Remove every watchpoint on exactly `range`.
+    pub fn remove_watchpoint(&mut self, range: Range<usize>) {
+        self.watchpoints.retain(|(watched, _)| *watched != range);
+    }
+
+    /// Record an access of `width` bytes at `addr` by the guest, for watchpoints.
+    pub(crate) fn watch(&mut self, addr: usize, width: usize, access: AccessType) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        let end = addr.saturating_add(width);
+        let hit = self.watchpoints.iter().any(|(range, watched)| {
+            range.start < end
+                && addr < range.end
+                && (access.can_read() && watched.can_read()
+                    || access.can_write() && watched.can_write())
+        });
+        if hit {
+            self.watch_hit = Some((addr, access));
+        }
+    }
+}
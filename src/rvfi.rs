@@ -0,0 +1,404 @@
+//! A server for the RVFI-DII protocol of TestRIG, so that the emulator can take part in random
+//! testing against other implementations, either as the reference or as the implementation under
+//! test.
+//!
+//! TestRIG injects instructions one at a time, and each is executed at the pc as if it had been
+//! fetched from there, whatever memory holds. Every instruction is answered with an RVFI trace of
+//! what it did, including when it traps. An end of trace command resets the hart and its memory
+//! to a fresh state with the pc at [`RESET_PC`], and is answered with a trace that only has
+//! `rvfi_halt` set.
+//!
+//! Packets are those of version 1 of the protocol, little endian and fixed size. An instruction
+//! packet is 8 bytes: the instruction (4 bytes), a time (2), the command (1) and padding (1). An
+//! execution packet is 88 bytes, laid out as in [`Trace::to_bytes`].
+
+use std::io::{self, Read, Write};
+
+use crate::{instructions::AccessKind, Emulator};
+
+/// Where the pc starts after a reset.
+pub const RESET_PC: u64 = 0x8000_0000;
+
+/// The command ending a trace, which resets the hart.
+const CMD_END: u8 = 0;
+/// The command to execute an instruction.
+const CMD_INSTRUCTION: u8 = 1;
+
+/// What an instruction did, as reported by the RISC-V Formal Interface.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Trace {
+    /// The position of the instruction in the trace, counting from 0.
+    pub order: u64,
+    pub pc_rdata: u64,
+    pub pc_wdata: u64,
+    pub insn: u64,
+    pub rs1_data: u64,
+    pub rs2_data: u64,
+    pub rd_wdata: u64,
+    pub mem_addr: u64,
+    pub mem_rdata: u64,
+    pub mem_wdata: u64,
+    /// The bytes read from `mem_addr` onwards, one bit per byte.
+    pub mem_rmask: u8,
+    /// The bytes written to `mem_addr` onwards, one bit per byte.
+    pub mem_wmask: u8,
+    pub rs1_addr: u8,
+    pub rs2_addr: u8,
+    /// The register written, which is 0 when nothing is written.
+    pub rd_addr: u8,
+    pub trap: bool,
+    pub halt: bool,
+    /// Whether this is the first instruction of a trap handler.
+    pub intr: bool,
+}
+
+impl Trace {
+    /// The execution packet holding the trace: the ten 64 bit fields from `order` to
+    /// `mem_wdata`, then the eight single byte fields from `mem_rmask` to `intr`.
+    pub fn to_bytes(&self) -> [u8; 88] {
+        let words = [
+            self.order,
+            self.pc_rdata,
+            self.pc_wdata,
+            self.insn,
+            self.rs1_data,
+            self.rs2_data,
+            self.rd_wdata,
+            self.mem_addr,
+            self.mem_rdata,
+            self.mem_wdata,
+        ];
+        let bytes = [
+            self.mem_rmask,
+            self.mem_wmask,
+            self.rs1_addr,
+            self.rs2_addr,
+            self.rd_addr,
+            self.trap.into(),
+            self.halt.into(),
+            self.intr.into(),
+        ];
+        let mut packet = [0; 88];
+        for (chunk, word) in packet.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        packet[80..].copy_from_slice(&bytes);
+        packet
+    }
+}
+
+impl Emulator {
+    /// Execute `opcode` at the pc as if it had been fetched from there, and describe what it did.
+    /// Interrupts are never taken, and `order` and `intr` are left for the caller to fill in.
+    pub fn inject(&mut self, opcode: u32) -> Trace {
+        let decoded = crate::decode(opcode);
+        let pc = self.pc;
+        let x = self.x;
+        let (rd, [rs1, rs2]) = match &decoded {
+            Ok(instruction) => instruction.registers(),
+            Err(_) => (None, [None, None]),
+        };
+        let (rs1, rs2) = (rs1.unwrap_or(0), rs2.unwrap_or(0));
+        let access = decoded
+            .as_ref()
+            .ok()
+            .and_then(|instruction| instruction.memory_access());
+        // Loads must see memory as it was, but only RAM and ROM can be read without side effects
+        let read_before = access.map(|access| {
+            let addr = x[access.base].wrapping_add_signed(access.offset);
+            self.memory_word(addr, access.width)
+        });
+
+        let trapped = self.retire(decoded.clone().map(|instruction| (instruction, opcode)));
+        let mut trace = Trace {
+            pc_rdata: pc,
+            pc_wdata: self.pc,
+            insn: opcode.into(),
+            rs1_addr: rs1 as u8,
+            rs1_data: x[rs1],
+            rs2_addr: rs2 as u8,
+            rs2_data: x[rs2],
+            trap: trapped,
+            ..Trace::default()
+        };
+        if trapped {
+            return trace;
+        }
+
+        if let Some(rd) = rd.filter(|&rd| rd != 0) {
+            trace.rd_addr = rd as u8;
+            trace.rd_wdata = self.x[rd];
+        }
+        if let (Some(access), Ok(instruction)) = (access, &decoded) {
+            let addr = x[access.base].wrapping_add_signed(access.offset);
+            let mask = ((1u16 << access.width) - 1) as u8;
+            trace.mem_addr = addr;
+            if access.kind != AccessKind::Store {
+                trace.mem_rmask = mask;
+                trace.mem_rdata = read_before.unwrap_or(0);
+            }
+            match access.kind {
+                AccessKind::Load => {}
+                AccessKind::Store => {
+                    if self.store_succeeded(instruction, addr, x) {
+                        trace.mem_wmask = mask;
+                        trace.mem_wdata = x[rs2] & (u64::MAX >> (64 - 8 * access.width));
+                    }
+                }
+                AccessKind::Amo => {
+                    trace.mem_wmask = mask;
+                    trace.mem_wdata = self.memory_word(addr, access.width);
+                }
+            }
+        }
+        trace
+    }
+
+    /// The `width` bytes at `addr` as a little endian value, or 0 if they aren't all in RAM or
+    /// ROM.
+    fn memory_word(&self, addr: u64, width: usize) -> u64 {
+        let bytes = self.debug_read(addr as usize, width);
+        if bytes.len() != width {
+            return 0;
+        }
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |val, &byte| val << 8 | u64::from(byte))
+    }
+}
+
+/// Serve a single TestRIG connection, reading instruction packets from `input` and answering on
+/// `output`, until TestRIG disconnects. `reset` creates the emulator in its state after a reset,
+/// and the pc is then moved to [`RESET_PC`].
+pub fn serve(
+    mut reset: impl FnMut() -> Emulator,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut emu = reset();
+    emu.set_pc(RESET_PC);
+    let mut order = 0;
+    let mut trapped = false;
+    let mut packet = [0; 8];
+    loop {
+        match input.read_exact(&mut packet) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let opcode = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let trace = match packet[6] {
+            CMD_END => {
+                emu = reset();
+                emu.set_pc(RESET_PC);
+                order = 0;
+                trapped = false;
+                Trace {
+                    halt: true,
+                    ..Trace::default()
+                }
+            }
+            CMD_INSTRUCTION => {
+                let trace = Trace {
+                    order,
+                    intr: trapped,
+                    ..emu.inject(opcode)
+                };
+                order += 1;
+                trapped = trace.trap;
+                trace
+            }
+            cmd => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown RVFI-DII command {cmd}"),
+                ))
+            }
+        };
+        output.write_all(&trace.to_bytes())?;
+        output.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(opcode: u32) -> [u8; 8] {
+        let mut packet = [0; 8];
+        packet[..4].copy_from_slice(&opcode.to_le_bytes());
+        packet[6] = CMD_INSTRUCTION;
+        packet
+    }
+
+    const END: [u8; 8] = [0, 0, 0, 0, 0, 0, CMD_END, 0];
+
+    /// Read back a trace from its execution packet.
+    fn trace(packet: &[u8]) -> Trace {
+        let word =
+            |idx: usize| u64::from_le_bytes(packet[idx * 8..idx * 8 + 8].try_into().unwrap());
+        let flag = |idx: usize| match packet[idx] {
+            0 => false,
+            1 => true,
+            byte => panic!("{byte} isn't a flag"),
+        };
+        Trace {
+            order: word(0),
+            pc_rdata: word(1),
+            pc_wdata: word(2),
+            insn: word(3),
+            rs1_data: word(4),
+            rs2_data: word(5),
+            rd_wdata: word(6),
+            mem_addr: word(7),
+            mem_rdata: word(8),
+            mem_wdata: word(9),
+            mem_rmask: packet[80],
+            mem_wmask: packet[81],
+            rs1_addr: packet[82],
+            rs2_addr: packet[83],
+            rd_addr: packet[84],
+            trap: flag(85),
+            halt: flag(86),
+            intr: flag(87),
+        }
+    }
+
+    /// Serve `packets` on a fresh emulator, returning the traces sent back.
+    fn session(packets: &[[u8; 8]]) -> Vec<Trace> {
+        let mut output = Vec::new();
+        serve(
+            || Emulator::new(1 << 16),
+            packets.as_flattened(),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output.len() % 88, 0);
+        output.chunks_exact(88).map(trace).collect()
+    }
+
+    #[test]
+    fn traces_round_trip_through_packets() {
+        let original = Trace {
+            order: 1,
+            pc_rdata: 2,
+            pc_wdata: 3,
+            insn: 4,
+            rs1_data: 5,
+            rs2_data: 6,
+            rd_wdata: 7,
+            mem_addr: 8,
+            mem_rdata: 9,
+            mem_wdata: u64::MAX,
+            mem_rmask: 0x0f,
+            mem_wmask: 0xf0,
+            rs1_addr: 31,
+            rs2_addr: 30,
+            rd_addr: 29,
+            trap: true,
+            halt: false,
+            intr: true,
+        };
+        assert_eq!(trace(&original.to_bytes()), original);
+        assert_eq!(trace(&Trace::default().to_bytes()), Trace::default());
+    }
+
+    #[test]
+    fn instructions_are_answered_with_what_they_did() {
+        let traces = session(&[
+            instruction(0x00500513), // li a0, 5
+            instruction(0x00000297), // auipc t0, 0
+            instruction(0x04a2b023), // sd a0, 64(t0)
+            instruction(0x0402b583), // ld a1, 64(t0)
+            instruction(0x04028313), // addi t1, t0, 64
+            instruction(0x00a3362f), // amoadd.d a2, a0, (t1)
+        ]);
+        let pc = RESET_PC;
+        assert_eq!(
+            traces[0],
+            Trace {
+                order: 0,
+                pc_rdata: pc,
+                pc_wdata: pc + 4,
+                insn: 0x00500513,
+                rd_addr: 10,
+                rd_wdata: 5,
+                ..Trace::default()
+            }
+        );
+        assert_eq!((traces[1].rd_addr, traces[1].rd_wdata), (5, pc + 4));
+        assert_eq!(
+            traces[2],
+            Trace {
+                order: 2,
+                pc_rdata: pc + 8,
+                pc_wdata: pc + 12,
+                insn: 0x04a2b023,
+                rs1_addr: 5,
+                rs1_data: pc + 4,
+                rs2_addr: 10,
+                rs2_data: 5,
+                mem_addr: pc + 68,
+                mem_wmask: 0xff,
+                mem_wdata: 5,
+                ..Trace::default()
+            }
+        );
+        let load = &traces[3];
+        assert_eq!((load.rd_addr, load.rd_wdata), (11, 5));
+        assert_eq!(
+            (load.mem_addr, load.mem_rmask, load.mem_rdata),
+            (pc + 68, 0xff, 5)
+        );
+        assert_eq!(load.mem_wmask, 0);
+        let amo = &traces[5];
+        assert_eq!((amo.rd_addr, amo.rd_wdata), (12, 5));
+        assert_eq!((amo.mem_rmask, amo.mem_rdata), (0xff, 5));
+        assert_eq!((amo.mem_wmask, amo.mem_wdata), (0xff, 10));
+        let orders: Vec<u64> = traces.iter().map(|trace| trace.order).collect();
+        assert_eq!(orders, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn traps_and_resets() {
+        let traces = session(&[
+            instruction(0x00500513), // li a0, 5
+            instruction(0xffffffff),
+            instruction(0x00500513),
+            END,
+            instruction(0x00050593), // mv a1, a0
+        ]);
+        assert!(traces[1].trap && !traces[1].intr);
+        assert_eq!((traces[1].rd_addr, traces[1].rd_wdata), (0, 0));
+        // The instruction after a trap is the first of the handler
+        assert!(!traces[2].trap && traces[2].intr);
+        assert_eq!(traces[2].pc_rdata, traces[1].pc_wdata);
+        assert_eq!(
+            traces[3],
+            Trace {
+                halt: true,
+                ..Trace::default()
+            }
+        );
+        // After the reset a0 is 0 again, and the order and pc start over
+        let after = &traces[4];
+        assert_eq!(
+            (after.order, after.pc_rdata, after.intr),
+            (0, RESET_PC, false)
+        );
+        assert_eq!((after.rs1_addr, after.rs1_data, after.rd_wdata), (10, 0, 0));
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let mut packet = instruction(0x00500513);
+        packet[6] = 7;
+        let mut output = Vec::new();
+        let err = serve(|| Emulator::new(4096), &packet[..], &mut output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(output.is_empty());
+        // A partial packet is the end of the connection
+        serve(|| Emulator::new(4096), &packet[..5], &mut output).unwrap();
+        assert!(output.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! Confining the host files a guest can reach to a single directory.
+//!
+//! Guest paths are resolved as if the sandbox root were the root of the filesystem: absolute
+//! paths start at the root, relative paths start there too since the guest has no working
+//! directory of its own, and `..` can't go above it. Symbolic links are followed by the host, so
+//! a path is also refused if the host would resolve it to somewhere outside the root.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    /// The canonical path of the root.
+    root: PathBuf,
+}
+
+impl Sandbox {
+    /// A sandbox of the directory `root`, which must exist.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Sandbox> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Sandbox { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The host path of the guest path `path`.
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let mut resolved = self.root.clone();
+        let mut depth = 0;
+        for component in Path::new(path).components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => {
+                    resolved = self.root.clone();
+                    depth = 0;
+                }
+                Component::CurDir => {}
+                Component::ParentDir if depth == 0 => return Err(escaped(path)),
+                Component::ParentDir => {
+                    resolved.pop();
+                    depth -= 1;
+                }
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    depth += 1;
+                }
+            }
+        }
+        // A dangling link could be followed to create a file anywhere
+        if resolved.symlink_metadata().is_ok() && resolved.canonicalize().is_err() {
+            return Err(escaped(path));
+        }
+        // The path itself might not exist yet, when it is being created, so check where the host
+        // puts the deepest part of it that does
+        let existing = resolved
+            .ancestors()
+            .find_map(|ancestor| ancestor.canonicalize().ok())
+            .ok_or_else(|| escaped(path))?;
+        if !existing.starts_with(&self.root) {
+            return Err(escaped(path));
+        }
+        Ok(resolved)
+    }
+}
+
+fn escaped(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{path} is outside the sandbox"),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A new empty directory, which is removed along with everything in it when dropped.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("riscv-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path.canonicalize().unwrap())
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A sandbox with a directory `dir` holding a file `file`, next to a directory `outside`
+    /// with a file `secret` that isn't in it.
+    fn sandbox(name: &str) -> (TempDir, Sandbox) {
+        let temp = TempDir::new(name);
+        fs::create_dir_all(temp.path().join("root/dir")).unwrap();
+        fs::write(temp.path().join("root/dir/file"), "").unwrap();
+        fs::create_dir(temp.path().join("outside")).unwrap();
+        fs::write(temp.path().join("outside/secret"), "").unwrap();
+        let sandbox = Sandbox::new(temp.path().join("root")).unwrap();
+        (temp, sandbox)
+    }
+
+    fn is_escape(result: io::Result<PathBuf>) -> bool {
+        result.is_err_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+    }
+
+    #[test]
+    fn parent_dirs_stop_at_the_root() {
+        let (_temp, sandbox) = sandbox("parent");
+        assert!(is_escape(sandbox.resolve("..")));
+        assert!(is_escape(sandbox.resolve("../outside/secret")));
+        assert!(is_escape(sandbox.resolve("dir/../../outside/secret")));
+        assert!(is_escape(sandbox.resolve("/..")));
+        assert_eq!(sandbox.resolve("dir/..").unwrap(), sandbox.root());
+    }
+
+    #[test]
+    fn absolute_paths_start_at_the_root() {
+        let (temp, sandbox) = sandbox("absolute");
+        let file = sandbox.root().join("dir/file");
+        assert_eq!(sandbox.resolve("/dir/file").unwrap(), file);
+        assert_eq!(sandbox.resolve("dir/file").unwrap(), file);
+        assert_eq!(sandbox.resolve("dir//./file").unwrap(), file);
+        // A host path is taken to be inside the root, where it doesn't exist
+        let secret = temp.path().join("outside/secret");
+        let inside = sandbox.root().join(secret.strip_prefix("/").unwrap());
+        assert_eq!(sandbox.resolve(secret.to_str().unwrap()).unwrap(), inside);
+    }
+
+    #[test]
+    fn parent_dirs_are_resolved_by_name() {
+        let (_temp, sandbox) = sandbox("by-name");
+        assert_eq!(
+            sandbox.resolve("missing/../dir/file").unwrap(),
+            sandbox.root().join("dir/file")
+        );
+        assert_eq!(
+            sandbox.resolve("dir/../new").unwrap(),
+            sandbox.root().join("new")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_within_the_root() {
+        let (_temp, sandbox) = sandbox("link-inside");
+        std::os::unix::fs::symlink("dir", sandbox.root().join("link")).unwrap();
+        assert_eq!(
+            sandbox.resolve("link/file").unwrap(),
+            sandbox.root().join("link/file")
+        );
+        assert!(sandbox.resolve("link/new").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_dirs_outside_the_root_are_refused() {
+        let (temp, sandbox) = sandbox("link-outside");
+        let link = sandbox.root().join("dir/out");
+        std::os::unix::fs::symlink(temp.path().join("outside"), &link).unwrap();
+        assert!(is_escape(sandbox.resolve("dir/out")));
+        assert!(is_escape(sandbox.resolve("dir/out/secret")));
+        // Nor can a file be created through it
+        assert!(is_escape(sandbox.resolve("dir/out/new")));
+        assert!(is_escape(sandbox.resolve("dir/out/new/deeper")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlinks_are_refused() {
+        let (temp, sandbox) = sandbox("dangling");
+        let target = temp.path().join("outside/new");
+        std::os::unix::fs::symlink(target, sandbox.root().join("dangling")).unwrap();
+        // Opening it to create a file would create the target, outside the root
+        assert!(is_escape(sandbox.resolve("dangling")));
+    }
+}
@@ -0,0 +1,477 @@
+//! RISC-V semihosting, through which bare-metal programs use the host's console, files and
+//! clock.
+//!
+//! A call is an `ebreak` between a `slli x0, x0, 0x1f` and a `srai x0, x0, 7`, all three
+//! uncompressed. The operation is in a0 and its parameter in a1, which for most operations is
+//! the address of a block of 64 bit arguments, and the result is returned in a0. The operations
+//! are those of Arm semihosting:
+//!
+//! - `SYS_OPEN`, `SYS_CLOSE`, `SYS_READ`, `SYS_WRITE`, `SYS_SEEK`, `SYS_FLEN` and `SYS_ISTTY` on
+//!   files, where the file named `:tt` is the host's stdin when opened for reading, stdout when
+//!   opened for writing and stderr when opened for appending,
+//! - `SYS_WRITEC` and `SYS_WRITE0`, which write a character or a NUL terminated string to stdout,
+//! - `SYS_CLOCK`, the centiseconds since semihosting was enabled, and `SYS_TIME`, the seconds
+//!   since the Unix epoch,
+//! - `SYS_ERRNO`, the Linux error number of the last call that failed,
+//! - `SYS_HEAPINFO`, which leaves the heap and stack where the program's runtime puts them,
+//! - `SYS_EXIT` and `SYS_EXIT_EXTENDED`, which exit with the code given along with
+//!   `ADP_Stopped_ApplicationExit`, or with 1 for any other reason.
+//!
+//! Other operations fail with `ENOSYS`. Files other than the console can only be opened inside
+//! the sandbox, and opening them fails with `EACCES` when there is none.
+//!
+//! The result of each call and the bytes it writes to memory are inputs from the host, which are
+//! logged when recording a run and kept in the debugger's history. When the call is replayed, or
+//! executed again after going back in history, it returns the logged result without reaching the
+//! host, so files aren't written or opened again and the clock reads as it did.
+
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom};
+use std::mem;
+use std::time::{Instant, SystemTime};
+
+use crate::host_file::{
+    errno, write_console, HostFile, EACCES, EBADF, EFAULT, EINVAL, ENAMETOOLONG, ENOSYS,
+    MAX_TRANSFER, PATH_MAX,
+};
+use crate::replay::HostInput;
+use crate::sandbox::Sandbox;
+use crate::Emulator;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// The instructions around the `ebreak` of a call.
+const ENTRY: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+const EXIT: u32 = 0x40705013;
+
+/// The longest string written by `SYS_WRITE0`.
+const MAX_STRING: usize = 1 << 16;
+
+/// What a call did to the guest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CallResult {
+    /// The result returned in a0.
+    pub value: u64,
+    /// The bytes written to memory, with the address they were written at.
+    pub writes: Vec<(u64, Vec<u8>)>,
+    /// The exit code the guest asked for.
+    pub exit: Option<u32>,
+}
+
+pub struct Semihosting {
+    sandbox: Option<Sandbox>,
+    /// Open files by handle, where 0 is never used since handles are nonzero.
+    files: Vec<Option<HostFile>>,
+    errno: u64,
+    start: Instant,
+    /// The result of the call in progress, so far.
+    result: CallResult,
+    /// The logged result of the next call, which it returns instead of reaching the host.
+    logged: Option<CallResult>,
+}
+
+impl Semihosting {
+    /// Semihosting which can reach the host files in `sandbox`, if any.
+    pub fn new(sandbox: Option<Sandbox>) -> Semihosting {
+        Semihosting {
+            sandbox,
+            files: vec![None],
+            errno: 0,
+            start: Instant::now(),
+            result: CallResult::default(),
+            logged: None,
+        }
+    }
+
+    /// Have the next call return `result`, as logged when it was first executed.
+    pub(crate) fn feed(&mut self, result: CallResult) {
+        self.logged = Some(result);
+    }
+
+    /// Carry out operation `op` with parameter `param`, returning what it did to the guest.
+    fn call(&mut self, emu: &mut Emulator, op: u64, param: u64) -> CallResult {
+        let value = match op {
+            SYS_OPEN => self.open(emu, param),
+            SYS_CLOSE => params(emu, param).and_then(|[handle]| {
+                self.files
+                    .get_mut(handle as usize)
+                    .and_then(Option::take)
+                    .map(|_| 0)
+                    .ok_or(EBADF)
+            }),
+            SYS_WRITEC => read(emu, param, 1).map(|byte| {
+                let _ = write_console(&mut io::stdout(), &byte);
+                0
+            }),
+            SYS_WRITE0 => {
+                let string: Vec<u8> = (param..)
+                    .take(MAX_STRING)
+                    .map_while(|addr| read(emu, addr, 1).ok().map(|byte| byte[0]))
+                    .take_while(|&byte| byte != 0)
+                    .collect();
+                let _ = write_console(&mut io::stdout(), &string);
+                Ok(0)
+            }
+            SYS_WRITE => params(emu, param).map(|[handle, buf, len]| {
+                let written = self.write(emu, handle, buf, len).unwrap_or_else(|errno| {
+                    self.errno = errno;
+                    0
+                });
+                len - written
+            }),
+            SYS_READ => params(emu, param).map(|[handle, buf, len]| {
+                let count = self.read(emu, handle, buf, len).unwrap_or_else(|errno| {
+                    self.errno = errno;
+                    0
+                });
+                len - count
+            }),
+            SYS_ISTTY => params(emu, param).and_then(|[handle]| match self.file(handle)? {
+                HostFile::File(_) => Ok(0),
+                _ => Ok(1),
+            }),
+            SYS_SEEK => params(emu, param).and_then(|[handle, pos]| match self.file(handle)? {
+                HostFile::File(file) => file.seek(SeekFrom::Start(pos)).map(|_| 0).map_err(errno),
+                _ => Err(EINVAL),
+            }),
+            SYS_FLEN => params(emu, param).and_then(|[handle]| match self.file(handle)? {
+                HostFile::File(file) => file.metadata().map(|meta| meta.len()).map_err(errno),
+                _ => Err(EINVAL),
+            }),
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u64),
+            SYS_TIME => Ok(SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())),
+            SYS_ERRNO => Ok(self.errno),
+            // Zeros leave the heap and stack where the runtime's defaults put them
+            SYS_HEAPINFO => params(emu, param)
+                .and_then(|[block]| self.store(emu, block, &[0; 32]))
+                .map(|()| 0),
+            SYS_EXIT | SYS_EXIT_EXTENDED => params(emu, param).map(|[reason, code]| {
+                self.result.exit = Some(if reason == ADP_STOPPED_APPLICATION_EXIT {
+                    code as u32
+                } else {
+                    1
+                });
+                0
+            }),
+            _ => Err(ENOSYS),
+        };
+        self.result.value = value.unwrap_or_else(|errno| {
+            self.errno = errno;
+            u64::MAX
+        });
+        mem::take(&mut self.result)
+    }
+
+    fn open(&mut self, emu: &mut Emulator, param: u64) -> Result<u64, u64> {
+        let [name, mode, len] = params(emu, param)?;
+        if len > PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let name = read(emu, name, len)?;
+        let name = std::str::from_utf8(&name).map_err(|_| EINVAL)?;
+        // The modes are those of fopen, with every other one binary
+        let file = match (name, mode / 2) {
+            (":tt", 0 | 1) => HostFile::Stdin,
+            (":tt", 2 | 3) => HostFile::Stdout,
+            (":tt", 4 | 5) => HostFile::Stderr,
+            (_, 0..=5) => {
+                let sandbox = self.sandbox.as_ref().ok_or(EACCES)?;
+                let path = sandbox.resolve(name).map_err(errno)?;
+                let mode = mode / 2;
+                OpenOptions::new()
+                    .read(mode != 2 && mode != 4)
+                    .write(mode == 1 || mode == 2 || mode == 3)
+                    .append(mode >= 4)
+                    .create(mode >= 2)
+                    .truncate(mode == 2 || mode == 3)
+                    .open(path)
+                    .map(HostFile::File)
+                    .map_err(errno)?
+            }
+            _ => return Err(EINVAL),
+        };
+        let handle = match self.files.iter().skip(1).position(Option::is_none) {
+            Some(handle) => handle + 1,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[handle] = Some(file);
+        Ok(handle as u64)
+    }
+
+    /// Write `len` bytes at `buf` to `handle`, returning how many were written.
+    fn write(&mut self, emu: &mut Emulator, handle: u64, buf: u64, len: u64) -> Result<u64, u64> {
+        let bytes = read(emu, buf, len.min(MAX_TRANSFER))?;
+        let count = self.file(handle)?.write(&bytes)?;
+        Ok(count as u64)
+    }
+
+    /// Read up to `len` bytes from `handle` to `buf`, returning how many were read.
+    fn read(&mut self, emu: &mut Emulator, handle: u64, buf: u64, len: u64) -> Result<u64, u64> {
+        let mut bytes = vec![0; len.min(MAX_TRANSFER) as usize];
+        let count = self.file(handle)?.read(&mut bytes)?;
+        self.store(emu, buf, &bytes[..count])?;
+        Ok(count as u64)
+    }
+
+    /// Write `bytes` to memory at `addr`, as part of the result of the call.
+    fn store(&mut self, emu: &mut Emulator, addr: u64, bytes: &[u8]) -> Result<(), u64> {
+        emu.write_bytes(addr as usize, bytes).map_err(|_| EFAULT)?;
+        if !bytes.is_empty() {
+            self.result.writes.push((addr, bytes.to_vec()));
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, handle: u64) -> Result<&mut HostFile, u64> {
+        self.files
+            .get_mut(handle as usize)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+}
+
+/// The `N` arguments in the block at `addr`.
+fn params<const N: usize>(emu: &Emulator, addr: u64) -> Result<[u64; N], u64> {
+    let bytes = read(emu, addr, N as u64 * 8)?;
+    let mut params = [0; N];
+    for (param, word) in params.iter_mut().zip(bytes.chunks_exact(8)) {
+        *param = u64::from_le_bytes(word.try_into().unwrap());
+    }
+    Ok(params)
+}
+
+fn read(emu: &Emulator, addr: u64, len: u64) -> Result<Vec<u8>, u64> {
+    emu.read_bytes(addr as usize, len as usize)
+        .map(|bytes| bytes.into_owned())
+        .map_err(|_| EFAULT)
+}
+
+impl Emulator {
+    /// Service semihosting calls with `semihosting` from now on, rather than raising a breakpoint
+    /// exception for them.
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(Box::new(semihosting));
+    }
+
+    /// Service the call if the `ebreak` at the pc is a semihosting call, returning whether it
+    /// was.
+    pub(crate) fn semihosting_call(&mut self) -> bool {
+        let word = |addr: Option<u64>| {
+            addr.and_then(|addr| self.memory.read_bytes(addr as usize, 4).ok())
+                .map(|bytes| u32::from_le_bytes(bytes[..].try_into().unwrap()))
+        };
+        if self.semihosting.is_none()
+            || word(Some(self.pc)) != Some(EBREAK)
+            || word(self.pc.checked_sub(4)) != Some(ENTRY)
+            || word(self.pc.checked_add(4)) != Some(EXIT)
+        {
+            return false;
+        }
+        let mut semihosting = self.semihosting.take().unwrap();
+        let result = match semihosting.logged.take() {
+            Some(result) => {
+                for (addr, bytes) in &result.writes {
+                    // The writes succeeded when the call was first executed
+                    let _ = self.write_bytes(*addr as usize, bytes);
+                }
+                result
+            }
+            None => {
+                let result = semihosting.call(self, self.x[10], self.x[11]);
+                let input = HostInput::Semihosting(result.clone());
+                self.log_host_input(&input);
+                if let Some(history) = &mut self.history {
+                    history.get_mut().record_host_input(input);
+                }
+                result
+            }
+        };
+        self.semihosting = Some(semihosting);
+        self.x[10] = result.value;
+        if result.exit.is_some() {
+            self.exit_code = result.exit;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::RAM_BASE;
+    use crate::sandbox::tests::TempDir;
+    use crate::StopReason;
+
+    /// Where the arguments of the calls start, which the program has in s0.
+    const ARGS: usize = RAM_BASE + 0x1000;
+    const NAME: usize = ARGS + 0x100;
+    const MESSAGE: usize = ARGS + 0x110;
+    const BUFFER: usize = ARGS + 0x120;
+
+    #[test]
+    fn writes_and_reads_back_a_file() {
+        // Opens out.txt to write, writes to it, closes it, opens it again to read, reads it back
+        // and exits with 3, calling each through `semihost`. The results of the writes and reads
+        // go in s1 and s2.
+        let program: [u32; 27] = [
+            0x00100513, // li a0, 1
+            0x00040593, // mv a1, s0
+            0x054000ef, // jal semihost
+            0x02a43023, // sd a0, 32(s0)
+            0x00500513, // li a0, 5
+            0x02040593, // addi a1, s0, 32
+            0x044000ef, // jal semihost
+            0x00050493, // mv s1, a0
+            0x00200513, // li a0, 2
+            0x02040593, // addi a1, s0, 32
+            0x034000ef, // jal semihost
+            0x00100513, // li a0, 1
+            0x04040593, // addi a1, s0, 64
+            0x028000ef, // jal semihost
+            0x06a43023, // sd a0, 96(s0)
+            0x00600513, // li a0, 6
+            0x06040593, // addi a1, s0, 96
+            0x018000ef, // jal semihost
+            0x00050913, // mv s2, a0
+            0x01800513, // li a0, 24
+            0x08040593, // addi a1, s0, 128
+            0x008000ef, // jal semihost
+            0x0000006f, // j done
+            0x01f01013, // slli zero, zero, 31
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x00008067, // ret
+        ];
+        let temp = TempDir::new("semihosting");
+        let mut emu = Emulator::new(1 << 16);
+        emu.enable_semihosting(Semihosting::new(Some(Sandbox::new(temp.path()).unwrap())));
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        let (name, message, buffer) = (NAME as u64, MESSAGE as u64, BUFFER as u64);
+        let args = [
+            [name, 4, 7, 0],                         // SYS_OPEN of out.txt with "w"
+            [0, message, 5, 0],                      // SYS_WRITE, then SYS_CLOSE, of the handle
+            [name, 0, 7, 0],                         // SYS_OPEN of out.txt with "r"
+            [0, buffer, 5, 0],                       // SYS_READ of the handle
+            [ADP_STOPPED_APPLICATION_EXIT, 3, 0, 0], // SYS_EXIT
+        ];
+        write_words(&mut emu, ARGS, args.as_flattened());
+        emu.write_bytes(NAME, b"out.txt").unwrap();
+        emu.write_bytes(MESSAGE, b"hello").unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = ARGS as u64;
+
+        assert_eq!(emu.run(1000), StopReason::Exit(3));
+        // Nothing was left unwritten or unread
+        assert_eq!((emu.x[9], emu.x[18]), (0, 0));
+        assert_eq!(emu.memory.read_array(BUFFER).unwrap(), *b"hello");
+        assert_eq!(
+            std::fs::read(temp.path().join("out.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn files_are_only_opened_in_the_sandbox() {
+        let temp = TempDir::new("semihosting-outside");
+        let mut emu = Emulator::new(1 << 16);
+        for (name, sandbox) in [
+            (&b"../out.txt"[..], Some(Sandbox::new(temp.path()).unwrap())),
+            (b"out.txt", None),
+        ] {
+            let mut semihosting = Semihosting::new(sandbox);
+            emu.write_bytes(NAME, name).unwrap();
+            write_words(&mut emu, ARGS, &[NAME as u64, 4, name.len() as u64]);
+            assert_eq!(
+                semihosting.call(&mut emu, SYS_OPEN, ARGS as u64).value,
+                u64::MAX
+            );
+            assert_eq!(semihosting.call(&mut emu, SYS_ERRNO, 0).value, EACCES);
+        }
+        assert!(!temp.path().join("out.txt").exists());
+    }
+
+    #[test]
+    fn calls_are_not_repeated_when_going_back_in_history() {
+        // Opens out.txt to append, writes to it and reads the clock into s1
+        let program: [u32; 15] = [
+            0x00100513, // li a0, 1
+            0x00040593, // mv a1, s0
+            0x024000ef, // jal semihost
+            0x02a43023, // sd a0, 32(s0)
+            0x00500513, // li a0, 5
+            0x02040593, // addi a1, s0, 32
+            0x014000ef, // jal semihost
+            0x01000513, // li a0, 16
+            0x00c000ef, // jal semihost
+            0x00050493, // mv s1, a0
+            0x0000006f, // j done
+            0x01f01013, // slli zero, zero, 31
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x00008067, // ret
+        ];
+        let temp = TempDir::new("semihosting-history");
+        let mut emu = Emulator::new(1 << 16);
+        emu.enable_semihosting(Semihosting::new(Some(Sandbox::new(temp.path()).unwrap())));
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        let (name, message) = (NAME as u64, MESSAGE as u64);
+        // SYS_OPEN of out.txt with "a", then SYS_WRITE to the handle
+        write_words(&mut emu, ARGS, &[name, 8, 7, 0, 0, message, 5, 0]);
+        emu.write_bytes(NAME, b"out.txt").unwrap();
+        emu.write_bytes(MESSAGE, b"hello").unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = ARGS as u64;
+        emu.enable_history(4);
+
+        let mut states = vec![(emu.pc, emu.x)];
+        for _ in 0..30 {
+            assert_eq!(emu.run(1), StopReason::Limit);
+            states.push((emu.pc, emu.x));
+        }
+        // Long enough for the clock to read differently if it were read again
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        for (step, state) in states.iter().enumerate().rev().skip(1) {
+            assert_eq!(emu.reverse_step(), StopReason::Limit);
+            assert_eq!(emu.history_position(), Some(step as u64));
+            assert_eq!((emu.pc, emu.x), *state);
+        }
+        for state in &states[1..] {
+            assert_eq!(emu.run(1), StopReason::Limit);
+            assert_eq!((emu.pc, emu.x), *state);
+        }
+        // The file was opened and written once
+        assert_eq!(
+            std::fs::read(temp.path().join("out.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    fn write_words(emu: &mut Emulator, addr: usize, words: &[u64]) {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(addr, &bytes).unwrap();
+    }
+}
@@ -0,0 +1,661 @@
+//! Saving the entire state of an emulator and restoring it later, possibly in another process.
+//!
+//! On disk a snapshot starts with [`MAGIC`] and a little endian `u32` version, followed by the
+//! hart state, the csrs, the written pages of every RAM and ROM region and the state of every
+//! device, all little endian. Pages that have never been written are left out, so a snapshot is
+//! about as large as the memory the guest has touched.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::{
+    clint::Clint,
+    csr::MachineCsrs,
+    hpm::HPM_COUNTERS,
+    mem::{Page, RegionKind, PAGE_SIZE},
+    Emulator, Privilege,
+};
+
+/// The bytes every snapshot file starts with.
+pub const MAGIC: &[u8; 8] = b"RVSNAPSH";
+/// The version of the format written by [`Snapshot::write_to`].
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The data isn't a snapshot, or is truncated.
+    Corrupt,
+    UnsupportedVersion(u32),
+    /// The snapshot was taken of an emulator implementing a different ISA.
+    IsaMismatch {
+        snapshot: String,
+        emulator: String,
+    },
+    /// The snapshot was taken of an emulator with a different memory map.
+    MemoryMapMismatch,
+    /// The snapshot was taken of an emulator with different devices attached.
+    DeviceMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{err}"),
+            SnapshotError::Corrupt => write!(f, "not a snapshot, or the snapshot is corrupt"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported snapshot version {version}, expected {VERSION}"
+                )
+            }
+            SnapshotError::IsaMismatch { snapshot, emulator } => write!(
+                f,
+                "the snapshot is of a {snapshot} core but the emulator implements {emulator}"
+            ),
+            SnapshotError::MemoryMapMismatch => {
+                write!(f, "the snapshot was taken with a different memory map")
+            }
+            SnapshotError::DeviceMismatch => {
+                write!(f, "the snapshot was taken with different devices attached")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt,
+            _ => SnapshotError::Io(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RegionState {
+    name: String,
+    kind: RegionKind,
+    base: usize,
+    size: usize,
+    pages: Vec<(usize, Arc<Page>)>,
+}
+
+#[derive(Debug, Clone)]
+struct DeviceState {
+    range: Range<usize>,
+    state: Vec<u8>,
+}
+
+/// The complete state of an emulator at some point in time, taken by [`Emulator::snapshot`].
+///
+/// Memory isn't copied when taking a snapshot. Its pages are shared with the emulator until the
+/// emulator writes to them, so a snapshot costs about as much memory as is written after it.
+///
+/// Breakpoints, watchpoints and the JIT aren't part of the state of the machine, so they are
+/// neither saved nor restored.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    isa: String,
+    pc: u64,
+    privilege: Privilege,
+    waiting: bool,
+    reservation: usize,
+    x: [u64; 32],
+    csrs: MachineCsrs,
+    /// `mtime` and `mtimecmp`.
+    timer: [u64; 2],
+    regions: Vec<RegionState>,
+    devices: Vec<DeviceState>,
+}
+
+impl Snapshot {
+    /// Write the snapshot in the current version of the on disk format.
+    pub fn write_to(&self, mut w: impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        write_bytes(&mut w, self.isa.as_bytes())?;
+        write_u64(&mut w, self.pc)?;
+        w.write_all(&[u64::from(self.privilege) as u8, self.waiting as u8])?;
+        write_u64(&mut w, self.reservation as u64)?;
+        for reg in self.x {
+            write_u64(&mut w, reg)?;
+        }
+        for val in csr_values(&self.csrs, self.timer) {
+            write_u64(&mut w, val)?;
+        }
+        write_u64(&mut w, self.regions.len() as u64)?;
+        for region in &self.regions {
+            write_bytes(&mut w, region.name.as_bytes())?;
+            w.write_all(&[kind_to_u8(region.kind)])?;
+            write_u64(&mut w, region.base as u64)?;
+            write_u64(&mut w, region.size as u64)?;
+            write_u64(&mut w, region.pages.len() as u64)?;
+            for (idx, page) in &region.pages {
+                write_u64(&mut w, *idx as u64)?;
+                w.write_all(&page[..])?;
+            }
+        }
+        write_u64(&mut w, self.devices.len() as u64)?;
+        for device in &self.devices {
+            write_u64(&mut w, device.range.start as u64)?;
+            write_u64(&mut w, device.range.end as u64)?;
+            write_bytes(&mut w, &device.state)?;
+        }
+        Ok(())
+    }
+
+    /// Read a snapshot written by [`Snapshot::write_to`].
+    pub fn read_from(mut r: impl Read) -> Result<Snapshot, SnapshotError> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut version = [0; 4];
+        r.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let isa = String::from_utf8(read_bytes(&mut r)?).map_err(|_| SnapshotError::Corrupt)?;
+        let pc = read_u64(&mut r)?;
+        let mut flags = [0; 2];
+        r.read_exact(&mut flags)?;
+        let privilege = Privilege::try_from(flags[0] as u64).map_err(|_| SnapshotError::Corrupt)?;
+        let waiting = flags[1] != 0;
+        let reservation = read_u64(&mut r)? as usize;
+        let mut x = [0; 32];
+        for reg in x.iter_mut() {
+            *reg = read_u64(&mut r)?;
+        }
+        let mut values = [0; CSR_VALUES];
+        for val in values.iter_mut() {
+            *val = read_u64(&mut r)?;
+        }
+        let (csrs, timer) = csrs_from_values(&values);
+
+        let mut regions = Vec::new();
+        for _ in 0..read_u64(&mut r)? {
+            let name =
+                String::from_utf8(read_bytes(&mut r)?).map_err(|_| SnapshotError::Corrupt)?;
+            let mut kind = [0];
+            r.read_exact(&mut kind)?;
+            let kind = kind_from_u8(kind[0]).ok_or(SnapshotError::Corrupt)?;
+            let base = read_u64(&mut r)? as usize;
+            let size = read_u64(&mut r)? as usize;
+            let mut pages = Vec::new();
+            for _ in 0..read_u64(&mut r)? {
+                let idx = read_u64(&mut r)? as usize;
+                if kind == RegionKind::Mmio || idx >= size.div_ceil(PAGE_SIZE) {
+                    return Err(SnapshotError::Corrupt);
+                }
+                let mut page = [0; PAGE_SIZE];
+                r.read_exact(&mut page)?;
+                pages.push((idx, Arc::new(page)));
+            }
+            regions.push(RegionState {
+                name,
+                kind,
+                base,
+                size,
+                pages,
+            });
+        }
+
+        let mut devices = Vec::new();
+        for _ in 0..read_u64(&mut r)? {
+            let start = read_u64(&mut r)? as usize;
+            let end = read_u64(&mut r)? as usize;
+            let state = read_bytes(&mut r)?;
+            devices.push(DeviceState {
+                range: start..end,
+                state,
+            });
+        }
+
+        Ok(Snapshot {
+            isa,
+            pc,
+            privilege,
+            waiting,
+            reservation,
+            x,
+            csrs,
+            timer,
+            regions,
+            devices,
+        })
+    }
+}
+
+impl Emulator {
+    /// Capture the entire state of the emulator.
+    pub fn snapshot(&self) -> Snapshot {
+        let regions = self
+            .memory
+            .regions()
+            .map(|region| RegionState {
+                name: region.name.clone(),
+                kind: region.kind,
+                base: region.base,
+                size: region.size,
+                pages: region
+                    .allocated_pages()
+                    .map(|(idx, page)| (idx, page.clone()))
+                    .collect(),
+            })
+            .collect();
+        let devices = self
+            .devices
+            .iter()
+            .map(|device| {
+                let device = device.borrow();
+                DeviceState {
+                    range: device.address_range(),
+                    state: device.save(),
+                }
+            })
+            .collect();
+        Snapshot {
+            isa: self.isa.to_string(),
+            pc: self.pc,
+            privilege: self.privilege,
+            waiting: self.waiting,
+            reservation: self.reservation.load(Ordering::Relaxed),
+            x: self.x,
+            csrs: self.machine_csrs.clone(),
+            timer: {
+                let clint = self.clint.borrow();
+                [clint.mtime, clint.mtimecmp]
+            },
+            regions,
+            devices,
+        }
+    }
+
+    /// Return the emulator to the state captured in `snapshot`. The emulator must implement the
+    /// same ISA and have the same memory map and devices as the one the snapshot was taken of.
+    /// Nothing is changed if restoring fails.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let isa = self.isa.to_string();
+        if snapshot.isa != isa {
+            return Err(SnapshotError::IsaMismatch {
+                snapshot: snapshot.isa.clone(),
+                emulator: isa,
+            });
+        }
+        let same_map = self.memory.regions().count() == snapshot.regions.len()
+            && self
+                .memory
+                .regions()
+                .zip(&snapshot.regions)
+                .all(|(region, saved)| {
+                    (&region.name, region.kind, region.base, region.size)
+                        == (&saved.name, saved.kind, saved.base, saved.size)
+                        && saved
+                            .pages
+                            .iter()
+                            .all(|(idx, _)| *idx < region.size.div_ceil(PAGE_SIZE))
+                });
+        if !same_map {
+            return Err(SnapshotError::MemoryMapMismatch);
+        }
+        let same_devices = self.devices.len() == snapshot.devices.len()
+            && self
+                .devices
+                .iter()
+                .zip(&snapshot.devices)
+                .all(|(device, saved)| device.borrow().address_range() == saved.range);
+        if !same_devices {
+            return Err(SnapshotError::DeviceMismatch);
+        }
+
+        // Devices are the only part that can still fail, so they go first, and are put back as
+        // they were if any of them does
+        let current: Vec<Vec<u8>> = self
+            .devices
+            .iter()
+            .map(|device| device.borrow().save())
+            .collect();
+        for (idx, (device, saved)) in self.devices.iter().zip(&snapshot.devices).enumerate() {
+            let restored = device.borrow_mut().restore(&saved.state);
+            if let Err(err) = restored {
+                for (device, state) in self.devices[..=idx].iter().zip(&current) {
+                    let _ = device.borrow_mut().restore(state);
+                }
+                return Err(err);
+            }
+        }
+        for (region, saved) in self.memory.regions_mut().zip(&snapshot.regions) {
+            region.set_pages(saved.pages.iter().cloned());
+        }
+        self.pc = snapshot.pc;
+        self.privilege = snapshot.privilege;
+        self.waiting = snapshot.waiting;
+        self.reservation
+            .store(snapshot.reservation, Ordering::Relaxed);
+        self.x = snapshot.x;
+        self.machine_csrs = snapshot.csrs.clone();
+        let [mtime, mtimecmp] = snapshot.timer;
+        *self.clint.borrow_mut() = Clint { mtime, mtimecmp };
+        self.trap = None;
+        self.exit_code = None;
+        self.watch_hit = None;
+        self.trap_hit = None;
+        self.flush_decoded();
+        Ok(())
+    }
+}
+
+/// The number of values in [`csr_values`].
+const CSR_VALUES: usize = 16 + 2 * HPM_COUNTERS;
+
+/// The state of the csrs and the timer in the order they are saved. The hpm event listeners
+/// aren't saved, as they are derived from `mhpmevent`.
+fn csr_values(csrs: &MachineCsrs, timer: [u64; 2]) -> [u64; CSR_VALUES] {
+    let mut values = [0; CSR_VALUES];
+    let fixed = [
+        csrs.mstatus,
+        csrs.mtvec,
+        csrs.mip,
+        csrs.mie,
+        csrs.mcycle,
+        csrs.minstret,
+        csrs.mcounteren as u64,
+        csrs.mcountinhibit as u64,
+        csrs.mscratch,
+        csrs.mepc,
+        csrs.mcause,
+        csrs.mtval,
+        csrs.menvcfg,
+        csrs.mseccfg,
+        timer[0],
+        timer[1],
+    ];
+    values[..16].copy_from_slice(&fixed);
+    values[16..16 + HPM_COUNTERS].copy_from_slice(&csrs.mhpmcounter);
+    values[16 + HPM_COUNTERS..].copy_from_slice(&csrs.mhpmevent);
+    values
+}
+
+fn csrs_from_values(values: &[u64; CSR_VALUES]) -> (MachineCsrs, [u64; 2]) {
+    let mut csrs = MachineCsrs {
+        mstatus: values[0],
+        mtvec: values[1],
+        mip: values[2],
+        mie: values[3],
+        mcycle: values[4],
+        minstret: values[5],
+        mcounteren: values[6] as u32,
+        mcountinhibit: values[7] as u32,
+        mscratch: values[8],
+        mepc: values[9],
+        mcause: values[10],
+        mtval: values[11],
+        menvcfg: values[12],
+        mseccfg: values[13],
+        ..MachineCsrs::default()
+    };
+    csrs.mhpmcounter
+        .copy_from_slice(&values[16..16 + HPM_COUNTERS]);
+    csrs.mhpmevent.copy_from_slice(&values[16 + HPM_COUNTERS..]);
+    csrs.rebuild_hpm_listeners();
+    (csrs, [values[14], values[15]])
+}
+
+fn kind_to_u8(kind: RegionKind) -> u8 {
+    match kind {
+        RegionKind::Ram => 0,
+        RegionKind::Rom => 1,
+        RegionKind::Mmio => 2,
+    }
+}
+
+fn kind_from_u8(kind: u8) -> Option<RegionKind> {
+    match kind {
+        0 => Some(RegionKind::Ram),
+        1 => Some(RegionKind::Rom),
+        2 => Some(RegionKind::Mmio),
+        _ => None,
+    }
+}
+
+fn write_u64(w: &mut impl Write, val: u64) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+/// Write `bytes` prefixed with their length.
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::device::{BusError, Device};
+    use crate::mem::RAM_BASE;
+
+    /// Where the devices of the tests are, which is outside the memory map.
+    const FIRST: usize = 0x10000000;
+    const SECOND: usize = FIRST + 8;
+
+    /// A device with a single register, which it saves.
+    struct Latch {
+        addr: usize,
+        val: u64,
+    }
+
+    impl Device for Latch {
+        fn address_range(&self) -> Range<usize> {
+            self.addr..self.addr + 8
+        }
+
+        fn read(&mut self, _offset: usize, _width: usize) -> Result<u64, BusError> {
+            Ok(self.val)
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, val: u64) -> Result<(), BusError> {
+            self.val = val;
+            Ok(())
+        }
+
+        fn save(&self) -> Vec<u8> {
+            self.val.to_le_bytes().to_vec()
+        }
+
+        fn restore(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+            self.val = u64::from_le_bytes(state.try_into().map_err(|_| SnapshotError::Corrupt)?);
+            Ok(())
+        }
+    }
+
+    /// A device without any state to save, which rejects any that it is given.
+    struct Stateless {
+        addr: usize,
+    }
+
+    impl Device for Stateless {
+        fn address_range(&self) -> Range<usize> {
+            self.addr..self.addr + 8
+        }
+
+        fn read(&mut self, _offset: usize, _width: usize) -> Result<u64, BusError> {
+            Ok(0)
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, _val: u64) -> Result<(), BusError> {
+            Ok(())
+        }
+    }
+
+    fn latch(addr: usize) -> Rc<RefCell<Latch>> {
+        Rc::new(RefCell::new(Latch { addr, val: 0 }))
+    }
+
+    /// An emulator that has run for a little, with some of everything that is saved changed.
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(4 * PAGE_SIZE);
+        let program = [
+            0x00150513u32, // addi a0, a0, 1
+            0x34051073,    // csrw mscratch, a0
+            0x00a43023,    // sd a0, 0(s0)
+        ];
+        let program: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+        emu.write_bytes(RAM_BASE, &program).unwrap();
+        emu.set_pc(RAM_BASE as u64);
+        emu.x[8] = (RAM_BASE + 2 * PAGE_SIZE) as u64;
+        emu.set_mtime(1234);
+        emu.run(3);
+        emu
+    }
+
+    fn bytes(snapshot: &Snapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let emu = emulator();
+        let saved = bytes(&emu.snapshot());
+        let snapshot = Snapshot::read_from(&saved[..]).unwrap();
+
+        let mut restored = Emulator::new(4 * PAGE_SIZE);
+        restored.trap_hit = Some(3);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(bytes(&restored.snapshot()), saved);
+        assert_eq!(restored.trap_hit, None);
+        assert_eq!(
+            (restored.pc, restored.x[10], restored.machine_csrs.mscratch),
+            (RAM_BASE as u64 + 12, 1, 1)
+        );
+        assert_eq!(restored.clint.borrow().mtime, 1234);
+        assert_eq!(
+            restored
+                .memory
+                .read_array(RAM_BASE + 2 * PAGE_SIZE)
+                .unwrap(),
+            1u64.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn devices_are_saved_and_restored() {
+        let mut emu = emulator();
+        let (first, second) = (latch(FIRST), latch(SECOND));
+        emu.add_device(first.clone());
+        emu.add_device(second.clone());
+        emu.write_u64(FIRST, 1).unwrap();
+        emu.write_u64(SECOND, 2).unwrap();
+        let snapshot = emu.snapshot();
+        emu.write_u64(FIRST, 3).unwrap();
+        emu.write_u64(SECOND, 4).unwrap();
+        emu.restore(&snapshot).unwrap();
+        assert_eq!((first.borrow().val, second.borrow().val), (1, 2));
+
+        // Without the same devices, the snapshot doesn't apply
+        let mut other = emulator();
+        other.add_device(latch(FIRST));
+        assert!(matches!(
+            other.restore(&snapshot),
+            Err(SnapshotError::DeviceMismatch)
+        ));
+
+        // A device failing to restore leaves everything as it was, including the devices that
+        // were restored before it
+        let mut other = emulator();
+        let first = latch(FIRST);
+        other.add_device(first.clone());
+        other.add_device(Rc::new(RefCell::new(Stateless { addr: SECOND })));
+        other.write_u64(FIRST, 5).unwrap();
+        other.write_bytes(RAM_BASE + PAGE_SIZE, &[6]).unwrap();
+        let before = bytes(&other.snapshot());
+        assert!(matches!(
+            other.restore(&snapshot),
+            Err(SnapshotError::Corrupt)
+        ));
+        assert_eq!(first.borrow().val, 5);
+        assert_eq!(bytes(&other.snapshot()), before);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let saved = bytes(&emulator().snapshot());
+
+        let mut bad_magic = saved.clone();
+        bad_magic[0] ^= 1;
+        assert!(matches!(
+            Snapshot::read_from(&bad_magic[..]),
+            Err(SnapshotError::Corrupt)
+        ));
+
+        let mut bad_version = saved.clone();
+        bad_version[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Snapshot::read_from(&bad_version[..]),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+
+        // Cut off within every part of the file
+        for len in (0..saved.len()).step_by(7) {
+            assert!(
+                matches!(
+                    Snapshot::read_from(&saved[..len]),
+                    Err(SnapshotError::Corrupt)
+                ),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn pages_are_shared_until_written() {
+        let mut emu = Emulator::new(2 * PAGE_SIZE);
+        emu.write_bytes(RAM_BASE, &[1]).unwrap();
+        emu.write_bytes(RAM_BASE + PAGE_SIZE, &[2]).unwrap();
+        let snapshot = emu.snapshot();
+        emu.write_bytes(RAM_BASE + PAGE_SIZE, &[3]).unwrap();
+
+        let ram = emu.memory.region_at(RAM_BASE).unwrap();
+        let live: Vec<_> = ram.allocated_pages().collect();
+        let saved = &snapshot
+            .regions
+            .iter()
+            .find(|r| r.base == RAM_BASE)
+            .unwrap()
+            .pages;
+        assert!(Arc::ptr_eq(live[0].1, &saved[0].1));
+        assert!(!Arc::ptr_eq(live[1].1, &saved[1].1));
+        assert_eq!((live[1].1[0], saved[1].1[0]), (3, 2));
+
+        emu.restore(&snapshot).unwrap();
+        assert_eq!(emu.memory.read_array(RAM_BASE + PAGE_SIZE).unwrap(), [2]);
+    }
+}
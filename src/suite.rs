@@ -0,0 +1,615 @@
+//! Running a whole suite of test executables, such as riscv-tests or riscv-arch-test, in
+//! parallel.
+//!
+//! Every test reports its result through `tohost`, which is found from the symbol of that name,
+//! and passes if it exits with code 0. A test with `begin_signature` and `end_signature` symbols
+//! must also leave the same signature as its golden signature, if it has one. The golden
+//! signature of `add-01.elf` is `add-01.signature` next to it, or in the golden directory if one
+//! is given. In a riscof work directory, where the executable is `dut/my.elf`, it is the
+//! signature left by the reference model in `ref`.
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::htif::Htif;
+use crate::isa::Isa;
+use crate::mem::MemoryMap;
+use crate::{Emulator, StopReason};
+
+/// The number of instructions executed between checks of the timeout.
+const CHUNK: u64 = 1_000_000;
+
+/// How to run each test.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub isa: Isa,
+    pub memory: MemoryMap,
+    /// How long a test may run for before it fails.
+    pub timeout: Duration,
+    /// The most instructions a test may execute before it fails.
+    pub max_instructions: Option<u64>,
+    /// Where else to look for golden signatures.
+    pub golden: Option<PathBuf>,
+    #[cfg(feature = "jit")]
+    pub jit: bool,
+}
+
+/// A test executable.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Test {
+    /// The path of the executable relative to the directory it was found in, or as given.
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The test ran, but reported a failure, left the wrong signature or never finished.
+    Failed(String),
+    /// The test couldn't be run.
+    Error(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "PASS"),
+            Outcome::Failed(_) => write!(f, "FAIL"),
+            Outcome::Error(_) => write!(f, "ERROR"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub test: Test,
+    pub outcome: Outcome,
+    pub time: Duration,
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<5} {} ({:.2}s)",
+            self.outcome,
+            self.test.name,
+            self.time.as_secs_f64()
+        )?;
+        match &self.outcome {
+            Outcome::Passed => Ok(()),
+            Outcome::Failed(message) | Outcome::Error(message) => write!(f, ": {message}"),
+        }
+    }
+}
+
+/// Find the tests named by `patterns`, each of which is an executable, a directory searched
+/// recursively for executables, or a glob with `*` and `?` in any of its components. Files found
+/// in directories or by globs that aren't ELF files, such as disassembly, are left out.
+pub fn find_tests(patterns: &[String]) -> io::Result<Vec<Test>> {
+    let mut tests = Vec::new();
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        if pattern.contains(['*', '?']) {
+            let found = tests.len();
+            glob(
+                PathBuf::new(),
+                &path.components().collect::<Vec<_>>(),
+                &mut tests,
+            )?;
+            if tests.len() == found {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no executables match {pattern}"),
+                ));
+            }
+        } else if path.is_dir() {
+            search(path, path, &mut tests)?;
+        } else if path.is_file() {
+            tests.push(Test {
+                name: pattern.clone(),
+                path: path.to_path_buf(),
+            });
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{pattern} does not exist"),
+            ));
+        }
+    }
+    tests.sort();
+    tests.dedup();
+    Ok(tests)
+}
+
+/// Add the executables in `dir` and its subdirectories, named relative to `base`.
+fn search(base: &Path, dir: &Path, tests: &mut Vec<Test>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            search(base, &path, tests)?;
+        } else if is_elf(&path) {
+            let name = path.strip_prefix(base).unwrap_or(&path);
+            tests.push(Test {
+                name: name.display().to_string(),
+                path,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Add the executables matching the rest of a glob's components under `dir`.
+fn glob(
+    dir: PathBuf,
+    components: &[std::path::Component],
+    tests: &mut Vec<Test>,
+) -> io::Result<()> {
+    let Some((component, rest)) = components.split_first() else {
+        if is_elf(&dir) {
+            tests.push(Test {
+                name: dir.display().to_string(),
+                path: dir,
+            });
+        }
+        return Ok(());
+    };
+    let pattern = component.as_os_str().to_string_lossy();
+    if !pattern.contains(['*', '?']) {
+        return glob(dir.join(component), rest, tests);
+    }
+    let read = if dir.as_os_str().is_empty() {
+        fs::read_dir(".")
+    } else {
+        fs::read_dir(&dir)
+    };
+    // A component that isn't a directory matches nothing under it
+    let Ok(entries) = read else {
+        return Ok(());
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        if wildcard_match(pattern.as_bytes(), name.to_string_lossy().as_bytes()) {
+            glob(dir.join(name), rest, tests)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters and `?` any one.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => {
+            wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => wildcard_match(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => wildcard_match(rest, name),
+        _ => false,
+    }
+}
+
+fn is_elf(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && magic == *b"\x7fELF"
+}
+
+/// Run `tests` on `jobs` threads, calling `report` with each result as it comes in. The results
+/// are returned in the order of `tests`.
+pub fn run_all(
+    tests: &[Test],
+    options: &Options,
+    jobs: usize,
+    mut report: impl FnMut(&TestResult),
+) -> Vec<TestResult> {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut results = vec![None; tests.len()];
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, tests.len().max(1)) {
+            let (next, sender, options) = (&next, sender.clone(), options.clone());
+            scope.spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(test) = tests.get(idx) else {
+                    return;
+                };
+                if sender.send((idx, run(test, &options))).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+        for (idx, result) in receiver {
+            report(&result);
+            results[idx] = Some(result);
+        }
+    });
+    results.into_iter().flatten().collect()
+}
+
+/// Run a single test.
+pub fn run(test: &Test, options: &Options) -> TestResult {
+    let start = Instant::now();
+    let running = std::panic::AssertUnwindSafe(|| run_emulator(test, options));
+    let outcome = std::panic::catch_unwind(running).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Outcome::Error(format!("the emulator panicked: {message}"))
+    });
+    TestResult {
+        test: test.clone(),
+        outcome,
+        time: start.elapsed(),
+    }
+}
+
+fn run_emulator(test: &Test, options: &Options) -> Outcome {
+    let start = Instant::now();
+    let mut emu = Emulator::with_memory_map(options.memory.clone(), options.isa.clone());
+    #[cfg(feature = "jit")]
+    emu.set_jit(options.jit);
+    let elf = match emu.load_binary(&test.path.to_string_lossy()) {
+        Ok(elf) => elf,
+        Err(err) => return Outcome::Error(format!("failed to load: {err}")),
+    };
+    let Some(tohost) = elf.get_symbol("tohost") else {
+        return Outcome::Error("no tohost symbol".to_string());
+    };
+    let fromhost = elf.get_symbol("fromhost").map(|symbol| symbol.value);
+    let argv = vec![test.path.to_string_lossy().into_owned()];
+    for device in Htif::new(argv, None).into_devices(tohost.value, fromhost) {
+        emu.add_device(device);
+    }
+
+    let limit = options.max_instructions.unwrap_or(u64::MAX);
+    let mut executed = 0;
+    let code = loop {
+        if executed >= limit {
+            return Outcome::Failed(format!("still running after {limit} instructions"));
+        }
+        if start.elapsed() > options.timeout {
+            return Outcome::Failed(format!(
+                "timed out after {:.1}s",
+                options.timeout.as_secs_f64()
+            ));
+        }
+        let chunk = CHUNK.min(limit - executed);
+        match emu.run(chunk) {
+            StopReason::Exit(code) => break code,
+            StopReason::Limit => executed += chunk,
+            reason => return Outcome::Failed(format!("stopped at pc {:#x}: {reason}", emu.pc())),
+        }
+    };
+    if code != 0 {
+        return Outcome::Failed(format!("exited with code {code}"));
+    }
+
+    let (Some(begin), Some(end)) = (
+        elf.get_symbol("begin_signature"),
+        elf.get_symbol("end_signature"),
+    ) else {
+        return Outcome::Passed;
+    };
+    let Some(golden) = golden_signature(&test.path, options.golden.as_deref()) else {
+        return Outcome::Passed;
+    };
+    let expected = match fs::read_to_string(&golden) {
+        Ok(expected) => expected,
+        Err(err) => return Outcome::Error(format!("failed to read {}: {err}", golden.display())),
+    };
+    let Some(actual) = emu.signature(begin.value, end.value) else {
+        return Outcome::Error("the signature isn't in memory".to_string());
+    };
+    compare_signatures(&actual, &expected).map_or(Outcome::Passed, |diff| {
+        Outcome::Failed(format!(
+            "signature differs from {}: {diff}",
+            golden.display()
+        ))
+    })
+}
+
+/// The golden signature of the executable at `path`, if it has one.
+fn golden_signature(path: &Path, golden: Option<&Path>) -> Option<PathBuf> {
+    let stem = path.file_stem()?;
+    let mut candidates = vec![path.with_extension("signature")];
+    if let Some(dir) = golden {
+        candidates.push(dir.join(stem).with_extension("signature"));
+    }
+    if let Some(work_dir) = path.parent().and_then(Path::parent) {
+        if let Ok(entries) = fs::read_dir(work_dir.join("ref")) {
+            let mut signatures: Vec<_> = entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "signature"))
+                .collect();
+            signatures.sort();
+            candidates.extend(signatures.into_iter().next());
+        }
+    }
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// Where `actual` first differs from `expected`, ignoring case and blank lines.
+fn compare_signatures(actual: &str, expected: &str) -> Option<String> {
+    let words = |signature: &str| -> Vec<String> {
+        signature
+            .lines()
+            .map(|line| line.trim().to_ascii_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect()
+    };
+    let (actual, expected) = (words(actual), words(expected));
+    if let Some(line) = (0..actual.len().min(expected.len())).find(|&i| actual[i] != expected[i]) {
+        return Some(format!(
+            "line {} is {}, not {}",
+            line + 1,
+            actual[line],
+            expected[line]
+        ));
+    }
+    (actual.len() != expected.len())
+        .then(|| format!("{} lines long, not {}", actual.len(), expected.len()))
+}
+
+/// Write `results` as a JUnit XML report, as read by most CI systems.
+pub fn write_junit(results: &[TestResult], mut out: impl Write) -> io::Result<()> {
+    let count = |failed: fn(&Outcome) -> bool| {
+        results
+            .iter()
+            .filter(|result| failed(&result.outcome))
+            .count()
+    };
+    let failures = count(|outcome| matches!(outcome, Outcome::Failed(_)));
+    let errors = count(|outcome| matches!(outcome, Outcome::Error(_)));
+    let time: f64 = results.iter().map(|result| result.time.as_secs_f64()).sum();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<testsuites tests="{}" failures="{failures}" errors="{errors}" time="{time:.3}">"#,
+        results.len()
+    )?;
+    writeln!(
+        out,
+        r#"  <testsuite name="riscv" tests="{}" failures="{failures}" errors="{errors}" time="{time:.3}">"#,
+        results.len()
+    )?;
+    for result in results {
+        let name = Path::new(&result.test.name);
+        let class = name
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map_or("riscv".to_string(), |parent| {
+                let parent = parent.display().to_string();
+                parent
+                    .trim_start_matches(['/', '\\'])
+                    .replace(['/', '\\'], ".")
+            });
+        write!(
+            out,
+            r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+            escape(&name.file_name().unwrap_or_default().to_string_lossy()),
+            escape(&class),
+            result.time.as_secs_f64()
+        )?;
+        match &result.outcome {
+            Outcome::Passed => writeln!(out, "/>")?,
+            Outcome::Failed(message) => writeln!(
+                out,
+                r#"><failure message="{}"/></testcase>"#,
+                escape(message)
+            )?,
+            Outcome::Error(message) => {
+                writeln!(out, r#"><error message="{}"/></testcase>"#, escape(message))?
+            }
+        }
+    }
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")
+}
+
+/// Escape `text` for an XML attribute.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' | '\t' => {
+                let _ = write!(escaped, "&#{};", c as u32);
+            }
+            // Other control characters can't appear in XML at all
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::tests::TempDir;
+
+    /// Create the file `name` in `dir`, with the ELF magic if `elf`.
+    fn create(dir: &Path, name: &str, elf: bool) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let contents: &[u8] = if elf {
+            b"\x7fELF\x02\x01"
+        } else {
+            b"disassembly"
+        };
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn names(tests: &[Test]) -> Vec<&str> {
+        tests.iter().map(|test| test.name.as_str()).collect()
+    }
+
+    #[test]
+    fn wildcards_match_names() {
+        let matches =
+            |pattern: &str, name: &str| wildcard_match(pattern.as_bytes(), name.as_bytes());
+        assert!(matches("add.elf", "add.elf"));
+        assert!(!matches("add.elf", "add.elf2"));
+        assert!(matches("*", ""));
+        assert!(matches("*.elf", "add.elf"));
+        assert!(!matches("*.elf", "add.dump"));
+        assert!(matches("rv64ui-p-*", "rv64ui-p-add"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(!matches("a*b*c", "aXXbYY"));
+        assert!(matches("add-0?.elf", "add-01.elf"));
+        assert!(!matches("add-0?.elf", "add-0.elf"));
+        assert!(!matches("", "add"));
+    }
+
+    #[test]
+    fn tests_are_found_in_directories_and_by_globs() {
+        let temp = TempDir::new("suite-find");
+        let dir = temp.path();
+        let add = create(dir, "rv64ui/add.elf", true);
+        create(dir, "rv64ui/add.dump", false);
+        create(dir, "rv64um/mul.elf", true);
+        create(dir, "rv64um/deep/div", true);
+
+        let found = find_tests(&[dir.display().to_string()]).unwrap();
+        assert_eq!(
+            names(&found),
+            ["rv64ui/add.elf", "rv64um/deep/div", "rv64um/mul.elf"]
+        );
+        assert_eq!(found[0].path, add);
+
+        // Globs match each component, leaving out what isn't an executable
+        let pattern = format!("{}/rv64u?/*", dir.display());
+        let found = find_tests(std::slice::from_ref(&pattern)).unwrap();
+        let expected = [
+            format!("{}/rv64ui/add.elf", dir.display()),
+            format!("{}/rv64um/mul.elf", dir.display()),
+        ];
+        assert_eq!(names(&found), expected);
+        // A test found twice is run once
+        let found = find_tests(&[pattern, expected[0].clone()]).unwrap();
+        assert_eq!(names(&found), expected);
+
+        // An executable named directly is run even if it doesn't look like one
+        let dump = format!("{}/rv64ui/add.dump", dir.display());
+        assert_eq!(
+            names(&find_tests(std::slice::from_ref(&dump)).unwrap()),
+            [dump]
+        );
+
+        let missing = format!("{}/rv64ua", dir.display());
+        let error = find_tests(&[missing]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let unmatched = format!("{}/*/sub.elf", dir.display());
+        let error = find_tests(&[unmatched]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn golden_signatures_are_found() {
+        let temp = TempDir::new("suite-golden");
+        let dir = temp.path();
+        let add = create(dir, "tests/add-01.elf", true);
+        let sub = create(dir, "tests/sub-01.elf", true);
+        assert_eq!(golden_signature(&add, None), None);
+
+        let golden = create(dir, "golden/add-01.signature", false);
+        assert_eq!(
+            golden_signature(&add, Some(&dir.join("golden"))),
+            Some(golden.clone())
+        );
+        // One next to the executable comes first
+        let beside = create(dir, "tests/add-01.signature", false);
+        assert_eq!(
+            golden_signature(&add, Some(&dir.join("golden"))),
+            Some(beside)
+        );
+        assert_eq!(golden_signature(&sub, Some(&dir.join("golden"))), None);
+
+        // In a riscof work directory, the reference model leaves its signature in ref
+        let riscof = create(dir, "work/add-01/dut/my.elf", true);
+        let reference = create(
+            dir,
+            "work/add-01/ref/Reference-sail_c_simulator.signature",
+            false,
+        );
+        create(dir, "work/add-01/ref/ref.elf", true);
+        assert_eq!(golden_signature(&riscof, None), Some(reference));
+    }
+
+    #[test]
+    fn signatures_are_compared_by_line() {
+        let expected = "deadbeef\n00000001\n";
+        assert_eq!(compare_signatures("DEADBEEF\n\n00000001", expected), None);
+        assert_eq!(
+            compare_signatures("  deadbeef \r\n00000001\n\n", expected),
+            None
+        );
+        assert_eq!(
+            compare_signatures("deadbeef\n00000002\n", expected),
+            Some("line 2 is 00000002, not 00000001".to_string())
+        );
+        assert_eq!(
+            compare_signatures("deadbeef\n", expected),
+            Some("1 lines long, not 2".to_string())
+        );
+    }
+
+    #[test]
+    fn results_are_written_as_junit() {
+        let result = |name: &str, outcome, millis| TestResult {
+            test: Test {
+                name: name.to_string(),
+                path: PathBuf::from(name),
+            },
+            outcome,
+            time: Duration::from_millis(millis),
+        };
+        let results = [
+            result("rv64ui/add.elf", Outcome::Passed, 250),
+            result(
+                "/abs/rv64um/mul.elf",
+                Outcome::Failed("exited with 3 & <more>".to_string()),
+                500,
+            ),
+            result("sub.elf", Outcome::Error("not \"an ELF\"\n".to_string()), 0),
+        ];
+        let mut out = Vec::new();
+        write_junit(&results, &mut out).unwrap();
+        let expected = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites tests="3" failures="1" errors="1" time="0.750">
+  <testsuite name="riscv" tests="3" failures="1" errors="1" time="0.750">
+    <testcase name="add.elf" classname="rv64ui" time="0.250"/>
+    <testcase name="mul.elf" classname="abs.rv64um" time="0.500"><failure message="exited with 3 &amp; &lt;more&gt;"/></testcase>
+    <testcase name="sub.elf" classname="riscv" time="0.000"><error message="not &quot;an ELF&quot;&#10;"/></testcase>
+  </testsuite>
+</testsuites>
+"#;
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn attributes_are_escaped() {
+        assert_eq!(escape("a<b>&\"c\""), "a&lt;b&gt;&amp;&quot;c&quot;");
+        assert_eq!(escape("tab\there\n"), "tab&#9;here&#10;");
+        assert_eq!(escape("bell\x07 and nul\0"), "bell and nul");
+        assert_eq!(escape("plain 'quotes' ok"), "plain 'quotes' ok");
+    }
+}
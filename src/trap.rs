@@ -11,6 +11,17 @@ pub enum Trap {
 }
 
 impl Trap {
+    pub const ALL: [Trap; 8] = [
+        Trap::InstrAddrMisaligned,
+        Trap::InstrAccessFault,
+        Trap::IllegalInstruction,
+        Trap::Breakpoint,
+        Trap::LoadAccessFault,
+        Trap::StoreAccessFault,
+        Trap::ECallU,
+        Trap::ECallM,
+    ];
+
     pub fn to_code(self) -> u64 {
         match self {
             Trap::InstrAddrMisaligned => 0,
@@ -24,3 +35,35 @@ impl Trap {
         }
     }
 }
+
+/// The interrupts that can be taken in M mode, in decreasing order of priority.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Interrupt {
+    MachineExternal,
+    MachineSoftware,
+    MachineTimer,
+    CounterOverflow,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 4] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::CounterOverflow,
+    ];
+
+    /// The exception code of the interrupt, which is also its bit in `mip` and `mie`.
+    pub fn to_code(self) -> u64 {
+        match self {
+            Interrupt::MachineSoftware => 3,
+            Interrupt::MachineTimer => 7,
+            Interrupt::MachineExternal => 11,
+            Interrupt::CounterOverflow => 13,
+        }
+    }
+
+    pub fn mask(self) -> u64 {
+        1 << self.to_code()
+    }
+}